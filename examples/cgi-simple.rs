@@ -18,7 +18,7 @@ impl Application for MyApp {
 
 You have given a query: {query}"
             ),
-            None => format!(""),
+            None => String::new(),
         };
 
         let path_message = match !request.path.is_empty() {
@@ -27,7 +27,7 @@ You have given a query: {query}"
 You have requested for the path: {}",
                 request.path
             ),
-            false => format!(""),
+            false => String::new(),
         };
 
         Ok(Response::success(
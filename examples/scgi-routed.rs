@@ -2,7 +2,7 @@ use gemfra::{
     error::AnyError,
     protocol::Scgi,
     request::Request,
-    response::Response,
+    response::{Redirect, Response},
     routed::{route, RoutedApp},
 };
 
@@ -39,10 +39,7 @@ This page was curated specifically for you \"{name}\", Congrats!
 #[route("/people")]
 async fn person_route_select(request: Request) -> Result<Response, AnyError> {
     if let Some(query) = request.query {
-        Ok(Response::redirect(format!(
-            "{}{}/{}",
-            request.script, request.path, query
-        )))
+        Ok(Redirect::to(format!("{}{}/{}", request.script, request.path, query)).into())
     } else {
         Ok(Response::input("Enter your name"))
     }
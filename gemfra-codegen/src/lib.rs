@@ -17,13 +17,154 @@
 //!     todo!()
 //! }
 //! ```
+//!
+//! ## [static_site](macro@static_site) macro
+//!
+//! A macro that embeds a directory of files into a
+//! [RoutedApp](gemfra::routed::RoutedApp) at compile time.
+//!
+//! ```ignore
+//! use gemfra_codegen::static_site;
+//!
+//! let app = static_site!("content/");
+//! ```
 
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use proc_macro::TokenStream;
 use proc_macro_error::{abort, proc_macro_error};
 use quote::{quote, quote_spanned};
-use syn::{parse_macro_input, spanned::Spanned, FnArg, Item, LitStr, Type};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    spanned::Spanned,
+    FnArg, Ident, Item, LitStr, Token, Type,
+};
+
+/// The arguments given to the route macro
+struct RouteArgs {
+    endpoint: LitStr,
+    aliases: Vec<LitStr>,
+    mime: Option<LitStr>,
+    on_error: Option<Ident>,
+}
+
+impl Parse for RouteArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let endpoint = input.parse()?;
+        let mut aliases = Vec::new();
+        let mut mime = None;
+        let mut on_error = None;
+
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            if input.peek(LitStr) {
+                aliases.push(input.parse()?);
+                continue;
+            }
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            match key.to_string().as_str() {
+                "mime" => mime = Some(input.parse()?),
+                "on_error" => on_error = Some(input.parse()?),
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        format!("Unknown route option `{key}`"),
+                    ))
+                }
+            }
+        }
+
+        Ok(Self {
+            endpoint,
+            aliases,
+            mime,
+            on_error,
+        })
+    }
+}
+
+/// Get the names of the params and named wildcards in an endpoint
+fn endpoint_params(endpoint: &LitStr) -> HashSet<String> {
+    let endpoint_val = endpoint.value();
+    let mut param_names = HashSet::new();
+    for segment in endpoint_val.split('/') {
+        if segment.starts_with(':') || segment.starts_with('*') {
+            if segment == "*" {
+                // We don't want unnamed
+                continue;
+            }
+            if !(param_names.insert(segment[1..].to_owned())) {
+                abort!(
+                    endpoint.span(),
+                    "Cannot have multiple named parameters with the same name";
+                    help = "Rename or remove one of the parameters named `{}`", &segment[1..]
+                );
+            }
+        }
+    }
+    param_names
+}
+
+/// Get the type of the shared state if a parameter is `gemfra::routed::State<S>`
+///
+/// The path may be `State`, `routed::State` or `gemfra::routed::State`.
+fn state_type(ty: &Type) -> Option<&Type> {
+    let path = match ty {
+        Type::Path(path) if path.qself.is_none() => &path.path,
+        _ => return None,
+    };
+    let idents: Vec<_> = path.segments.iter().map(|s| s.ident.to_string()).collect();
+    let idents: Vec<_> = idents.iter().map(String::as_str).collect();
+    let full = ["gemfra", "routed", "State"];
+    let matches = match path.leading_colon {
+        Some(_) => idents == full,
+        None => !idents.is_empty() && full.ends_with(&idents),
+    };
+    if !matches {
+        return None;
+    }
+    match &path.segments.last()?.arguments {
+        syn::PathArguments::AngleBracketed(args) if args.args.len() == 1 => match &args.args[0] {
+            syn::GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Check whether a parameter is marked with `#[query]`
+fn is_query(arg: &syn::PatType) -> bool {
+    arg.attrs.iter().any(|attr| attr.path.is_ident("query"))
+}
+
+/// Get the type that wraps a query parameter (`Vec` or `Option`) and the type
+/// of its values
+fn query_wrapper(ty: &Type) -> Option<(String, &Type)> {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last()?,
+        _ => return None,
+    };
+    let wrapper = segment.ident.to_string();
+    if wrapper != "Vec" && wrapper != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) => match args.args.first()? {
+            syn::GenericArgument::Type(inner) => Some((wrapper, inner)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
 
 /// Convert the provided route into a struct that implements [Route](gemfra::routed::Route).
 ///
@@ -46,9 +187,47 @@ use syn::{parse_macro_input, spanned::Spanned, FnArg, Item, LitStr, Type};
 /// [FromStr](std::str::FromStr). The param will be parsed, and if it fails, a
 /// `51 File not found` will be sent.
 ///
+/// A parameter with the type `State<S>` will be given the shared state of the
+/// app, see [State](gemfra::routed::State). The name of the parameter doesn't
+/// matter.
+///
 /// > Note that currently, it is not possible to have mutliple routes with the
 /// > same endpoint, but different parameter types.
 ///
+/// Parameters marked with `#[query]` are read from a query of `key=value`
+/// pairs instead of the endpoint, see [query_values](gemfra::request::Request::query_values).
+/// The type of the parameter decides how many values are taken:
+///
+/// * `Vec<T>`: Every value of the key, which may be empty.
+/// * `Option<T>`: The first value of the key, if there is one.
+/// * `T`: The first value of the key. If the key is missing, a
+///   `59 Bad Request` will be sent.
+///
+/// Each value is parsed with [FromStr](std::str::FromStr), and if any value
+/// fails to parse, a `59 Bad Request` will be sent. Query values are decoded,
+/// so use `String` rather than `&str`.
+///
+/// The route function returns a `Result`. The success value can be anything
+/// that converts into a [Response](gemfra::response::Response), such as a
+/// [Redirect](gemfra::response::Redirect), and the error can be anything that
+/// converts into an [AnyError](gemfra::error::AnyError). See
+/// [IntoRouteResult](gemfra::routed::IntoRouteResult).
+///
+/// Additional endpoints can be given after the first to register the route
+/// under aliases, for example `#[route("/x/:id", "/y/:id")]`. Every parameter
+/// of the route function must exist in all of the endpoints.
+///
+/// After the endpoints, the following options can be given:
+///
+/// * `mime = "..."`: The MIME type the route is expected to respond with. See
+///   [content_hint](gemfra::routed::Route::content_hint).
+/// * `on_error = ...`: The error type that errors from the route are sent as,
+///   instead of __42__ CGI Error. This is the [slug](gemfra::error::GemErrorType::slug)
+///   of the error type, such as `not_found` or `bad_request`. A [GemError](gemfra::error::GemError)
+///   returned by the route keeps its own error type, so the route can still
+///   choose a different response for specific errors. Other errors are logged
+///   and replaced with the default message of the error type.
+///
 /// ### Examples
 ///
 /// ```
@@ -69,34 +248,45 @@ use syn::{parse_macro_input, spanned::Spanned, FnArg, Item, LitStr, Type};
 ///     Ok(Response::success("text/gemini", format!("# Hello {my_var}")))
 /// }
 ///
-/// #[route("/foo/:year")]
+/// #[route("/foo/:year", mime = "text/gemini")]
 /// async fn typed_param(_request: Request, year: i32) -> Result<Response, AnyError> {
 ///     // Any non i32 value for year will result in a `51 File not found`
 ///     Ok(Response::success("text/gemini", format!("# The year is {year}")))
 /// }
+///
+/// #[route("/search")]
+/// async fn search(
+///     _request: Request,
+///     #[query] tag: Vec<String>,
+///     #[query] limit: Option<u32>,
+/// ) -> Result<Response, AnyError> {
+///     // For `/search?tag=rust&tag=web`, tag will be `["rust", "web"]`
+///     let limit = limit.unwrap_or(10);
+///     Ok(Response::success("text/gemini", format!("# Top {limit} results for {tag:?}")))
+/// }
+///
+/// #[route("/page/:name", on_error = not_found)]
+/// async fn page(_request: Request, name: &str) -> Result<Response, AnyError> {
+///     // Failing to read the page will result in a `51 File not found`
+///     let body = std::fs::read_to_string(format!("pages/{name}.gmi"))?;
+///     Ok(Response::gemtext(body))
+/// }
 /// ```
 #[proc_macro_error]
 #[proc_macro_attribute]
 pub fn route(args: TokenStream, input: TokenStream) -> TokenStream {
-    let endpoint = parse_macro_input!(args as LitStr);
+    let RouteArgs {
+        endpoint,
+        aliases,
+        mime,
+        on_error,
+    } = parse_macro_input!(args as RouteArgs);
 
-    let endpoint_val = endpoint.value();
-    let mut param_names = HashSet::new();
-    for segment in endpoint_val.split("/") {
-        if segment.starts_with(":") || segment.starts_with("*") {
-            if segment == "*" {
-                // We don't want unnamed
-                continue;
-            }
-            if !(param_names.insert(segment[1..].to_owned())) {
-                abort!(
-                    endpoint.span(),
-                    "Cannot have multiple named parameters with the same name";
-                    help = "Rename or remove one of the parameters named `{}`", &segment[1..]
-                );
-            }
-        }
-    }
+    let param_names = endpoint_params(&endpoint);
+    let alias_params: Vec<_> = aliases
+        .iter()
+        .map(|alias| (alias, endpoint_params(alias)))
+        .collect();
 
     let input = parse_macro_input!(input as Item);
 
@@ -108,12 +298,13 @@ pub fn route(args: TokenStream, input: TokenStream) -> TokenStream {
         }
     };
     let name = &func.sig.ident;
-    let return_ty = &func.sig.output;
     let block = &func.block;
 
     // Extract all the parameters
     let mut request_arg = None;
     let mut params = Vec::new();
+    let mut states = Vec::new();
+    let mut queries = Vec::new();
     for arg in &func.sig.inputs {
         if let FnArg::Typed(arg) = arg {
             if let syn::Pat::Ident(ident) = arg.pat.as_ref() {
@@ -122,7 +313,17 @@ pub fn route(args: TokenStream, input: TokenStream) -> TokenStream {
                     arg_name.remove(0);
                 }
                 if arg_name == "request" {
-                    request_arg = Some(arg);
+                    request_arg = Some((arg, ident));
+                } else if let Some(state) = state_type(&arg.ty) {
+                    states.push((ident, &arg.ty, state));
+                } else if is_query(arg) {
+                    if let Type::Reference(r) = arg.ty.as_ref() {
+                        abort!(
+                            r.span(), "Query parameters can't be references";
+                            help = "Use an owned type such as `String`"
+                        );
+                    }
+                    queries.push((ident, &arg.ty, LitStr::new(&arg_name, ident.ident.span())));
                 } else {
                     if !param_names.contains(&arg_name) {
                         abort!(
@@ -130,6 +331,14 @@ pub fn route(args: TokenStream, input: TokenStream) -> TokenStream {
                             note = endpoint.span() => "Add `{}` to the endpoint", arg_name
                         );
                     }
+                    for (alias, names) in &alias_params {
+                        if !names.contains(&arg_name) {
+                            abort!(
+                                arg.span(), "Parameter `{}` not in endpoint {}", arg_name, alias.value();
+                                note = alias.span() => "Every endpoint must have the parameter `{}`", arg_name
+                            );
+                        }
+                    }
 
                     let ty = &arg.ty;
                     let param_lit = LitStr::new(&arg_name, ident.ident.span());
@@ -142,7 +351,7 @@ pub fn route(args: TokenStream, input: TokenStream) -> TokenStream {
                     if let Type::Reference(r) = ty.as_ref() {
                         if let Type::Path(path) = r.elem.as_ref() {
                             if let Some(segment) = path.path.segments.first() {
-                                if segment.ident.to_string() == "str" {
+                                if segment.ident == "str" {
                                     params.push(quote_spanned! {arg.span()=>
                                         let #ident: #ty = #get_param;
                                     });
@@ -163,27 +372,297 @@ pub fn route(args: TokenStream, input: TokenStream) -> TokenStream {
             }
         }
     }
-    let request_arg = match request_arg {
+    let (request_arg, request_ident) = match request_arg {
         Some(v) => v,
         None => {
             abort!(func.sig.span(), "input `request` is a required parameter");
         }
     };
 
+    // The shared state is stored in the request extensions by the RoutedApp
+    let request_ident = &request_ident.ident;
+    let state = states.first().map(|(_, _, state)| *state);
+    for (_, ty, other) in &states {
+        let same = |state: &Type| quote!(#state).to_string() == quote!(#other).to_string();
+        if state.is_some_and(|state| !same(state)) {
+            abort!(
+                ty.span(), "All `State` parameters must have the same type";
+                note = state.span() => "The state was first given as this type"
+            );
+        }
+    }
+    for (ident, ty, _) in states {
+        params.push(quote_spanned! {ty.span()=>
+            let #ident: #ty = gemfra::error::ToGemError::replace_gem(
+                #request_ident.extensions.get::<#ty>().cloned(),
+                gemfra::error::GemErrorType::RuntimeError,
+                "The app does not have the requested state",
+            )?;
+        });
+    }
+
+    // Query parameters are parsed from the query of the request
+    for (ident, ty, key) in queries {
+        let values = quote! { #request_ident.query_values(#key).into_iter() };
+        let parse = match query_wrapper(ty) {
+            Some((wrapper, _)) if wrapper == "Vec" => quote! {
+                #values.map(|value| value.parse()).collect::<Result<#ty, _>>()
+            },
+            Some(_) => quote! {
+                #values.next().map(|value| value.parse()).transpose()
+            },
+            None => quote! {
+                gemfra::error::ToGemError::replace_gem(
+                    #values.next(),
+                    gemfra::error::GemErrorType::BadRequest,
+                    concat!("Missing query parameter `", #key, "`"),
+                )?
+                .parse()
+            },
+        };
+        params.push(quote_spanned! {ty.span()=>
+            let #ident: #ty = gemfra::error::ToGemError::into_gem_type(
+                #parse,
+                gemfra::error::GemErrorType::BadRequest
+            )?;
+        });
+    }
+
+    let content_hint = mime.as_ref().map(|mime| {
+        quote! {
+            fn content_hint(&self) -> Option<&str> {
+                Some(#mime)
+            }
+        }
+    });
+
+    // A route with state can only be registered to an app with the same state
+    let (route_impl, inherent) = match state {
+        Some(state) => (quote! { impl gemfra::routed::Route<#state> for #name }, None),
+        None => {
+            // The route is implemented for every state, so the metadata is
+            // also given as methods of the route to keep calls unambiguous
+            let mime = match &mime {
+                Some(mime) => quote! { Some(#mime) },
+                None => quote! { None },
+            };
+            let inherent = quote! {
+                #[allow(dead_code)]
+                impl #name {
+                    fn endpoint(&self) -> &'static str {
+                        #endpoint
+                    }
+
+                    fn aliases(&self) -> &'static [&'static str] {
+                        &[#(#aliases),*]
+                    }
+
+                    fn content_hint(&self) -> Option<&'static str> {
+                        #mime
+                    }
+                }
+            };
+            (
+                quote! { impl<GemfraState> gemfra::routed::Route<GemfraState> for #name },
+                Some(inherent),
+            )
+        }
+    };
+
+    let output = match &func.sig.output {
+        syn::ReturnType::Type(_, ty) => ty,
+        syn::ReturnType::Default => {
+            abort!(func.sig.span(), "The route must return a result");
+        }
+    };
+    // Errors from the body are sent with the configured error type
+    let on_error = on_error.as_ref().map(|slug| {
+        // The slug is resolved by gemfra, so unknown slugs fail to compile
+        let slug_str = LitStr::new(&slug.to_string(), slug.span());
+        let message = LitStr::new(&format!("Unknown error type `{slug}`"), slug.span());
+        let error_type = quote_spanned! {slug.span()=>
+            const ON_ERROR: gemfra::error::GemErrorType =
+                match gemfra::error::GemErrorType::from_slug(#slug_str) {
+                    Some(error_type) => error_type,
+                    None => panic!(#message),
+                };
+        };
+        quote! {
+            #error_type
+            let result = match result {
+                Ok(response) => Ok(response),
+                // Closing the connection is not an error to report
+                Err(err) if err.is::<gemfra::error::CloseConnection>() => Err(err),
+                Err(err) => match err.downcast::<gemfra::error::GemError>() {
+                    Ok(err) => Ok(gemfra::response::Response::from(*err)),
+                    Err(err) => {
+                        eprintln!("Error while handling request: {err}");
+                        let error_type = ON_ERROR;
+                        Ok(gemfra::response::Response::from(gemfra::error::GemError::new(
+                            error_type,
+                            error_type.to_string(),
+                        )))
+                    }
+                },
+            };
+        }
+    });
+    let body = quote! {
+        // Give the async block the return type of the route
+        fn route_body<F: ::std::future::Future<Output = #output>>(f: F) -> F {
+            f
+        }
+        let result = gemfra::routed::IntoRouteResult::into_route_result(
+            route_body(async move #block).await
+        );
+        #on_error
+        result
+    };
+
+    let aliases = if aliases.is_empty() {
+        None
+    } else {
+        Some(quote! {
+            fn aliases(&self) -> &[&str] {
+                &[#(#aliases),*]
+            }
+        })
+    };
+
     TokenStream::from(quote! {
         #[allow(non_camel_case_types)]
         struct #name;
 
+        #inherent
+
         #[async_trait::async_trait]
-        impl gemfra::routed::Route for #name {
+        #route_impl {
             fn endpoint(&self) -> &str {
                 #endpoint
             }
 
-            async fn handle(&self, params: &gemfra::routed::Params, #request_arg) #return_ty {
+            #aliases
+
+            #content_hint
+
+            async fn handle(
+                &self,
+                params: &gemfra::routed::Params,
+                #request_arg
+            ) -> Result<gemfra::response::Response, gemfra::error::AnyError> {
                 #(#params)*
-                #block
+                #body
             }
         }
     })
 }
+
+/// Recursively collect the files in `dir`, skipping hidden files
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_none_or(|name| name.starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Embed a directory of files into a [RoutedApp](gemfra::routed::RoutedApp).
+///
+/// The directory is relative to the root of the crate (the directory with the
+/// `Cargo.toml`). Every file in the directory is included in the binary with
+/// [include_bytes] and served by a
+/// [StaticRoute](gemfra::routed::StaticRoute) at its path relative to the
+/// directory, so the site has no runtime disk access.
+///
+/// * The MIME type is guessed from the file extension with
+///   [mime_for_extension](gemfra::response::mime_for_extension).
+/// * `index.gmi` files are also served at their directory, so
+///   `content/blog/index.gmi` is served at both `/blog/index.gmi` and
+///   `/blog/`.
+/// * Hidden files and files whose names contain `:` or `*` are skipped.
+///
+/// The macro evaluates to a `RoutedApp`, so more routes can be registered
+/// after it is created.
+///
+/// > Changes to embedded files are picked up automatically, but adding or
+/// > removing files requires the crate to be rebuilt.
+///
+/// ### Example
+///
+/// ```ignore
+/// use gemfra::protocol::Cgi;
+/// use gemfra_codegen::static_site;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let app = static_site!("content/");
+///     app.run_cgi().await;
+/// }
+/// ```
+#[proc_macro_error]
+#[proc_macro]
+pub fn static_site(input: TokenStream) -> TokenStream {
+    let dir = parse_macro_input!(input as LitStr);
+
+    let root = match std::env::var("CARGO_MANIFEST_DIR") {
+        Ok(manifest) => Path::new(&manifest).join(dir.value()),
+        Err(_) => PathBuf::from(dir.value()),
+    };
+    let mut files = Vec::new();
+    if let Err(err) = collect_files(&root, &mut files) {
+        abort!(dir.span(), "Unable to read {}: {}", root.display(), err);
+    }
+    files.sort();
+
+    let routes = files.iter().filter_map(|path| {
+        let relative = path.strip_prefix(&root).ok()?;
+        let segments = relative
+            .components()
+            .map(|component| component.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()?;
+        if segments.iter().any(|segment| segment.contains([':', '*'])) {
+            return None;
+        }
+        let endpoint = format!("/{}", segments.join("/"));
+        let aliases = endpoint
+            .strip_suffix("index.gmi")
+            .filter(|dir| dir.ends_with('/'))
+            .map(|dir| vec![dir.to_owned()])
+            .unwrap_or_default();
+
+        let file = path.to_str()?;
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default();
+        Some(quote! {
+            app.register_owned(
+                gemfra::routed::StaticRoute::new(
+                    #endpoint,
+                    gemfra::response::mime_for_extension(#extension),
+                    include_bytes!(#file),
+                )
+                .with_aliases(&[#(#aliases),*])
+            );
+        })
+    });
+
+    TokenStream::from(quote! {
+        {
+            let mut app = gemfra::routed::RoutedApp::new();
+            #(#routes)*
+            app
+        }
+    })
+}
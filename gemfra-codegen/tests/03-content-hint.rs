@@ -0,0 +1,17 @@
+use gemfra::{error::AnyError, request::Request, response::Response, routed::Route};
+use gemfra_codegen::route;
+
+#[route("/", mime = "text/gemini")]
+async fn my_route(_request: Request) -> Result<Response, AnyError> {
+    Ok(Response::success("text/gemini", "# Hello World!"))
+}
+
+#[route("/plain")]
+async fn plain_route(_request: Request) -> Result<Response, AnyError> {
+    Ok(Response::success("text/plain", "Hello World!"))
+}
+
+fn main() {
+    assert_eq!(my_route.content_hint(), Some("text/gemini"));
+    assert_eq!(plain_route.content_hint(), None);
+}
@@ -0,0 +1,12 @@
+use gemfra::{error::AnyError, request::Request, response::Response, routed::Route};
+use gemfra_codegen::route;
+
+#[route("/x/:id", "/y/:id", mime = "text/plain")]
+async fn my_route(_request: Request, id: u32) -> Result<Response, AnyError> {
+    Ok(Response::success("text/plain", id.to_string()))
+}
+
+fn main() {
+    assert_eq!(my_route.endpoint(), "/x/:id");
+    assert_eq!(my_route.aliases(), &["/y/:id"]);
+}
@@ -0,0 +1,11 @@
+use gemfra_codegen::route;
+
+#[route("/x/:id", "/y/:name")]
+async fn my_route(
+    _request: gemfra::request::Request,
+    id: u32,
+) -> Result<gemfra::response::Response, gemfra::error::AnyError> {
+    Ok(gemfra::response::Response::success("text/plain", id.to_string()))
+}
+
+fn main() {}
@@ -0,0 +1,28 @@
+use std::sync::Arc;
+
+use gemfra::{
+    application::Application,
+    error::AnyError,
+    request::Request,
+    response::Response,
+    routed::{RoutedApp, State},
+};
+use gemfra_codegen::route;
+
+#[route("/:name")]
+async fn my_route(_request: Request, name: &str, count: State<Arc<u32>>) -> Result<Response, AnyError> {
+    Ok(Response::success("text/plain", format!("{name} {}", *count)))
+}
+
+#[route("/full/path")]
+async fn full_path(_request: Request, count: gemfra::routed::State<Arc<u32>>) -> Result<Response, AnyError> {
+    Ok(Response::success("text/plain", count.to_string()))
+}
+
+fn main() {
+    let mut app = RoutedApp::with_state(Arc::new(3));
+    app.register(&my_route);
+    app.register(&full_path);
+
+    let _: &dyn Application = &app;
+}
@@ -0,0 +1,20 @@
+use gemfra::{
+    error::{AnyError, GemError, GemErrorType},
+    request::Request,
+    response::Response,
+    routed::Route,
+};
+use gemfra_codegen::route;
+
+#[route("/page/:name", on_error = not_found)]
+async fn page(_request: Request, name: &str) -> Result<Response, AnyError> {
+    if name == "gone" {
+        return Err(GemError::new(GemErrorType::Gone, "Gone").into());
+    }
+    let number: u32 = name.parse()?;
+    Ok(Response::text(number.to_string()))
+}
+
+fn main() {
+    assert_eq!(page.endpoint(), "/page/:name");
+}
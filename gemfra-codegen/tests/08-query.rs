@@ -0,0 +1,18 @@
+use gemfra::{error::AnyError, request::Request, response::Response, routed::Route};
+use gemfra_codegen::route;
+
+#[route("/search/:kind")]
+async fn search(
+    _request: Request,
+    kind: &str,
+    #[query] q: String,
+    #[query] tag: Vec<String>,
+    #[query] year: Vec<i32>,
+    #[query] page: Option<u32>,
+) -> Result<Response, AnyError> {
+    Ok(Response::text(format!("{kind} {q} {tag:?} {year:?} {page:?}")))
+}
+
+fn main() {
+    assert_eq!(search.endpoint(), "/search/:kind");
+}
@@ -0,0 +1,19 @@
+use gemfra::{
+    error::GemError,
+    request::Request,
+    response::Redirect,
+    routed::Route,
+};
+use gemfra_codegen::route;
+
+#[route("/old/*path", on_error = gone)]
+async fn old(_request: Request, path: &str) -> Result<Redirect, GemError> {
+    if path.is_empty() {
+        return Err(GemError::bad_request("Missing path"));
+    }
+    Ok(Redirect::permanent(format!("/new/{path}")))
+}
+
+fn main() {
+    assert_eq!(old.endpoint(), "/old/*path");
+}
@@ -0,0 +1,17 @@
+use gemfra::{
+    error::AnyError,
+    request::Request,
+    response::Response,
+    routed::{RoutedApp, State},
+};
+use gemfra_codegen::route;
+
+#[route("/")]
+async fn my_route(_request: Request, count: State<u32>) -> Result<Response, AnyError> {
+    Ok(Response::success("text/plain", count.to_string()))
+}
+
+fn main() {
+    let mut app = RoutedApp::with_state("not a number");
+    app.register(&my_route);
+}
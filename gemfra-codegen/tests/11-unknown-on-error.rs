@@ -0,0 +1,9 @@
+use gemfra::{error::AnyError, request::Request, response::Response};
+use gemfra_codegen::route;
+
+#[route("/", on_error = not_a_slug)]
+async fn my_route(_request: Request) -> Result<Response, AnyError> {
+    Ok(Response::success("text/plain", "Hello"))
+}
+
+fn main() {}
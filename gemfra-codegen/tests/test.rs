@@ -3,4 +3,13 @@ fn macro_tests() {
     let t = trybuild::TestCases::new();
     t.pass("tests/01-simple-route.rs");
     t.pass("tests/02-named-param.rs");
+    t.pass("tests/03-content-hint.rs");
+    t.pass("tests/04-aliases.rs");
+    t.pass("tests/06-state.rs");
+    t.pass("tests/07-on-error.rs");
+    t.pass("tests/08-query.rs");
+    t.pass("tests/09-into-response.rs");
+    t.compile_fail("tests/05-alias-missing-param.rs");
+    t.compile_fail("tests/10-state-mismatch.rs");
+    t.compile_fail("tests/11-unknown-on-error.rs");
 }
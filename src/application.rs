@@ -41,7 +41,11 @@
 
 use async_trait::async_trait;
 
-use crate::{error::AnyError, request::Request, response::Response};
+use crate::{
+    error::AnyError,
+    request::{Request, Upload},
+    response::Response,
+};
 
 /// Base Application
 ///
@@ -61,4 +65,577 @@ pub trait Application {
     ///    apropriate response.
     /// 3. Return the response that you would like the client to see.
     async fn handle_request(&self, request: Request) -> Result<Response, AnyError>;
+
+    /// Process an incoming request, only keeping the status and meta.
+    ///
+    /// This is useful for tools such as link checkers that only need to know
+    /// whether a page exists. The handler is still run, but the body of the
+    /// response is dropped without being read, so streamed bodies are never
+    /// generated.
+    ///
+    /// Applications that can determine the status more cheaply may override
+    /// this.
+    async fn handle_request_meta(&self, request: Request) -> Result<Response, AnyError> {
+        let response = self.handle_request(request).await?;
+        Ok(Response::new(response.code, response.meta))
+    }
+
+    /// Process an incoming upload.
+    ///
+    /// Uploads are sent with the [Titan](crate::protocol::Titan) protocol. The
+    /// request is the same as for [handle_request](Application::handle_request),
+    /// and the uploaded body is read from `upload`. Errors are handled in the
+    /// same way as for requests.
+    ///
+    /// By default, every upload is rejected with a `59 Bad Request`.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use async_trait::async_trait;
+    /// use gemfra::{
+    ///     application::Application,
+    ///     error::AnyError,
+    ///     request::{Request, Upload},
+    ///     response::Response,
+    /// };
+    ///
+    /// struct MyApp;
+    ///
+    /// #[async_trait]
+    /// impl Application for MyApp {
+    ///     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    ///         todo!("Handle the request")
+    ///     }
+    ///
+    ///     async fn handle_upload(
+    ///         &self,
+    ///         request: Request,
+    ///         upload: Upload<'_>,
+    ///     ) -> Result<Response, AnyError> {
+    ///         if upload.params.token.as_deref() != Some("secret") {
+    ///             return Ok(Response::cert_not_authorised("Invalid token"));
+    ///         }
+    ///         let body = upload.bytes().await?;
+    ///         Ok(Response::redirect(request.path))
+    ///     }
+    /// }
+    /// ```
+    async fn handle_upload(
+        &self,
+        _request: Request,
+        _upload: Upload<'_>,
+    ) -> Result<Response, AnyError> {
+        Ok(Response::bad_request("Uploads are not supported"))
+    }
+
+    /// The application that this application wraps, if any
+    ///
+    /// Wrappers such as [RestrictMime] only change how requests are handled.
+    /// By returning the wrapped application here, every other hook, such as
+    /// [internal_error_response](Application::internal_error_response) and
+    /// [shutdown](Application::shutdown), is delegated to it by default.
+    fn inner(&self) -> Option<&(dyn Application + Send + Sync)> {
+        None
+    }
+
+    /// Create the response for an error that isn't a [GemError](crate::error::GemError).
+    ///
+    /// When [handle_request](Application::handle_request) returns an error,
+    /// the protocol logs it and converts GemErrors into their response. Any
+    /// other error is given to this function. By default, the
+    /// [inner](Application::inner) application creates the response, or a
+    /// `42 Internal Server Error` is sent if there is none.
+    ///
+    /// This can be overridden to send a custom error page. Since the client
+    /// will see the response, be careful not to expose internal details in
+    /// production.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use async_trait::async_trait;
+    /// use gemfra::{application::Application, error::AnyError, request::Request, response::Response};
+    ///
+    /// struct MyApp;
+    ///
+    /// #[async_trait]
+    /// impl Application for MyApp {
+    ///     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    ///         todo!("Handle the request")
+    ///     }
+    ///
+    ///     fn internal_error_response(&self, err: &AnyError) -> Response {
+    ///         if cfg!(debug_assertions) {
+    ///             Response::gemtext(format!("# Something went wrong\n\n{err}\n"))
+    ///         } else {
+    ///             Response::error_cgi("Something went wrong, please try again later")
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    fn internal_error_response(&self, err: &AnyError) -> Response {
+        match self.inner() {
+            Some(inner) => inner.internal_error_response(err),
+            None => Response::error_cgi("Internal Server Error"),
+        }
+    }
+
+    /// Clean up the application before the server stops.
+    ///
+    /// This is called by [run_scgi_with_shutdown](crate::protocol::Scgi::run_scgi_with_shutdown)
+    /// after every connection has been handled. It can be used to flush caches
+    /// or close connection pools. By default, only the
+    /// [inner](Application::inner) application is shut down.
+    async fn shutdown(&self) {
+        if let Some(inner) = self.inner() {
+            inner.shutdown().await
+        }
+    }
+}
+
+/// Restrict the MIME types that an application may respond with
+///
+/// Every success (__2X__) response from the wrapped application is checked
+/// against an allowlist of MIME types. Parameters such as `charset` are
+/// ignored and the comparison is case-insensitive. Responses with a MIME type
+/// that isn't allowed are logged and replaced with a `42 Internal Server Error`.
+///
+/// This is useful for catching handler mistakes during development.
+///
+/// ### Example
+///
+/// ```no_run
+/// use async_trait::async_trait;
+/// use gemfra::{
+///     application::{Application, RestrictMime},
+///     error::AnyError,
+///     protocol::Cgi,
+///     request::Request,
+///     response::Response,
+/// };
+///
+/// struct MyApp;
+///
+/// #[async_trait]
+/// impl Application for MyApp {
+///     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+///         Ok(Response::success("text/gemini", "# Hello World!"))
+///     }
+/// }
+///
+/// #[tokio::main]
+/// async fn main() {
+///     RestrictMime::new(MyApp, ["text/gemini", "text/plain"]).run_cgi().await;
+/// }
+/// ```
+pub struct RestrictMime<A> {
+    app: A,
+    allowed: Vec<String>,
+}
+
+impl<A> RestrictMime<A> {
+    /// Wrap `app`, only allowing the MIME types in `allowed`
+    pub fn new<I, S>(app: A, allowed: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            app,
+            allowed: allowed.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Replace the response if its MIME type isn't allowed
+    fn check(&self, response: Response) -> Response {
+        let mime = match response.mime() {
+            Some(mime) => mime,
+            None => return response,
+        };
+        if self.allowed.iter().any(|m| mime.is(m)) {
+            response
+        } else {
+            eprintln!("Response MIME type `{}` is not allowed", mime.essence());
+            Response::error_cgi("Internal Server Error")
+        }
+    }
+}
+
+#[async_trait]
+impl<A> Application for RestrictMime<A>
+where
+    A: Application + Send + Sync,
+{
+    async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+        let response = self.app.handle_request(request).await?;
+        Ok(self.check(response))
+    }
+
+    async fn handle_upload(
+        &self,
+        request: Request,
+        upload: Upload<'_>,
+    ) -> Result<Response, AnyError> {
+        let response = self.app.handle_upload(request, upload).await?;
+        Ok(self.check(response))
+    }
+
+    fn inner(&self) -> Option<&(dyn Application + Send + Sync)> {
+        Some(&self.app)
+    }
+}
+
+/// Redirect requests for other hostnames to a canonical host
+///
+/// Capsules are often reachable through several hostnames, such as with and
+/// without `www.`. Requests whose [server_name](Request::server_name) is not
+/// the canonical host are sent a __31__ permanent redirect to the same URL on
+/// the canonical host, keeping the port, path and query. Hostnames are
+/// compared case-insensitively. Requests for the canonical host are handled by
+/// the wrapped application.
+///
+/// ### Example
+///
+/// ```no_run
+/// use async_trait::async_trait;
+/// use gemfra::{
+///     application::{Application, CanonicalHost},
+///     error::AnyError,
+///     protocol::Cgi,
+///     request::Request,
+///     response::Response,
+/// };
+///
+/// struct MyApp;
+///
+/// #[async_trait]
+/// impl Application for MyApp {
+///     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+///         Ok(Response::gemtext("# Hello World!"))
+///     }
+/// }
+///
+/// #[tokio::main]
+/// async fn main() {
+///     CanonicalHost::new(MyApp, "example.com").run_cgi().await;
+/// }
+/// ```
+pub struct CanonicalHost<A> {
+    app: A,
+    host: String,
+}
+
+impl<A> CanonicalHost<A> {
+    /// Wrap `app`, redirecting every other hostname to `host`
+    pub fn new(app: A, host: impl Into<String>) -> Self {
+        Self {
+            app,
+            host: host.into(),
+        }
+    }
+
+    /// Build the URL of the request on the canonical host
+    fn canonical_url(&self, request: &Request) -> String {
+        if let Some((scheme, rest)) = request.url.split_once("://") {
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            let (authority, rest) = rest.split_at(end);
+            // Keep the port, skipping any userinfo
+            let authority = authority.rsplit('@').next().unwrap_or(authority);
+            let port = authority
+                .rfind(':')
+                .filter(|i| !authority[*i..].contains(']'))
+                .map_or("", |i| &authority[i..]);
+            return format!("{scheme}://{}{port}{rest}", self.host);
+        }
+
+        let mut url = format!(
+            "gemini://{}{}{}",
+            self.host,
+            request.script.trim_end_matches('/'),
+            request.path
+        );
+        if let Some(query) = &request.query {
+            url.push('?');
+            url.push_str(query);
+        }
+        url
+    }
+}
+
+#[async_trait]
+impl<A> Application for CanonicalHost<A>
+where
+    A: Application + Send + Sync,
+{
+    async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+        if request.server_name.eq_ignore_ascii_case(&self.host) {
+            self.app.handle_request(request).await
+        } else {
+            Ok(Response::redirect_perm(self.canonical_url(&request)))
+        }
+    }
+
+    async fn handle_upload(
+        &self,
+        request: Request,
+        upload: Upload<'_>,
+    ) -> Result<Response, AnyError> {
+        if request.server_name.eq_ignore_ascii_case(&self.host) {
+            self.app.handle_upload(request, upload).await
+        } else {
+            Ok(Response::redirect_perm(self.canonical_url(&request)))
+        }
+    }
+
+    fn inner(&self) -> Option<&(dyn Application + Send + Sync)> {
+        Some(&self.app)
+    }
+}
+
+/// Allow or deny clients by their IP address
+///
+/// Clients are checked against lists of allowed and denied networks in CIDR
+/// notation, such as `10.0.0.0/8` or `fd00::/8`, before the request is given
+/// to the wrapped application. A client is denied with a __50__ Permanent
+/// Error if:
+///
+/// * Its address is in a [denied](IpFilter::deny) network.
+/// * Any networks are [allowed](IpFilter::allow), and its address isn't in
+///   any of them.
+///
+/// With empty lists, every client is allowed. If either list is set and the
+/// client's [IP address](Request::remote_ip) can't be parsed, it is denied.
+///
+/// ### Example
+///
+/// ```no_run
+/// use async_trait::async_trait;
+/// use gemfra::{
+///     application::{Application, IpFilter},
+///     error::AnyError,
+///     protocol::Cgi,
+///     request::Request,
+///     response::Response,
+/// };
+///
+/// struct MyApp;
+///
+/// #[async_trait]
+/// impl Application for MyApp {
+///     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+///         Ok(Response::gemtext("# Hello LAN!"))
+///     }
+/// }
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let app = IpFilter::new(MyApp)
+///         .allow("192.168.0.0/16".parse().unwrap())
+///         .allow("fd00::/8".parse().unwrap())
+///         .deny("192.168.1.0/24".parse().unwrap());
+///     app.run_cgi().await;
+/// }
+/// ```
+#[cfg(feature = "ip-filter")]
+pub struct IpFilter<A> {
+    app: A,
+    allowed: Vec<ipnet::IpNet>,
+    denied: Vec<ipnet::IpNet>,
+}
+
+#[cfg(feature = "ip-filter")]
+impl<A> IpFilter<A> {
+    /// Wrap `app`, allowing every client until networks are added
+    pub fn new(app: A) -> Self {
+        Self {
+            app,
+            allowed: Vec::new(),
+            denied: Vec::new(),
+        }
+    }
+
+    /// Allow clients in `net`
+    ///
+    /// Once a network is allowed, clients outside of the allowed networks are
+    /// denied.
+    pub fn allow(mut self, net: ipnet::IpNet) -> Self {
+        self.allowed.push(net);
+        self
+    }
+
+    /// Deny clients in `net`, even if they are in an allowed network
+    pub fn deny(mut self, net: ipnet::IpNet) -> Self {
+        self.denied.push(net);
+        self
+    }
+
+    /// Check whether a client is allowed
+    fn is_allowed(&self, request: &Request) -> bool {
+        if self.allowed.is_empty() && self.denied.is_empty() {
+            return true;
+        }
+        let ip = match request.remote_ip() {
+            Some(ip) => ip,
+            None => return false,
+        };
+        (self.allowed.is_empty() || self.allowed.iter().any(|net| net.contains(&ip)))
+            && !self.denied.iter().any(|net| net.contains(&ip))
+    }
+}
+
+#[cfg(feature = "ip-filter")]
+#[async_trait]
+impl<A> Application for IpFilter<A>
+where
+    A: Application + Send + Sync,
+{
+    async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+        if self.is_allowed(&request) {
+            self.app.handle_request(request).await
+        } else {
+            Ok(Response::error_perm("Access denied"))
+        }
+    }
+
+    async fn handle_upload(
+        &self,
+        request: Request,
+        upload: Upload<'_>,
+    ) -> Result<Response, AnyError> {
+        if self.is_allowed(&request) {
+            self.app.handle_upload(request, upload).await
+        } else {
+            Ok(Response::error_perm("Access denied"))
+        }
+    }
+
+    fn inner(&self) -> Option<&(dyn Application + Send + Sync)> {
+        Some(&self.app)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    struct MimeApp(&'static str);
+
+    #[async_trait]
+    impl Application for MimeApp {
+        async fn handle_request(&self, _request: Request) -> Result<Response, AnyError> {
+            Ok(Response::success(self.0, ""))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restrict_mime() {
+        let app = RestrictMime::new(MimeApp("Text/Gemini; charset=utf-8"), ["text/gemini"]);
+        let response = app.handle_request(Request::mock("/")).await.unwrap();
+        assert_eq!(response.code, 20);
+
+        let app = RestrictMime::new(MimeApp("image/png"), ["text/gemini"]);
+        let response = app.handle_request(Request::mock("/")).await.unwrap();
+        assert_eq!(response.code, 42);
+    }
+
+    #[tokio::test]
+    async fn test_canonical_host() {
+        let app = CanonicalHost::new(MimeApp("text/gemini"), "Example.com");
+
+        let mut request = Request::mock("/");
+        request.server_name = "example.COM".to_owned();
+        let response = app.handle_request(request).await.unwrap();
+        assert_eq!(response.code, 20);
+
+        let mut request = Request::mock("/a/b");
+        request.server_name = "www.example.com".to_owned();
+        request.url = "gemini://www.example.com:1966/a/b?c%20d".to_owned();
+        let response = app.handle_request(request).await.unwrap();
+        assert_eq!(
+            (response.code, response.meta.as_str()),
+            (31, "gemini://Example.com:1966/a/b?c%20d")
+        );
+
+        let response = app.handle_request(Request::mock("/a")).await.unwrap();
+        assert_eq!(response.meta, "gemini://Example.com/a");
+    }
+
+    #[cfg(feature = "ip-filter")]
+    #[tokio::test]
+    async fn test_ip_filter() {
+        async fn code(app: &IpFilter<MimeApp>, addr: &str) -> u32 {
+            let mut request = Request::mock("/");
+            request.remote_addr = addr.to_owned();
+            app.handle_request(request).await.unwrap().code
+        }
+
+        let app = IpFilter::new(MimeApp("text/gemini"));
+        assert_eq!(code(&app, "203.0.113.5").await, 20);
+        assert_eq!(code(&app, "unknown").await, 20);
+
+        let app = IpFilter::new(MimeApp("text/gemini"))
+            .allow("10.0.0.0/8".parse().unwrap())
+            .allow("fd00::/8".parse().unwrap())
+            .deny("10.1.0.0/16".parse().unwrap());
+        assert_eq!(code(&app, "10.2.3.4").await, 20);
+        assert_eq!(code(&app, "::ffff:10.2.3.4").await, 20);
+        assert_eq!(code(&app, "fd12::1").await, 20);
+        assert_eq!(code(&app, "10.1.3.4").await, 50);
+        assert_eq!(code(&app, "203.0.113.5").await, 50);
+        assert_eq!(code(&app, "2001:db8::1").await, 50);
+        assert_eq!(code(&app, "unknown").await, 50);
+    }
+
+    struct HookApp(Arc<AtomicBool>);
+
+    #[async_trait]
+    impl Application for HookApp {
+        async fn handle_request(&self, _request: Request) -> Result<Response, AnyError> {
+            Err("broken".into())
+        }
+
+        fn internal_error_response(&self, _err: &AnyError) -> Response {
+            Response::gemtext("# Broken")
+        }
+
+        async fn shutdown(&self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrapper_hooks() {
+        async fn check(app: &(dyn Application + Send + Sync), stopped: &AtomicBool) {
+            let response = app.internal_error_response(&"broken".into());
+            assert_eq!(response.meta, "text/gemini");
+            app.shutdown().await;
+            assert!(stopped.swap(false, Ordering::SeqCst));
+        }
+
+        // Wrappers delegate every hook other than the handlers
+        let stopped = Arc::new(AtomicBool::new(false));
+        let app = RestrictMime::new(HookApp(stopped.clone()), ["text/gemini"]);
+        check(&app, &stopped).await;
+        let app = CanonicalHost::new(HookApp(stopped.clone()), "example.com");
+        check(&app, &stopped).await;
+        #[cfg(feature = "ip-filter")]
+        check(&IpFilter::new(HookApp(stopped.clone())), &stopped).await;
+    }
+
+    #[tokio::test]
+    async fn test_handle_request_meta() {
+        let response = MimeApp("text/gemini")
+            .handle_request_meta(Request::mock("/"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 20);
+        assert_eq!(response.meta, "text/gemini");
+        assert_eq!(response.len(), None);
+    }
 }
@@ -0,0 +1,398 @@
+//! Directory Archives
+//!
+//! Serve the contents of a directory as a single download with
+//! [tar_directory]. The archive is generated while it is being sent, so large
+//! directories are never buffered in memory.
+//!
+//! ### Example
+//!
+//! ```no_run
+//! use gemfra::{archive::tar_directory, error::AnyError, request::Request, response::Response};
+//!
+//! fn download(request: &Request) -> Result<Response, AnyError> {
+//!     // Serve `/srv/files/{path}` as a tarball
+//!     let path = request.path.trim_start_matches("/download/");
+//!     Ok(tar_directory("/srv/files", path)?)
+//! }
+//! ```
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File, ReadDir},
+    future::Future,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::UNIX_EPOCH,
+};
+
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    task::JoinHandle,
+};
+
+use crate::{error::GemError, response::Response};
+
+/// The size of a tar block
+const BLOCK: usize = 512;
+
+/// How much of the archive is generated at a time on a blocking thread
+const CHUNK: usize = 64 * 1024;
+
+/// Create a response that streams a directory as a tar archive
+///
+/// `path` is the directory to archive relative to `root`, usually taken from
+/// the request path. Paths that would leave `root` (such as `..`) or go
+/// through a symlink are rejected with a __51__ Not Found. Symlinks inside the
+/// directory are skipped, so they can't be used to escape `root` either.
+///
+/// Entries in the archive are relative to `root`, so archiving `docs` will
+/// produce entries like `docs/index.gmi`. Only directories and regular files
+/// are included. Files that can't be read are logged and skipped.
+///
+/// The directory is read while the response is sent. Reading uses blocking
+/// IO, so it is done on a [blocking thread](tokio::task::spawn_blocking) to
+/// keep the runtime free for other requests.
+pub fn tar_directory(root: impl AsRef<Path>, path: &str) -> Result<Response, GemError> {
+    let root = root.as_ref().to_path_buf();
+    let dir = resolve(&root, path).ok_or_else(|| GemError::not_found("Path not found"))?;
+
+    let mut stream = TarStream {
+        root,
+        dirs: VecDeque::new(),
+        buf: Vec::new(),
+        pos: 0,
+        file: None,
+        finished: false,
+    };
+    if dir != stream.root {
+        stream.push_header(&dir, &fs::metadata(&dir).map_err(not_found)?);
+    }
+    stream
+        .dirs
+        .push_back(fs::read_dir(&dir).map_err(not_found)?);
+
+    let body = AsyncTarStream {
+        stream: Some(stream),
+        reading: None,
+        buf: Vec::new(),
+        pos: 0,
+    };
+    Ok(Response::success_async("application/x-tar", body))
+}
+
+fn not_found(_err: io::Error) -> GemError {
+    GemError::not_found("Path not found")
+}
+
+/// Join `path` to `root`, making sure that the result is a directory within
+/// `root` that is not reached through a symlink
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut dir = root.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => dir.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+        if fs::symlink_metadata(&dir).ok()?.file_type().is_symlink() {
+            return None;
+        }
+    }
+    fs::metadata(&dir).ok()?.is_dir().then_some(dir)
+}
+
+/// Write `value` as a NUL terminated octal number into `field`
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+/// Split a name into the ustar prefix and name fields
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= 100 {
+        return Some(("", name));
+    }
+    name.match_indices('/')
+        .map(|(i, _)| (&name[..i], &name[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && !name.is_empty() && name.len() <= 100)
+}
+
+/// Build a ustar header
+fn header(name: &str, size: u64, mtime: u64, is_dir: bool) -> Option<[u8; BLOCK]> {
+    // Sizes are limited to 11 octal digits
+    if size >= 1 << 33 {
+        return None;
+    }
+    let (prefix, name) = split_name(name)?;
+
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], if is_dir { 0o755 } else { 0o644 });
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], mtime);
+    header[156] = if is_dir { b'5' } else { b'0' };
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is calculated with the checksum field set to spaces
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|&b| b as u64).sum();
+    write_octal(&mut header[148..155], checksum);
+    header[154] = 0;
+
+    Some(header)
+}
+
+/// A file that is being written to the archive
+struct TarFile {
+    file: File,
+    remaining: u64,
+    padding: usize,
+}
+
+/// A lazy reader that generates a tar archive of a directory
+struct TarStream {
+    root: PathBuf,
+    dirs: VecDeque<ReadDir>,
+    buf: Vec<u8>,
+    pos: usize,
+    file: Option<TarFile>,
+    finished: bool,
+}
+
+impl TarStream {
+    /// Queue the header for `path`, returning false if it can't be archived
+    fn push_header(&mut self, path: &Path, metadata: &fs::Metadata) -> bool {
+        let name = match path.strip_prefix(&self.root).ok().and_then(Path::to_str) {
+            Some(name) => name.replace(std::path::MAIN_SEPARATOR, "/"),
+            None => return false,
+        };
+        let is_dir = metadata.is_dir();
+        let name = if is_dir { format!("{name}/") } else { name };
+        let size = if is_dir { 0 } else { metadata.len() };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |time| time.as_secs());
+
+        match header(&name, size, mtime, is_dir) {
+            Some(header) => {
+                self.buf.extend_from_slice(&header);
+                true
+            }
+            None => {
+                eprintln!("Unable to archive {name}: the name or size is too large");
+                false
+            }
+        }
+    }
+
+    /// Queue the next entry of the archive
+    ///
+    /// Returns false once every entry has been queued.
+    fn next_entry(&mut self) -> bool {
+        while let Some(dir) = self.dirs.front_mut() {
+            let entry = match dir.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => {
+                    eprintln!("Unable to read directory entry: {err}");
+                    continue;
+                }
+                None => {
+                    self.dirs.pop_front();
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    eprintln!("Unable to read {}: {err}", path.display());
+                    continue;
+                }
+            };
+
+            if metadata.is_dir() {
+                match fs::read_dir(&path) {
+                    Ok(read_dir) => {
+                        if self.push_header(&path, &metadata) {
+                            self.dirs.push_back(read_dir);
+                            return true;
+                        }
+                    }
+                    Err(err) => eprintln!("Unable to read {}: {err}", path.display()),
+                }
+            } else if metadata.is_file() {
+                match File::open(&path) {
+                    Ok(file) => {
+                        if self.push_header(&path, &metadata) {
+                            let size = metadata.len();
+                            self.file = Some(TarFile {
+                                file,
+                                remaining: size,
+                                padding: (BLOCK - (size as usize % BLOCK)) % BLOCK,
+                            });
+                            return true;
+                        }
+                    }
+                    Err(err) => eprintln!("Unable to read {}: {err}", path.display()),
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Read for TarStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.buf.len() {
+                let len = out.len().min(self.buf.len() - self.pos);
+                out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+                self.pos += len;
+                return Ok(len);
+            }
+            self.buf.clear();
+            self.pos = 0;
+
+            if let Some(file) = &mut self.file {
+                if file.remaining > 0 {
+                    let len = out.len().min(file.remaining as usize);
+                    let read = file.file.read(&mut out[..len])?;
+                    if read > 0 {
+                        file.remaining -= read as u64;
+                        return Ok(read);
+                    }
+                    // The file shrunk since the header was written
+                    self.buf.resize(file.remaining as usize, 0);
+                }
+                self.buf.resize(self.buf.len() + file.padding, 0);
+                self.file = None;
+                continue;
+            }
+
+            if self.finished {
+                return Ok(0);
+            }
+            if !self.next_entry() {
+                // The archive ends with two empty blocks
+                self.buf.resize(BLOCK * 2, 0);
+                self.finished = true;
+            }
+        }
+    }
+}
+
+/// Generates a [TarStream] on a blocking thread, one chunk at a time
+struct AsyncTarStream {
+    stream: Option<TarStream>,
+    reading: Option<JoinHandle<(TarStream, io::Result<Vec<u8>>)>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncRead for AsyncTarStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.pos < this.buf.len() {
+                let len = out.remaining().min(this.buf.len() - this.pos);
+                out.put_slice(&this.buf[this.pos..this.pos + len]);
+                this.pos += len;
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(reading) = &mut this.reading {
+                let result = ready!(Pin::new(reading).poll(cx));
+                this.reading = None;
+                let (stream, chunk) = result.map_err(io::Error::other)?;
+                this.stream = Some(stream);
+                this.buf = chunk?;
+                this.pos = 0;
+                if this.buf.is_empty() {
+                    // The archive has ended
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+
+            let mut stream = match this.stream.take() {
+                Some(stream) => stream,
+                // The blocking thread panicked
+                None => return Poll::Ready(Ok(())),
+            };
+            this.reading = Some(tokio::task::spawn_blocking(move || {
+                let mut chunk = Vec::with_capacity(CHUNK);
+                let result = (&mut stream)
+                    .take(CHUNK as u64)
+                    .read_to_end(&mut chunk)
+                    .map(|_| chunk);
+                (stream, result)
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let block = header("a.txt", 5, 0, false).unwrap();
+        assert_eq!(&block[..6], b"a.txt\0");
+        assert_eq!(&block[124..136], b"00000000005\0");
+        assert_eq!(&block[148..156], b"006721\0 ");
+
+        let long = format!("{}/{}", "a".repeat(120), "b".repeat(50));
+        let block = header(&long, 0, 0, false).unwrap();
+        assert_eq!(&block[..50], "b".repeat(50).as_bytes());
+        assert_eq!(&block[345..465], "a".repeat(120).as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_tar_directory() {
+        let root = std::env::temp_dir().join(format!("gemfra-tar-{}", std::process::id()));
+        fs::create_dir_all(root.join("docs/sub")).unwrap();
+        fs::write(root.join("docs/index.gmi"), "# Hello").unwrap();
+        fs::write(root.join("docs/sub/file.txt"), "").unwrap();
+        fs::write(root.join("secret.txt"), "secret").unwrap();
+
+        assert!(tar_directory(&root, "../").is_err());
+        assert!(tar_directory(&root, "/etc").is_err());
+        assert!(tar_directory(&root, "docs/index.gmi").is_err());
+
+        let response = tar_directory(&root, "docs").unwrap();
+        assert_eq!(response.meta, "application/x-tar");
+        let body = response.read_body().await;
+        fs::remove_dir_all(&root).unwrap();
+
+        let names: Vec<_> = body
+            .as_bytes()
+            .chunks(BLOCK)
+            .filter(|block| &block[257..262] == b"ustar")
+            .map(|block| {
+                String::from_utf8_lossy(&block[..100])
+                    .trim_end_matches('\0')
+                    .to_owned()
+            })
+            .collect();
+        assert_eq!(names.len(), 4);
+        for name in ["docs/", "docs/index.gmi", "docs/sub/", "docs/sub/file.txt"] {
+            assert!(names.contains(&name.to_owned()), "{names:?}");
+        }
+        assert!(body.contains("# Hello"));
+        assert!(!body.contains("secret"));
+        assert_eq!(body.len() % BLOCK, 0);
+    }
+}
@@ -0,0 +1,172 @@
+//! Handler benchmarks
+//!
+//! Measure how long an [Application] takes to generate responses without
+//! setting up a full benchmarking harness. Each iteration handles a request and
+//! [drains](crate::response::Response::drain) the body of the response so that
+//! lazily generated bodies are included in the measurement.
+//!
+//! ### Example
+//!
+//! ```no_run
+//! use async_trait::async_trait;
+//! use gemfra::{
+//!     application::Application,
+//!     bench::bench,
+//!     error::AnyError,
+//!     request::Request,
+//!     response::Response,
+//! };
+//!
+//! struct MyApp;
+//!
+//! #[async_trait]
+//! impl Application for MyApp {
+//!     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+//!         Ok(Response::gemtext("# Hello World!"))
+//!     }
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let report = bench(&MyApp, 1000, || Request::builder("/").build()).await;
+//!     println!("{report}");
+//! }
+//! ```
+
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use crate::{application::Application, request::Request};
+
+/// The results of a [bench] run
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    /// The number of requests that were handled
+    pub iterations: usize,
+    /// The number of requests that returned an error
+    pub errors: usize,
+    /// The total number of body bytes generated
+    pub bytes: u64,
+    /// The total time spent handling requests
+    pub total: Duration,
+    /// The fastest request
+    pub min: Duration,
+    /// The median request
+    pub p50: Duration,
+    /// The 90th percentile request
+    pub p90: Duration,
+    /// The 99th percentile request
+    pub p99: Duration,
+    /// The slowest request
+    pub max: Duration,
+}
+
+impl BenchReport {
+    /// The number of requests handled per second
+    pub fn throughput(&self) -> f64 {
+        match self.total.as_secs_f64() {
+            secs if secs > 0.0 => self.iterations as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} requests ({} errors, {} bytes) in {:?}: {:.1} req/s",
+            self.iterations,
+            self.errors,
+            self.bytes,
+            self.total,
+            self.throughput()
+        )?;
+        write!(
+            f,
+            "min {:?}, p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.min, self.p50, self.p90, self.p99, self.max
+        )
+    }
+}
+
+/// Run `app` for `iterations` requests created by `make_request`
+///
+/// Requests are handled one at a time. The latency of each request covers both
+/// [handle_request](Application::handle_request) and reading the body.
+pub async fn bench<A, F>(app: &A, iterations: usize, mut make_request: F) -> BenchReport
+where
+    A: Application + Sync,
+    F: FnMut() -> Request,
+{
+    let mut times = Vec::with_capacity(iterations);
+    let mut errors = 0;
+    let mut bytes = 0;
+
+    for _ in 0..iterations {
+        let request = make_request();
+        let start = Instant::now();
+        match app.handle_request(request).await {
+            Ok(response) => match response.drain().await {
+                Ok(read) => bytes += read,
+                Err(_) => errors += 1,
+            },
+            Err(_) => errors += 1,
+        }
+        times.push(start.elapsed());
+    }
+
+    times.sort();
+    let percentile = |p: usize| {
+        times
+            .get(times.len().saturating_sub(1) * p / 100)
+            .copied()
+            .unwrap_or_default()
+    };
+
+    BenchReport {
+        iterations,
+        errors,
+        bytes,
+        total: times.iter().sum(),
+        min: percentile(0),
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+        max: percentile(100),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{error::AnyError, response::Response};
+
+    struct HelloApp;
+
+    #[async_trait]
+    impl Application for HelloApp {
+        async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+            match request.path.as_str() {
+                "/" => Ok(Response::gemtext("Hello")),
+                _ => Err("error".into()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bench() {
+        let report = bench(&HelloApp, 10, || Request::mock("/")).await;
+        assert_eq!(report.iterations, 10);
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.bytes, 50);
+        assert!(report.min <= report.p50 && report.p50 <= report.max);
+
+        let report = bench(&HelloApp, 3, || Request::mock("/err")).await;
+        assert_eq!(report.errors, 3);
+    }
+}
@@ -9,13 +9,28 @@
 use std::{
     error::Error,
     fmt::{Debug, Display},
+    io,
 };
 
-use crate::response::Response;
+use crate::{gemtext::preformatted, response::Response};
 
 /// Shorthand for a boxed error
 pub type AnyError = Box<dyn Error + Send + Sync>;
 
+/// Check whether an IO error was caused by the client closing the connection
+///
+/// Clients are free to disconnect at any time, for example while a large body
+/// is still being streamed. These errors are a normal part of serving requests
+/// and should not be treated as failures of the application.
+pub fn is_client_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
 /// Convert a struct into a [GemError] result.
 ///
 /// This is implemented already for [Result](std::result::Result) and
@@ -126,7 +141,7 @@ impl<T> ToGemError<T> for Option<T> {
 ///
 /// These errors corespond to gemini response codes and are used to determine
 /// the type of response that is sent.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GemErrorType {
     /// __40__ Temporary Error
     TempError,
@@ -157,6 +172,151 @@ pub enum GemErrorType {
     BadCert,
 }
 
+/// Compare two strings in a const context
+const fn const_str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+impl GemErrorType {
+    /// All of the error types
+    const ALL: [GemErrorType; 13] = [
+        GemErrorType::TempError,
+        GemErrorType::PermError,
+        GemErrorType::Unavailable,
+        GemErrorType::RuntimeError,
+        GemErrorType::ProxyError,
+        GemErrorType::TooManyRequests,
+        GemErrorType::NotFound,
+        GemErrorType::Gone,
+        GemErrorType::ProxyRefused,
+        GemErrorType::BadRequest,
+        GemErrorType::CertNeeded,
+        GemErrorType::CertUnAuthorised,
+        GemErrorType::BadCert,
+    ];
+
+    /// Get the response code of the error type
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::error::GemErrorType;
+    ///
+    /// assert_eq!(GemErrorType::NotFound.code(), 51);
+    /// ```
+    pub fn code(&self) -> u32 {
+        match self {
+            GemErrorType::TempError => 40,
+            GemErrorType::Unavailable => 41,
+            GemErrorType::RuntimeError => 42,
+            GemErrorType::ProxyError => 43,
+            GemErrorType::TooManyRequests => 44,
+            GemErrorType::PermError => 50,
+            GemErrorType::NotFound => 51,
+            GemErrorType::Gone => 52,
+            GemErrorType::ProxyRefused => 53,
+            GemErrorType::BadRequest => 59,
+            GemErrorType::CertNeeded => 60,
+            GemErrorType::CertUnAuthorised => 61,
+            GemErrorType::BadCert => 62,
+        }
+    }
+
+    /// Get a stable identifier of the error type
+    ///
+    /// Unlike the [Display] output, the slug is meant to be machine-readable
+    /// and will not change, which makes it suitable for structured logs and
+    /// metrics.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::error::GemErrorType;
+    ///
+    /// assert_eq!(GemErrorType::NotFound.slug(), "not_found");
+    /// ```
+    pub const fn slug(&self) -> &'static str {
+        match self {
+            GemErrorType::TempError => "temp_error",
+            GemErrorType::PermError => "perm_error",
+            GemErrorType::Unavailable => "unavailable",
+            GemErrorType::RuntimeError => "runtime_error",
+            GemErrorType::ProxyError => "proxy_error",
+            GemErrorType::TooManyRequests => "too_many_requests",
+            GemErrorType::NotFound => "not_found",
+            GemErrorType::Gone => "gone",
+            GemErrorType::ProxyRefused => "proxy_refused",
+            GemErrorType::BadRequest => "bad_request",
+            GemErrorType::CertNeeded => "cert_needed",
+            GemErrorType::CertUnAuthorised => "cert_unauthorised",
+            GemErrorType::BadCert => "bad_cert",
+        }
+    }
+
+    /// Get the error type of a [slug](GemErrorType::slug)
+    ///
+    /// This is a `const fn`, so the [route](macro@crate::routed::route) macro
+    /// can check the `on_error` option at compile time.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::error::GemErrorType;
+    ///
+    /// assert_eq!(GemErrorType::from_slug("gone"), Some(GemErrorType::Gone));
+    /// assert_eq!(GemErrorType::from_slug("missing"), None);
+    /// ```
+    pub const fn from_slug(slug: &str) -> Option<Self> {
+        let mut i = 0;
+        while i < Self::ALL.len() {
+            if const_str_eq(Self::ALL[i].slug(), slug) {
+                return Some(Self::ALL[i]);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Get the error type of a response code
+    ///
+    /// Codes without a specific error type fall back to the generic type of
+    /// their class, so `45` is a [TempError](GemErrorType::TempError) and `54`
+    /// is a [PermError](GemErrorType::PermError). Unknown certificate codes are
+    /// [CertNeeded](GemErrorType::CertNeeded). Codes that are not errors, such
+    /// as success or redirect codes, return `None`.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::error::GemErrorType;
+    ///
+    /// assert_eq!(GemErrorType::from_code(51), Some(GemErrorType::NotFound));
+    /// assert_eq!(GemErrorType::from_code(20), None);
+    /// ```
+    pub fn from_code(code: u32) -> Option<Self> {
+        if let Some(error_type) = Self::ALL.into_iter().find(|t| t.code() == code) {
+            return Some(error_type);
+        }
+        match code {
+            40..=49 => Some(GemErrorType::TempError),
+            50..=59 => Some(GemErrorType::PermError),
+            60..=69 => Some(GemErrorType::CertNeeded),
+            _ => None,
+        }
+    }
+}
+
 impl Display for GemErrorType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(match self {
@@ -272,6 +432,68 @@ impl GemError {
             msg: GemErrorMsg::Message(msg.into()),
         }
     }
+    /// Render the error and every error in its source chain
+    ///
+    /// The first line is the error itself, followed by a `caused by:` line for
+    /// each underlying error. This is useful for logging wrapped errors.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::error::{GemError, GemErrorType};
+    /// use std::io;
+    ///
+    /// let err = io::Error::new(io::ErrorKind::Other, "disk on fire");
+    /// let err = GemError::from_err(GemErrorType::RuntimeError, err);
+    /// assert_eq!(err.source_chain(), "RuntimeError: disk on fire");
+    /// ```
+    pub fn source_chain(&self) -> String {
+        let mut chain = self.to_string();
+        // The embedded error is already part of the message
+        let mut source = self.source().and_then(Error::source);
+        while let Some(err) = source {
+            chain.push_str("\ncaused by: ");
+            chain.push_str(&err.to_string());
+            source = err.source();
+        }
+        chain
+    }
+
+    /// Get the fields of the error for a structured log
+    ///
+    /// The fields are the response code, the [slug](GemErrorType::slug) of the
+    /// error type, and the message.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::error::GemError;
+    ///
+    /// let err = GemError::not_found("No such page");
+    /// let (code, kind, message) = err.log_fields();
+    /// eprintln!("code={code} kind={kind} message={message:?}");
+    /// assert_eq!((code, kind), (51, "not_found"));
+    /// ```
+    pub fn log_fields(&self) -> (u32, &'static str, String) {
+        (
+            self.error_type.code(),
+            self.error_type.slug(),
+            self.msg.to_string(),
+        )
+    }
+
+    /// Create a diagnostic page that includes the full [source chain](GemError::source_chain)
+    ///
+    /// The response is a __20__ gemtext page so that the details are visible in
+    /// any client. This exposes the internals of the application, so it should
+    /// only be used during development. In production, convert the error into a
+    /// [Response] instead.
+    pub fn diagnostic_response(&self) -> Response {
+        let mut body = format!("# {}\n\n", self.error_type);
+        body.push_str(&preformatted("error", &self.source_chain()));
+        Response::gemtext(body)
+    }
+
     /// Create a new error using an existing error
     #[inline]
     pub fn from_err<E>(error_type: GemErrorType, msg: E) -> Self
@@ -365,3 +587,123 @@ impl GemError {
         Self::new(GemErrorType::BadCert, msg)
     }
 }
+
+/// An error that closes the connection without sending a response
+///
+/// Returning this from a handler makes the protocol log the reason and close
+/// the connection without writing anything. This is meant for dropping
+/// clearly malicious clients, and should be rare: well-behaved clients will
+/// see a broken connection rather than an error they can understand.
+///
+/// Only servers that own the connection can do this. Under CGI, the script
+/// exits without writing a response, and it is up to the front-end what the
+/// client receives (usually a __42__ CGI Error).
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::{error::{AnyError, CloseConnection}, request::Request, response::Response};
+///
+/// fn handle(request: Request) -> Result<Response, AnyError> {
+///     if request.path.contains("wp-admin") {
+///         return Err(CloseConnection::new("vulnerability scanner").into());
+///     }
+///     Ok(Response::gemtext("# Hello"))
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseConnection {
+    reason: String,
+}
+
+impl CloseConnection {
+    /// Close the connection for `reason`, which is only logged
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+
+    /// Get the reason that the connection was closed
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl Display for CloseConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Closing the connection: {}", self.reason)
+    }
+}
+
+impl Error for CloseConnection {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Wrapper(io::Error);
+
+    impl Display for Wrapper {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("could not load page")
+        }
+    }
+
+    impl Error for Wrapper {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn source_chain() {
+        let err = Wrapper(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        let err = GemError::from_err(GemErrorType::NotFound, err);
+        assert_eq!(
+            err.source_chain(),
+            "NotFound: could not load page\ncaused by: no such file"
+        );
+        assert_eq!(
+            GemError::not_found("missing").source_chain(),
+            "NotFound: missing"
+        );
+        assert_eq!(err.diagnostic_response().code, 20);
+    }
+
+    #[test]
+    fn error_type_code() {
+        for error_type in GemErrorType::ALL {
+            assert_eq!(GemErrorType::from_code(error_type.code()), Some(error_type));
+            let response = Response::from(GemError::new(error_type, "1"));
+            assert_eq!(response.code, error_type.code());
+        }
+        assert_eq!(GemErrorType::from_code(45), Some(GemErrorType::TempError));
+        assert_eq!(GemErrorType::from_code(31), None);
+    }
+
+    #[test]
+    fn log_fields() {
+        let mut slugs: Vec<_> = GemErrorType::ALL.iter().map(GemErrorType::slug).collect();
+        slugs.sort();
+        slugs.dedup();
+        assert_eq!(slugs.len(), GemErrorType::ALL.len());
+
+        let err = io::Error::other("disk on fire");
+        let err = GemError::from_err(GemErrorType::RuntimeError, err);
+        assert_eq!(
+            err.log_fields(),
+            (42, "runtime_error", "disk on fire".to_owned())
+        );
+    }
+
+    #[test]
+    fn client_disconnect() {
+        assert!(is_client_disconnect(&io::ErrorKind::BrokenPipe.into()));
+        assert!(is_client_disconnect(&io::ErrorKind::ConnectionReset.into()));
+        assert!(!is_client_disconnect(
+            &io::ErrorKind::PermissionDenied.into()
+        ));
+    }
+}
@@ -0,0 +1,383 @@
+//! Multi-field forms
+//!
+//! Gemini clients can only send a single line of input with each request. A
+//! [Form] collects several fields by prompting for one field at a time with a
+//! __10__ Input response. The answers given so far are kept in the path, so no
+//! state needs to be stored on the server.
+//!
+//! For a form at `/signup` with the fields `name` and `age`, the client will
+//! go through the following paths:
+//!
+//! * `/signup`: Prompt for the name
+//! * `/signup?alice`: Redirect to `/signup/_alice`
+//! * `/signup/_alice`: Prompt for the age
+//! * `/signup/_alice?42`: Redirect to `/signup/_alice/_42`
+//! * `/signup/_alice/_42`: The form is complete
+//!
+//! Each answer is kept in its own segment, starting with `_` so that empty
+//! answers can be recorded. The front-end decodes the path before it reaches
+//! the application, so the answers are percent-encoded twice in the URL. This
+//! lets answers contain any character, including `/` and `%`.
+//!
+//! Since the answers are part of the URL, forms should not be used for
+//! sensitive information such as passwords.
+//!
+//! The answers can be read from the [FormValues], or a type that implements
+//! [FromForm] can be built from them with [process_into](Form::process_into).
+//!
+//! ### Example
+//!
+//! ```
+//! use gemfra::{
+//!     error::{AnyError, GemError},
+//!     form::{Form, FormValues, FromForm},
+//!     request::Request,
+//!     response::Response,
+//! };
+//!
+//! struct Signup {
+//!     name: String,
+//!     age: u32,
+//! }
+//!
+//! impl FromForm for Signup {
+//!     fn from_form(values: &FormValues) -> Result<Self, GemError> {
+//!         Ok(Self {
+//!             name: values.parse("name")?,
+//!             age: values.parse("age")?,
+//!         })
+//!     }
+//! }
+//!
+//! // Handles `/signup` and every path below it
+//! async fn signup(request: Request) -> Result<Response, AnyError> {
+//!     let form = Form::new()
+//!         .field("name", "What is your name?")
+//!         .typed_field::<u32>("age", "How old are you?");
+//!     let signup: Signup = match form.process_into("/signup", &request) {
+//!         Ok(signup) => signup,
+//!         Err(response) => return Ok(response),
+//!     };
+//!     Ok(Response::gemtext(format!("# Welcome {}", signup.name)))
+//! }
+//! ```
+
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use crate::{
+    error::{GemError, GemErrorType, ToGemError},
+    query::{encode_segment, join_segments, percent_decode},
+    request::Request,
+    response::Response,
+};
+
+/// Check whether an answer is valid, returning an error message if it isn't
+type Validator = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+struct Field {
+    name: String,
+    prompt: String,
+    validate: Validator,
+}
+
+/// A form that prompts for each of its fields in turn
+///
+/// See the [module](self) for how the form is filled in.
+#[derive(Default)]
+pub struct Form {
+    fields: Vec<Field>,
+}
+
+impl Form {
+    /// Create a form without any fields
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a text field
+    pub fn field(self, name: impl Into<String>, prompt: impl Into<String>) -> Self {
+        self.validated_field(name, prompt, |_| Ok(()))
+    }
+
+    /// Add a field that must parse as `T`
+    ///
+    /// If the answer can't be parsed, the prompt is shown again with the parse
+    /// error.
+    pub fn typed_field<T>(self, name: impl Into<String>, prompt: impl Into<String>) -> Self
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.validated_field(name, prompt, |answer| {
+            answer
+                .parse::<T>()
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        })
+    }
+
+    /// Add a field with a custom validator
+    ///
+    /// The validator returns an error message for invalid answers, which is
+    /// shown along with the prompt.
+    pub fn validated_field<F>(
+        mut self,
+        name: impl Into<String>,
+        prompt: impl Into<String>,
+        validate: F,
+    ) -> Self
+    where
+        F: Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.fields.push(Field {
+            name: name.into(),
+            prompt: prompt.into(),
+            validate: Box::new(validate),
+        });
+        self
+    }
+
+    /// Process a request for the form at `base`
+    ///
+    /// Returns the values of the form once every field has been answered.
+    /// Otherwise, the response that continues the form is returned, which is
+    /// one of:
+    ///
+    /// * __10__ Input: The prompt for the next field. If the previous answer
+    ///   was invalid, the prompt includes the error.
+    /// * __30__ Redirect: The answer was accepted and the client is sent to
+    ///   the path for the next field.
+    /// * __59__ Bad Request: The path is not part of the form, or has an
+    ///   invalid answer.
+    pub fn process(&self, base: &str, request: &Request) -> Result<FormValues, Response> {
+        let base = base.trim_end_matches('/');
+        let rest = match request.path.strip_prefix(base) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            _ => return Err(Response::bad_request("Invalid form path")),
+        };
+        let answers = match rest
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| segment.strip_prefix('_').map(percent_decode))
+            .collect::<Option<Vec<String>>>()
+        {
+            Some(answers) => answers,
+            None => return Err(Response::bad_request("Invalid form path")),
+        };
+        if answers.len() > self.fields.len() {
+            return Err(Response::bad_request("Too many answers"));
+        }
+
+        let mut values = HashMap::new();
+        for (field, answer) in self.fields.iter().zip(answers.iter().cloned()) {
+            if (field.validate)(&answer).is_err() {
+                return Err(Response::bad_request(format!("Invalid {}", field.name)));
+            }
+            values.insert(field.name.clone(), answer);
+        }
+
+        let field = match self.fields.get(values.len()) {
+            Some(field) => field,
+            None => return Ok(FormValues { values }),
+        };
+        let answer = match request.query.as_deref().map(percent_decode) {
+            Some(answer) => answer,
+            None => return Err(Response::input(&field.prompt)),
+        };
+        if let Err(err) = (field.validate)(&answer) {
+            return Err(Response::input(format!("{err}: {}", field.prompt)));
+        }
+
+        let mut url = request.script.trim_end_matches('/').to_owned();
+        if !base.is_empty() {
+            url.push('/');
+            url.push_str(&join_segments(&base.split('/').skip(1).collect::<Vec<_>>()));
+        }
+        for answer in answers.iter().chain(Some(&answer)) {
+            url.push('/');
+            url.push_str(&encode_segment(&format!("_{}", encode_segment(answer))));
+        }
+        Err(Response::redirect(url))
+    }
+
+    /// Process a request for the form at `base`, building a `T` once the form
+    /// is complete
+    ///
+    /// This is the same as [process](Form::process), but the answers are given
+    /// to [from_form](FromForm::from_form). If that fails, its error is
+    /// returned as the response.
+    pub fn process_into<T>(&self, base: &str, request: &Request) -> Result<T, Response>
+    where
+        T: FromForm,
+    {
+        let values = self.process(base, request)?;
+        T::from_form(&values).map_err(Response::from)
+    }
+}
+
+/// A type that can be built from the answers of a completed [Form]
+///
+/// The fields of the type should be added to the form with
+/// [typed_field](Form::typed_field), so that invalid answers are prompted for
+/// again instead of failing once the form is complete.
+pub trait FromForm: Sized {
+    /// Build the value from the answers of the form
+    fn from_form(values: &FormValues) -> Result<Self, GemError>;
+}
+
+/// The answers of a completed [Form]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormValues {
+    values: HashMap<String, String>,
+}
+
+impl FormValues {
+    /// Get the answer of a field
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// Parse the answer of a field
+    ///
+    /// Fields added with [typed_field](Form::typed_field) have already been
+    /// checked, so parsing them as the same type will succeed.
+    pub fn parse<T>(&self, name: &str) -> Result<T, GemError>
+    where
+        T: FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.get(name)
+            .replace_gem(GemErrorType::BadRequest, format!("Missing {name}"))?
+            .parse()
+            .into_gem_type(GemErrorType::BadRequest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(path: &str, query: Option<&str>) -> Request {
+        let mut request = Request::mock(path);
+        request.query = query.map(str::to_owned);
+        request
+    }
+
+    #[test]
+    fn test_form() {
+        let form = Form::new()
+            .field("name", "Name?")
+            .typed_field::<u32>("age", "Age?");
+
+        let response = form
+            .process("/signup", &request("/signup", None))
+            .unwrap_err();
+        assert_eq!((response.code, response.meta.as_str()), (10, "Name?"));
+
+        let response = form
+            .process("/signup", &request("/signup", Some("Jane%20Doe")))
+            .unwrap_err();
+        assert_eq!(
+            (response.code, response.meta.as_str()),
+            (30, "/signup/_Jane%2520Doe")
+        );
+
+        // The front-end decodes the path once
+        let response = form
+            .process("/signup", &request("/signup/_Jane%20Doe", Some("old")))
+            .unwrap_err();
+        assert_eq!(response.code, 10);
+        assert!(response.meta.ends_with(": Age?"), "{}", response.meta);
+
+        let response = form
+            .process("/signup", &request("/signup/_Jane%20Doe/", Some("42")))
+            .unwrap_err();
+        assert_eq!(response.meta, "/signup/_Jane%2520Doe/_42");
+
+        let values = form
+            .process("/signup", &request("/signup/_Jane%20Doe/_42", None))
+            .ok()
+            .unwrap();
+        assert_eq!(values.get("name"), Some("Jane Doe"));
+        assert_eq!(values.parse::<u32>("age").unwrap(), 42);
+
+        for path in [
+            "/signup/_Jane/_x",
+            "/signup/_a/_1/_b",
+            "/signups",
+            "/signup/a",
+        ] {
+            let response = form.process("/signup", &request(path, None)).unwrap_err();
+            assert_eq!(response.code, 59, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_form_answers() {
+        let form = Form::new().field("a", "A?").field("b", "B?");
+        let mut request = request("/form", Some("x%2Fy%25z"));
+        request.script = "/cgi".to_owned();
+
+        // Answers containing `/` and `%` stay in one segment
+        let response = form.process("/form", &request).unwrap_err();
+        assert_eq!(response.meta, "/cgi/form/_x%252Fy%2525z");
+
+        // The path that the client is redirected to, once it is decoded
+        request.path = "/form/_x%2Fy%25z".to_owned();
+        request.query = Some("".to_owned());
+        let response = form.process("/form", &request).unwrap_err();
+        assert_eq!(response.meta, "/cgi/form/_x%252Fy%2525z/_");
+
+        request.path = "/form/_x%2Fy%25z/_".to_owned();
+        request.query = None;
+        let values = form.process("/form", &request).ok().unwrap();
+        assert_eq!(values.get("a"), Some("x/y%z"));
+        assert_eq!(values.get("b"), Some(""));
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Signup {
+        name: String,
+        age: u32,
+    }
+
+    impl FromForm for Signup {
+        fn from_form(values: &FormValues) -> Result<Self, GemError> {
+            Ok(Self {
+                name: values.parse("name")?,
+                age: values.parse("age")?,
+            })
+        }
+    }
+
+    #[test]
+    fn test_process_into() {
+        let form = Form::new()
+            .field("name", "Name?")
+            .typed_field::<u32>("age", "Age?");
+
+        let signup = form
+            .process_into::<Signup>("/signup", &request("/signup/_Jane/_42", None))
+            .ok()
+            .unwrap();
+        assert_eq!(
+            signup,
+            Signup {
+                name: "Jane".to_owned(),
+                age: 42
+            }
+        );
+
+        let response = form
+            .process_into::<Signup>("/signup", &request("/signup/_Jane", None))
+            .unwrap_err();
+        assert_eq!(response.code, 10);
+
+        // The type can be stricter than the form
+        let form = Form::new().field("name", "Name?").field("age", "Age?");
+        let response = form
+            .process_into::<Signup>("/signup", &request("/signup/_Jane/_old", None))
+            .unwrap_err();
+        assert_eq!(response.code, 59);
+    }
+}
@@ -0,0 +1,1055 @@
+//! Gemtext helpers
+//!
+//! Utilities for working with `text/gemini` documents.
+//!
+//! ### Example
+//!
+//! ```
+//! use gemfra::gemtext;
+//!
+//! let text = gemtext::wrap("This line is a little too long", 16);
+//! assert_eq!(text, "This line is a\nlittle too long");
+//! ```
+
+/// Check whether a line toggles preformatted mode
+#[inline]
+fn is_toggle(line: &str) -> bool {
+    line.starts_with("```")
+}
+
+/// Greedily wrap the words of `text` into lines no longer than `width`.
+///
+/// Words longer than `width` are placed on their own line.
+fn wrap_words(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+
+    for word in text.split_whitespace() {
+        let word_len = word.chars().count();
+        if current_len > 0 && current_len + 1 + word_len > width {
+            lines.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        if current_len > 0 {
+            current.push(' ');
+            current_len += 1;
+        }
+        current.push_str(word);
+        current_len += word_len;
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    lines
+}
+
+/// Soft-wrap a gemtext document at `width` columns
+///
+/// Only text lines and quote lines are wrapped. Quote lines keep their `>`
+/// prefix on every wrapped line. Link lines, headings, list items, and
+/// everything between preformatted toggles (` ``` `) are left exactly as they
+/// are. Blank lines are preserved.
+///
+/// A wrapped line is reflowed, so any run of whitespace within it becomes a
+/// single space. Lines that aren't wrapped are kept exactly, and every line
+/// keeps its line ending (`\n` or `\r\n`).
+///
+/// Columns are counted in unicode scalar values.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::gemtext;
+///
+/// let text = gemtext::wrap("> quoted text that wraps", 12);
+/// assert_eq!(text, "> quoted\n> text that\n> wraps");
+/// ```
+pub fn wrap(text: &str, width: usize) -> String {
+    let width = width.max(1);
+    let mut output = String::with_capacity(text.len());
+    let mut preformatted = false;
+
+    for raw in text.split_inclusive('\n') {
+        let line = raw.strip_suffix('\n').unwrap_or(raw);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let ending = &raw[line.len()..];
+        if is_toggle(line) {
+            preformatted = !preformatted;
+        }
+        if preformatted
+            || is_toggle(line)
+            || line.starts_with("=>")
+            || line.starts_with('#')
+            || line.starts_with("* ")
+            || line.chars().count() <= width
+        {
+            output.push_str(raw);
+            continue;
+        }
+
+        let wrapped = match line.strip_prefix('>') {
+            Some(quote) => wrap_words(quote, width.saturating_sub(2).max(1))
+                .into_iter()
+                .map(|wrapped| format!("> {wrapped}"))
+                .collect(),
+            None => wrap_words(line, width),
+        };
+        // The wrapped lines use the same line ending as the original line
+        let separator = if ending.is_empty() { "\n" } else { ending };
+        output.push_str(&wrapped.join(separator));
+        output.push_str(ending);
+    }
+
+    output
+}
+
+/// Make sure that `text` ends with exactly one line feed
+///
+/// Any trailing line endings (`\n` or `\r\n`) are replaced with a single
+/// `\n`. Empty text is left empty. Applying this more than once has no
+/// further effect.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::gemtext;
+///
+/// assert_eq!(gemtext::trailing_newline("Hello"), "Hello\n");
+/// assert_eq!(gemtext::trailing_newline("Hello\n\n\n"), "Hello\n");
+/// ```
+pub fn trailing_newline(text: &str) -> String {
+    if text.is_empty() {
+        return String::new();
+    }
+    let mut text = text.trim_end_matches(['\r', '\n']).to_owned();
+    text.push('\n');
+    text
+}
+
+/// Guard a line of preformatted content from closing the block
+///
+/// Lines that start with a toggle are prefixed with a space.
+fn guard_toggle(line: &str) -> String {
+    if is_toggle(line) {
+        format!(" {line}")
+    } else {
+        line.to_owned()
+    }
+}
+
+/// Wrap `content` in a preformatted block with `alt` text
+///
+/// Any line of `content` that would toggle preformatted mode is prefixed with
+/// a space so that arbitrary text can't close the block early. Newlines are
+/// removed from the alt text. The block ends with a newline.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::gemtext;
+///
+/// let block = gemtext::preformatted("rust", "let a = 1;\n```\n");
+/// assert_eq!(block, "```rust\nlet a = 1;\n ```\n```\n");
+/// ```
+pub fn preformatted(alt: &str, content: &str) -> String {
+    let alt: String = alt.chars().filter(|c| !matches!(c, '\r' | '\n')).collect();
+    let mut block = format!("```{alt}\n");
+    for line in content.lines() {
+        block.push_str(&guard_toggle(line));
+        block.push('\n');
+    }
+    block.push_str("```\n");
+    block
+}
+
+/// Wrap ASCII or Unicode art in a preformatted block
+///
+/// This is the same as [preformatted], except that blank lines at the start
+/// and end of `art` are removed so that the art can be written as a raw string
+/// literal on its own lines. Everything else, including trailing whitespace on
+/// each line, is kept as is so that the art renders verbatim. `alt` should
+/// describe the art for clients that can't display it, such as screen readers.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::gemtext;
+///
+/// let art = r"
+///  _  _
+/// | || |
+/// |_||_|
+/// ";
+/// let banner = gemtext::banner(art, "Hi");
+/// assert_eq!(banner, "```Hi\n _  _\n| || |\n|_||_|\n```\n");
+/// ```
+pub fn banner(art: &str, alt: &str) -> String {
+    let lines: Vec<&str> = art.lines().collect();
+    let start = lines
+        .iter()
+        .position(|line| !line.trim().is_empty())
+        .unwrap_or(lines.len());
+    let end = lines
+        .iter()
+        .rposition(|line| !line.trim().is_empty())
+        .map_or(start, |end| end + 1);
+    preformatted(alt, &lines[start..end].join("\n"))
+}
+
+/// How user-provided text is sanitized by [sanitize]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeMode {
+    /// Render every line as a plain text line
+    ///
+    /// Lines that start with `=>`, `#`, `*`, `>`, or ` ``` ` are escaped.
+    PlainText,
+    /// Allow list items (`* `) and quotes (`>`) but escape everything else
+    ///
+    /// Lines that start with `=>`, `#`, or ` ``` ` are escaped, so links,
+    /// headings, and preformatted blocks can't be created.
+    SafeSubset,
+}
+
+/// Sanitize user-provided gemtext before embedding it in a page
+///
+/// Any line that would be interpreted as a line type that isn't allowed by
+/// `mode` is escaped by inserting a single space at the start of the line.
+/// Gemtext line types are only recognized at the very start of a line, so the
+/// escaped line is displayed as plain text. This prevents user text from
+/// injecting links, breaking the layout with headings, or toggling
+/// preformatted mode for the rest of the page.
+///
+/// Line endings are normalized to `\n`. A trailing line ending is kept, so
+/// sanitized text can be joined with other gemtext.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::gemtext::{self, SanitizeMode};
+///
+/// let text = gemtext::sanitize("=> gemini://evil.example Click me\n* item", SanitizeMode::SafeSubset);
+/// assert_eq!(text, " => gemini://evil.example Click me\n* item");
+/// ```
+pub fn sanitize(text: &str, mode: SanitizeMode) -> String {
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| {
+            let escape = line.starts_with("=>")
+                || line.starts_with('#')
+                || is_toggle(line)
+                || (mode == SanitizeMode::PlainText
+                    && (line.starts_with('*') || line.starts_with('>')));
+            if escape {
+                format!(" {line}")
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect();
+    let mut sanitized = lines.join("\n");
+    if text.ends_with('\n') {
+        sanitized.push('\n');
+    }
+    sanitized
+}
+
+/// Render key/value pairs as a gemtext list sorted by key
+///
+/// Each pair is rendered as a `* key = value` list item. Pairs are sorted by
+/// key, then by value, so content from a [HashMap](std::collections::HashMap)
+/// or [env::vars](std::env::vars) always renders in the same order. Line
+/// breaks in keys and values are replaced with spaces so that each pair stays
+/// on one line.
+///
+/// ### Example
+///
+/// ```
+/// use std::collections::HashMap;
+/// use gemfra::gemtext;
+///
+/// let vars = HashMap::from([("PATH_INFO", "/"), ("AUTH_TYPE", "")]);
+/// assert_eq!(
+///     gemtext::sorted_definition_list(vars),
+///     "* AUTH_TYPE = \n* PATH_INFO = /\n"
+/// );
+/// ```
+pub fn sorted_definition_list<I, K, V>(pairs: I) -> String
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let single_line = |text: &str| text.replace(['\r', '\n'], " ");
+    let mut pairs: Vec<(String, String)> = pairs
+        .into_iter()
+        .map(|(key, value)| (single_line(key.as_ref()), single_line(value.as_ref())))
+        .collect();
+    pairs.sort();
+
+    let mut list = String::new();
+    for (key, value) in pairs {
+        list.push_str(&format!("* {key} = {value}\n"));
+    }
+    list
+}
+
+/// A block of a [GemDoc]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Block {
+    /// A heading with a level from 1 to 3
+    Heading { level: u8, text: String },
+    /// A line of text, which may be empty
+    Text(String),
+    /// A link with an optional label
+    Link { url: String, label: Option<String> },
+    /// A list where each item is rendered on its own line
+    List(Vec<String>),
+    /// A quote, where each line of the text is rendered as a quote line
+    Quote(String),
+    /// A preformatted block with alt text
+    Preformatted { alt: String, content: String },
+}
+
+/// A gemtext document as a tree of [blocks](Block)
+///
+/// Building a page as a string works well for simple pages, but the structure
+/// of the page is lost once it is written. A GemDoc keeps the structure until
+/// the page is [rendered](GemDoc::render), so it can be inspected and
+/// transformed first, for example to build a table of contents from its
+/// [headings](GemDoc::headings) or to convert it to another format. Existing
+/// gemtext can be turned into a GemDoc with [parse](GemDoc::parse).
+///
+/// Rendering always produces valid gemtext: text that would be interpreted as
+/// another line type is [escaped](sanitize), preformatted content can't close
+/// its block early, and line breaks are removed from headings, links, and list
+/// items.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::gemtext::{Block, GemDoc};
+///
+/// let doc = GemDoc::new()
+///     .push(Block::Heading { level: 1, text: "Links".to_owned() })
+///     .push(Block::Text("=> not a link".to_owned()))
+///     .push(Block::Link { url: "/about".to_owned(), label: Some("About".to_owned()) });
+/// assert_eq!(doc.render(), "# Links\n => not a link\n=> /about About\n");
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GemDoc {
+    /// The blocks of the document in order
+    pub blocks: Vec<Block>,
+}
+
+impl GemDoc {
+    /// Create an empty document
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a block to the end of the document
+    pub fn push(mut self, block: Block) -> Self {
+        self.blocks.push(block);
+        self
+    }
+
+    /// Parse a gemtext document
+    ///
+    /// Consecutive list items and quote lines are grouped into a single
+    /// [List](Block::List) or [Quote](Block::Quote). Headings deeper than
+    /// `###` are treated as level 3 headings.
+    pub fn parse(text: &str) -> Self {
+        let mut blocks = Vec::new();
+        let mut lines = text.lines();
+        while let Some(line) = lines.next() {
+            if let Some(alt) = line.strip_prefix("```") {
+                let mut content = Vec::new();
+                for line in lines.by_ref() {
+                    if is_toggle(line) {
+                        break;
+                    }
+                    content.push(line);
+                }
+                blocks.push(Block::Preformatted {
+                    alt: alt.trim().to_owned(),
+                    content: content.join("\n"),
+                });
+            } else if let Some(link) = line.strip_prefix("=>") {
+                let link = link.trim();
+                let (url, label) = match link.split_once(char::is_whitespace) {
+                    Some((url, label)) => (url, Some(label.trim().to_owned())),
+                    None => (link, None),
+                };
+                blocks.push(Block::Link {
+                    url: url.to_owned(),
+                    label,
+                });
+            } else if line.starts_with('#') {
+                let text = line.trim_start_matches('#');
+                let level = (line.len() - text.len()).min(3) as u8;
+                blocks.push(Block::Heading {
+                    level,
+                    text: text.trim().to_owned(),
+                });
+            } else if let Some(item) = line.strip_prefix("* ") {
+                match blocks.last_mut() {
+                    Some(Block::List(items)) => items.push(item.to_owned()),
+                    _ => blocks.push(Block::List(vec![item.to_owned()])),
+                }
+            } else if let Some(quote) = line.strip_prefix('>') {
+                let quote = quote.strip_prefix(' ').unwrap_or(quote);
+                match blocks.last_mut() {
+                    Some(Block::Quote(text)) => {
+                        text.push('\n');
+                        text.push_str(quote);
+                    }
+                    _ => blocks.push(Block::Quote(quote.to_owned())),
+                }
+            } else {
+                blocks.push(Block::Text(line.to_owned()));
+            }
+        }
+        Self { blocks }
+    }
+
+    /// Get the level and text of every heading in order
+    pub fn headings(&self) -> impl Iterator<Item = (u8, &str)> {
+        self.blocks.iter().filter_map(|block| match block {
+            Block::Heading { level, text } => Some((*level, text.as_str())),
+            _ => None,
+        })
+    }
+
+    /// Render the document as gemtext
+    pub fn render(&self) -> String {
+        let single_line = |text: &str| text.replace(['\r', '\n'], " ");
+        let mut out = String::new();
+        for block in &self.blocks {
+            match block {
+                Block::Heading { level, text } => {
+                    let level = (*level).clamp(1, 3) as usize;
+                    out.push_str(&"#".repeat(level));
+                    out.push(' ');
+                    out.push_str(&single_line(text));
+                    out.push('\n');
+                }
+                Block::Text(text) if text.is_empty() => out.push('\n'),
+                Block::Text(text) => {
+                    out.push_str(&sanitize(text, SanitizeMode::PlainText));
+                    out.push('\n');
+                }
+                Block::Link { url, label } => {
+                    out.push_str("=> ");
+                    out.push_str(&url.replace(char::is_whitespace, "%20"));
+                    if let Some(label) = label {
+                        out.push(' ');
+                        out.push_str(&single_line(label));
+                    }
+                    out.push('\n');
+                }
+                Block::List(items) => {
+                    for item in items {
+                        out.push_str("* ");
+                        out.push_str(&single_line(item));
+                        out.push('\n');
+                    }
+                }
+                Block::Quote(text) => {
+                    for line in text.lines() {
+                        out.push_str("> ");
+                        out.push_str(line);
+                        out.push('\n');
+                    }
+                }
+                Block::Preformatted { alt, content } => {
+                    out.push_str(&preformatted(alt, content));
+                }
+            }
+        }
+        out
+    }
+}
+
+impl GemDoc {
+    /// Render the document as plain text
+    ///
+    /// Headings, list items and quotes keep a simple marker so the structure
+    /// is still readable, and links are written as `label: url`.
+    pub fn to_plain_text(&self) -> String {
+        let mut out = String::new();
+        for block in &self.blocks {
+            match block {
+                Block::Heading { text, .. } => {
+                    out.push_str(text);
+                    out.push('\n');
+                }
+                Block::Text(text) => {
+                    out.push_str(text);
+                    out.push('\n');
+                }
+                Block::Link { url, label } => {
+                    if let Some(label) = label {
+                        out.push_str(label);
+                        out.push_str(": ");
+                    }
+                    out.push_str(url);
+                    out.push('\n');
+                }
+                Block::List(items) => {
+                    for item in items {
+                        out.push_str("- ");
+                        out.push_str(item);
+                        out.push('\n');
+                    }
+                }
+                Block::Quote(text) => {
+                    for line in text.lines() {
+                        out.push_str("> ");
+                        out.push_str(line);
+                        out.push('\n');
+                    }
+                }
+                Block::Preformatted { content, .. } => {
+                    out.push_str(content);
+                    out.push('\n');
+                }
+            }
+        }
+        out
+    }
+
+    /// Render the document as Markdown
+    ///
+    /// Every text line and link becomes its own paragraph, since each line of
+    /// gemtext is displayed on its own. Characters that have a meaning in
+    /// Markdown are escaped in text, and preformatted blocks become fenced
+    /// code blocks.
+    pub fn to_markdown(&self) -> String {
+        fn escape(text: &str) -> String {
+            let mut escaped = String::with_capacity(text.len());
+            for c in text.chars() {
+                if matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#') {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+            escaped
+        }
+
+        let mut out = String::new();
+        for block in &self.blocks {
+            match block {
+                Block::Heading { level, text } => {
+                    out.push_str(&"#".repeat((*level).clamp(1, 3) as usize));
+                    out.push(' ');
+                    out.push_str(&escape(text));
+                }
+                Block::Text(text) if text.is_empty() => continue,
+                Block::Text(text) => out.push_str(&escape(text)),
+                Block::Link { url, label } => {
+                    let label = label.as_deref().unwrap_or(url);
+                    out.push_str(&format!("[{}](<{url}>)", escape(label)));
+                }
+                Block::List(items) => {
+                    let items: Vec<String> = items
+                        .iter()
+                        .map(|item| format!("- {}", escape(item)))
+                        .collect();
+                    out.push_str(&items.join("\n"));
+                }
+                Block::Quote(text) => {
+                    let lines: Vec<String> = text
+                        .lines()
+                        .map(|line| format!("> {}", escape(line)))
+                        .collect();
+                    out.push_str(&lines.join("\n"));
+                }
+                Block::Preformatted { alt, content } => {
+                    // The fence must be longer than any run of backticks in
+                    // the content
+                    let longest = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+                    let fence = "`".repeat(longest.max(2) + 1);
+                    let alt = alt.replace(['\r', '\n', '`'], " ");
+                    out.push_str(&format!("{fence}{alt}\n{content}\n{fence}"));
+                }
+            }
+            out.push_str("\n\n");
+        }
+        out
+    }
+}
+
+/// Converts a stream of markdown events into gemtext lines
+#[cfg(feature = "markdown")]
+#[derive(Default)]
+struct MarkdownConverter {
+    /// The finished lines of gemtext
+    lines: Vec<String>,
+    /// The inline text of the current block
+    text: String,
+    /// Links found in the current block as (url, label)
+    links: Vec<(String, String)>,
+    /// The link currently being read as (url, start of the label in text, is image)
+    link: Option<(String, usize, bool)>,
+    /// The heading level of the current block
+    heading: Option<usize>,
+    /// How deeply nested the current block is within quotes
+    quote: usize,
+    /// How deeply nested the current block is within list items
+    items: usize,
+    /// The contents of the current code block
+    code: Option<String>,
+    /// The rows of the current table
+    table: Option<Vec<Vec<String>>>,
+    /// The cells of the current table row
+    row: Vec<String>,
+    /// Whether the last block written was a list item
+    last_was_item: bool,
+}
+
+#[cfg(feature = "markdown")]
+impl MarkdownConverter {
+    /// Separate a new block from the previous one
+    fn start_block(&mut self, is_item: bool) {
+        let continues_list = is_item && self.last_was_item;
+        if !self.lines.is_empty() && !continues_list {
+            self.lines.push(String::new());
+        }
+        self.last_was_item = is_item;
+    }
+
+    /// Write the links collected in the current block
+    fn flush_links(&mut self) {
+        for (url, label) in self.links.drain(..) {
+            let label = label.split_whitespace().collect::<Vec<_>>().join(" ");
+            match label.is_empty() {
+                true => self.lines.push(format!("=> {url}")),
+                false => self.lines.push(format!("=> {url} {label}")),
+            }
+        }
+    }
+
+    /// Write the inline text of the current block using `prefix` for each line
+    fn flush_text(&mut self, prefix: &str, is_item: bool) {
+        let text = std::mem::take(&mut self.text);
+        let text = text.trim();
+
+        // A paragraph that only holds a link doesn't need to repeat the label
+        let only_link =
+            !is_item && matches!(self.links.as_slice(), [(_, label)] if label.trim() == text);
+        if text.is_empty() || only_link {
+            if !self.links.is_empty() {
+                self.start_block(false);
+                self.flush_links();
+            }
+            return;
+        }
+
+        self.start_block(is_item);
+        let quote = if self.quote > 0 { "> " } else { "" };
+        for line in text.lines() {
+            self.lines.push(format!("{quote}{prefix}{}", line.trim()));
+        }
+        self.flush_links();
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if let Some(code) = &mut self.code {
+            code.push_str(text);
+        } else {
+            self.text.push_str(text);
+        }
+    }
+
+    fn convert<'a>(mut self, events: impl Iterator<Item = pulldown_cmark::Event<'a>>) -> String {
+        use pulldown_cmark::{CodeBlockKind, Event, Tag, TagEnd};
+
+        for event in events {
+            match event {
+                Event::Start(tag) => match tag {
+                    Tag::Heading { level, .. } => self.heading = Some(level as usize),
+                    Tag::BlockQuote(_) => self.quote += 1,
+                    Tag::Item => {
+                        if self.items > 0 {
+                            // Flatten nested lists by writing the parent item first
+                            self.flush_text("* ", true);
+                        }
+                        self.items += 1;
+                    }
+                    Tag::CodeBlock(kind) => {
+                        self.flush_text("", false);
+                        self.start_block(false);
+                        let alt = match kind {
+                            CodeBlockKind::Fenced(lang) => lang.to_string(),
+                            CodeBlockKind::Indented => String::new(),
+                        };
+                        self.lines.push(format!("```{alt}"));
+                        self.code = Some(String::new());
+                    }
+                    Tag::Table(_) => self.table = Some(Vec::new()),
+                    Tag::Link { dest_url, .. } => {
+                        self.link = Some((dest_url.to_string(), self.text.len(), false))
+                    }
+                    Tag::Image { dest_url, .. } => {
+                        self.link = Some((dest_url.to_string(), self.text.len(), true))
+                    }
+                    _ => {}
+                },
+                Event::End(tag) => match tag {
+                    TagEnd::Paragraph if self.items > 0 => self.text.push(' '),
+                    TagEnd::Paragraph => self.flush_text("", false),
+                    TagEnd::Heading(_) => {
+                        let level = self.heading.take().unwrap_or(1).clamp(1, 3);
+                        self.flush_text(&format!("{} ", "#".repeat(level)), false);
+                    }
+                    TagEnd::BlockQuote(_) => {
+                        self.flush_text("", false);
+                        self.quote = self.quote.saturating_sub(1);
+                    }
+                    TagEnd::Item => {
+                        self.flush_text("* ", true);
+                        self.items = self.items.saturating_sub(1);
+                    }
+                    TagEnd::CodeBlock => {
+                        let code = self.code.take().unwrap_or_default();
+                        self.lines
+                            .extend(code.trim_end_matches('\n').lines().map(guard_toggle));
+                        self.lines.push("```".to_owned());
+                        self.last_was_item = false;
+                    }
+                    TagEnd::TableCell => {
+                        let cell = std::mem::take(&mut self.text);
+                        self.row.push(cell.trim().to_owned());
+                    }
+                    TagEnd::TableHead | TagEnd::TableRow => {
+                        let row = std::mem::take(&mut self.row);
+                        if let Some(table) = &mut self.table {
+                            table.push(row);
+                        }
+                    }
+                    TagEnd::Table => {
+                        let rows = self.table.take().unwrap_or_default();
+                        self.start_block(false);
+                        self.lines.push("```".to_owned());
+                        self.lines.extend(render_table(&rows));
+                        self.lines.push("```".to_owned());
+                        self.flush_links();
+                    }
+                    TagEnd::Link | TagEnd::Image => {
+                        if let Some((url, start, is_image)) = self.link.take() {
+                            let label = self.text[start..].to_owned();
+                            if is_image {
+                                self.text.truncate(start);
+                            }
+                            self.links.push((url, label));
+                        }
+                    }
+                    _ => {}
+                },
+                Event::Text(text) => self.push_text(&text),
+                Event::Code(code) => self.push_text(&code),
+                Event::SoftBreak => self.push_text(" "),
+                Event::HardBreak => self.push_text("\n"),
+                Event::Rule => {
+                    self.flush_text("", false);
+                    self.start_block(false);
+                    self.lines.push("---".to_owned());
+                }
+                _ => {}
+            }
+        }
+        self.flush_text("", false);
+
+        let mut output = self.lines.join("\n");
+        output.push('\n');
+        output
+    }
+}
+
+/// Render the rows of a table as aligned columns
+#[cfg(feature = "markdown")]
+fn render_table(rows: &[Vec<String>]) -> Vec<String> {
+    let mut widths = Vec::new();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            let len = cell.chars().count();
+            match widths.get_mut(i) {
+                Some(width) if *width < len => *width = len,
+                Some(_) => {}
+                None => widths.push(len),
+            }
+        }
+    }
+
+    rows.iter()
+        .map(|row| {
+            let cells: Vec<_> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .collect();
+            cells.join(" | ").trim_end().to_owned()
+        })
+        .collect()
+}
+
+/// Convert a markdown document into gemtext
+///
+/// Since gemtext has no inline formatting, the markdown is converted as
+/// follows:
+///
+/// * Headings keep their level, clamped to at most 3 (`###`).
+/// * Inline links keep their label in the text, and each link is written on
+///   its own `=>` line after the block it was found in.
+/// * Images are removed from the text and become `=>` link lines with their
+///   alt text as the label.
+/// * Lists become `*` items. Nested lists are flattened and ordered lists lose
+///   their numbering.
+/// * Blockquotes become `>` lines.
+/// * Code blocks become preformatted blocks with the language as alt text.
+/// * Tables become preformatted blocks with aligned columns.
+/// * Emphasis and other inline formatting is removed.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::gemtext;
+///
+/// let text = gemtext::from_markdown("# Hello\n\nSee [my site](gemini://example.com).");
+/// assert_eq!(text, "# Hello\n\nSee my site.\n=> gemini://example.com my site\n");
+/// ```
+#[cfg(feature = "markdown")]
+pub fn from_markdown(markdown: &str) -> String {
+    use pulldown_cmark::{Options, Parser};
+
+    let parser = Parser::new_ext(markdown, Options::ENABLE_TABLES);
+    MarkdownConverter::default().convert(parser)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("short", 10), "short");
+        assert_eq!(wrap("one two three four", 9), "one two\nthree\nfour");
+        assert_eq!(wrap("unbreakableword", 4), "unbreakableword");
+        assert_eq!(wrap("a b\n\nc d", 1), "a\nb\n\nc\nd");
+        assert_eq!(wrap("a b\n", 1), "a\nb\n");
+    }
+
+    #[test]
+    fn test_wrap_whitespace() {
+        // Wrapped lines are reflowed, other lines are kept as they are
+        assert_eq!(wrap("one  two   three", 9), "one two\nthree");
+        assert_eq!(wrap("a  b", 9), "a  b");
+        assert_eq!(
+            wrap("one two three\r\nshort  line\r\n", 12),
+            "one two\r\nthree\r\nshort  line\r\n"
+        );
+    }
+
+    #[test]
+    fn test_trailing_newline() {
+        assert_eq!(trailing_newline(""), "");
+        assert_eq!(trailing_newline("a"), "a\n");
+        assert_eq!(trailing_newline("a\n"), "a\n");
+        assert_eq!(trailing_newline("a\n\r\n\n"), "a\n");
+        assert_eq!(trailing_newline(&trailing_newline("a\nb")), "a\nb\n");
+    }
+
+    #[test]
+    fn test_gem_doc() {
+        let text = "# Title\n\nSome text\n=> /a A link\n=>/b\n* one\n* two\n> quote\n>more\n```alt\n# code\n```\n#### Deep\n";
+        let doc = GemDoc::parse(text);
+        assert_eq!(
+            doc.blocks,
+            [
+                Block::Heading {
+                    level: 1,
+                    text: "Title".to_owned()
+                },
+                Block::Text("".to_owned()),
+                Block::Text("Some text".to_owned()),
+                Block::Link {
+                    url: "/a".to_owned(),
+                    label: Some("A link".to_owned())
+                },
+                Block::Link {
+                    url: "/b".to_owned(),
+                    label: None
+                },
+                Block::List(vec!["one".to_owned(), "two".to_owned()]),
+                Block::Quote("quote\nmore".to_owned()),
+                Block::Preformatted {
+                    alt: "alt".to_owned(),
+                    content: "# code".to_owned()
+                },
+                Block::Heading {
+                    level: 3,
+                    text: "Deep".to_owned()
+                },
+            ]
+        );
+        assert_eq!(
+            doc.headings().collect::<Vec<_>>(),
+            [(1, "Title"), (3, "Deep")]
+        );
+        assert_eq!(
+            doc.render(),
+            "# Title\n\nSome text\n=> /a A link\n=> /b\n* one\n* two\n> quote\n> more\n```alt\n# code\n```\n### Deep\n"
+        );
+
+        let doc = GemDoc::new()
+            .push(Block::Heading {
+                level: 0,
+                text: "a\nb".to_owned(),
+            })
+            .push(Block::Text("* item\n```".to_owned()))
+            .push(Block::Link {
+                url: "/a b".to_owned(),
+                label: None,
+            })
+            .push(Block::Preformatted {
+                alt: "".to_owned(),
+                content: "```".to_owned(),
+            });
+        assert_eq!(
+            doc.render(),
+            "# a b\n * item\n ```\n=> /a%20b\n```\n ```\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_gem_doc_formats() {
+        let doc = GemDoc::parse("# A *title*\nText\n\n=> /x X\n* a\n```\n ``` x\n```");
+        assert_eq!(
+            doc.to_plain_text(),
+            "A *title*\nText\n\nX: /x\n- a\n ``` x\n"
+        );
+        assert_eq!(
+            doc.to_markdown(),
+            "# A \\*title\\*\n\nText\n\n[X](</x>)\n\n- a\n\n````\n ``` x\n````\n\n"
+        );
+    }
+
+    #[test]
+    fn test_preformatted() {
+        assert_eq!(
+            preformatted("a\nb", "```\ntext\n  ```"),
+            "```ab\n ```\ntext\n  ```\n```\n"
+        );
+        assert_eq!(preformatted("", ""), "```\n```\n");
+    }
+
+    #[test]
+    fn test_sorted_definition_list() {
+        let pairs = vec![("b", "2"), ("a", "multi\nline"), ("b", "1")];
+        assert_eq!(
+            sorted_definition_list(pairs),
+            "* a = multi line\n* b = 1\n* b = 2\n"
+        );
+        assert_eq!(sorted_definition_list(Vec::<(String, String)>::new()), "");
+    }
+
+    #[test]
+    fn test_banner() {
+        assert_eq!(
+            banner("\n  \n /\\  \n```\n\n", "art"),
+            "```art\n /\\  \n ```\n```\n"
+        );
+        assert_eq!(banner("\n\n", ""), "```\n```\n");
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let text = "# Title\r\n=>/ link\n```\n* item\n> quote\ntext";
+        assert_eq!(
+            sanitize(text, SanitizeMode::PlainText),
+            " # Title\n =>/ link\n ```\n * item\n > quote\ntext"
+        );
+        assert_eq!(
+            sanitize(text, SanitizeMode::SafeSubset),
+            " # Title\n =>/ link\n ```\n* item\n> quote\ntext"
+        );
+
+        // The line ending of the last line is kept
+        let text = format!("{}{}", sanitize("a\r\n", SanitizeMode::PlainText), "# b\n");
+        assert_eq!(text, "a\n# b\n");
+        assert_eq!(sanitize("\n", SanitizeMode::PlainText), "\n");
+        assert_eq!(sanitize("", SanitizeMode::PlainText), "");
+    }
+
+    #[test]
+    fn test_wrap_preserves_lines() {
+        let text =
+            "=> gemini://example.com a long link label\n# A long heading\n* a long list item";
+        assert_eq!(wrap(text, 5), text);
+
+        let text = "```\nsome long preformatted text\n```\nsome long text";
+        assert_eq!(
+            wrap(text, 9),
+            "```\nsome long preformatted text\n```\nsome long\ntext"
+        );
+    }
+
+    #[cfg(feature = "markdown")]
+    #[test]
+    fn test_from_markdown() {
+        let markdown = "# Title
+
+Some *text* with [a link](gemini://a.com) and
+a soft break.
+
+![an image](/img.png)
+
+* one
+  * nested
+* [two](/two)
+
+> quoted
+
+1. first
+2. second
+
+```rust
+let a = 1;
+```
+
+| a | bb |
+|---|----|
+| ccc | d |
+";
+        let expected = "# Title
+
+Some text with a link and a soft break.
+=> gemini://a.com a link
+
+=> /img.png an image
+
+* one
+* nested
+* two
+=> /two two
+
+> quoted
+
+* first
+* second
+
+```rust
+let a = 1;
+```
+
+```
+a   | bb
+ccc | d
+```
+";
+        assert_eq!(from_markdown(markdown), expected);
+        assert_eq!(from_markdown("#### Deep"), "### Deep\n");
+    }
+}
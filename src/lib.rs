@@ -26,12 +26,30 @@
 //!
 //! * [run_cgi](protocol::Cgi::run_cgi): Run a CGI application
 //! * [run_scgi](protocol::Scgi::run_scgi): Run a SCGI application
-#![cfg_attr(doc, feature(doc_auto_cfg))]
+//! * [run_spartan](protocol::Spartan::run_spartan): Run a Spartan server
+//! * [run_gemini](protocol::Gemini::run_gemini): Run a standalone Gemini server over TLS
+//! * [run_titan](protocol::Titan::run_titan): Run a standalone Gemini server that accepts Titan uploads
+#![cfg_attr(docsrs, feature(doc_cfg))]
+
+// Allow the codegen macros to be used within the crate's own tests
+#[cfg(test)]
+extern crate self as gemfra;
 
 pub mod application;
+pub mod archive;
+#[cfg(feature = "bench")]
+pub mod bench;
 pub mod error;
+pub mod form;
+pub mod gemtext;
+pub mod memfs;
 pub mod protocol;
+pub mod query;
 pub mod request;
 pub mod response;
+pub mod robots;
 #[cfg(feature = "routed")]
 pub mod routed;
+pub mod session;
+#[cfg(feature = "stats")]
+pub mod stats;
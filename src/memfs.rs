@@ -0,0 +1,153 @@
+//! In-memory Filesystem
+//!
+//! Serve files that are baked into the binary or loaded at startup with
+//! [MemoryFs]. This allows for fully self-contained capsules that don't depend
+//! on any files on disk. Content can be included at compile time with
+//! [include_bytes!](std::include_bytes) or generated at startup.
+//!
+//! ### Example
+//!
+//! ```no_run
+//! use gemfra::{memfs::MemoryFs, protocol::Cgi};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let mut fs = MemoryFs::new();
+//!     fs.insert("/index.gmi", "text/gemini", "# Hello World!");
+//!     fs.insert("/about/index.gmi", "text/gemini", "# About");
+//!
+//!     fs.run_cgi().await;
+//! }
+//! ```
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::{
+    application::Application, error::AnyError, query::join_segments, request::Request,
+    response::Response,
+};
+
+/// The file that is served when a directory is requested
+const INDEX: &str = "index.gmi";
+
+/// An application that serves files from memory
+///
+/// Each path maps to a MIME type and its content. Requests for a path that
+/// isn't stored will respond with __51__ Not Found.
+///
+/// Directories are served from their `index.gmi` file, so a request for `/dir/`
+/// will serve `/dir/index.gmi`. A request for `/dir` will be redirected to
+/// `/dir/` so that relative links in the index work as expected.
+#[derive(Default)]
+pub struct MemoryFs {
+    files: HashMap<String, (String, Bytes)>,
+}
+
+impl MemoryFs {
+    /// Create an empty filesystem
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a file to the filesystem
+    ///
+    /// A leading `/` is added to the path if it is missing. Any file already
+    /// stored at the path is replaced.
+    pub fn insert(
+        &mut self,
+        path: impl AsRef<str>,
+        mime: impl Into<String>,
+        content: impl Into<Bytes>,
+    ) {
+        let path = path.as_ref();
+        let path = if path.starts_with('/') {
+            path.to_owned()
+        } else {
+            format!("/{path}")
+        };
+        self.files.insert(path, (mime.into(), content.into()));
+    }
+
+    /// Remove a file from the filesystem
+    pub fn remove(&mut self, path: &str) -> Option<(String, Bytes)> {
+        self.files.remove(path)
+    }
+
+    /// Get the response for a request
+    fn lookup(&self, request: &Request) -> Response {
+        let path = match request.path.as_str() {
+            "" => "/",
+            path => path,
+        };
+
+        if path.ends_with('/') {
+            if let Some((mime, content)) = self.files.get(&format!("{path}{INDEX}")) {
+                return Response::success(mime, content.clone());
+            }
+        } else if let Some((mime, content)) = self.files.get(path) {
+            return Response::success(mime, content.clone());
+        } else if self.files.contains_key(&format!("{path}/{INDEX}")) {
+            let segments: Vec<_> = path.split('/').collect();
+            return Response::redirect_perm(format!(
+                "{}{}/",
+                request.script.trim_end_matches('/'),
+                join_segments(&segments)
+            ));
+        }
+
+        Response::not_found("Path not found")
+    }
+}
+
+#[async_trait]
+impl Application for MemoryFs {
+    async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+        Ok(self.lookup(&request))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_fs() {
+        let mut fs = MemoryFs::new();
+        fs.insert("index.gmi", "text/gemini", "root");
+        fs.insert("/dir/index.gmi", "text/gemini", "dir");
+        fs.insert("/dir/file.txt", "text/plain", "file");
+
+        let response = fs.handle_request(Request::mock("/")).await.unwrap();
+        assert_eq!(response.read_body().await, "root");
+
+        let response = fs.handle_request(Request::mock("/dir/")).await.unwrap();
+        assert_eq!(response.read_body().await, "dir");
+
+        let response = fs.handle_request(Request::mock("/dir")).await.unwrap();
+        assert_eq!(response.code, 31);
+        assert_eq!(response.meta, "/dir/");
+
+        let response = fs
+            .handle_request(Request::mock("/dir/file.txt"))
+            .await
+            .unwrap();
+        assert_eq!(response.meta, "text/plain");
+        assert_eq!(response.read_body().await, "file");
+
+        let response = fs.handle_request(Request::mock("/missing")).await.unwrap();
+        assert_eq!(response.code, 51);
+
+        // The redirect stays within the app when it is mounted under a script
+        fs.insert("/my dir/index.gmi", "text/gemini", "spaced");
+        let mut request = Request::mock("/my dir");
+        request.script = "/cgi".to_owned();
+        let response = fs.handle_request(request).await.unwrap();
+        assert_eq!(
+            (response.code, response.meta.as_str()),
+            (31, "/cgi/my%20dir/")
+        );
+    }
+}
@@ -4,31 +4,127 @@
 //! for each protocol to be executed in the same way regardless of the application
 //! that is used.
 
+#[cfg(any(
+    feature = "cgi",
+    feature = "scgi",
+    feature = "spartan",
+    feature = "gemini"
+))]
 use async_trait::async_trait;
-use std::{env, io};
+#[cfg(any(
+    feature = "cgi",
+    feature = "scgi",
+    feature = "spartan",
+    feature = "gemini"
+))]
+use std::io;
+#[cfg(feature = "cgi")]
+use std::{env, io::Write};
 
+#[cfg(feature = "gemini")]
+use crate::request::{TitanParams, Upload};
+#[cfg(feature = "spartan")]
+use bytes::Bytes;
 #[cfg(feature = "scgi")]
 use bytes::BytesMut;
+#[cfg(any(feature = "scgi", feature = "spartan", feature = "gemini"))]
+use std::sync::Arc;
+#[cfg(any(
+    feature = "cgi",
+    feature = "scgi",
+    feature = "spartan",
+    feature = "gemini"
+))]
+use std::time::Duration;
 #[cfg(feature = "scgi")]
-use std::{collections::HashMap, error::Error, sync::Arc};
+use std::{collections::HashMap, error::Error, future::Future};
+#[cfg(any(feature = "spartan", feature = "gemini"))]
+use tokio::io::{AsyncRead, AsyncWrite};
 #[cfg(feature = "scgi")]
+use tokio::net::TcpStream;
+#[cfg(any(feature = "scgi", feature = "spartan", feature = "gemini"))]
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, ToSocketAddrs},
+    net::ToSocketAddrs,
 };
+#[cfg(feature = "scgi")]
+use tokio::{sync::Semaphore, task::JoinSet};
+#[cfg(feature = "gemini")]
+pub use tokio_rustls::rustls;
 
+#[cfg(any(feature = "cgi", feature = "scgi"))]
+use crate::error::GemErrorType;
+#[cfg(feature = "cgi")]
+use crate::error::ToGemError;
+#[cfg(any(
+    feature = "cgi",
+    feature = "scgi",
+    feature = "spartan",
+    feature = "gemini"
+))]
 use crate::{
     application::Application,
-    error::{GemError, ToGemError},
+    error::{is_client_disconnect, AnyError, CloseConnection, GemError},
     request::Request,
     response::Response,
 };
 
 #[cfg(feature = "cgi")]
-async fn send_cgi_response(response: Response) {
-    if let Err(err) = response.send_sync(&mut io::stdout()).await {
-        eprintln!("Could not send response: {err}");
+async fn send_cgi_response<W>(response: Response, writer: &mut W, config: &CgiConfig)
+where
+    W: Write + ?Sized,
+{
+    match response
+        .send_sync_buffered(writer, config.buffer_size)
+        .await
+    {
+        Err(err) if is_client_disconnect(&err) => {}
+        Err(err) => eprintln!("Could not send response: {err}"),
+        Ok(_) => {}
+    }
+}
+
+/// Run the application's handler, giving up after `timeout`
+///
+/// The handler runs as part of the returned future, so it is dropped rather
+/// than left running once the timeout elapses.
+#[cfg(any(feature = "cgi", feature = "scgi"))]
+async fn handle_with_timeout<A>(
+    app: &A,
+    request: Request,
+    timeout: Option<Duration>,
+) -> Result<Response, AnyError>
+where
+    A: Application + ?Sized,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return app.handle_request(request).await,
     };
+    match tokio::time::timeout(timeout, app.handle_request(request)).await {
+        Ok(result) => result,
+        Err(_) => {
+            eprintln!("Handler timed out after {timeout:?}");
+            Ok(Response::error_cgi("Handler timed out"))
+        }
+    }
+}
+
+/// Convert an error from an application into a response
+#[cfg(any(
+    feature = "cgi",
+    feature = "scgi",
+    feature = "spartan",
+    feature = "gemini"
+))]
+fn error_response<A>(app: &A, err: AnyError) -> Response
+where
+    A: Application + ?Sized,
+{
+    match err.downcast::<GemError>() {
+        Ok(err) => Response::from(*err),
+        Err(err) => app.internal_error_response(&err),
+    }
 }
 
 #[cfg(feature = "cgi")]
@@ -36,6 +132,56 @@ fn get_cgi_header(key: &str) -> Result<String, GemError> {
     env::var(key).into_gem()
 }
 
+/// Settings for a CGI script
+///
+/// See [run_cgi_with_config](Cgi::run_cgi_with_config).
+#[cfg(feature = "cgi")]
+#[derive(Debug, Clone)]
+pub struct CgiConfig {
+    buffer_size: usize,
+    request_timeout: Option<Duration>,
+}
+
+#[cfg(feature = "cgi")]
+impl CgiConfig {
+    /// Create the default settings
+    ///
+    /// By default, streamed bodies are written to stdout in chunks of
+    /// [DEFAULT_BUFFER_SIZE](Response::DEFAULT_BUFFER_SIZE) bytes and requests
+    /// have no timeout.
+    pub fn new() -> Self {
+        Self {
+            buffer_size: Response::DEFAULT_BUFFER_SIZE,
+            request_timeout: None,
+        }
+    }
+
+    /// Set the size of the buffer used when streaming a body to stdout
+    ///
+    /// Larger buffers need fewer writes, which speeds up large downloads.
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size.max(1);
+        self
+    }
+
+    /// Give up on the application's handler after `timeout`
+    ///
+    /// If [handle_request](Application::handle_request) hasn't finished in
+    /// time, it is cancelled and a __42__ CGI Error is sent instead. The
+    /// timeout only covers the handler, not sending a streamed body.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+}
+
+#[cfg(feature = "cgi")]
+impl Default for CgiConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Common Gateway Interface
 ///
 /// Run the application using the CGI protocol. This is a one-shot program that
@@ -88,33 +234,206 @@ pub trait Cgi: Application + Sized + Send + Sync + 'static {
     /// }
     /// ```
     async fn run_cgi(self) {
-        let request = match Request::parse_request(get_cgi_header) {
-            Ok(request) => request,
+        self.run_cgi_to(&mut io::stdout()).await;
+    }
+
+    /// Run the application using the CGI protocol, sending the response to
+    /// `writer` instead of stdout.
+    ///
+    /// This is the same as [run_cgi](Cgi::run_cgi), but allows the response
+    /// to be captured, which is useful for tests and wrappers.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::{application::Application, request::Request, response::Response, error::AnyError};
+    /// # use async_trait::async_trait;
+    /// use gemfra::protocol::Cgi;
+    ///
+    /// # struct MyApp;
+    /// # #[async_trait]
+    /// # impl Application for MyApp {
+    /// #     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    /// #         todo!("Handle the request")
+    /// #     }
+    /// # }
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let mut output = Vec::new();
+    ///     MyApp.run_cgi_to(&mut output).await;
+    /// }
+    /// ```
+    async fn run_cgi_to<W>(self, writer: &mut W)
+    where
+        W: Write + Send + ?Sized,
+    {
+        serve_cgi(self, get_cgi_header, writer, &CgiConfig::default()).await;
+    }
+
+    /// Run the application using the CGI protocol with custom settings
+    ///
+    /// This is the same as [run_cgi](Cgi::run_cgi), but the script is
+    /// configured with `config`. See [CgiConfig] for the available settings.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::{application::Application, request::Request, response::Response, error::AnyError};
+    /// # use async_trait::async_trait;
+    /// use gemfra::protocol::{Cgi, CgiConfig};
+    ///
+    /// # struct MyApp;
+    /// # #[async_trait]
+    /// # impl Application for MyApp {
+    /// #     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    /// #         todo!("Handle the request")
+    /// #     }
+    /// # }
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let config = CgiConfig::new().buffer_size(64 * 1024);
+    ///     MyApp.run_cgi_with_config(config).await;
+    /// }
+    /// ```
+    async fn run_cgi_with_config(self, config: CgiConfig) {
+        serve_cgi(self, get_cgi_header, &mut io::stdout(), &config).await;
+    }
+
+    /// Run the application using the CGI protocol without an async main.
+    ///
+    /// This is the same as [run_cgi](Cgi::run_cgi), but it creates a minimal
+    /// single threaded runtime to drive the application. Since a new process
+    /// is started for every CGI request, this can reduce the startup time of
+    /// the application and removes the need for `#[tokio::main]`.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// use gemfra::{
+    ///     protocol::Cgi,
+    ///     application::Application,
+    ///     request::Request,
+    ///     response::Response,
+    ///     error::AnyError,
+    /// };
+    /// use async_trait::async_trait;
+    ///
+    /// struct MyApp;
+    /// #[async_trait]
+    /// impl Application for MyApp {
+    ///     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    ///         todo!("Handle the request")
+    ///     }
+    /// }
+    ///
+    /// fn main() {
+    ///     MyApp.run_cgi_blocking();
+    /// }
+    /// ```
+    #[cfg(feature = "cgi-blocking")]
+    fn run_cgi_blocking(self) {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
             Err(err) => {
-                eprintln!("Invalid CGI header: {err}");
-                send_cgi_response(Response::error_cgi("Invalid CGI header")).await;
+                eprintln!("Could not start the runtime: {err}");
                 return;
             }
         };
+        runtime.block_on(self.run_cgi());
+    }
+}
 
-        let response = match self.handle_request(request).await {
-            Ok(response) => response,
-            Err(err) => {
-                eprintln!("Error while handling request: {err}");
-                match err.downcast::<GemError>() {
-                    Ok(err) => Response::from(*err),
-                    Err(_) => Response::error_cgi("Internal Server Error"),
-                }
-            }
-        };
+#[cfg(feature = "cgi")]
+async fn serve_cgi<T, F, W>(app: T, get_var: F, writer: &mut W, config: &CgiConfig)
+where
+    T: Application + Send + Sync,
+    F: Fn(&str) -> Result<String, GemError>,
+    W: Write + Send + ?Sized,
+{
+    let request = match Request::parse_request(get_var) {
+        Ok(request) => request,
+        Err(err) => {
+            eprintln!("Invalid CGI header: {err}");
+            let response = match err.error_type {
+                GemErrorType::BadRequest => Response::from(err),
+                _ => Response::error_cgi("Invalid CGI header"),
+            };
+            send_cgi_response(response, writer, config).await;
+            return;
+        }
+    };
 
-        send_cgi_response(response).await;
-    }
+    let response = match handle_with_timeout(&app, request, config.request_timeout).await {
+        Ok(response) => response,
+        Err(err) if err.is::<CloseConnection>() => {
+            eprintln!("{err}");
+            return;
+        }
+        Err(err) => {
+            eprintln!("Error while handling request: {err}");
+            error_response(&app, err)
+        }
+    };
+
+    send_cgi_response(response, writer, config).await;
 }
 
 #[cfg(feature = "cgi")]
 impl<A> Cgi for A where A: Application + Send + Sync + 'static {}
 
+/// The number of bytes of a raw SCGI header that are kept for diagnostics
+#[cfg(feature = "scgi")]
+const MAX_RAW_HEADER: usize = 512;
+
+/// Attach the raw SCGI header to a parse error so that it can be logged.
+#[cfg(feature = "scgi")]
+fn raw_header_error(err: impl std::fmt::Display, raw: &[u8]) -> Box<dyn Error + Send + Sync> {
+    let shown = &raw[..raw.len().min(MAX_RAW_HEADER)];
+    let ellipsis = if raw.len() > MAX_RAW_HEADER {
+        "..."
+    } else {
+        ""
+    };
+    Box::new(GemError::runtime_error(format!(
+        "{err} (raw header: \"{}{ellipsis}\")",
+        shown.escape_ascii()
+    )))
+}
+
+#[cfg(feature = "scgi")]
+fn parse_scgi_headers(buffer: &[u8]) -> Result<Request, Box<dyn Error + Send + Sync>> {
+    // Parse the headers
+    let mut headers = HashMap::new();
+    let mut values = buffer.split(|c| *c == b'\0');
+    while let Some(key) = values.next() {
+        if let Some(val) = values.next() {
+            let key = std::str::from_utf8(key)?;
+            let val = std::str::from_utf8(val)?;
+            headers.insert(key, val);
+        } else {
+            if !key.is_empty() {
+                return Err(Box::new(GemError::runtime_error("Missing header value")));
+            }
+            break;
+        }
+    }
+
+    // Create a Request from the headers
+    Ok(Request::parse_request(|k| {
+        headers
+            .get(k)
+            .map(|v| (*v).to_owned())
+            .ok_or(GemError::runtime_error(format!("Missing header {k}")))
+    })?)
+}
+
+/// The maximum size of the SCGI headers
+#[cfg(feature = "scgi")]
+const MAX_SCGI_HEADERS: usize = 64 * 1024;
+
 #[cfg(feature = "scgi")]
 async fn read_scgi_request(conn: &mut TcpStream) -> Result<Request, Box<dyn Error + Send + Sync>> {
     // Read the length of the headers
@@ -125,50 +444,210 @@ async fn read_scgi_request(conn: &mut TcpStream) -> Result<Request, Box<dyn Erro
             break;
         }
         buf.push(chr);
+        if buf.len() > 20 {
+            return Err(raw_header_error("Header length is too long", &buf));
+        }
     }
-    let size: usize = String::from_utf8(buf)?.parse()?;
+    let size: usize = match std::str::from_utf8(&buf).map(str::parse) {
+        Ok(Ok(size)) if size > MAX_SCGI_HEADERS => {
+            return Err(raw_header_error("Headers are too large", &buf))
+        }
+        Ok(Ok(size)) => size,
+        _ => return Err(raw_header_error("Invalid header length", &buf)),
+    };
 
     // Read the headers
     let mut buffer = BytesMut::zeroed(size);
     conn.read_exact(buffer.as_mut()).await?;
+    // The headers are a netstring, so they end with a comma
+    if conn.read_u8().await? != b',' {
+        return Err(raw_header_error(
+            "Headers are missing the trailing comma",
+            &buffer,
+        ));
+    }
 
-    // Parse the headers
-    let mut headers = HashMap::new();
-    let mut values = buffer.as_ref().split(|c| *c == b'\0');
-    loop {
-        if let Some(key) = values.next() {
-            if let Some(val) = values.next() {
-                let key = std::str::from_utf8(key)?;
-                let val = std::str::from_utf8(val)?;
-                headers.insert(key, val);
-            } else {
-                if !key.is_empty() {
-                    return Err(Box::new(GemError::runtime_error("Missing header value")));
+    parse_scgi_headers(&buffer).map_err(|e| match e.downcast::<GemError>() {
+        // Invalid requests are sent to the client as they are
+        Ok(err) if err.error_type == GemErrorType::BadRequest => err,
+        Ok(err) => raw_header_error(err, &buffer),
+        Err(e) => raw_header_error(e, &buffer),
+    })
+}
+
+/// Send a SCGI response, returning the number of bytes sent if it succeeded
+#[cfg(feature = "scgi")]
+async fn send_scgi_response(mut conn: TcpStream, response: Response) -> Option<u64> {
+    // A client that disconnects early is normal, so it is not logged as a failure
+    let sent = match response.send_async(&mut conn).await {
+        Err(e) if is_client_disconnect(&e) => return None,
+        Err(e) => {
+            eprintln!("Could not send body: {e}");
+            None
+        }
+        Ok(sent) => Some(sent),
+    };
+    if let Err(e) = conn.shutdown().await {
+        if !is_client_disconnect(&e) {
+            eprintln!("Could not shutdown connection: {e}");
+        }
+    };
+    sent
+}
+
+#[cfg(feature = "scgi")]
+async fn handle_scgi_connection<A>(app: &A, mut conn: TcpStream, config: &ScgiConfig)
+where
+    A: Application + Sync,
+{
+    let mut path = None;
+    let response = match read_scgi_request(&mut conn).await {
+        Ok(request) => {
+            path = Some(request.path.clone());
+            match handle_with_timeout(app, request, config.request_timeout).await {
+                Ok(response) => response,
+                Err(err) if err.is::<CloseConnection>() => {
+                    eprintln!("{}\t{err}", path.unwrap_or_default());
+                    return;
+                }
+                Err(err) => {
+                    eprintln!("Error while handling request: {err}");
+                    error_response(app, err)
                 }
-                break;
             }
-        } else {
-            break;
         }
+        Err(e) => {
+            eprintln!("Invalid SCGI header: {e}");
+            match e.downcast::<GemError>() {
+                Ok(err) if err.error_type == GemErrorType::BadRequest => Response::from(*err),
+                _ => Response::error_cgi("Invalid CGI header"),
+            }
+        }
+    };
+
+    let (code, meta, len) = (response.code, response.meta.clone(), response.len());
+    let sent = send_scgi_response(conn, response).await;
+    if config.verbosity >= Verbosity::Requests {
+        let or_dash = |value: Option<u64>| value.map_or("-".into(), |value| value.to_string());
+        eprintln!(
+            "{}\t{}\t{}\t{}\t{}",
+            path.unwrap_or("".into()),
+            code,
+            meta,
+            or_dash(len),
+            or_dash(sent)
+        );
     }
+}
 
-    // Create a Request from the headers
-    Ok(Request::parse_request(|k| {
-        headers
-            .get(k)
-            .map(|v| (*v).to_owned())
-            .ok_or(GemError::runtime_error(format!("Missing header {k}")))
-    })?)
+/// How much a SCGI or Spartan server logs to stderr
+///
+/// Errors are always logged.
+#[cfg(any(feature = "scgi", feature = "spartan"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only log errors
+    Quiet,
+    /// Log the address that the server is listening to
+    Startup,
+    /// Log the address and the path, status, meta, body length, and bytes sent
+    /// of every request
+    ///
+    /// The body length is `-` if it isn't known ahead of time, and the bytes
+    /// sent include the header. Spartan servers don't log the body length.
+    Requests,
+}
+
+/// Settings for a SCGI server
+///
+/// See [run_scgi_with_config](Scgi::run_scgi_with_config).
+#[cfg(feature = "scgi")]
+#[derive(Debug, Clone)]
+pub struct ScgiConfig {
+    max_connections: usize,
+    slow_down: Option<(u32, u32)>,
+    verbosity: Verbosity,
+    request_timeout: Option<Duration>,
 }
 
 #[cfg(feature = "scgi")]
-async fn send_scgi_response(mut conn: TcpStream, response: Response) {
-    if let Err(e) = response.send_async(&mut conn).await {
-        eprintln!("Could not send body: {e}");
+impl ScgiConfig {
+    /// The fraction of connections in use before adaptive shedding begins
+    const SHED_THRESHOLD: f64 = 0.75;
+
+    /// How long a shed connection has to accept its __44__ Slow Down response
+    const SHED_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// Create the default settings
+    ///
+    /// By default, up to 1024 connections are handled at once, requests are
+    /// not shed, every request is logged, and requests have no timeout.
+    pub fn new() -> Self {
+        Self {
+            max_connections: 1024,
+            slow_down: None,
+            verbosity: Verbosity::Requests,
+            request_timeout: None,
+        }
+    }
+
+    /// Set the maximum number of connections that are handled at once
+    ///
+    /// Once the limit is reached, new connections wait until another
+    /// connection finishes.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max.max(1);
+        self
+    }
+
+    /// Shed load by responding with __44__ Slow Down when the server is busy
+    ///
+    /// Once 75% of the [connections](ScgiConfig::max_connections) are in use,
+    /// new connections are rejected before the request is read. The number of
+    /// seconds that the client is asked to wait scales from `min_seconds` to
+    /// `max_seconds` as the server approaches the connection limit.
+    pub fn adaptive_slow_down(mut self, min_seconds: u32, max_seconds: u32) -> Self {
+        self.slow_down = Some((min_seconds, max_seconds.max(min_seconds)));
+        self
+    }
+
+    /// Set how much is logged to stderr
+    ///
+    /// Nothing is ever written to stdout, so it is free for other uses.
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Give up on the application's handler after `timeout`
+    ///
+    /// If [handle_request](Application::handle_request) hasn't finished in
+    /// time, it is cancelled and a __42__ CGI Error is sent instead, which
+    /// frees the connection. The timeout only covers the handler, not sending
+    /// a streamed body.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Get the number of seconds to ask a client to wait, if the connection
+    /// should be shed
+    fn slow_down_seconds(&self, in_use: usize) -> Option<u32> {
+        let (min, max) = self.slow_down?;
+        let usage = in_use as f64 / self.max_connections as f64;
+        if usage < Self::SHED_THRESHOLD {
+            return None;
+        }
+        let pressure = ((usage - Self::SHED_THRESHOLD) / (1.0 - Self::SHED_THRESHOLD)).min(1.0);
+        Some(min + ((max - min) as f64 * pressure).round() as u32)
+    }
+}
+
+#[cfg(feature = "scgi")]
+impl Default for ScgiConfig {
+    fn default() -> Self {
+        Self::new()
     }
-    if let Err(e) = conn.shutdown().await {
-        eprintln!("Could not shutdown connection: {e}");
-    };
 }
 
 /// Simple Common Gateway Interface
@@ -218,48 +697,1671 @@ pub trait Scgi: Application + Sized + Send + Sync + 'static {
     where
         A: ToSocketAddrs + Send + Sync,
     {
-        let listener = tokio::net::TcpListener::bind(addr).await?;
-        println!("Listening to {:?}", listener.local_addr()?);
+        self.run_scgi_with_shutdown(addr, std::future::pending())
+            .await
+    }
+
+    /// Run a SCGI server until `signal` completes
+    ///
+    /// This is the same as [run_scgi](Scgi::run_scgi), except that the server
+    /// stops accepting connections once `signal` completes. Any connections that
+    /// are still being handled are allowed to finish, then
+    /// [shutdown](Application::shutdown) is called on the application before
+    /// returning.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::{application::Application, request::Request, response::Response, error::AnyError};
+    /// # use async_trait::async_trait;
+    /// use gemfra::protocol::Scgi;
+    ///
+    /// # struct MyApp;
+    /// # #[async_trait]
+    /// # impl Application for MyApp {
+    /// #     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    /// #         todo!("Handle the request")
+    /// #     }
+    /// # }
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let ctrl_c = async {
+    ///         tokio::signal::ctrl_c().await.unwrap();
+    ///     };
+    ///     MyApp.run_scgi_with_shutdown("127.0.0.1:8000", ctrl_c).await.unwrap();
+    /// }
+    /// ```
+    async fn run_scgi_with_shutdown<A, S>(self, addr: A, signal: S) -> io::Result<()>
+    where
+        A: ToSocketAddrs + Send + Sync,
+        S: Future<Output = ()> + Send,
+    {
+        serve_scgi(self, addr, ScgiConfig::default(), signal).await
+    }
 
-        let self_arc = Arc::new(self);
+    /// Run a SCGI server with custom settings
+    ///
+    /// This is the same as [run_scgi](Scgi::run_scgi), but the server is
+    /// configured with `config`. See [ScgiConfig] for the available settings.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::{application::Application, request::Request, response::Response, error::AnyError};
+    /// # use async_trait::async_trait;
+    /// use gemfra::protocol::{Scgi, ScgiConfig};
+    ///
+    /// # struct MyApp;
+    /// # #[async_trait]
+    /// # impl Application for MyApp {
+    /// #     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    /// #         todo!("Handle the request")
+    /// #     }
+    /// # }
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let config = ScgiConfig::new()
+    ///         .max_connections(64)
+    ///         .adaptive_slow_down(1, 30);
+    ///     MyApp.run_scgi_with_config("127.0.0.1:8000", config).await;
+    /// }
+    /// ```
+    async fn run_scgi_with_config<A>(self, addr: A, config: ScgiConfig) -> io::Result<()>
+    where
+        A: ToSocketAddrs + Send + Sync,
+    {
+        serve_scgi(self, addr, config, std::future::pending()).await
+    }
+}
 
-        loop {
-            let (mut conn, _) = listener.accept().await?;
+#[cfg(feature = "scgi")]
+async fn serve_scgi<T, A, S>(app: T, addr: A, config: ScgiConfig, signal: S) -> io::Result<()>
+where
+    T: Application + Send + Sync + 'static,
+    A: ToSocketAddrs,
+    S: Future<Output = ()>,
+{
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    if config.verbosity >= Verbosity::Startup {
+        eprintln!("Listening to {:?}", listener.local_addr()?);
+    }
 
-            let self_ref = self_arc.clone();
-            tokio::spawn(async move {
-                let mut path = None;
-                let response = match read_scgi_request(&mut conn).await {
-                    Ok(request) => {
-                        path = Some(request.path.clone());
-                        match self_ref.handle_request(request).await {
-                            Ok(response) => response,
-                            Err(err) => {
-                                eprintln!("Error while handling request: {err}");
-                                match err.downcast::<GemError>() {
-                                    Ok(err) => Response::from(*err),
-                                    Err(_) => Response::error_cgi("Internal Server Error"),
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Invalid SCGI header: {e}");
-                        Response::error_cgi("Invalid CGI header")
+    let app = Arc::new(app);
+    let permits = Arc::new(Semaphore::new(config.max_connections));
+    let mut connections = JoinSet::new();
+    tokio::pin!(signal);
+
+    loop {
+        tokio::select! {
+            _ = &mut signal => break,
+            // Clean up connections that have finished
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            accepted = listener.accept() => {
+                let conn = match accepted {
+                    Ok((conn, _)) => conn,
+                    Err(err) => {
+                        // Errors such as running out of file descriptors are
+                        // usually temporary, so wait a moment before accepting again
+                        eprintln!("Could not accept a connection: {err}");
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
                     }
                 };
 
-                println!(
-                    "{}\t{}\t{}",
-                    path.unwrap_or("".into()),
-                    response.code,
-                    response.meta
-                );
-                send_scgi_response(conn, response).await;
-            });
+                let in_use = config.max_connections - permits.available_permits();
+                if let Some(seconds) = config.slow_down_seconds(in_use) {
+                    // The response is sent inline, so shedding can't spawn more
+                    // tasks than the connection limit allows
+                    let response = send_scgi_response(conn, Response::slow_down(seconds));
+                    let _ = tokio::time::timeout(ScgiConfig::SHED_TIMEOUT, response).await;
+                    continue;
+                }
+
+                // Keep listening for the signal while waiting for a free slot
+                let permit = tokio::select! {
+                    _ = &mut signal => break,
+                    permit = permits.clone().acquire_owned() => {
+                        permit.expect("the semaphore is never closed")
+                    }
+                };
+                let app = app.clone();
+                let config = config.clone();
+                connections.spawn(async move {
+                    handle_scgi_connection(app.as_ref(), conn, &config).await;
+                    drop(permit);
+                });
+            }
         }
     }
+
+    while connections.join_next().await.is_some() {}
+    app.shutdown().await;
+
+    Ok(())
 }
 
 #[cfg(feature = "scgi")]
 impl<A> Scgi for A where A: Application + Sized + Send + Sync + 'static {}
+
+/// The maximum length of a Spartan request line, including the host and the
+/// content length
+#[cfg(feature = "spartan")]
+const MAX_SPARTAN_LINE: usize = 2048;
+
+/// The maximum size of a Spartan request body
+#[cfg(feature = "spartan")]
+const MAX_SPARTAN_BODY: usize = 1024 * 1024;
+
+/// The data uploaded with a Spartan request
+///
+/// Spartan requests can carry a body, which is how clients send user input.
+/// When a request has a body, it is stored in the
+/// [extensions](crate::request::Request::extensions) of the request.
+#[cfg(feature = "spartan")]
+#[derive(Debug, Clone)]
+pub struct SpartanBody(pub Bytes);
+
+/// Settings for a Spartan server
+///
+/// See [run_spartan_with_config](Spartan::run_spartan_with_config).
+#[cfg(feature = "spartan")]
+#[derive(Debug, Clone)]
+pub struct SpartanConfig {
+    max_connections: usize,
+    request_timeout: Duration,
+    verbosity: Verbosity,
+}
+
+#[cfg(feature = "spartan")]
+impl SpartanConfig {
+    /// Create the default settings
+    ///
+    /// By default, up to 1024 connections are handled at once, clients have
+    /// 10 seconds to send their request and its body, and every request is
+    /// logged.
+    pub fn new() -> Self {
+        Self {
+            max_connections: 1024,
+            request_timeout: Duration::from_secs(10),
+            verbosity: Verbosity::Requests,
+        }
+    }
+
+    /// Set the maximum number of connections that are handled at once
+    ///
+    /// Once the limit is reached, new connections are not accepted until
+    /// another connection finishes.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max.max(1);
+        self
+    }
+
+    /// Set how long a client has to send its request, including the body
+    ///
+    /// If the request hasn't been received in time, the connection is closed
+    /// without a response.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Set how much is logged to stderr
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+}
+
+#[cfg(feature = "spartan")]
+impl Default for SpartanConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Spartan
+///
+/// [Spartan](gemini://spartan.mozz.us) is a simple protocol that is similar to
+/// gemini, but without TLS. Requests are sent as `host path content-length`
+/// followed by an optional body.
+#[cfg(feature = "spartan")]
+#[async_trait]
+pub trait Spartan: Application + Sized + Send + Sync + 'static {
+    /// Run a Spartan server
+    ///
+    /// Requests are converted into a [Request] with the protocol `SPARTAN`.
+    /// Spartan has no queries, instead input is uploaded as the body of the
+    /// request. The body is stored as a [SpartanBody] extension, and if it is
+    /// valid UTF-8, it is also given as the (encoded) [query](Request::query)
+    /// so that input handlers work with both protocols. Bodies are limited to
+    /// 1 MiB.
+    ///
+    /// Spartan only has four statuses, so gemini responses are mapped as
+    /// follows:
+    ///
+    /// | Gemini                | Spartan              |
+    /// |-----------------------|----------------------|
+    /// | __2X__                | __2__ Success        |
+    /// | __3X__                | __3__ Redirect       |
+    /// | __40__-__44__, __50__ | __5__ Server Error   |
+    /// | Anything else         | __4__ Client Error   |
+    ///
+    /// Redirects should be to an absolute path, as Spartan doesn't allow
+    /// redirects to other hosts.
+    ///
+    /// Clients that are too slow to send their request are disconnected. The
+    /// connection limit and timeout are the defaults of [SpartanConfig], see
+    /// [run_spartan_with_config](Spartan::run_spartan_with_config).
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// use gemfra::{
+    ///     protocol::Spartan,
+    ///     application::Application,
+    ///     request::Request,
+    ///     response::Response,
+    ///     error::AnyError,
+    /// };
+    /// use async_trait::async_trait;
+    ///
+    /// struct MyApp;
+    /// #[async_trait]
+    /// impl Application for MyApp {
+    ///     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    ///         todo!("Handle the request")
+    ///     }
+    /// }
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     MyApp.run_spartan("0.0.0.0:300").await.unwrap();
+    /// }
+    /// ```
+    async fn run_spartan<A>(self, addr: A) -> io::Result<()>
+    where
+        A: ToSocketAddrs + Send + Sync,
+    {
+        self.run_spartan_with_config(addr, SpartanConfig::default())
+            .await
+    }
+
+    /// Run a Spartan server with custom settings
+    ///
+    /// This is the same as [run_spartan](Spartan::run_spartan), but the server
+    /// is configured with `config`. See [SpartanConfig] for the available
+    /// settings.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::{application::Application, request::Request, response::Response, error::AnyError};
+    /// # use async_trait::async_trait;
+    /// use gemfra::protocol::{Spartan, SpartanConfig};
+    ///
+    /// # struct MyApp;
+    /// # #[async_trait]
+    /// # impl Application for MyApp {
+    /// #     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    /// #         todo!("Handle the request")
+    /// #     }
+    /// # }
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let config = SpartanConfig::new().max_connections(64);
+    ///     MyApp.run_spartan_with_config("0.0.0.0:300", config).await.unwrap();
+    /// }
+    /// ```
+    async fn run_spartan_with_config<A>(self, addr: A, config: SpartanConfig) -> io::Result<()>
+    where
+        A: ToSocketAddrs + Send + Sync,
+    {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let local = listener.local_addr()?;
+        if config.verbosity >= Verbosity::Startup {
+            eprintln!("Listening to {local:?}");
+        }
+
+        let app = Arc::new(self);
+        let config = Arc::new(config);
+        let permits = Arc::new(tokio::sync::Semaphore::new(config.max_connections));
+        loop {
+            let permit = permits
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            let (conn, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // Errors such as running out of file descriptors are
+                    // usually temporary, so wait a moment before accepting again
+                    eprintln!("Could not accept a connection: {err}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let app = app.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                let remote_addr = peer.ip().to_string();
+                handle_spartan_connection(app.as_ref(), conn, remote_addr, local.port(), &config)
+                    .await;
+                drop(permit);
+            });
+        }
+    }
+}
+
+#[cfg(feature = "spartan")]
+impl<A> Spartan for A where A: Application + Sized + Send + Sync + 'static {}
+
+/// Get the Spartan header of a response
+#[cfg(feature = "spartan")]
+fn spartan_header(response: &Response) -> String {
+    let meta = response.meta.lines().next().unwrap_or("");
+    let status = match response.code {
+        20..=29 => 2,
+        30..=39 => 3,
+        40..=44 | 50 => 5,
+        _ => 4,
+    };
+    format!("{status} {meta}\r\n")
+}
+
+/// Percent-decode the path of a request, defaulting to `/`
+///
+/// Clients resolve dot segments before sending a request, so `None` is
+/// returned if the path still has any.
+#[cfg(any(feature = "spartan", feature = "gemini"))]
+fn decode_request_path(path: &str) -> Option<String> {
+    let path = match path {
+        "" => "/".to_owned(),
+        path => crate::query::percent_decode(path),
+    };
+    if path
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return None;
+    }
+    Some(path)
+}
+
+/// Read a Spartan request from a connection
+#[cfg(feature = "spartan")]
+async fn read_spartan_request<S>(
+    conn: &mut S,
+    remote_addr: String,
+    port: u16,
+) -> Result<Request, String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = Vec::new();
+    loop {
+        let chr = conn.read_u8().await.map_err(|e| e.to_string())?;
+        if chr == b'\n' {
+            break;
+        }
+        line.push(chr);
+        if line.len() > MAX_SPARTAN_LINE {
+            return Err("Request is too long".to_owned());
+        }
+    }
+    let line = std::str::from_utf8(&line).map_err(|e| e.to_string())?;
+    let line = line.strip_suffix('\r').unwrap_or(line);
+
+    let mut parts = line.split(' ');
+    let (host, target, length) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(host), Some(target), Some(length), None) if !host.is_empty() => {
+            (host, target, length)
+        }
+        _ => return Err("Invalid request line".to_owned()),
+    };
+    if !target.starts_with('/') {
+        return Err("The path must be absolute".to_owned());
+    }
+    let (path, query) = crate::query::split_query(target);
+    let path = decode_request_path(path).ok_or_else(|| "Invalid path".to_owned())?;
+    let length: usize = length
+        .parse()
+        .map_err(|_| "Invalid content length".to_owned())?;
+    if length > MAX_SPARTAN_BODY {
+        return Err("Request body is too large".to_owned());
+    }
+    // The body is buffered as it arrives, so a large length alone doesn't
+    // allocate anything
+    let mut body = Vec::new();
+    conn.take(length as u64)
+        .read_to_end(&mut body)
+        .await
+        .map_err(|e| e.to_string())?;
+    if body.len() < length {
+        return Err("The request body is incomplete".to_owned());
+    }
+
+    let mut query = query.map(str::to_owned);
+    let mut extensions = crate::request::Extensions::new();
+    if !body.is_empty() {
+        if let Ok(input) = std::str::from_utf8(&body) {
+            query = Some(crate::query::encode_segment(input));
+        }
+        extensions.insert(SpartanBody(body.into()));
+    }
+
+    Ok(Request {
+        path,
+        script: String::new(),
+        query,
+        server_name: host.to_owned(),
+        server_port: port,
+        url: format!("spartan://{host}{target}"),
+        remote_host: remote_addr.clone(),
+        remote_addr,
+        protocol: "SPARTAN".to_owned(),
+        client_cert: None,
+        front_end: crate::request::FrontEnd::Unknown,
+        sni_host: None,
+        extensions,
+    })
+}
+
+#[cfg(feature = "spartan")]
+async fn handle_spartan_connection<A, S>(
+    app: &A,
+    mut conn: S,
+    remote_addr: String,
+    port: u16,
+    config: &SpartanConfig,
+) where
+    A: Application + Sync,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut path = None;
+    let request = read_spartan_request(&mut conn, remote_addr, port);
+    let request = match tokio::time::timeout(config.request_timeout, request).await {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    let response = match request {
+        Ok(request) => {
+            path = Some(request.path.clone());
+            match app.handle_request(request).await {
+                Ok(response) => response,
+                Err(err) if err.is::<CloseConnection>() => {
+                    eprintln!("{}\t{err}", path.unwrap_or_default());
+                    return;
+                }
+                Err(err) => {
+                    eprintln!("Error while handling request: {err}");
+                    error_response(app, err)
+                }
+            }
+        }
+        Err(err) => {
+            eprintln!("Invalid Spartan request: {err}");
+            Response::bad_request(err)
+        }
+    };
+
+    let (code, meta) = (response.code, response.meta.clone());
+    let header = spartan_header(&response);
+    let sent = match response.send_async_with_header(&header, &mut conn).await {
+        Err(e) if is_client_disconnect(&e) => return,
+        Err(e) => {
+            eprintln!("Could not send body: {e}");
+            "-".into()
+        }
+        Ok(sent) => sent.to_string(),
+    };
+    if config.verbosity >= Verbosity::Requests {
+        eprintln!("{}\t{}\t{}\t{}", path.unwrap_or_default(), code, meta, sent);
+    }
+    if let Err(e) = conn.shutdown().await {
+        if !is_client_disconnect(&e) {
+            eprintln!("Could not shutdown connection: {e}");
+        }
+    }
+}
+
+/// Settings for a gemini server
+///
+/// See [run_gemini_with_config](Gemini::run_gemini_with_config).
+#[cfg(feature = "gemini")]
+#[derive(Debug, Clone)]
+pub struct GeminiConfig {
+    max_connections: usize,
+    handshake_timeout: Duration,
+    request_timeout: Duration,
+    upload_timeout: Duration,
+}
+
+#[cfg(feature = "gemini")]
+impl GeminiConfig {
+    /// Create the default settings
+    ///
+    /// By default, up to 1024 connections are handled at once, and clients
+    /// have 10 seconds to complete the TLS handshake and another 10 seconds
+    /// to send their request. Titan uploads must be received within 60
+    /// seconds.
+    pub fn new() -> Self {
+        Self {
+            max_connections: 1024,
+            handshake_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(10),
+            upload_timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// Set the maximum number of connections that are handled at once
+    ///
+    /// Once the limit is reached, new connections are not accepted until
+    /// another connection finishes.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max.max(1);
+        self
+    }
+
+    /// Set how long a client has to complete the TLS handshake
+    ///
+    /// If the handshake takes longer, the connection is closed.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Set how long a client has to send its request once the TLS handshake
+    /// is complete
+    ///
+    /// If the request line hasn't been received in time, the connection is
+    /// closed without a response.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Set how long a client has to send the body of a Titan upload
+    ///
+    /// The time starts once the request line has been read. Reading the body
+    /// after the deadline fails with [TimedOut](io::ErrorKind::TimedOut).
+    pub fn upload_timeout(mut self, timeout: Duration) -> Self {
+        self.upload_timeout = timeout;
+        self
+    }
+}
+
+/// A reader that fails once its deadline has passed
+#[cfg(feature = "gemini")]
+struct Deadline<R> {
+    reader: R,
+    sleep: std::pin::Pin<Box<tokio::time::Sleep>>,
+}
+
+#[cfg(feature = "gemini")]
+impl<R> Deadline<R> {
+    fn new(reader: R, timeout: Duration) -> Self {
+        Self {
+            reader,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+}
+
+#[cfg(feature = "gemini")]
+impl<R> AsyncRead for Deadline<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        if std::future::Future::poll(self.sleep.as_mut(), cx).is_ready() {
+            return std::task::Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+        }
+        std::pin::Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+#[cfg(feature = "gemini")]
+impl Default for GeminiConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A client certificate verifier that accepts any certificate
+///
+/// Gemini clients use self-signed certificates to identify themselves, so the
+/// certificate is not checked against any authority. The handshake still
+/// proves that the client has the certificate's private key.
+#[cfg(feature = "gemini")]
+#[derive(Debug)]
+struct AnyClientCert(Arc<rustls::crypto::CryptoProvider>);
+
+#[cfg(feature = "gemini")]
+impl rustls::server::danger::ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[rustls::DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::server::danger::ClientCertVerified, rustls::Error> {
+        Ok(rustls::server::danger::ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Create a TLS config for [run_gemini](Gemini::run_gemini)
+///
+/// The server presents `cert_chain`, which is usually a single self-signed
+/// certificate, with its private `key`. Clients may present any certificate,
+/// including self-signed ones, which is how gemini clients identify
+/// themselves. Certificates can be loaded from PEM files with
+/// [rustls::pki_types::pem::PemObject].
+///
+/// ### Example
+///
+/// ```no_run
+/// use gemfra::protocol::{gemini_tls_config, rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}};
+///
+/// let chain = CertificateDer::pem_file_iter("cert.pem")
+///     .unwrap()
+///     .collect::<Result<Vec<_>, _>>()
+///     .unwrap();
+/// let key = PrivateKeyDer::from_pem_file("key.pem").unwrap();
+/// let config = gemini_tls_config(chain, key).unwrap();
+/// ```
+#[cfg(feature = "gemini")]
+pub fn gemini_tls_config(
+    cert_chain: Vec<rustls::pki_types::CertificateDer<'static>>,
+    key: rustls::pki_types::PrivateKeyDer<'static>,
+) -> Result<Arc<rustls::ServerConfig>, rustls::Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(Arc::new(AnyClientCert(provider)))
+        .with_single_cert(cert_chain, key)?;
+    Ok(Arc::new(config))
+}
+
+/// Gemini
+///
+/// Serve the application directly over gemini, without a separate server in
+/// front of it. gemfra terminates TLS itself with
+/// [rustls](https://docs.rs/rustls).
+#[cfg(feature = "gemini")]
+#[async_trait]
+pub trait Gemini: Application + Sized + Send + Sync + 'static {
+    /// Run a gemini server
+    ///
+    /// Each connection is a single request. The request URL is mapped into
+    /// the same fields that a CGI server would provide: the host and path of
+    /// the URL are the [server_name](Request::server_name) and the decoded
+    /// [path](Request::path), the [script](Request::script) is empty, and the
+    /// [server_port](Request::server_port) is the port that the server is
+    /// listening to. The client certificate, if any, is the
+    /// [client_cert](Request::client_cert), and the host the client asked for
+    /// with SNI is the [sni_host](Request::sni_host).
+    ///
+    /// Requests for any scheme other than `gemini` are refused with a __53__,
+    /// and invalid requests, including ones longer than
+    /// [MAX_REQUEST_LEN](crate::request::MAX_REQUEST_LEN) bytes or with `.`
+    /// or `..` segments in the path, get a __59__. Clients that are too slow
+    /// to complete the TLS handshake or to send their request are
+    /// disconnected. The connection limit and timeouts are the defaults of
+    /// [GeminiConfig], see [run_gemini_with_config](Gemini::run_gemini_with_config).
+    /// See [gemini_tls_config] to create the TLS config.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// use gemfra::{
+    ///     protocol::{gemini_tls_config, Gemini},
+    ///     application::Application,
+    ///     request::Request,
+    ///     response::Response,
+    ///     error::AnyError,
+    /// };
+    /// use async_trait::async_trait;
+    ///
+    /// struct MyApp;
+    /// #[async_trait]
+    /// impl Application for MyApp {
+    ///     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    ///         todo!("Handle the request")
+    ///     }
+    /// }
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     # let (chain, key) = todo!();
+    ///     let config = gemini_tls_config(chain, key).unwrap();
+    ///     MyApp.run_gemini("0.0.0.0:1965", config).await.unwrap();
+    /// }
+    /// ```
+    async fn run_gemini<A>(self, addr: A, tls_config: Arc<rustls::ServerConfig>) -> io::Result<()>
+    where
+        A: ToSocketAddrs + Send + Sync,
+    {
+        serve_gemini(self, addr, tls_config, GeminiConfig::default(), None).await
+    }
+
+    /// Run a gemini server with custom settings
+    ///
+    /// This is the same as [run_gemini](Gemini::run_gemini), but the server is
+    /// configured with `config`. See [GeminiConfig] for the available
+    /// settings.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::{application::Application, request::Request, response::Response, error::AnyError};
+    /// # use async_trait::async_trait;
+    /// use gemfra::protocol::{gemini_tls_config, Gemini, GeminiConfig};
+    ///
+    /// # struct MyApp;
+    /// # #[async_trait]
+    /// # impl Application for MyApp {
+    /// #     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    /// #         todo!("Handle the request")
+    /// #     }
+    /// # }
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     # let (chain, key) = todo!();
+    ///     let tls_config = gemini_tls_config(chain, key).unwrap();
+    ///     let config = GeminiConfig::new().max_connections(64);
+    ///     MyApp.run_gemini_with_config("0.0.0.0:1965", tls_config, config).await.unwrap();
+    /// }
+    /// ```
+    async fn run_gemini_with_config<A>(
+        self,
+        addr: A,
+        tls_config: Arc<rustls::ServerConfig>,
+        config: GeminiConfig,
+    ) -> io::Result<()>
+    where
+        A: ToSocketAddrs + Send + Sync,
+    {
+        serve_gemini(self, addr, tls_config, config, None).await
+    }
+}
+
+#[cfg(feature = "gemini")]
+impl<A> Gemini for A where A: Application + Sized + Send + Sync + 'static {}
+
+/// Titan
+///
+/// [Titan](gemini://transjovian.org/titan) is a companion protocol to gemini
+/// for uploading files. Titan requests use the `titan://` scheme and are sent
+/// to the same server as gemini requests.
+#[cfg(feature = "titan")]
+#[async_trait]
+pub trait Titan: Application + Sized + Send + Sync + 'static {
+    /// Run a gemini server that also accepts titan uploads
+    ///
+    /// Gemini requests are handled in the same way as with
+    /// [run_gemini](Gemini::run_gemini). Titan requests have their
+    /// [parameters](TitanParams) removed from the path, their
+    /// [protocol](Request::protocol) is `TITAN`, and they are given to
+    /// [handle_upload](Application::handle_upload) along with the uploaded
+    /// body.
+    ///
+    /// Uploads larger than `max_upload` bytes are rejected with a __59__
+    /// before any of the body is read. The connection limit and timeouts are
+    /// the defaults of [GeminiConfig], see
+    /// [run_titan_with_config](Titan::run_titan_with_config).
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// use gemfra::{
+    ///     protocol::{gemini_tls_config, Titan},
+    ///     application::Application,
+    ///     request::{Request, Upload},
+    ///     response::Response,
+    ///     error::AnyError,
+    /// };
+    /// use async_trait::async_trait;
+    ///
+    /// struct MyApp;
+    /// #[async_trait]
+    /// impl Application for MyApp {
+    ///     async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+    ///         todo!("Handle the request")
+    ///     }
+    ///
+    ///     async fn handle_upload(&self, request: Request, upload: Upload<'_>) -> Result<Response, AnyError> {
+    ///         todo!("Handle the upload")
+    ///     }
+    /// }
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     # let (chain, key) = todo!();
+    ///     let config = gemini_tls_config(chain, key).unwrap();
+    ///     MyApp.run_titan("0.0.0.0:1965", config, 1024 * 1024).await.unwrap();
+    /// }
+    /// ```
+    async fn run_titan<A>(
+        self,
+        addr: A,
+        tls_config: Arc<rustls::ServerConfig>,
+        max_upload: u64,
+    ) -> io::Result<()>
+    where
+        A: ToSocketAddrs + Send + Sync,
+    {
+        self.run_titan_with_config(addr, tls_config, GeminiConfig::default(), max_upload)
+            .await
+    }
+
+    /// Run a gemini server that accepts titan uploads with custom settings
+    ///
+    /// This is the same as [run_titan](Titan::run_titan), but the server is
+    /// configured with `config`. See [GeminiConfig] for the available
+    /// settings.
+    async fn run_titan_with_config<A>(
+        self,
+        addr: A,
+        tls_config: Arc<rustls::ServerConfig>,
+        config: GeminiConfig,
+        max_upload: u64,
+    ) -> io::Result<()>
+    where
+        A: ToSocketAddrs + Send + Sync,
+    {
+        serve_gemini(self, addr, tls_config, config, Some(max_upload)).await
+    }
+}
+
+#[cfg(feature = "titan")]
+impl<A> Titan for A where A: Application + Sized + Send + Sync + 'static {}
+
+/// Serve gemini requests, and titan requests if `max_upload` is set
+#[cfg(feature = "gemini")]
+async fn serve_gemini<A, T>(
+    app: A,
+    addr: T,
+    tls_config: Arc<rustls::ServerConfig>,
+    config: GeminiConfig,
+    max_upload: Option<u64>,
+) -> io::Result<()>
+where
+    A: Application + Send + Sync + 'static,
+    T: ToSocketAddrs,
+{
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let local = listener.local_addr()?;
+    eprintln!("Listening to {local:?}");
+
+    let acceptor = tokio_rustls::TlsAcceptor::from(tls_config);
+    let app = Arc::new(app);
+    let config = Arc::new(config);
+    let permits = Arc::new(tokio::sync::Semaphore::new(config.max_connections));
+    loop {
+        let permit = permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let (conn, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Errors such as running out of file descriptors are usually
+                // temporary, so wait a moment before accepting again
+                eprintln!("Could not accept a connection: {err}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let app = app.clone();
+        let acceptor = acceptor.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            let handshake = acceptor.accept(conn);
+            let conn = match tokio::time::timeout(config.handshake_timeout, handshake).await {
+                Ok(Ok(conn)) => conn,
+                Ok(Err(err)) => {
+                    eprintln!("TLS handshake failed: {err}");
+                    return;
+                }
+                Err(_) => return,
+            };
+            let (_, session) = conn.get_ref();
+            let info = GeminiConnection {
+                remote_addr: peer.ip().to_string(),
+                port: local.port(),
+                sni_host: session.server_name().map(str::to_owned),
+                client_cert: session
+                    .peer_certificates()
+                    .and_then(|chain| chain.first())
+                    .map(|cert| crate::request::Certificate::from_der(cert)),
+            };
+            handle_gemini_connection(app.as_ref(), conn, info, &config, max_upload).await;
+            drop(permit);
+        });
+    }
+}
+
+/// What is known about a gemini connection before its request is read
+#[cfg(feature = "gemini")]
+struct GeminiConnection {
+    remote_addr: String,
+    port: u16,
+    sni_host: Option<String>,
+    client_cert: Option<Result<crate::request::Certificate, GemError>>,
+}
+
+/// Read a gemini request from a connection
+///
+/// If `titan` is set, titan requests are also accepted and their parameters
+/// are returned along with the request.
+#[cfg(feature = "gemini")]
+async fn read_gemini_request<S>(
+    conn: &mut S,
+    info: GeminiConnection,
+    titan: bool,
+) -> Result<(Request, Option<TitanParams>), GemError>
+where
+    S: AsyncRead + Unpin,
+{
+    use crate::request::MAX_REQUEST_LEN;
+
+    let mut line = Vec::new();
+    loop {
+        let chr = conn
+            .read_u8()
+            .await
+            .map_err(|_| GemError::bad_request("Incomplete request"))?;
+        if chr == b'\n' {
+            break;
+        }
+        line.push(chr);
+        // Leave room for the `\r`
+        if line.len() > MAX_REQUEST_LEN + 1 {
+            return Err(GemError::bad_request("Request is too long"));
+        }
+    }
+    let line = std::str::from_utf8(&line).map_err(|_| GemError::bad_request("Invalid UTF-8"))?;
+    let url = line.strip_suffix('\r').unwrap_or(line);
+    if url.len() > MAX_REQUEST_LEN {
+        return Err(GemError::bad_request("Request is too long"));
+    }
+
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| GemError::bad_request("The URL must be absolute"))?;
+    let titan = if scheme.eq_ignore_ascii_case("gemini") {
+        false
+    } else if titan && scheme.eq_ignore_ascii_case("titan") {
+        true
+    } else {
+        return Err(GemError::proxy_refused("Proxy requests are not allowed"));
+    };
+    let rest = rest.split('#').next().unwrap_or(rest);
+    let target_start = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, target) = rest.split_at(target_start);
+    if authority.contains('@') {
+        return Err(GemError::bad_request("The URL must not have user info"));
+    }
+    let (host, port) = match authority.rfind(':') {
+        Some(i) if !authority[i..].contains(']') => (&authority[..i], Some(&authority[i + 1..])),
+        _ => (authority, None),
+    };
+    if host.is_empty() {
+        return Err(GemError::bad_request("The URL must have a host"));
+    }
+    if port.is_some_and(|port| port.parse::<u16>().is_err()) {
+        return Err(GemError::bad_request("Invalid port"));
+    }
+
+    let (path, query) = crate::query::split_query(target);
+    let (path, params) = match titan {
+        true => TitanParams::split(path).map(|(path, params)| (path, Some(params)))?,
+        false => (path, None),
+    };
+    let path = decode_request_path(path).ok_or_else(|| GemError::bad_request("Invalid path"))?;
+    let client_cert = info.client_cert.transpose()?;
+
+    let request = Request {
+        path,
+        script: String::new(),
+        query: query.map(str::to_owned),
+        server_name: host.to_owned(),
+        server_port: info.port,
+        url: url.to_owned(),
+        remote_host: info.remote_addr.clone(),
+        remote_addr: info.remote_addr,
+        protocol: if titan { "TITAN" } else { "GEMINI" }.to_owned(),
+        client_cert,
+        front_end: crate::request::FrontEnd::Unknown,
+        sni_host: info.sni_host,
+        extensions: crate::request::Extensions::new(),
+    };
+    Ok((request, params))
+}
+
+/// Handle a gemini connection, accepting uploads up to `max_upload` bytes
+#[cfg(feature = "gemini")]
+async fn handle_gemini_connection<A, S>(
+    app: &A,
+    mut conn: S,
+    info: GeminiConnection,
+    config: &GeminiConfig,
+    max_upload: Option<u64>,
+) where
+    A: Application + Sync,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut path = None;
+    let read = read_gemini_request(&mut conn, info, max_upload.is_some());
+    let result = match tokio::time::timeout(config.request_timeout, read).await {
+        Ok(Ok((request, params))) => {
+            path = Some(request.path.clone());
+            match params {
+                None => app.handle_request(request).await,
+                Some(params) if max_upload.is_some_and(|max| params.size > max) => {
+                    Err(GemError::bad_request("Upload is too large").into())
+                }
+                Some(params) => {
+                    let body = (&mut conn).take(params.size);
+                    let mut body = Deadline::new(body, config.upload_timeout);
+                    let result = app
+                        .handle_upload(request, Upload::new(params, &mut body))
+                        .await;
+                    // Read whatever the handler left of the body, so that the
+                    // client has finished sending before the response
+                    if let Err(err) = tokio::io::copy(&mut body, &mut tokio::io::sink()).await {
+                        eprintln!("Could not read the rest of the upload: {err}");
+                    }
+                    result
+                }
+            }
+        }
+        Ok(Err(err)) => {
+            eprintln!("Invalid gemini request: {err}");
+            Ok(Response::from(err))
+        }
+        Err(_) => return,
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(err) if err.is::<CloseConnection>() => {
+            eprintln!("{}\t{err}", path.unwrap_or_default());
+            return;
+        }
+        Err(err) => {
+            eprintln!("Error while handling request: {err}");
+            error_response(app, err)
+        }
+    };
+
+    let (code, meta) = (response.code, response.meta.clone());
+    let sent = match response.send_async(&mut conn).await {
+        Err(e) if is_client_disconnect(&e) => return,
+        Err(e) => {
+            eprintln!("Could not send body: {e}");
+            "-".into()
+        }
+        Ok(sent) => sent.to_string(),
+    };
+    eprintln!("{}\t{}\t{}\t{}", path.unwrap_or_default(), code, meta, sent);
+    if let Err(e) = conn.shutdown().await {
+        if !is_client_disconnect(&e) {
+            eprintln!("Could not shutdown connection: {e}");
+        }
+    }
+}
+
+#[cfg(all(test, feature = "scgi"))]
+mod test {
+    use super::*;
+    use crate::error::AnyError;
+
+    #[test]
+    fn test_scgi_parse_error_has_raw_header() {
+        let raw = b"CONTENT_LENGTH\x000\x00SCGI\x001\x00";
+        let err = parse_scgi_headers(raw)
+            .map_err(|e| raw_header_error(e, raw))
+            .err()
+            .unwrap();
+        let message = err.to_string();
+        assert!(message.contains("Missing header PATH_INFO"), "{message}");
+        assert!(
+            message.contains(r"CONTENT_LENGTH\x000\x00SCGI"),
+            "{message}"
+        );
+
+        let long = [b'a'; MAX_RAW_HEADER * 2];
+        let message = raw_header_error("error", &long).to_string();
+        assert!(message.ends_with("...\")"));
+    }
+
+    struct ShutdownApp(Arc<std::sync::atomic::AtomicBool>);
+
+    #[async_trait]
+    impl Application for ShutdownApp {
+        async fn handle_request(&self, _request: Request) -> Result<Response, AnyError> {
+            Ok(Response::gemtext("Hello"))
+        }
+
+        async fn shutdown(&self) {
+            self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scgi_shutdown() {
+        let stopped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        ShutdownApp(stopped.clone())
+            .run_scgi_with_shutdown("127.0.0.1:0", async {})
+            .await
+            .unwrap();
+        assert!(stopped.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_adaptive_slow_down() {
+        let config = ScgiConfig::new().max_connections(100);
+        assert_eq!(config.slow_down_seconds(100), None);
+
+        let config = config.adaptive_slow_down(2, 10);
+        assert_eq!(config.slow_down_seconds(50), None);
+        assert_eq!(config.slow_down_seconds(75), Some(2));
+        assert_eq!(config.slow_down_seconds(90), Some(7));
+        assert_eq!(config.slow_down_seconds(100), Some(10));
+    }
+
+    struct SlowApp;
+
+    #[async_trait]
+    impl Application for SlowApp {
+        async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+            if request.path == "/hang" {
+                std::future::pending::<()>().await;
+            }
+            Ok(Response::gemtext("Hello"))
+        }
+    }
+
+    struct DropApp;
+
+    #[async_trait]
+    impl Application for DropApp {
+        async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+            if request.path == "/drop" {
+                return Err(CloseConnection::new("dropped").into());
+            }
+            Ok(Response::gemtext("Hello"))
+        }
+    }
+
+    async fn scgi_raw(raw: &[u8]) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+
+        client.write_all(raw).await.unwrap();
+        handle_scgi_connection(&DropApp, conn, &ScgiConfig::new()).await;
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        output
+    }
+
+    async fn scgi_request(path: &str) -> String {
+        let headers = format!(
+            "CONTENT_LENGTH\x000\x00SCGI\x001\x00PATH_INFO\x00{path}\x00\
+             SCRIPT_NAME\x00/scgi\x00SERVER_NAME\x00example.com\x00SERVER_PORT\x001965\x00REMOTE_ADDR\x00127.0.0.1\x00"
+        );
+        scgi_raw(format!("{}:{headers},", headers.len()).as_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn test_scgi_close_connection() {
+        assert_eq!(scgi_request("/").await, "20 text/gemini\r\nHello");
+        assert_eq!(scgi_request("/drop").await, "");
+    }
+
+    #[tokio::test]
+    async fn test_scgi_header_limit() {
+        // Huge lengths are rejected before anything is allocated
+        for raw in ["18446744073709551615:", "65537:"] {
+            assert_eq!(scgi_raw(raw.as_bytes()).await, "42 Invalid CGI header\r\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let timeout = Some(Duration::from_millis(10));
+        let response = handle_with_timeout(&SlowApp, Request::mock("/hang"), timeout)
+            .await
+            .unwrap();
+        assert_eq!(
+            (response.code, response.meta.as_str()),
+            (42, "Handler timed out")
+        );
+
+        let response = handle_with_timeout(&SlowApp, Request::mock("/"), timeout)
+            .await
+            .unwrap();
+        assert_eq!(response.code, 20);
+        let response = handle_with_timeout(&SlowApp, Request::mock("/"), None)
+            .await
+            .unwrap();
+        assert_eq!(response.code, 20);
+    }
+}
+
+#[cfg(all(test, feature = "spartan"))]
+mod spartan_test {
+    use super::*;
+    use crate::error::AnyError;
+
+    struct EchoApp;
+
+    #[async_trait]
+    impl Application for EchoApp {
+        async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+            if request.path == "/old" {
+                return Ok(Response::redirect("/new"));
+            }
+            if request.path == "/drop" {
+                return Err(CloseConnection::new("dropped").into());
+            }
+            let body = request.extensions.get::<SpartanBody>().map(|b| b.0.len());
+            Ok(Response::text(format!(
+                "{} {} {:?} {body:?}",
+                request.server_name, request.path, request.query
+            )))
+        }
+    }
+
+    async fn request_with(raw: &[u8], close: bool, config: &SpartanConfig) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(raw).await.unwrap();
+        if close {
+            client.shutdown().await.unwrap();
+        }
+        handle_spartan_connection(&EchoApp, server, "127.0.0.1".to_owned(), 300, config).await;
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        output
+    }
+
+    async fn request(raw: &[u8]) -> String {
+        request_with(raw, true, &SpartanConfig::default()).await
+    }
+
+    #[tokio::test]
+    async fn test_spartan() {
+        assert_eq!(
+            request(b"example.com /hello 0\r\n").await,
+            "2 text/plain\r\nexample.com /hello None None"
+        );
+        assert_eq!(
+            request(b"example.com /hi 5\r\na b c").await,
+            "2 text/plain\r\nexample.com /hi Some(\"a%20b%20c\") Some(5)"
+        );
+        assert_eq!(request(b"example.com /old 0\r\n").await, "3 /new\r\n");
+        assert_eq!(request(b"example.com /drop 0\r\n").await, "");
+        assert!(request(b"example.com hello 0\r\n").await.starts_with("4 "));
+        assert!(request(b"example.com /hello\r\n").await.starts_with("4 "));
+    }
+
+    #[tokio::test]
+    async fn test_spartan_path() {
+        // Paths are decoded the same way as gemini paths
+        assert_eq!(
+            request(b"example.com /a%20b 0\r\n").await,
+            "2 text/plain\r\nexample.com /a b None None"
+        );
+        assert!(request(b"example.com /../x 0\r\n").await.starts_with("4 "));
+        assert!(request(b"example.com /a/%2E%2E/x 0\r\n")
+            .await
+            .starts_with("4 "));
+    }
+
+    #[tokio::test]
+    async fn test_spartan_limits() {
+        // A body that is shorter than its length is rejected
+        assert!(request(b"example.com /hi 5\r\nab").await.starts_with("4 "));
+        // A huge length is only limited by the maximum body size
+        let raw = format!("example.com /hi {MAX_SPARTAN_BODY}\r\nab");
+        assert!(request(raw.as_bytes()).await.starts_with("4 "));
+
+        // Slow clients are disconnected without a response
+        let config = SpartanConfig::new().request_timeout(Duration::from_millis(10));
+        assert_eq!(request_with(b"example.com /hi", false, &config).await, "");
+        assert_eq!(
+            request_with(b"example.com /hi 5\r\nab", false, &config).await,
+            ""
+        );
+    }
+}
+
+#[cfg(all(test, feature = "gemini"))]
+mod gemini_test {
+    use super::*;
+    use crate::error::AnyError;
+
+    struct EchoApp;
+
+    #[async_trait]
+    impl Application for EchoApp {
+        async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+            if request.path == "/drop" {
+                return Err(CloseConnection::new("dropped").into());
+            }
+            Ok(Response::text(format!(
+                "{}:{} {} {:?} {:?}",
+                request.server_name,
+                request.server_port,
+                request.path,
+                request.query,
+                request.sni_host
+            )))
+        }
+    }
+
+    async fn request(raw: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(raw).await.unwrap();
+        client.shutdown().await.unwrap();
+        let info = GeminiConnection {
+            remote_addr: "127.0.0.1".to_owned(),
+            port: 1965,
+            sni_host: Some("example.com".to_owned()),
+            client_cert: None,
+        };
+        handle_gemini_connection(&EchoApp, server, info, &GeminiConfig::new(), None).await;
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn test_gemini() {
+        assert_eq!(
+            request(b"gemini://example.com/a%20b?x%20y\r\n").await,
+            "20 text/plain\r\nexample.com:1965 /a b Some(\"x%20y\") Some(\"example.com\")"
+        );
+        assert_eq!(
+            request(b"gemini://[::1]:1966\r\n").await,
+            "20 text/plain\r\n[::1]:1965 / None Some(\"example.com\")"
+        );
+        assert_eq!(request(b"gemini://example.com/drop\r\n").await, "");
+        assert!(request(b"https://example.com/\r\n")
+            .await
+            .starts_with("53 "));
+        assert!(request(b"/hello\r\n").await.starts_with("59 "));
+        for path in ["/../../x", "/a/./b", "/a/%2E%2E/b", "/a%2F.."] {
+            let raw = format!("gemini://example.com{path}\r\n");
+            assert!(request(raw.as_bytes()).await.starts_with("59 "), "{path}");
+        }
+        assert!(request(b"gemini://example.com:x/\r\n")
+            .await
+            .starts_with("59 "));
+        assert!(request(b"gemini://example.com/").await.starts_with("59 "));
+
+        let long = format!("gemini://example.com/{}\r\n", "a".repeat(1024));
+        assert!(request(long.as_bytes()).await.starts_with("59 "));
+    }
+
+    #[tokio::test]
+    async fn test_gemini_timeout() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        // The client never finishes its request
+        client.write_all(b"gemini://example.com/").await.unwrap();
+        let info = GeminiConnection {
+            remote_addr: "127.0.0.1".to_owned(),
+            port: 1965,
+            sni_host: None,
+            client_cert: None,
+        };
+        let config = GeminiConfig::new().request_timeout(Duration::from_millis(10));
+        handle_gemini_connection(&EchoApp, server, info, &config, None).await;
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        assert_eq!(output, "");
+    }
+
+    #[tokio::test]
+    async fn test_titan_timeout() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        // The client never finishes its upload
+        client
+            .write_all(b"titan://example.com/a;size=10\r\nhello")
+            .await
+            .unwrap();
+        let info = GeminiConnection {
+            remote_addr: "127.0.0.1".to_owned(),
+            port: 1965,
+            sni_host: None,
+            client_cert: None,
+        };
+        let config = GeminiConfig::new().upload_timeout(Duration::from_millis(10));
+        handle_gemini_connection(&UploadApp, server, info, &config, Some(16)).await;
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        assert!(output.starts_with("42 "), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_titan_drain() {
+        // The body is larger than the connection's buffer, so the client can
+        // only finish sending it if the server reads all of it
+        let (mut client, server) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            client
+                .write_all(b"titan://example.com/ignore;size=1000\r\n")
+                .await?;
+            client.write_all(&[b'a'; 1000]).await?;
+            let mut output = String::new();
+            client.read_to_string(&mut output).await?;
+            io::Result::Ok(output)
+        });
+        let info = GeminiConnection {
+            remote_addr: "127.0.0.1".to_owned(),
+            port: 1965,
+            sni_host: None,
+            client_cert: None,
+        };
+        handle_gemini_connection(&UploadApp, server, info, &GeminiConfig::new(), Some(1000)).await;
+        let output = writer.await.unwrap().unwrap();
+        assert_eq!(output, "20 text/plain\r\nignored");
+    }
+
+    async fn upload(raw: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(raw).await.unwrap();
+        client.shutdown().await.unwrap();
+        let info = GeminiConnection {
+            remote_addr: "127.0.0.1".to_owned(),
+            port: 1965,
+            sni_host: None,
+            client_cert: None,
+        };
+        handle_gemini_connection(&UploadApp, server, info, &GeminiConfig::new(), Some(16)).await;
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        output
+    }
+
+    struct UploadApp;
+
+    #[async_trait]
+    impl Application for UploadApp {
+        async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+            Ok(Response::text(request.protocol))
+        }
+
+        async fn handle_upload(
+            &self,
+            request: Request,
+            upload: Upload<'_>,
+        ) -> Result<Response, AnyError> {
+            if request.path == "/ignore" {
+                return Ok(Response::text("ignored"));
+            }
+            let params = upload.params.clone();
+            let body = upload.bytes().await?;
+            Ok(Response::text(format!(
+                "{} {} {} {:?} {:?}",
+                request.protocol, request.path, params.mime, params.token, body
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_titan() {
+        assert_eq!(
+            upload(
+                b"titan://example.com/a%20b.txt;mime=text/plain;size=5;token=t%3B\r\nhello extra"
+            )
+            .await,
+            "20 text/plain\r\nTITAN /a b.txt text/plain Some(\"t;\") b\"hello\""
+        );
+        assert_eq!(
+            upload(b"gemini://example.com/\r\n").await,
+            "20 text/plain\r\nGEMINI"
+        );
+        assert!(upload(b"titan://example.com/a;size=17\r\n")
+            .await
+            .starts_with("59 "));
+        assert!(upload(b"titan://example.com/a;mime=text/plain\r\n")
+            .await
+            .starts_with("59 "));
+        // The connection closed before the whole body was sent
+        assert!(upload(b"titan://example.com/a;size=10\r\nhello")
+            .await
+            .starts_with("42 "));
+        // Titan is only accepted when uploads are enabled
+        assert!(request(b"titan://example.com/a;size=5\r\nhello")
+            .await
+            .starts_with("53 "));
+    }
+}
+
+#[cfg(all(test, feature = "cgi"))]
+mod cgi_test {
+    use super::*;
+    use crate::error::AnyError;
+
+    struct HelloApp;
+
+    #[async_trait]
+    impl Application for HelloApp {
+        async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+            if request.path == "/drop" {
+                return Err(CloseConnection::new("dropped").into());
+            }
+            Ok(Response::gemtext("Hello"))
+        }
+    }
+
+    async fn run(path: &str) -> Vec<u8> {
+        run_with(&[
+            ("PATH_INFO", path),
+            ("SCRIPT_NAME", "/cgi"),
+            ("SERVER_NAME", "example.com"),
+            ("SERVER_PORT", "1965"),
+            ("REMOTE_ADDR", "127.0.0.1"),
+        ])
+        .await
+    }
+
+    async fn run_with(vars: &[(&str, &str)]) -> Vec<u8> {
+        let get_var = |key: &str| {
+            vars.iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.to_string())
+                .ok_or_else(|| GemError::runtime_error(format!("Missing {key}")))
+        };
+        let mut output = Vec::new();
+        serve_cgi(HelloApp, get_var, &mut output, &CgiConfig::default()).await;
+        output
+    }
+
+    #[tokio::test]
+    async fn test_close_connection() {
+        assert_eq!(run("/").await, b"20 text/gemini\r\nHello");
+        assert_eq!(run("/drop").await, b"");
+    }
+
+    #[tokio::test]
+    async fn test_missing_cgi_vars() {
+        // Without any CGI variables, the request is invalid
+        assert_eq!(run_with(&[]).await, b"42 Invalid CGI header\r\n");
+    }
+
+    struct BrandedApp;
+
+    #[async_trait]
+    impl Application for BrandedApp {
+        async fn handle_request(&self, _request: Request) -> Result<Response, AnyError> {
+            Err("broken".into())
+        }
+
+        fn internal_error_response(&self, err: &AnyError) -> Response {
+            Response::gemtext(format!("# Oops\n\n{err}"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_response() {
+        let response = error_response(&HelloApp, "broken".into());
+        assert_eq!(response.header(), "42 Internal Server Error\r\n");
+
+        let response = error_response(&BrandedApp, "broken".into());
+        assert_eq!(response.read_body().await, "# Oops\n\nbroken");
+
+        let response = error_response(&BrandedApp, GemError::not_found("Missing").into());
+        assert_eq!(response.code, 51);
+    }
+}
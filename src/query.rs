@@ -0,0 +1,325 @@
+//! Query helpers
+//!
+//! Gemini clients send user input as a percent-encoded query. This module
+//! provides helpers for decoding the query and parsing it into a structured
+//! [SearchQuery].
+//!
+//! ### Example
+//!
+//! ```
+//! use gemfra::query::SearchQuery;
+//!
+//! let query = SearchQuery::parse(r#"tag:rust author:"Jane Doe" async"#);
+//! assert_eq!(query.field("tag"), Some("rust"));
+//! assert_eq!(query.field("author"), Some("Jane Doe"));
+//! assert_eq!(query.terms, ["async"]);
+//! ```
+
+use std::collections::HashMap;
+
+use crate::error::GemError;
+
+/// Decode a percent-encoded string
+///
+/// Invalid escapes are left as they are, and any bytes that are not valid
+/// UTF-8 are replaced with `U+FFFD`.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::query::percent_decode;
+///
+/// assert_eq!(percent_decode("hello%20world"), "hello world");
+/// ```
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(byte) = hex {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Decode a percent-encoded string, failing on invalid input
+///
+/// Unlike [percent_decode], a `%` that isn't followed by two hex digits or a
+/// result that isn't valid UTF-8 is a __59__ Bad Request error. A `+` is kept
+/// as it is, since gemini does not use form encoding.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::query::percent_decode_strict;
+///
+/// assert_eq!(percent_decode_strict("a+b%20c").unwrap(), "a+b c");
+/// assert!(percent_decode_strict("100%").is_err());
+/// assert!(percent_decode_strict("%FF").is_err());
+/// ```
+pub fn percent_decode_strict(value: &str) -> Result<String, GemError> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| GemError::bad_request("Invalid percent escape"))?;
+            decoded.push(byte);
+            i += 3;
+            continue;
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(decoded).map_err(|_| GemError::bad_request("Invalid UTF-8"))
+}
+
+/// Split a URL into the part before the query and the query
+///
+/// Only the first `?` separates the query, so any further `?` are part of the
+/// query. This happens when clients don't encode a `?` typed by the user. An
+/// empty query is treated as no query. The query is returned as it is, so it
+/// still needs to be [decoded](percent_decode), which also turns an encoded
+/// `%3F` into a `?`.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::query::{percent_decode, split_query};
+///
+/// let (url, query) = split_query("gemini://localhost/search?why?%3F");
+/// assert_eq!(url, "gemini://localhost/search");
+/// assert_eq!(query, Some("why?%3F"));
+/// assert_eq!(percent_decode(query.unwrap()), "why??");
+/// ```
+pub fn split_query(url: &str) -> (&str, Option<&str>) {
+    match url.split_once('?') {
+        Some((url, "")) => (url, None),
+        Some((url, query)) => (url, Some(query)),
+        None => (url, None),
+    }
+}
+
+/// Parse a query of `key=value` pairs separated by `&`
+///
+/// Keys and values are [decoded](percent_decode) after the query is split, so
+/// an encoded `%26` or `%3D` is part of the key or value. A pair without an
+/// `=` has an empty value, and empty pairs are skipped. Keys can be repeated.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::query::parse_pairs;
+///
+/// assert_eq!(
+///     parse_pairs("tag=rust&tag=web%20dev&draft"),
+///     [
+///         ("tag".to_owned(), "rust".to_owned()),
+///         ("tag".to_owned(), "web dev".to_owned()),
+///         ("draft".to_owned(), "".to_owned()),
+///     ],
+/// );
+/// ```
+pub fn parse_pairs(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+/// Percent-encode a single path segment
+///
+/// Every byte other than the unreserved characters of RFC 3986 is encoded,
+/// including `/`, so the result is always a single segment. This is useful
+/// when building links from user data, such as a post title.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::query::encode_segment;
+///
+/// assert_eq!(encode_segment("Hello, World/2"), "Hello%2C%20World%2F2");
+/// ```
+pub fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Percent-encode each segment and join them with `/`
+///
+/// See [encode_segment] for how each segment is encoded.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::query::join_segments;
+///
+/// let title = "Why Rust?";
+/// assert_eq!(
+///     format!("/posts/{}", join_segments(&["2024", title])),
+///     "/posts/2024/Why%20Rust%3F",
+/// );
+/// ```
+pub fn join_segments(segments: &[&str]) -> String {
+    segments
+        .iter()
+        .map(|segment| encode_segment(segment))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// A search query made of `key:value` fields and free-text terms
+///
+/// Values and terms can be quoted to include spaces, such as
+/// `author:"Jane Doe"` or `"two words"`. If the quotes are unbalanced, the
+/// whole query is treated as free text.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SearchQuery {
+    /// The values of each field in the order they were given
+    pub fields: HashMap<String, Vec<String>>,
+    /// The free-text terms
+    pub terms: Vec<String>,
+}
+
+impl SearchQuery {
+    /// Parse a decoded query
+    pub fn parse(query: &str) -> Self {
+        Self::parse_quoted(query).unwrap_or_else(|| Self {
+            fields: HashMap::new(),
+            terms: query.split_whitespace().map(str::to_owned).collect(),
+        })
+    }
+
+    /// Parse a query, returning `None` if the quotes are unbalanced
+    fn parse_quoted(query: &str) -> Option<Self> {
+        let mut search = Self::default();
+        let mut key: Option<String> = None;
+        let mut buf = String::new();
+        let mut quoted = false;
+        let mut in_quote = false;
+
+        for c in query.chars().chain(std::iter::once(' ')) {
+            match c {
+                '"' => {
+                    in_quote = !in_quote;
+                    quoted = true;
+                }
+                ':' if !in_quote && !quoted && key.is_none() && !buf.is_empty() => {
+                    key = Some(std::mem::take(&mut buf));
+                }
+                c if c.is_whitespace() && !in_quote => {
+                    let value = std::mem::take(&mut buf);
+                    match key.take() {
+                        Some(key) if !value.is_empty() || quoted => {
+                            search.fields.entry(key).or_default().push(value)
+                        }
+                        Some(key) => search.terms.push(format!("{key}:")),
+                        None if !value.is_empty() => search.terms.push(value),
+                        None => {}
+                    }
+                    quoted = false;
+                }
+                c => buf.push(c),
+            }
+        }
+
+        if in_quote {
+            None
+        } else {
+            Some(search)
+        }
+    }
+
+    /// Get the first value of a field
+    pub fn field(&self, key: &str) -> Option<&str> {
+        self.fields
+            .get(key)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+
+    /// Check whether the query is empty
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.terms.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b%zz%2"), "a b%zz%2");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+    }
+
+    #[test]
+    fn test_split_query() {
+        assert_eq!(split_query("/a?b?c"), ("/a", Some("b?c")));
+        assert_eq!(split_query("/a?"), ("/a", None));
+        assert_eq!(split_query("/a"), ("/a", None));
+        assert_eq!(percent_decode("%3F%3f?"), "???");
+    }
+
+    #[test]
+    fn test_parse_pairs() {
+        assert!(parse_pairs("").is_empty());
+        assert_eq!(
+            parse_pairs("a=1&&b%3D=2%263=4&a"),
+            [
+                ("a".to_owned(), "1".to_owned()),
+                ("b=".to_owned(), "2&3=4".to_owned()),
+                ("a".to_owned(), "".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_segment() {
+        assert_eq!(encode_segment("a-b_c.d~e"), "a-b_c.d~e");
+        assert_eq!(encode_segment("café 100%"), "caf%C3%A9%20100%25");
+        assert_eq!(percent_decode(&encode_segment("a/b?c#d")), "a/b?c#d");
+        assert_eq!(join_segments(&["a b", "c/d", ""]), "a%20b/c%2Fd/");
+    }
+
+    #[test]
+    fn test_search_query() {
+        let query = SearchQuery::parse(r#"tag:rust  tag:"web dev" "two words" a:b:c text:"#);
+        assert_eq!(query.fields["tag"], ["rust", "web dev"]);
+        assert_eq!(query.field("a"), Some("b:c"));
+        assert_eq!(query.terms, ["two words", "text:"]);
+
+        let query = SearchQuery::parse(r#"tag:"rust async"#);
+        assert!(query.fields.is_empty());
+        assert_eq!(query.terms, [r#"tag:"rust"#, "async"]);
+    }
+}
@@ -2,11 +2,56 @@
 //!
 //! The gemini request contains all the information needed to handle a request.
 
-use std::collections::HashMap;
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-use chrono::{DateTime, FixedOffset};
+use bytes::Bytes;
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 
-use crate::error::{GemError, ToGemError};
+use crate::{
+    error::{GemError, ToGemError},
+    query::{
+        encode_segment, join_segments, parse_pairs, percent_decode, percent_decode_strict,
+        split_query, SearchQuery,
+    },
+};
+
+/// A list of allowed client certificates
+///
+/// Hashes are compared by [fingerprint](Certificate::fingerprint), so the same
+/// certificate is allowed however its hash is formatted.
+#[cfg(feature = "routed")]
+#[derive(Debug, Default, Clone)]
+pub(crate) struct CertAllowlist(Vec<String>);
+
+#[cfg(feature = "routed")]
+impl CertAllowlist {
+    /// Allow the certificate with `hash`
+    pub(crate) fn allow(&mut self, hash: &str) {
+        self.0.push(Certificate::normalize_hash(hash));
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check whether `cert` is allowed
+    pub(crate) fn allows(&self, cert: &Certificate) -> bool {
+        self.0.contains(&cert.fingerprint())
+    }
+}
+
+/// The meta of a response that requires a certificate for `cn`
+pub(crate) fn cert_required_cn_message(cn: &str) -> String {
+    format!("A certificate for {cn} is required")
+}
 
 /// Parse an X.509 Name into a hashmap.
 fn parse_client_name(name: impl AsRef<str>) -> Result<HashMap<String, String>, GemError> {
@@ -42,6 +87,121 @@ pub struct Certificate {
 }
 
 impl Certificate {
+    /// Get the normalized fingerprint of the certificate
+    ///
+    /// Front-ends format the certificate [hash](Certificate::hash) differently.
+    /// The fingerprint removes any algorithm prefix (e.g. `SHA256:`) and
+    /// separating colons, and lowercases the hex digits so that the same
+    /// certificate always has the same fingerprint.
+    ///
+    /// ```
+    /// # use gemfra::request::Certificate;
+    /// assert_eq!(Certificate::normalize_hash("SHA256:AB:CD:EF"), "abcdef");
+    /// ```
+    pub fn fingerprint(&self) -> String {
+        Self::normalize_hash(&self.hash)
+    }
+
+    /// Normalize a certificate hash into a fingerprint
+    ///
+    /// See [fingerprint](Certificate::fingerprint).
+    pub fn normalize_hash(hash: &str) -> String {
+        let hash = match hash.split_once(':') {
+            Some((algorithm, rest)) if !algorithm.chars().all(|c| c.is_ascii_hexdigit()) => rest,
+            _ => hash,
+        };
+        hash.chars()
+            .filter(|c| *c != ':')
+            .map(|c| c.to_ascii_lowercase())
+            .collect()
+    }
+
+    /// Get the time left until the certificate expires
+    ///
+    /// The duration is negative if the certificate has already expired.
+    pub fn time_until_expiry(&self) -> Duration {
+        self.not_after.with_timezone(&Utc) - Utc::now()
+    }
+
+    /// Get a gemtext warning if the certificate expires within `threshold`
+    ///
+    /// The warning is a quote line that can be prepended to a response to
+    /// remind the user to renew their certificate before they are locked out.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::request::Certificate;
+    /// # fn example(cert: &Certificate) {
+    /// use chrono::Duration;
+    ///
+    /// let mut body = String::new();
+    /// if let Some(warning) = cert.expiry_warning(Duration::days(7)) {
+    ///     body.push_str(&warning);
+    /// }
+    /// # }
+    /// ```
+    pub fn expiry_warning(&self, threshold: Duration) -> Option<String> {
+        Self::expiry_message(self.time_until_expiry(), threshold)
+    }
+
+    fn expiry_message(remaining: Duration, threshold: Duration) -> Option<String> {
+        if remaining >= threshold {
+            return None;
+        }
+        let message = if remaining <= Duration::zero() {
+            "Your certificate has expired, please create a new one".to_owned()
+        } else if remaining.num_days() > 1 {
+            format!(
+                "Your certificate expires in {} days, please renew it soon",
+                remaining.num_days()
+            )
+        } else if remaining.num_hours() > 1 {
+            format!(
+                "Your certificate expires in {} hours, please renew it soon",
+                remaining.num_hours()
+            )
+        } else {
+            "Your certificate expires within the hour, please renew it soon".to_owned()
+        };
+        Some(format!("> {message}\n"))
+    }
+
+    /// Check whether the certificate was issued by an allowed issuer
+    ///
+    /// Every `(attribute, value)` pair in `allowed` must be present in the
+    /// [issuer](Certificate::issuer), for example `[("O", "My Capsule CA")]`.
+    /// Attribute names are compared case-insensitively and values exactly. An
+    /// empty `allowed` list matches no certificate.
+    ///
+    /// Most Gemini clients use self-signed certificates, so this is only
+    /// useful for capsules that run their own certificate authority. Anyone
+    /// can create a certificate that names any issuer, so this check is only
+    /// meaningful when the front-end verifies the certificate chain against
+    /// that CA. [run_gemini](crate::protocol::Gemini::run_gemini) accepts
+    /// every certificate without checking its chain, so the issuer gives no
+    /// protection there. See [require_issuer](Request::require_issuer) for a
+    /// guard.
+    pub fn issued_by(&self, allowed: &[(&str, &str)]) -> bool {
+        !allowed.is_empty()
+            && allowed.iter().all(|(attribute, value)| {
+                self.issuer.iter().any(|(key, val)| {
+                    key.trim().eq_ignore_ascii_case(attribute.trim()) && val.trim() == value.trim()
+                })
+            })
+    }
+
+    /// Get the common name (`CN`) of the certificate's subject
+    ///
+    /// Clients usually set the common name to the name of the identity that
+    /// the user chose when creating the certificate.
+    pub fn common_name(&self) -> Option<&str> {
+        self.subject
+            .iter()
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("CN"))
+            .map(|(_, value)| value.trim())
+    }
+
     pub fn parse_cert<F>(get_var: F) -> Result<Self, GemError>
     where
         F: Fn(&str) -> Result<String, GemError>,
@@ -61,15 +221,235 @@ impl Certificate {
             subject: parse_client_name(subject)?,
         })
     }
+
+    /// Parse a DER encoded X.509 certificate
+    ///
+    /// This is used when gemfra terminates TLS itself, see
+    /// [Gemini](crate::protocol::Gemini). The [hash](Certificate::hash) is the
+    /// SHA-256 of the certificate as lowercase hex, which is already a
+    /// [fingerprint](Certificate::fingerprint). The issuer and subject are
+    /// keyed by the short name of each attribute, such as `CN`.
+    #[cfg(feature = "gemini")]
+    pub fn from_der(der: &[u8]) -> Result<Self, GemError> {
+        use sha2::{Digest, Sha256};
+        use x509_parser::{
+            objects::{oid2abbrev, oid_registry},
+            prelude::{FromDer, X509Certificate, X509Name},
+            time::ASN1Time,
+        };
+
+        let (_, cert) = X509Certificate::from_der(der)
+            .map_err(|_| GemError::bad_cert("Invalid certificate"))?;
+        let name = |name: &X509Name| -> HashMap<String, String> {
+            name.iter_attributes()
+                .filter_map(|attr| {
+                    let key = match oid2abbrev(attr.attr_type(), oid_registry()) {
+                        Ok(key) => key.to_owned(),
+                        Err(_) => attr.attr_type().to_id_string(),
+                    };
+                    Some((key, attr.as_str().ok()?.to_owned()))
+                })
+                .collect()
+        };
+        let time = |time: ASN1Time| {
+            DateTime::from_timestamp(time.timestamp(), 0)
+                .map(|time| time.fixed_offset())
+                .ok_or_else(|| GemError::bad_cert("Invalid certificate validity"))
+        };
+        let hash = Sha256::digest(der)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        Ok(Self {
+            hash,
+            issuer: name(cert.issuer()),
+            subject: name(cert.subject()),
+            not_after: time(cert.validity().not_after)?,
+            not_before: time(cert.validity().not_before)?,
+        })
+    }
+}
+
+/// Typed data attached to a [Request]
+///
+/// Extensions allow data to be passed along with a request, for example from
+/// a middleware that authenticates a user to the handler that uses the user.
+/// At most one value of each type can be stored.
+///
+/// The values are owned by the request and are dropped along with it. Since
+/// handlers receive the request by value, a handler can take ownership of a
+/// value with [remove](Extensions::remove).
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::request::Extensions;
+///
+/// struct User(String);
+///
+/// let mut extensions = Extensions::new();
+/// extensions.insert(User("alice".to_owned()));
+///
+/// assert_eq!(extensions.get::<User>().unwrap().0, "alice");
+/// ```
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Create an empty set of extensions
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a value, returning the previous value of the same type
+    pub fn insert<T>(&mut self, val: T) -> Option<T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(val))
+            .and_then(|prev| prev.downcast().ok())
+            .map(|prev| *prev)
+    }
+
+    /// Get a reference to the value of type `T`
+    pub fn get<T>(&self) -> Option<&T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_ref())
+    }
+
+    /// Get a mutable reference to the value of type `T`
+    pub fn get_mut<T>(&mut self) -> Option<&mut T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_mut())
+    }
+
+    /// Remove the value of type `T`, taking ownership of it
+    pub fn remove<T>(&mut self) -> Option<T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|val| val.downcast().ok())
+            .map(|val| *val)
+    }
+}
+
+/// The front-end server that ran the application
+///
+/// Gemini servers expose slightly different sets of CGI variables. The
+/// front-end is detected from the `SERVER_SOFTWARE` variable, and
+/// [parse_request](Request::parse_request) uses it to work around the
+/// differences. The detected front-ends and their handled quirks are:
+///
+/// | Front-end | `SERVER_SOFTWARE` | Quirks                                   |
+/// |-----------|-------------------|------------------------------------------|
+/// | gmid      | `gmid/...`        | None                                     |
+/// | Jetforce  | `jetforce/...`    | None                                     |
+/// | Molly Brown | `MOLLY_BROWN`   | No `REMOTE_HOST` or `GEMINI_URL`         |
+/// | stargazer | `stargazer/...`   | None                                     |
+///
+/// When a variable isn't provided, `REMOTE_HOST` falls back to
+/// `REMOTE_ADDR`, and `GEMINI_URL` is rebuilt from the server name, port,
+/// script, path and query. For other front-ends, these variables are only
+/// rebuilt if they are missing. For every front-end, a missing `SCRIPT_NAME`
+/// is treated as an empty script, as if the app was served from the root.
+///
+/// Front-ends also format the certificate hash differently (for example with
+/// or without a `SHA256:` prefix), which is handled by
+/// [fingerprint](Certificate::fingerprint) for every front-end.
+///
+/// Agate only serves static files, so it never runs an application.
+///
+/// More front-ends may be detected in the future, so matching on this enum
+/// needs a wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FrontEnd {
+    /// [gmid](https://gmid.omarpolo.com), detected from `gmid/...`
+    Gmid,
+    /// [Jetforce](https://github.com/michael-lazar/jetforce), detected from
+    /// `jetforce/...`
+    Jetforce,
+    /// [Molly Brown](https://tildegit.org/solderpunk/molly-brown), detected
+    /// from `MOLLY_BROWN`
+    MollyBrown,
+    /// [stargazer](https://git.sr.ht/~zethra/stargazer), detected from
+    /// `stargazer/...`
+    Stargazer,
+    /// The front-end is unknown or didn't provide `SERVER_SOFTWARE`
+    Unknown,
+}
+
+impl FrontEnd {
+    /// Detect the front-end from the `SERVER_SOFTWARE` variable
+    ///
+    /// The name before the version is compared case-insensitively, ignoring
+    /// `-`, `_` and spaces.
+    ///
+    /// ```
+    /// # use gemfra::request::FrontEnd;
+    /// assert_eq!(FrontEnd::detect("gmid/2.0"), FrontEnd::Gmid);
+    /// assert_eq!(FrontEnd::detect("MOLLY_BROWN"), FrontEnd::MollyBrown);
+    /// ```
+    pub fn detect(server_software: &str) -> Self {
+        let name: String = server_software
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| !matches!(c, '-' | '_' | ' '))
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        match name.as_str() {
+            "gmid" => Self::Gmid,
+            "jetforce" => Self::Jetforce,
+            "mollybrown" => Self::MollyBrown,
+            "stargazer" => Self::Stargazer,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Whether the front-end provides the `REMOTE_HOST` variable
+    pub fn provides_remote_host(self) -> bool {
+        self != Self::MollyBrown
+    }
+
+    /// Whether the front-end provides the `GEMINI_URL` variable
+    pub fn provides_url(self) -> bool {
+        self != Self::MollyBrown
+    }
 }
 
 /// Information about a request
+///
+/// Requests are created by the protocols, with
+/// [parse_request](Request::parse_request), or with
+/// [builder](Request::builder). More fields may be added in the future, so a
+/// request can't be created with a struct expression outside of gemfra.
+#[non_exhaustive]
 pub struct Request {
     /// URL Path relative to the script
     pub path: String,
     /// URL Path of the script
     pub script: String,
     /// Query component of the URL
+    ///
+    /// This is everything after the first `?` of the URL, so it can contain
+    /// more `?` if the client didn't encode them. The query is not decoded.
     pub query: Option<String>,
     /// Server component of the URL
     pub server_name: String,
@@ -81,32 +461,122 @@ pub struct Request {
     pub remote_addr: String,
     /// FQDN of the client (if unresolvable, will be the same as remote_addr)
     pub remote_host: String,
-    /// The protocol of the URL (should always be "GEMINI")
+    /// The protocol of the URL (should always be "GEMINI"). If the front-end
+    /// doesn't provide the protocol, this defaults to "GEMINI".
     pub protocol: String,
     /// The client certificate if one was provided
     pub client_cert: Option<Certificate>,
+    /// The front-end that ran the application
+    pub front_end: FrontEnd,
+    /// The host name that the client asked for with TLS SNI, if the front-end
+    /// provides it
+    ///
+    /// See [sni_mismatch](Request::sni_mismatch).
+    pub sni_host: Option<String>,
+    /// Extra data attached to the request by the application
+    pub extensions: Extensions,
+}
+
+/// The variables that front-ends use for the TLS SNI host, in the order they
+/// are checked
+const SNI_VARS: [&str; 2] = ["TLS_CLIENT_SERVER_NAME", "SSL_TLS_SNI"];
+
+/// Compare two host names, ignoring case and a trailing `.`
+fn same_host(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
 }
 
+/// The maximum length of a request URL in bytes
+///
+/// Gemini requests longer than this are invalid and are rejected with a __59__
+/// Bad Request.
+pub const MAX_REQUEST_LEN: usize = 1024;
+
 impl Request {
+    /// Build a request for `path`
+    ///
+    /// This is mostly useful for testing handlers. The path is not encoded,
+    /// like [path](Request::path). See [RequestBuilder] for the defaults.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::request::Request;
+    ///
+    /// let request = Request::builder("/search")
+    ///     .query("rust%20async")
+    ///     .server_name("example.com")
+    ///     .build();
+    /// assert_eq!(request.url, "gemini://example.com/search?rust%20async");
+    /// ```
+    pub fn builder(path: impl Into<String>) -> RequestBuilder {
+        RequestBuilder::new(path.into())
+    }
+
+    /// Parse a request from CGI variables
+    ///
+    /// Requests whose URL or query is longer than [MAX_REQUEST_LEN] are
+    /// rejected with a [BadRequest](crate::error::GemErrorType::BadRequest)
+    /// error, in case the front-end doesn't enforce the limit.
     pub fn parse_request<F>(get_var: F) -> Result<Self, GemError>
     where
         F: Fn(&str) -> Result<String, GemError>,
     {
+        let front_end = get_var("SERVER_SOFTWARE")
+            .map_or(FrontEnd::Unknown, |software| FrontEnd::detect(&software));
         let path = get_var("PATH_INFO")?;
-        let script = get_var("SCRIPT_NAME")?;
+        let script = get_var("SCRIPT_NAME").unwrap_or_default();
         let server = get_var("SERVER_NAME")?;
-        let query = match get_var("QUERY_STRING").ok() {
-            Some(v) => match v.is_empty() {
+        let port: u16 = get_var("SERVER_PORT")?.parse().into_gem()?;
+        let url = match front_end.provides_url() {
+            true => get_var("GEMINI_URL").ok(),
+            false => None,
+        };
+        // Fall back to the query of the URL for front-ends that don't provide it
+        let query = match get_var("QUERY_STRING") {
+            Ok(v) => match v.is_empty() {
                 true => None,
                 false => Some(v),
             },
-            None => None,
+            Err(_) => url
+                .as_deref()
+                .and_then(|url| split_query(url).1)
+                .map(str::to_owned),
         };
-        let port: u16 = get_var("SERVER_PORT")?.parse().into_gem()?;
-        let url = get_var("GEMINI_URL")?;
+        let url = url.unwrap_or_else(|| {
+            let mut url = format!("gemini://{server}");
+            if port != 1965 {
+                url.push_str(&format!(":{port}"));
+            }
+            url.push_str(script.trim_end_matches('/'));
+            url.push_str(&path);
+            if let Some(query) = &query {
+                url.push('?');
+                url.push_str(query);
+            }
+            url
+        });
+        let too_long = url.len() > MAX_REQUEST_LEN
+            || query
+                .as_ref()
+                .is_some_and(|query| query.len() > MAX_REQUEST_LEN);
+        if too_long {
+            return Err(GemError::bad_request("Request is too long"));
+        }
         let remote_addr = get_var("REMOTE_ADDR")?;
-        let remote_host = get_var("REMOTE_HOST")?;
-        let protocol = get_var("SERVER_PROTOCOL")?;
+        let remote_host = match front_end.provides_remote_host() {
+            true => get_var("REMOTE_HOST").ok(),
+            false => None,
+        }
+        .unwrap_or_else(|| remote_addr.clone());
+        // Some minimal front-ends don't provide the protocol
+        let protocol = get_var("SERVER_PROTOCOL").unwrap_or_else(|_| "GEMINI".to_owned());
+
+        let sni_host = SNI_VARS
+            .iter()
+            .find_map(|var| get_var(var).ok())
+            .filter(|host| !host.is_empty());
 
         let cert = if get_var("AUTH_TYPE").unwrap_or("".to_owned()) == "CERTIFICATE" {
             Some(Certificate::parse_cert(get_var)?)
@@ -125,8 +595,530 @@ impl Request {
             remote_host,
             protocol,
             client_cert: cert,
+            front_end,
+            sni_host,
+            extensions: Extensions::new(),
         })
     }
+
+    /// Get the host of the requested URL
+    ///
+    /// The host is taken from the [url](Request::url) without the port, or
+    /// the [server_name](Request::server_name) if the URL has no host.
+    pub fn url_host(&self) -> &str {
+        let authority = self
+            .url
+            .split_once("://")
+            .map(|(_, rest)| rest.split(['/', '?', '#']).next().unwrap_or(rest))
+            .map(|authority| authority.rsplit('@').next().unwrap_or(authority))
+            .unwrap_or_default();
+        let host = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => &authority[..i],
+            _ => authority,
+        };
+        if host.is_empty() {
+            &self.server_name
+        } else {
+            host
+        }
+    }
+
+    /// Check whether the TLS SNI host differs from the host of the URL
+    ///
+    /// Clients send the host they are connecting to twice: once during the TLS
+    /// handshake (SNI), and again in the request URL. A client that sends
+    /// different hosts may be trying to reach a capsule that the certificate
+    /// it negotiated doesn't belong to, so security-conscious capsules can
+    /// reject these requests, for example with a __53__ Proxy Request Refused.
+    ///
+    /// The SNI host is read from `TLS_CLIENT_SERVER_NAME`, or `SSL_TLS_SNI` for
+    /// front-ends that follow Apache's naming, and stored in
+    /// [sni_host](Request::sni_host). The hosts are compared
+    /// case-insensitively, ignoring a trailing `.`, against the
+    /// [url_host](Request::url_host). If the front-end doesn't provide the SNI
+    /// host, this is always `false`.
+    pub fn sni_mismatch(&self) -> bool {
+        self.sni_host
+            .as_deref()
+            .is_some_and(|sni| !same_host(sni, self.url_host()))
+    }
+
+    /// Parse the IP address of the client
+    ///
+    /// Returns `None` if [remote_addr](Request::remote_addr) is not an IP
+    /// address. IPv4 addresses that are mapped into IPv6, such as
+    /// `::ffff:127.0.0.1`, are returned as IPv4.
+    pub fn remote_ip(&self) -> Option<IpAddr> {
+        self.remote_addr
+            .trim_matches(['[', ']'])
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| ip.to_canonical())
+    }
+
+    /// Build breadcrumb navigation for the request path
+    ///
+    /// A `(label, url)` pair is returned for the root, labeled `Home`, and for
+    /// each segment of the path. The label is the segment as it is in the
+    /// (decoded) [path](Request::path), and the url is the percent-encoded
+    /// cumulative path including the [script](Request::script). Empty segments
+    /// are ignored.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::request::Request;
+    /// # fn example(request: Request) {
+    /// // For a request to `/blog/2023/hello%20world`
+    /// let links: String = request
+    ///     .breadcrumbs()
+    ///     .into_iter()
+    ///     .map(|(label, url)| format!("=> {url} {label}\n"))
+    ///     .collect();
+    /// # }
+    /// ```
+    pub fn breadcrumbs(&self) -> Vec<(String, String)> {
+        let script = self.script.trim_end_matches('/');
+        let mut url = script.to_owned();
+        let mut crumbs = vec![("Home".to_owned(), format!("{script}/"))];
+        for segment in self.path.split('/').filter(|s| !s.is_empty()) {
+            url.push('/');
+            url.push_str(&encode_segment(segment));
+            crumbs.push((segment.to_owned(), url.clone()));
+        }
+        crumbs
+    }
+
+    /// Require a client certificate from an allowed issuer
+    ///
+    /// Returns a __60__ Certificate Required error if there is no certificate,
+    /// and a __61__ Certificate Not Authorised error if the certificate was not
+    /// [issued by](Certificate::issued_by) the allowed issuer.
+    ///
+    /// The issuer is read from the `TLS_CLIENT_ISSUER` variable, so the
+    /// front-end must pass the issuer of the client certificate for this to
+    /// work. Anyone can create a certificate that names any issuer, so this
+    /// should only be used when the front-end verifies the certificate chain
+    /// against the allowed CA. See [issued_by](Certificate::issued_by).
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::{request::Request, response::Response, error::AnyError};
+    /// # fn example(request: Request) -> Result<Response, AnyError> {
+    /// let cert = request.require_issuer(&[("O", "My Capsule CA")])?;
+    /// Ok(Response::text(format!("Welcome {}", cert.fingerprint())))
+    /// # }
+    /// ```
+    pub fn require_issuer(&self, allowed: &[(&str, &str)]) -> Result<&Certificate, GemError> {
+        let cert = self
+            .client_cert
+            .as_ref()
+            .ok_or_else(|| GemError::cert_needed("Certificate needed"))?;
+        if cert.issued_by(allowed) {
+            Ok(cert)
+        } else {
+            Err(GemError::cert_unauthorised(
+                "Certificate is not from a trusted issuer",
+            ))
+        }
+    }
+
+    /// Require a client certificate with a specific common name
+    ///
+    /// Returns the same __60__ Certificate Required error as
+    /// [Response::cert_required_cn](crate::response::Response::cert_required_cn)
+    /// if there is no certificate, and a __61__ Certificate Not Authorised
+    /// error if the [common name](Certificate::common_name) is not
+    /// `expected_cn`.
+    ///
+    /// Like other X.509 attribute values, the common name is compared
+    /// case-sensitively, so `Alice` does not match `alice`. Anyone can create a
+    /// certificate with any common name, so this should be combined with a
+    /// check of the [fingerprint](Certificate::fingerprint) or
+    /// [issuer](Request::require_issuer) when the identity matters.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::{request::Request, response::Response, error::AnyError};
+    /// # fn example(request: Request) -> Result<Response, AnyError> {
+    /// request.require_cn("alice")?;
+    /// Ok(Response::gemtext("# Alice's private page"))
+    /// # }
+    /// ```
+    pub fn require_cn(&self, expected_cn: &str) -> Result<&Certificate, GemError> {
+        let cert = self
+            .client_cert
+            .as_ref()
+            .ok_or_else(|| GemError::cert_needed(cert_required_cn_message(expected_cn)))?;
+        if cert.common_name() == Some(expected_cn) {
+            Ok(cert)
+        } else {
+            Err(GemError::cert_unauthorised(format!(
+                "This page is only available to {expected_cn}"
+            )))
+        }
+    }
+
+    /// Get every value of `key` from a query of `key=value` pairs
+    ///
+    /// The values are decoded and returned in the order they were given. See
+    /// [parse_pairs] for how the query is parsed.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::request::Request;
+    /// # fn example(request: Request) {
+    /// // For the query `tag=rust&tag=async`
+    /// let tags = request.query_values("tag");
+    /// assert_eq!(tags, ["rust", "async"]);
+    /// # }
+    /// ```
+    pub fn query_values(&self, key: &str) -> Vec<String> {
+        self.query
+            .as_deref()
+            .map(parse_pairs)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// Get the `key=value` pairs of the query as a map
+    ///
+    /// Keys and values are decoded, and a key without a value maps to an empty
+    /// string. If a key is given more than once, the last value is kept; use
+    /// [query_values](Request::query_values) to get all of them. See
+    /// [parse_pairs] for how the query is parsed.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::request::Request;
+    /// # fn example(request: Request) {
+    /// // For the query `sort=name&page=2`
+    /// let params = request.query_params();
+    /// assert_eq!(params["sort"], "name");
+    /// assert_eq!(params["page"], "2");
+    /// # }
+    /// ```
+    pub fn query_params(&self) -> HashMap<String, String> {
+        match self.query.as_deref() {
+            Some(query) if !query.is_empty() => parse_pairs(query).into_iter().collect(),
+            _ => HashMap::new(),
+        }
+    }
+
+    /// Get the last value of `key` from a query of `key=value` pairs
+    ///
+    /// This is the same as looking up `key` in
+    /// [query_params](Request::query_params).
+    pub fn query_param(&self, key: &str) -> Option<String> {
+        parse_pairs(self.query.as_deref()?)
+            .into_iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Get the percent-decoded query
+    ///
+    /// A `+` is kept as it is, since gemini does not use form encoding. The
+    /// query is empty if there isn't one. Invalid escapes and invalid UTF-8
+    /// are a __59__ Bad Request error, see
+    /// [percent_decode_strict](crate::query::percent_decode_strict). The raw
+    /// query is still available from [query](Request::query).
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::{error::AnyError, request::Request, response::Response};
+    ///
+    /// async fn greet(request: Request) -> Result<Response, AnyError> {
+    ///     let name = request.query_decoded()?;
+    ///     if name.is_empty() {
+    ///         return Ok(Response::input("What is your name?"));
+    ///     }
+    ///     Ok(Response::gemtext(format!("# Hello {name}")))
+    /// }
+    /// ```
+    pub fn query_decoded(&self) -> Result<String, GemError> {
+        match &self.query {
+            Some(query) => percent_decode_strict(query),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Decode the query and parse it as a [SearchQuery]
+    pub fn search_query(&self) -> Option<SearchQuery> {
+        self.query
+            .as_deref()
+            .map(|query| SearchQuery::parse(&percent_decode(query)))
+    }
+}
+
+/// The parameters of a Titan upload
+///
+/// Titan requests add their parameters to the end of the path, for example
+/// `titan://example.com/notes.gmi;size=11;mime=text/plain;token=secret`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TitanParams {
+    /// The size of the upload in bytes
+    pub size: u64,
+    /// The MIME type of the upload. This is `text/gemini` if the client
+    /// didn't give one.
+    pub mime: String,
+    /// The token that the client gave to authorise the upload
+    pub token: Option<String>,
+}
+
+impl TitanParams {
+    /// Split the parameters from the end of a titan path
+    ///
+    /// The path is returned without its parameters. The parameter values are
+    /// percent-decoded and unknown parameters are ignored. A __59__ Bad
+    /// Request is returned if the `size` is missing or invalid.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::request::TitanParams;
+    ///
+    /// let (path, params) = TitanParams::split("/notes.gmi;size=11;token=a%20b").unwrap();
+    /// assert_eq!(path, "/notes.gmi");
+    /// assert_eq!(params.size, 11);
+    /// assert_eq!(params.mime, "text/gemini");
+    /// assert_eq!(params.token.as_deref(), Some("a b"));
+    /// ```
+    pub fn split(path: &str) -> Result<(&str, Self), GemError> {
+        let (path, params) = path
+            .split_once(';')
+            .ok_or_else(|| GemError::bad_request("Missing upload size"))?;
+
+        let mut size = None;
+        let mut mime = None;
+        let mut token = None;
+        for param in params.split(';') {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_decode(value);
+            match key {
+                "size" => size = Some(value),
+                "mime" => mime = Some(value),
+                "token" => token = Some(value),
+                _ => {}
+            }
+        }
+        let size = size
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| GemError::bad_request("Invalid upload size"))?;
+
+        Ok((
+            path,
+            Self {
+                size,
+                mime: mime
+                    .filter(|mime| !mime.is_empty())
+                    .unwrap_or_else(|| "text/gemini".to_owned()),
+                token,
+            },
+        ))
+    }
+}
+
+/// The body of a Titan upload
+///
+/// The body is read directly from the connection, and reading stops after
+/// [size](TitanParams::size) bytes. Upload implements [AsyncRead], so it can be
+/// copied somewhere else with [tokio::io::copy], or it can be read into memory
+/// with [bytes](Upload::bytes).
+pub struct Upload<'a> {
+    /// The parameters of the upload
+    pub params: TitanParams,
+    reader: Pin<Box<dyn AsyncRead + Send + 'a>>,
+}
+
+impl<'a> Upload<'a> {
+    /// Create an upload that reads its body from `reader`
+    ///
+    /// At most [size](TitanParams::size) bytes are read from `reader`.
+    pub fn new<R>(params: TitanParams, reader: R) -> Self
+    where
+        R: AsyncRead + Send + 'a,
+    {
+        let reader = Box::pin(reader.take(params.size));
+        Self { params, reader }
+    }
+
+    /// Read the whole body into memory
+    ///
+    /// An error is returned if the connection closes before every byte of
+    /// the upload has been received.
+    pub async fn bytes(mut self) -> io::Result<Bytes> {
+        let mut body = Vec::new();
+        self.read_to_end(&mut body).await?;
+        if (body.len() as u64) < self.params.size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(body.into())
+    }
+}
+
+impl AsyncRead for Upload<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.reader.as_mut().poll_read(cx, buf)
+    }
+}
+
+/// A builder for a [Request]
+///
+/// This is created with [Request::builder]. Unless they are set, the request is
+/// a plain gemini request from `127.0.0.1` to `localhost` on port 1965, with no
+/// query, script, or certificate. The [url](Request::url) is built from the
+/// other fields unless it is [set](RequestBuilder::url).
+pub struct RequestBuilder {
+    request: Request,
+    url: Option<String>,
+}
+
+impl RequestBuilder {
+    fn new(path: String) -> Self {
+        Self {
+            request: Request {
+                path,
+                script: String::new(),
+                query: None,
+                server_name: "localhost".to_owned(),
+                server_port: 1965,
+                url: String::new(),
+                remote_addr: "127.0.0.1".to_owned(),
+                remote_host: "127.0.0.1".to_owned(),
+                protocol: "GEMINI".to_owned(),
+                client_cert: None,
+                front_end: FrontEnd::Unknown,
+                sni_host: None,
+                extensions: Extensions::new(),
+            },
+            url: None,
+        }
+    }
+
+    /// Set the path of the script
+    pub fn script(mut self, script: impl Into<String>) -> Self {
+        self.request.script = script.into();
+        self
+    }
+
+    /// Set the query, which should already be encoded
+    pub fn query(mut self, query: impl Into<String>) -> Self {
+        self.request.query = Some(query.into());
+        self
+    }
+
+    /// Set the host name of the server
+    pub fn server_name(mut self, name: impl Into<String>) -> Self {
+        self.request.server_name = name.into();
+        self
+    }
+
+    /// Set the port of the server
+    pub fn server_port(mut self, port: u16) -> Self {
+        self.request.server_port = port;
+        self
+    }
+
+    /// Set the full URL instead of building it from the other fields
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    /// Set the address of the client, which is also used as its host name
+    pub fn remote_addr(mut self, addr: impl Into<String>) -> Self {
+        let addr = addr.into();
+        self.request.remote_host = addr.clone();
+        self.request.remote_addr = addr;
+        self
+    }
+
+    /// Set the host name of the client
+    pub fn remote_host(mut self, host: impl Into<String>) -> Self {
+        self.request.remote_host = host.into();
+        self
+    }
+
+    /// Set the protocol, such as `TITAN`
+    pub fn protocol(mut self, protocol: impl Into<String>) -> Self {
+        self.request.protocol = protocol.into();
+        self
+    }
+
+    /// Set the client certificate
+    pub fn client_cert(mut self, cert: Certificate) -> Self {
+        self.request.client_cert = Some(cert);
+        self
+    }
+
+    /// Set the front-end that ran the application
+    pub fn front_end(mut self, front_end: FrontEnd) -> Self {
+        self.request.front_end = front_end;
+        self
+    }
+
+    /// Set the host name that the client asked for with TLS SNI
+    pub fn sni_host(mut self, host: impl Into<String>) -> Self {
+        self.request.sni_host = Some(host.into());
+        self
+    }
+
+    /// Attach a value to the [extensions](Request::extensions) of the request
+    pub fn extension<T>(mut self, value: T) -> Self
+    where
+        T: Send + Sync + 'static,
+    {
+        self.request.extensions.insert(value);
+        self
+    }
+
+    /// Create the request
+    pub fn build(self) -> Request {
+        let mut request = self.request;
+        request.url = self.url.unwrap_or_else(|| {
+            let scheme = request.protocol.to_ascii_lowercase();
+            let port = match request.server_port {
+                1965 => String::new(),
+                port => format!(":{port}"),
+            };
+            let path = format!("{}{}", request.script, request.path);
+            let path = path.split('/').collect::<Vec<_>>();
+            let query = request
+                .query
+                .as_ref()
+                .map_or(String::new(), |query| format!("?{query}"));
+            format!(
+                "{scheme}://{}{port}{}{query}",
+                request.server_name,
+                join_segments(&path)
+            )
+        });
+        request
+    }
+}
+
+#[cfg(test)]
+impl Request {
+    /// Create a request for `path` with no query or certificate
+    pub(crate) fn mock(path: &str) -> Self {
+        Request::builder(path)
+            .url(format!("gemini://localhost{path}"))
+            .build()
+    }
 }
 
 #[cfg(test)]
@@ -147,4 +1139,331 @@ mod test {
         let err = parse_client_name("CN").expect_err("Expected Error");
         assert_eq!(err.error_type, GemErrorType::BadCert);
     }
+
+    #[test]
+    fn test_extensions() {
+        struct User(&'static str);
+
+        let mut extensions = Extensions::new();
+        assert!(extensions.get::<User>().is_none());
+
+        assert!(extensions.insert(User("alice")).is_none());
+        assert!(extensions.insert(5u32).is_none());
+        assert_eq!(extensions.get::<User>().unwrap().0, "alice");
+
+        *extensions.get_mut::<u32>().unwrap() += 1;
+        assert_eq!(extensions.insert(User("bob")).unwrap().0, "alice");
+        assert_eq!(extensions.remove::<u32>(), Some(6));
+        assert_eq!(extensions.remove::<User>().unwrap().0, "bob");
+        assert!(extensions.get::<User>().is_none());
+    }
+
+    #[test]
+    fn test_builder() {
+        let request = Request::builder("/a b").build();
+        assert_eq!(request.url, "gemini://localhost/a%20b");
+        assert_eq!(request.remote_host, "127.0.0.1");
+
+        let request = Request::builder("/upload")
+            .script("/cgi")
+            .query("x")
+            .server_name("example.com")
+            .server_port(1966)
+            .protocol("TITAN")
+            .remote_addr("::1")
+            .extension(5u32)
+            .build();
+        assert_eq!(request.url, "titan://example.com:1966/cgi/upload?x");
+        assert_eq!(request.remote_host, "::1");
+        assert_eq!(request.extensions.get::<u32>(), Some(&5));
+
+        let request = Request::builder("/").url("gemini://other/").build();
+        assert_eq!(request.url, "gemini://other/");
+    }
+
+    #[test]
+    fn test_normalize_hash() {
+        assert_eq!(Certificate::normalize_hash("SHA256:AB:cd:EF"), "abcdef");
+        assert_eq!(Certificate::normalize_hash("ab:cd"), "abcd");
+        assert_eq!(Certificate::normalize_hash("ABCDEF"), "abcdef");
+    }
+
+    fn parse_vars(vars: &[(&str, &str)]) -> Result<Request, GemError> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        Request::parse_request(|k| {
+            vars.get(k)
+                .map(|v| (*v).to_owned())
+                .ok_or(GemError::runtime_error(format!("Missing header {k}")))
+        })
+    }
+
+    const VARS: &[(&str, &str)] = &[
+        ("PATH_INFO", "/foo"),
+        ("SCRIPT_NAME", "/cgi"),
+        ("SERVER_NAME", "localhost"),
+        ("QUERY_STRING", ""),
+        ("SERVER_PORT", "1965"),
+        ("GEMINI_URL", "gemini://localhost/cgi/foo"),
+        ("REMOTE_ADDR", "127.0.0.1"),
+        ("REMOTE_HOST", "localhost"),
+    ];
+
+    #[test]
+    fn test_breadcrumbs() {
+        let request = Request::builder("/blog//hello world/100%41/")
+            .script("/cgi/")
+            .build();
+        assert_eq!(
+            request.breadcrumbs(),
+            [
+                ("Home".to_owned(), "/cgi/".to_owned()),
+                ("blog".to_owned(), "/cgi/blog".to_owned()),
+                (
+                    "hello world".to_owned(),
+                    "/cgi/blog/hello%20world".to_owned()
+                ),
+                (
+                    "100%41".to_owned(),
+                    "/cgi/blog/hello%20world/100%2541".to_owned()
+                ),
+            ]
+        );
+        assert_eq!(Request::mock("").breadcrumbs().len(), 1);
+    }
+
+    #[test]
+    fn test_expiry_warning() {
+        let week = Duration::days(7);
+        assert_eq!(Certificate::expiry_message(Duration::days(8), week), None);
+        assert_eq!(
+            Certificate::expiry_message(Duration::days(3), week).as_deref(),
+            Some("> Your certificate expires in 3 days, please renew it soon\n")
+        );
+        assert_eq!(
+            Certificate::expiry_message(Duration::hours(5), week).as_deref(),
+            Some("> Your certificate expires in 5 hours, please renew it soon\n")
+        );
+        assert_eq!(
+            Certificate::expiry_message(-Duration::hours(5), week).as_deref(),
+            Some("> Your certificate has expired, please create a new one\n")
+        );
+    }
+
+    #[test]
+    fn test_require_issuer() {
+        let mut request = Request::mock("/");
+        let err = request.require_issuer(&[("O", "CA")]).err().unwrap();
+        assert_eq!(err.error_type, GemErrorType::CertNeeded);
+
+        let now = Utc::now().fixed_offset();
+        request.client_cert = Some(Certificate {
+            hash: "ab".to_owned(),
+            issuer: parse_client_name("CN=Root, O=CA").unwrap(),
+            subject: parse_client_name("CN=alice").unwrap(),
+            not_after: now,
+            not_before: now,
+        });
+        assert!(request.require_issuer(&[("o", "CA")]).is_ok());
+        assert!(request
+            .require_issuer(&[("CN", "Root"), ("O", "CA")])
+            .is_ok());
+        let err = request.require_issuer(&[("O", "Other")]).err().unwrap();
+        assert_eq!(err.error_type, GemErrorType::CertUnAuthorised);
+        // An empty list doesn't allow every certificate
+        let err = request.require_issuer(&[]).err().unwrap();
+        assert_eq!(err.error_type, GemErrorType::CertUnAuthorised);
+    }
+
+    #[test]
+    fn test_require_cn() {
+        let mut request = Request::mock("/alice/private");
+        let err = request.require_cn("alice").err().unwrap();
+        assert_eq!(err.error_type, GemErrorType::CertNeeded);
+        let response = crate::response::Response::from(err);
+        let expected = crate::response::Response::cert_required_cn("alice");
+        assert_eq!(
+            (response.code, response.meta),
+            (expected.code, expected.meta)
+        );
+
+        let now = Utc::now().fixed_offset();
+        request.client_cert = Some(Certificate {
+            hash: "ab".to_owned(),
+            issuer: parse_client_name("CN=alice").unwrap(),
+            subject: parse_client_name("O=Home, CN=alice").unwrap(),
+            not_after: now,
+            not_before: now,
+        });
+        assert_eq!(
+            request.client_cert.as_ref().unwrap().common_name(),
+            Some("alice")
+        );
+        assert!(request.require_cn("alice").is_ok());
+        for cn in ["Alice", "bob"] {
+            let err = request.require_cn(cn).err().unwrap();
+            assert_eq!(err.error_type, GemErrorType::CertUnAuthorised);
+        }
+    }
+
+    #[test]
+    fn test_missing_protocol() {
+        let request = parse_vars(VARS).unwrap();
+        assert_eq!(request.protocol, "GEMINI");
+
+        let mut vars = VARS.to_vec();
+        vars.push(("SERVER_PROTOCOL", "TITAN"));
+        let request = parse_vars(&vars).unwrap();
+        assert_eq!(request.protocol, "TITAN");
+    }
+
+    #[test]
+    fn test_remote_ip() {
+        let mut request = Request::mock("/");
+        assert_eq!(request.remote_ip(), Some(IpAddr::from([127, 0, 0, 1])));
+        request.remote_addr = "::ffff:10.0.0.1".to_owned();
+        assert_eq!(request.remote_ip(), Some(IpAddr::from([10, 0, 0, 1])));
+        request.remote_addr = "[::1]".to_owned();
+        assert_eq!(request.remote_ip(), Some("::1".parse().unwrap()));
+        request.remote_addr = "localhost".to_owned();
+        assert_eq!(request.remote_ip(), None);
+    }
+
+    #[test]
+    fn test_sni_mismatch() {
+        let request = parse_vars(VARS).unwrap();
+        assert_eq!(request.sni_host, None);
+        assert!(!request.sni_mismatch());
+
+        let mut vars = VARS.to_vec();
+        vars.push(("TLS_CLIENT_SERVER_NAME", "LocalHost."));
+        let mut request = parse_vars(&vars).unwrap();
+        assert!(!request.sni_mismatch());
+
+        request.url = "gemini://user@other.example:1965/".to_owned();
+        assert_eq!(request.url_host(), "other.example");
+        assert!(request.sni_mismatch());
+
+        request.url = "gemini://[::1]/".to_owned();
+        assert_eq!(request.url_host(), "[::1]");
+    }
+
+    #[test]
+    fn test_front_end() {
+        let request = parse_vars(VARS).unwrap();
+        assert_eq!(request.front_end, FrontEnd::Unknown);
+        assert_eq!(request.remote_host, "localhost");
+
+        let mut vars: Vec<_> = VARS
+            .iter()
+            .copied()
+            .filter(|(k, _)| !matches!(*k, "GEMINI_URL" | "REMOTE_HOST" | "QUERY_STRING"))
+            .collect();
+        vars.push(("SERVER_SOFTWARE", "MOLLY_BROWN"));
+        vars.push(("QUERY_STRING", "a%20b"));
+        let request = parse_vars(&vars).unwrap();
+        assert_eq!(request.front_end, FrontEnd::MollyBrown);
+        assert_eq!(request.url, "gemini://localhost/cgi/foo?a%20b");
+        assert_eq!(request.remote_host, "127.0.0.1");
+
+        // A missing script is served from the root
+        vars.retain(|(k, _)| *k != "SCRIPT_NAME");
+        let request = parse_vars(&vars).unwrap();
+        assert_eq!(request.script, "");
+        assert_eq!(request.url, "gemini://localhost/foo?a%20b");
+
+        assert_eq!(FrontEnd::detect("Jetforce/0.10"), FrontEnd::Jetforce);
+        assert_eq!(FrontEnd::detect("stargazer"), FrontEnd::Stargazer);
+        assert_eq!(FrontEnd::detect("Apache/2"), FrontEnd::Unknown);
+    }
+
+    #[test]
+    fn test_request_too_long() {
+        let query = "a".repeat(MAX_REQUEST_LEN + 1);
+        let mut vars = VARS.to_vec();
+        vars.retain(|(k, _)| *k != "QUERY_STRING");
+        vars.push(("QUERY_STRING", &query));
+        let err = parse_vars(&vars).err().unwrap();
+        assert_eq!(err.error_type, GemErrorType::BadRequest);
+
+        let url = format!("gemini://localhost/cgi/foo?{query}");
+        vars.retain(|(k, _)| *k != "QUERY_STRING" && *k != "GEMINI_URL");
+        vars.push(("GEMINI_URL", &url));
+        let err = parse_vars(&vars).err().unwrap();
+        assert_eq!(err.error_type, GemErrorType::BadRequest);
+    }
+
+    #[test]
+    fn test_query_from_url() {
+        let mut vars: Vec<_> = VARS
+            .iter()
+            .copied()
+            .filter(|(k, _)| *k != "QUERY_STRING")
+            .collect();
+        assert_eq!(parse_vars(&vars).unwrap().query, None);
+
+        vars.retain(|(k, _)| *k != "GEMINI_URL");
+        vars.push(("GEMINI_URL", "gemini://localhost/cgi/foo?a?b%3F"));
+        assert_eq!(parse_vars(&vars).unwrap().query.as_deref(), Some("a?b%3F"));
+    }
+
+    #[test]
+    fn test_query_params() {
+        let mut request = Request::mock("/");
+        assert!(request.query_params().is_empty());
+        assert_eq!(request.query_param("page"), None);
+
+        request.query = Some("sort=name&page=1&page=2&draft&a%20b=c%3Dd".to_owned());
+        let params = request.query_params();
+        assert_eq!(params.len(), 4);
+        assert_eq!(params["page"], "2");
+        assert_eq!(params["draft"], "");
+        assert_eq!(params["a b"], "c=d");
+        assert_eq!(request.query_param("page").as_deref(), Some("2"));
+        assert_eq!(request.query_param("sort").as_deref(), Some("name"));
+        assert_eq!(request.query_param("missing"), None);
+    }
+
+    #[test]
+    fn test_query_decoded() {
+        let mut request = Request::mock("/");
+        assert_eq!(request.query_decoded().unwrap(), "");
+
+        request.query = Some("hello%20world+%E2%9C%93%3f".to_owned());
+        assert_eq!(request.query_decoded().unwrap(), "hello world+\u{2713}?");
+
+        for query in ["%", "%2", "%zz", "%+1", "%C3%28"] {
+            request.query = Some(query.to_owned());
+            let err = request.query_decoded().unwrap_err();
+            assert_eq!(err.error_type, GemErrorType::BadRequest, "{query}");
+        }
+    }
+
+    #[cfg(feature = "gemini")]
+    #[test]
+    fn test_certificate_from_der() {
+        const PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBpTCCAUugAwIBAgIUdIeI0am+EWZeQ7+Am1QxVKBEMpQwCgYIKoZIzj0EAwIw
+JzEOMAwGA1UEAwwFYWxpY2UxFTATBgNVBAoMDEdlbWZyYSBUZXN0czAgFw0yNjEw
+MTYxMDU1MjlaGA8yMTI2MDkyMjEwNTUyOVowJzEOMAwGA1UEAwwFYWxpY2UxFTAT
+BgNVBAoMDEdlbWZyYSBUZXN0czBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABBzy
+X3KrCvs5WwLgXpuyUswiIwA2jqd6ZXcYCCMBOlEuOYt6h4oiCi7Ngj6aKwnnlVHB
+2jUWWl1WtvOSb7R0JnujUzBRMB0GA1UdDgQWBBQGwmAzqsN37nnFLHJfZbUEfJN2
+gjAfBgNVHSMEGDAWgBQGwmAzqsN37nnFLHJfZbUEfJN2gjAPBgNVHRMBAf8EBTAD
+AQH/MAoGCCqGSM49BAMCA0gAMEUCIFugsY3+qTBmYuN0ZGmSRfLuiiav3PEWckId
+vkToTH1TAiEA0ukJmAu1vj2hQfSUrV6TxuNDc0H/fwaIt5s1TaAvyZY=
+-----END CERTIFICATE-----
+";
+        let (_, pem) = x509_parser::pem::parse_x509_pem(PEM.as_bytes()).unwrap();
+        let cert = Certificate::from_der(&pem.contents).unwrap();
+        assert_eq!(
+            cert.hash,
+            "7fc747241dcfb1714dc2f7a0ee4a4c55d35c3878c0c3a47a4795e6caaec2940c"
+        );
+        assert_eq!(cert.subject.get("CN").unwrap(), "alice");
+        assert_eq!(cert.issuer.get("O").unwrap(), "Gemfra Tests");
+        assert!(cert.not_before < cert.not_after);
+
+        let err = Certificate::from_der(b"not a cert").err().unwrap();
+        assert_eq!(err.error_type, GemErrorType::BadCert);
+    }
 }
@@ -9,20 +9,41 @@
 //!
 //! let response = Response::success("text/gemini", "Hello World!");
 //! ```
+//!
+//! Since most responses are gemtext, [Response::gemtext] can be used as a
+//! shorthand.
+//!
+//! ```
+//! use gemfra::response::Response;
+//!
+//! let response = Response::gemtext("Hello World!");
+//! ```
 use std::{
+    fmt::Display,
     io::{self, Read, Write},
+    path::Path,
     pin::Pin,
 };
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use bytes::{Buf, Bytes};
+use bytes::Bytes;
+
+use crate::{
+    error::{GemError, ToGemError},
+    gemtext::{Block, GemDoc},
+    request::Request,
+};
 
 enum ResponseBody {
+    Bytes(Bytes),
     Async(Pin<Box<dyn AsyncRead + Send + Sync>>),
     Sync(Box<dyn Read + Send + Sync>),
 }
 
+/// A callback that is run once the response has been sent
+type SentCallback = Box<dyn FnOnce(u64) + Send + Sync>;
+
 /// Gemini Response
 ///
 /// The gemini response has two parts: A header and a body. The header is made
@@ -32,13 +53,26 @@ enum ResponseBody {
 ///
 /// * __10__ [input][Response::input] Request for input
 /// * __11__ [input_sensitive][Response::input_sensitive] Request for sensitive input
+/// * __20__ [gemtext][Response::gemtext] Success with a `text/gemini` body
+/// * __20__ [text][Response::text] Success with a `text/plain` body
 /// * __20__ [success][Response::success] Success with a string buffer body
+/// * __20__ [success_with_charset][Response::success_with_charset] Success with a body in an explicit charset
 /// * __20__ [success_sync][Response::success_sync] Success with a synchronous stream body
 /// * __20__ [success_async][Response::success_async] Success with an asynchronous stream body
+/// * __20__ [download][Response::download] Success with a file download
+/// * __20__ [file][Response::file] Success with a file from disk
+/// * __20__ [success_stdin][Response::success_stdin] Success with stdin as the body (CGI only)
+/// * __20__ [jsonl_stream][Response::jsonl_stream] Success with a stream of JSON lines
+/// * __20__ [gemtext_lines][Response::gemtext_lines] Success with a stream of gemtext lines
+/// * __20__ [success_blocking][Response::success_blocking] Success with a body generated on a blocking thread
+/// * __20__ [broadcast][Response::broadcast] Success with the messages of a broadcast channel as they arrive
 /// * __30__ [redirect][Response::redirect] Redirect to another page
 /// * __31__ [redirect_perm][Response::redirect] Redirect to another page
+/// * __30__ [redirect_by_cert][Response::redirect_by_cert] Redirect depending on the client certificate
 /// * __40__ [error_temp][Response::error_temp] Temporary error
+/// * __40__ [error_temp_retry][Response::error_temp_retry] Temporary error with a retry hint
 /// * __41__ [unavailable][Response::unavailable] Server unavailable
+/// * __41__ [unavailable_retry][Response::unavailable_retry] Server unavailable with a retry hint
 /// * __42__ [error_cgi][Response::error_cgi] CGI error
 /// * __43__ [error_proxy][Response::error_proxy] Unable to fetch proxy
 /// * __44__ [slow_down][Response::slow_down] Too many requests
@@ -50,25 +84,67 @@ enum ResponseBody {
 /// * __60__ [cert_required][Response::cert_required] Certificate is required
 /// * __61__ [cert_not_authorised][Response::cert_not_authorised] Certificate not authorised
 /// * __62__ [cert_not_valid][Response::cert_not_valid] Certificate is invalid
+///
+/// When the status is computed at runtime, [build](Response::build) can be
+/// used instead.
 pub struct Response {
     pub code: u32,
     pub meta: String,
     body: Option<ResponseBody>,
+    content_length: Option<u64>,
+    on_sent: Option<SentCallback>,
+    flush_header: bool,
 }
 
 impl Response {
+    /// The default buffer size used by [send_sync](Response::send_sync)
+    pub const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
+
     /// Create a new resposne
     pub fn new(code: u32, meta: impl Into<String>) -> Self {
         Self {
             code,
             meta: meta.into(),
             body: None,
+            content_length: None,
+            on_sent: None,
+            flush_header: false,
         }
     }
 
+    /// Build a response with a fluent builder
+    ///
+    /// The named constructors such as [gemtext](Response::gemtext) and
+    /// [not_found](Response::not_found) should be preferred when the status is
+    /// known ahead of time. The builder is meant for when the status is
+    /// computed, and it checks the response before it is created.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::{Response, Status};
+    ///
+    /// let found = true;
+    /// let status = if found { Status::Success } else { Status::NotFound };
+    /// let mut builder = Response::build().status(status);
+    /// builder = if found {
+    ///     builder.mime("text/gemini").body("# Hello")
+    /// } else {
+    ///     builder.meta("Not found")
+    /// };
+    /// let response = builder.finish().unwrap();
+    /// assert_eq!(response.code, 20);
+    /// ```
+    pub fn build() -> ResponseBuilder {
+        ResponseBuilder::default()
+    }
+
     /// Set the body of the response with a string
-    pub fn body(self, body: impl Into<Bytes>) -> Self {
-        self.body_sync(Bytes::from(body.into()).reader())
+    pub fn body(mut self, body: impl Into<Bytes>) -> Self {
+        let body = body.into();
+        self.content_length = Some(body.len() as u64);
+        self.body = Some(ResponseBody::Bytes(body));
+        self
     }
 
     /// Set the body of the response with a synchronous reader
@@ -77,6 +153,7 @@ impl Response {
         R: Read + Send + Sync + 'static,
     {
         self.body = Some(ResponseBody::Sync(Box::new(body)));
+        self.content_length = None;
         self
     }
 
@@ -86,9 +163,99 @@ impl Response {
         R: AsyncRead + Send + Sync + 'static,
     {
         self.body = Some(ResponseBody::Async(Box::pin(body)));
+        self.content_length = None;
+        self
+    }
+
+    /// Set the body of the response with an asynchronous reader of a known
+    /// length
+    ///
+    /// The length is not sent to the client, since gemini has no way to give
+    /// it, but it is available from [len](Response::len) for instrumentation
+    /// such as logging. The reader should produce exactly `len` bytes.
+    pub fn body_sized<R>(self, body: R, len: u64) -> Self
+    where
+        R: AsyncRead + Send + Sync + 'static,
+    {
+        let mut response = self.body_async(body);
+        response.content_length = Some(len);
+        response
+    }
+
+    /// Flush the header before sending the body
+    ///
+    /// When a streamed body is generated slowly, the client might not receive
+    /// anything until the first chunk of the body is ready, and some clients
+    /// time out while waiting. Flushing the header lets the client see the
+    /// status as soon as the response is sent. This is disabled by default.
+    pub fn flush_header(mut self, enabled: bool) -> Self {
+        self.flush_header = enabled;
+        self
+    }
+
+    /// Run `callback` after the response has been sent
+    ///
+    /// The callback receives the total number of bytes that were written,
+    /// including the header. It is only run if the whole response was sent
+    /// successfully, which makes it useful for accounting or cleaning up
+    /// temporary files once they have been delivered.
+    ///
+    /// If a callback was already set, both callbacks are run in the order that
+    /// they were added.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::gemtext("# Hello").on_sent(|bytes| {
+    ///     eprintln!("Sent {bytes} bytes");
+    /// });
+    /// ```
+    pub fn on_sent<F>(mut self, callback: F) -> Self
+    where
+        F: FnOnce(u64) + Send + Sync + 'static,
+    {
+        self.on_sent = Some(match self.on_sent.take() {
+            Some(previous) => Box::new(move |bytes| {
+                previous(bytes);
+                callback(bytes);
+            }),
+            None => Box::new(callback),
+        });
         self
     }
 
+    /// Get the length of the body if it is known
+    ///
+    /// Gemini has no Content-Length header, so this is only used for
+    /// instrumentation such as logging.
+    pub fn len(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    /// Get the body if it is buffered in memory
+    ///
+    /// Streamed bodies from [body_sync](Response::body_sync) or
+    /// [body_async](Response::body_async) can only be read once, so `None` is
+    /// returned for them.
+    pub fn buffered_body(&self) -> Option<&Bytes> {
+        match &self.body {
+            Some(ResponseBody::Bytes(body)) => Some(body),
+            _ => None,
+        }
+    }
+
+    /// Check whether a body has been set
+    pub fn has_body(&self) -> bool {
+        self.body.is_some()
+    }
+
+    /// Check whether the body is known to be empty
+    pub fn is_empty(&self) -> Option<bool> {
+        self.content_length.map(|len| len == 0)
+    }
+
     /// Request for a query input (__10__)
     ///
     /// > The requested resource accepts a line of textual user input. The <META>
@@ -130,6 +297,101 @@ impl Response {
     pub fn success(mime: impl Into<String>, body: impl Into<Bytes>) -> Self {
         Self::new(20, mime).body(body)
     }
+    /// Success response with a gemtext body (__20__)
+    ///
+    /// This is the same as `Response::success("text/gemini", body)`. The body
+    /// can also be a [GemtextBuilder].
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::gemtext("# Hello World!");
+    /// assert_eq!(response.meta, "text/gemini");
+    /// ```
+    #[inline]
+    pub fn gemtext(body: impl Into<Bytes>) -> Self {
+        Self::success("text/gemini", body)
+    }
+    /// Success response with a document in the format of the path's extension (__20__)
+    ///
+    /// The extension of the last segment of `path` selects how `doc` is
+    /// rendered:
+    ///
+    /// * `.txt`: Plain text (`text/plain`), see [to_plain_text](GemDoc::to_plain_text).
+    /// * `.md`: Markdown (`text/markdown`), see [to_markdown](GemDoc::to_markdown).
+    /// * Anything else: Gemtext (`text/gemini`), see [render](GemDoc::render).
+    ///
+    /// This allows the same content to be served at `/page`, `/page.txt` and
+    /// `/page.md`.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::{gemtext::GemDoc, response::Response};
+    ///
+    /// let doc = GemDoc::parse("# Hello");
+    /// assert_eq!(Response::negotiate(&doc, "/hello").meta, "text/gemini");
+    /// assert_eq!(Response::negotiate(&doc, "/hello.md").meta, "text/markdown");
+    /// ```
+    pub fn negotiate(doc: &GemDoc, path: &str) -> Self {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let extension = name
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("txt") => Self::text(doc.to_plain_text()),
+            Some("md") => Self::success("text/markdown", doc.to_markdown()),
+            _ => Self::gemtext(doc.render()),
+        }
+    }
+    /// Success response with a plain text body (__20__)
+    ///
+    /// This is the same as `Response::success("text/plain", body)`.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::text("Hello World!");
+    /// assert_eq!(response.meta, "text/plain");
+    /// ```
+    #[inline]
+    pub fn text(body: impl Into<Bytes>) -> Self {
+        Self::success("text/plain", body)
+    }
+    /// Success response with an explicit charset (__20__)
+    ///
+    /// The meta will be `{mime}; charset={charset}`. This is useful for serving
+    /// legacy content that is not UTF-8, such as `iso-8859-1` text.
+    ///
+    /// The body is sent exactly as given and is never transcoded. The
+    /// application is responsible for encoding the body to match the declared
+    /// charset. Text transformations such as [wrap](Response::wrap) are skipped
+    /// for bodies that are not declared as UTF-8.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// // "café" encoded as iso-8859-1
+    /// let response = Response::success_with_charset("text/plain", "iso-8859-1", &b"caf\xe9"[..]);
+    /// assert_eq!(response.meta, "text/plain; charset=iso-8859-1");
+    /// ```
+    #[inline]
+    pub fn success_with_charset(
+        mime: impl AsRef<str>,
+        charset: impl AsRef<str>,
+        body: impl Into<Bytes>,
+    ) -> Self {
+        Self::success(
+            format!("{}; charset={}", mime.as_ref(), charset.as_ref()),
+            body,
+        )
+    }
     /// Success response with a synchronous read body (__20__)
     ///
     /// > The request was handled successfully and a response body will follow the
@@ -179,10 +441,439 @@ impl Response {
     pub fn success_async<M, R>(mime: M, body: R) -> Self
     where
         M: Into<String>,
-        R: AsyncReadExt + Send + Sync + 'static,
+        R: AsyncRead + Send + Sync + 'static,
     {
         Self::new(20, mime).body_async(body)
     }
+    /// Success response for a file download (__20__)
+    ///
+    /// Gemini has no way to tell a client what name to save a file as, so
+    /// clients use the last segment of the URL. `path` should be the path of the
+    /// request. If its last segment is already `filename`, the body is sent.
+    /// Otherwise a __31__ redirect is sent to the request's URL with `filename`
+    /// appended as a new segment, so the route should also handle that path.
+    ///
+    /// The redirect is relative to the last segment of the request, so it keeps
+    /// the [script](crate::request::Request::script) that the app is served
+    /// under.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::download("/files/42", "report.pdf", "application/pdf", "...");
+    /// assert_eq!(response.code, 31);
+    /// assert_eq!(response.meta, "./42/report.pdf");
+    ///
+    /// let response = Response::download("/files/42/report.pdf", "report.pdf", "application/pdf", "...");
+    /// assert_eq!(response.code, 20);
+    /// ```
+    pub fn download(
+        path: &str,
+        filename: &str,
+        mime: impl Into<String>,
+        body: impl Into<Bytes>,
+    ) -> Self {
+        // The path of a request is already decoded
+        let last = path.rsplit('/').next().unwrap_or("");
+        if last == filename {
+            return Self::success(mime, body);
+        }
+        let filename = crate::query::encode_segment(filename);
+        match last {
+            "" => Self::redirect_perm(format!("./{filename}")),
+            last => Self::redirect_perm(format!(
+                "./{}/{filename}",
+                crate::query::encode_segment(last)
+            )),
+        }
+    }
+    /// Success response with the process's stdin as the body (__20__)
+    ///
+    /// Stdin is streamed until EOF. This is useful for thin CGI wrappers that
+    /// generate content externally and pipe it to the application.
+    ///
+    /// This only makes sense for CGI, where each request is a new process. Under
+    /// SCGI, stdin is shared by every request and is not related to the client.
+    ///
+    /// To limit how much is read, use [success_async](Response::success_async)
+    /// with [take](tokio::io::AsyncReadExt::take) instead.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::success_stdin("text/gemini");
+    /// ```
+    #[cfg(feature = "cgi")]
+    #[inline]
+    pub fn success_stdin(mime: impl Into<String>) -> Self {
+        Self::success_async(mime, tokio::io::stdin())
+    }
+    /// Success response that streams items as JSON lines (__20__)
+    ///
+    /// Each item is serialized as JSON on its own line with the MIME type
+    /// `application/jsonl`. Items are only pulled from the iterator and
+    /// serialized as the body is sent, so only one item is held in memory at a
+    /// time. This makes it suitable for exporting large datasets, as long as the
+    /// iterator itself produces items lazily.
+    ///
+    /// If an item fails to serialize, the error is logged and the body ends
+    /// early.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::jsonl_stream((1..=3).map(|id| ("item", id)));
+    /// assert_eq!(response.meta, "application/jsonl");
+    /// ```
+    #[cfg(feature = "json")]
+    pub fn jsonl_stream<I>(items: I) -> Self
+    where
+        I: IntoIterator,
+        I::IntoIter: Send + Sync + 'static,
+        I::Item: serde::Serialize,
+    {
+        Self::success_sync(
+            "application/jsonl",
+            JsonLines {
+                items: items.into_iter(),
+                buf: Vec::new(),
+                pos: 0,
+                done: false,
+            },
+        )
+    }
+    /// Success response that streams gemtext lines (__20__)
+    ///
+    /// Each line is sent with a single `\n` terminator, so lines may or may
+    /// not already end with one. Lines are only pulled from the iterator as the
+    /// body is sent, so a page with thousands of links never needs to be built
+    /// as one large string like it would with [gemtext](Response::gemtext).
+    /// This only saves memory if the iterator itself produces lines lazily.
+    ///
+    /// If generating a line can fail, use
+    /// [try_gemtext_lines](Response::try_gemtext_lines).
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::gemtext_lines(
+    ///     (1..=1000).map(|page| format!("=> /page/{page} Page {page}")),
+    /// );
+    /// assert_eq!(response.meta, "text/gemini");
+    /// ```
+    pub fn gemtext_lines<I>(lines: I) -> Self
+    where
+        I: IntoIterator,
+        I::IntoIter: Send + Sync + 'static,
+        I::Item: Into<String> + 'static,
+    {
+        Self::try_gemtext_lines(lines.into_iter().map(Ok::<_, std::convert::Infallible>))
+    }
+    /// Success response that streams gemtext lines that may fail (__20__)
+    ///
+    /// This is the same as [gemtext_lines](Response::gemtext_lines), but if a
+    /// line is an error, the error is logged and the body ends early. Since
+    /// the header has already been sent, the client will see a truncated page.
+    pub fn try_gemtext_lines<I, L, E>(lines: I) -> Self
+    where
+        I: IntoIterator<Item = Result<L, E>>,
+        I::IntoIter: Send + Sync + 'static,
+        L: Into<String>,
+        E: Display,
+    {
+        Self::success_sync(
+            "text/gemini",
+            GemtextLines {
+                lines: lines.into_iter(),
+                buf: Vec::new(),
+                pos: 0,
+                done: false,
+            },
+        )
+    }
+    /// Success response with a body generated on a blocking thread (__20__)
+    ///
+    /// CPU-heavy work such as image generation or syntax highlighting should not
+    /// run on the async runtime, since it stalls every other request being
+    /// handled. The closure is run with [spawn_blocking](tokio::task::spawn_blocking)
+    /// and the returned bytes become the body.
+    ///
+    /// If the closure returns an error or panics, the error is logged and a
+    /// __42__ CGI error is returned instead.
+    ///
+    /// This is meant for work that produces the whole body at once. If the
+    /// body comes from a blocking reader, such as a file, use
+    /// [success_sync](Response::success_sync) to stream it instead.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::{error::AnyError, response::Response};
+    ///
+    /// # tokio_test::block_on(async {
+    /// let response = Response::success_blocking("text/plain", || {
+    ///     let primes: Vec<String> = (2..1000u32)
+    ///         .filter(|n| (2..*n).all(|d| n % d != 0))
+    ///         .map(|n| n.to_string())
+    ///         .collect();
+    ///     Ok::<_, AnyError>(primes.join("\n"))
+    /// })
+    /// .await;
+    /// assert_eq!(response.code, 20);
+    /// # });
+    /// ```
+    pub async fn success_blocking<M, F, B, E>(mime: M, f: F) -> Self
+    where
+        M: Into<String>,
+        F: FnOnce() -> Result<B, E> + Send + 'static,
+        B: Into<Bytes> + Send + 'static,
+        E: Display + Send + 'static,
+    {
+        match tokio::task::spawn_blocking(f).await {
+            Ok(Ok(body)) => Self::success(mime, body),
+            Ok(Err(err)) => {
+                eprintln!("Error while generating body: {err}");
+                Self::error_cgi("Internal Server Error")
+            }
+            Err(err) => {
+                eprintln!("Error while generating body: {err}");
+                Self::error_cgi("Internal Server Error")
+            }
+        }
+    }
+    /// Success response that streams the output of a command (__20__)
+    ///
+    /// The command is spawned and its stdout is sent as the body. Stdin is
+    /// closed and each line of stderr is logged. The command is killed if it
+    /// hasn't finished after `timeout`.
+    ///
+    /// The response waits until the command writes its first output or exits.
+    /// If the command can't be spawned, or exits unsuccessfully or times out
+    /// before writing any output, a __42__ CGI Error is sent. Once output has
+    /// been written, the header has already been sent, so a later failure ends
+    /// the body early with an error instead.
+    ///
+    /// > The command is run as it is given, so make sure that untrusted input,
+    /// > such as the query, is never passed as an argument without validation.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # tokio_test::block_on(async {
+    /// use std::time::Duration;
+    /// use tokio::process::Command;
+    /// use gemfra::response::Response;
+    ///
+    /// let mut command = Command::new("fortune");
+    /// command.arg("-s");
+    /// let response = Response::from_command(command, "text/plain", Duration::from_secs(5)).await;
+    /// # });
+    /// ```
+    #[cfg(feature = "process")]
+    pub async fn from_command(
+        mut command: tokio::process::Command,
+        mime: impl Into<String>,
+        timeout: std::time::Duration,
+    ) -> Self {
+        use std::process::Stdio;
+        use tokio::io::AsyncBufReadExt;
+
+        let program = command
+            .as_std()
+            .get_program()
+            .to_string_lossy()
+            .into_owned();
+        let mut child = match command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(err) => {
+                eprintln!("Could not run {program}: {err}");
+                return Self::error_cgi("Internal Server Error");
+            }
+        };
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+
+        if let Some(stderr) = child.stderr.take() {
+            let program = program.clone();
+            tokio::spawn(async move {
+                let mut lines = tokio::io::BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    eprintln!("{program}: {line}");
+                }
+            });
+        }
+
+        // Killing the command on timeout also closes stdout, which ends the body
+        let status = tokio::spawn(async move {
+            match tokio::time::timeout(timeout, child.wait()).await {
+                Ok(status) => status,
+                Err(_) => {
+                    let _ = child.kill().await;
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "the command timed out",
+                    ))
+                }
+            }
+        });
+
+        let mut buf = vec![0; 1024];
+        let read = match stdout.read(&mut buf).await {
+            Ok(read) => read,
+            Err(err) => {
+                eprintln!("Could not read the output of {program}: {err}");
+                return Self::error_cgi("Internal Server Error");
+            }
+        };
+        if read == 0 {
+            return match command_status(status).await {
+                Ok(()) => Self::success(mime, Bytes::new()),
+                Err(err) => {
+                    eprintln!("Could not run {program}: {err}");
+                    Self::error_cgi("Internal Server Error")
+                }
+            };
+        }
+        buf.truncate(read);
+
+        Self::success_async(
+            mime,
+            CommandOutput {
+                first: Bytes::from(buf),
+                stdout,
+                status: Some(Box::pin(command_status(status))),
+            },
+        )
+    }
+
+    /// Success response that streams the messages of a broadcast channel (__20__)
+    ///
+    /// Each message that is published to the channel is sent as part of the
+    /// body as soon as it arrives, which allows for live pages such as a chat
+    /// room or a feed. The body ends cleanly once `max_wait` has passed since
+    /// the response started sending, or when every sender has been dropped.
+    /// If the client falls behind and misses messages, they are skipped.
+    ///
+    /// The header is [flushed](Response::flush_header) straight away so the
+    /// client knows the page exists while it waits for messages.
+    ///
+    /// > Gemini has no way for a server to push updates, so this relies on
+    /// > the client rendering the body while it is still being received. Many
+    /// > clients do, but some wait for the whole body before showing anything,
+    /// > and others give up on connections that stay open for too long. Keep
+    /// > `max_wait` short and link to the page again at the end of the body so
+    /// > that the user can reload it.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// # tokio_test::block_on(async {
+    /// use std::time::Duration;
+    /// use tokio::sync::broadcast;
+    /// use gemfra::response::Response;
+    ///
+    /// let (sender, receiver) = broadcast::channel::<String>(16);
+    /// let response = Response::broadcast("text/gemini", receiver, Duration::from_secs(30));
+    ///
+    /// sender.send("* alice: hello\n".to_owned()).unwrap();
+    /// # });
+    /// ```
+    #[cfg(feature = "broadcast")]
+    pub fn broadcast<M, T>(
+        mime: M,
+        receiver: tokio::sync::broadcast::Receiver<T>,
+        max_wait: std::time::Duration,
+    ) -> Self
+    where
+        M: Into<String>,
+        T: Into<Bytes> + Clone + Send + Sync + 'static,
+    {
+        Self::success_async(
+            mime,
+            BroadcastBody {
+                chunk: Bytes::new(),
+                receiver: Some(receiver),
+                max_wait,
+                deadline: None,
+                next: None,
+            },
+        )
+        .flush_header(true)
+    }
+    /// Success response with an already open file as the body (__20__)
+    ///
+    /// The length of the file is looked up from its metadata so that the
+    /// [length](Response::len) of the response is known. If the metadata can't
+    /// be read, the file is still served with an unknown length.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use std::io;
+    /// use tokio::fs::File;
+    /// use gemfra::response::Response;
+    ///
+    /// # tokio_test::block_on(async {
+    /// let file = File::open("index.gmi").await?;
+    ///
+    /// let response = Response::from_async_file("text/gemini", file).await;
+    /// # Ok::<(), io::Error>(()) }).unwrap();
+    /// ```
+    pub async fn from_async_file(mime: impl Into<String>, file: tokio::fs::File) -> Self {
+        match file.metadata().await {
+            Ok(meta) => Self::new(20, mime).body_sized(file, meta.len()),
+            Err(_) => Self::new(20, mime).body_async(file),
+        }
+    }
+    /// Success response with a file from disk as the body (__20__)
+    ///
+    /// The MIME type is guessed from the file's extension with
+    /// [mime_for_extension]. A __51__ Not Found error is returned if the file
+    /// doesn't exist or is a directory, and any other error opening the file
+    /// is a __42__ CGI Error.
+    ///
+    /// > The path is opened as it is given, so make sure that paths built
+    /// > from a request can't escape the directory being served, for example
+    /// > with `..` segments.
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// use gemfra::{error::AnyError, request::Request, response::Response};
+    ///
+    /// async fn handle(request: Request) -> Result<Response, AnyError> {
+    ///     Ok(Response::file("public/index.gmi").await?)
+    /// }
+    /// ```
+    pub async fn file(path: impl AsRef<Path>) -> Result<Self, GemError> {
+        let path = path.as_ref();
+        let file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(GemError::not_found("File not found"))
+            }
+            Err(err) => return Err(err).into_gem(),
+        };
+        let meta = file.metadata().await.into_gem()?;
+        if meta.is_dir() {
+            return Err(GemError::not_found("File not found"));
+        }
+        let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        Ok(Self::new(20, mime_for_extension(extension)).body_sized(file, meta.len()))
+    }
     /// Redirect response (__30__)
     ///
     /// > The server is redirecting the client to a new location for the requested
@@ -214,6 +905,34 @@ impl Response {
     pub fn redirect_perm(redirect: impl Into<String>) -> Self {
         Self::new(31, redirect)
     }
+    /// Redirect depending on whether the client sent a certificate (__30__)
+    ///
+    /// Clients with a certificate are sent to `with_cert` and every other
+    /// client is sent to `without_cert`. This is useful for sending logged in
+    /// users to their dashboard while other users see a public page. To
+    /// require a certificate instead, use [cert_required](Response::cert_required).
+    ///
+    /// ### Example
+    ///
+    /// ```no_run
+    /// # use gemfra::request::Request;
+    /// # fn example(request: &Request) {
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::redirect_by_cert(request, "/dashboard", "/welcome");
+    /// # }
+    /// ```
+    #[inline]
+    pub fn redirect_by_cert(
+        request: &Request,
+        with_cert: impl Into<String>,
+        without_cert: impl Into<String>,
+    ) -> Self {
+        match request.client_cert {
+            Some(_) => Self::redirect(with_cert),
+            None => Self::redirect(without_cert),
+        }
+    }
     /// Temporary error response (__40__)
     ///
     /// > The request has failed. There is no response body. The nature of the
@@ -231,11 +950,33 @@ impl Response {
     pub fn unavailable(message: impl Into<String>) -> Self {
         Self::new(41, message)
     }
-    /// CGI error response (__42__)
+    /// Temporary error response with a retry hint (__40__)
     ///
-    /// > A CGI process, or similar system for generating dynamic content, died
-    /// > unexpectedly or timed out.
-    #[inline]
+    /// The hint is added to the message, so `("Database is busy", 30)` sends
+    /// `40 Database is busy, retry in 30s`. Longer waits are rounded up to
+    /// minutes or hours.
+    ///
+    /// > The hint is only advisory and meant for people. Unlike the seconds of
+    /// > [slow_down](Response::slow_down), clients do not parse it.
+    pub fn error_temp_retry(message: impl Display, seconds: u32) -> Self {
+        Self::error_temp(format!("{message}, retry in {}", retry_hint(seconds)))
+    }
+    /// Unavailable response with a retry hint (__41__)
+    ///
+    /// This is useful during maintenance windows. The hint is added to the
+    /// message, so `("Down for maintenance", 600)` sends
+    /// `41 Down for maintenance, retry in 10m`.
+    ///
+    /// > The hint is only advisory and meant for people. Unlike the seconds of
+    /// > [slow_down](Response::slow_down), clients do not parse it.
+    pub fn unavailable_retry(message: impl Display, seconds: u32) -> Self {
+        Self::unavailable(format!("{message}, retry in {}", retry_hint(seconds)))
+    }
+    /// CGI error response (__42__)
+    ///
+    /// > A CGI process, or similar system for generating dynamic content, died
+    /// > unexpectedly or timed out.
+    #[inline]
     pub fn error_cgi(message: impl Into<String>) -> Self {
         Self::new(42, message)
     }
@@ -317,6 +1058,15 @@ impl Response {
     pub fn cert_required(message: impl Into<String>) -> Self {
         Self::new(60, message)
     }
+    /// Cert Required response (__60__) for a specific identity
+    ///
+    /// The meta names the common name of the certificate that is needed. See
+    /// [Request::require_cn](crate::request::Request::require_cn) for a guard
+    /// that checks the certificate.
+    #[inline]
+    pub fn cert_required_cn(expected_cn: &str) -> Self {
+        Self::cert_required(crate::request::cert_required_cn_message(expected_cn))
+    }
     /// Cert Not Authorised response (__61__)
     ///
     /// > The supplied client certificate is not authorised for accessing the
@@ -340,23 +1090,295 @@ impl Response {
         Self::new(62, message)
     }
 
+    /// Make sure that the body of a text response ends with a single newline
+    ///
+    /// This only applies to success responses with a `text/*` MIME type whose
+    /// body was set with a buffer (e.g. [success](Response::success)) and is
+    /// valid UTF-8. See [gemtext::trailing_newline](crate::gemtext::trailing_newline)
+    /// for the exact rules.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::gemtext("# Hello World!").trailing_newline();
+    /// ```
+    pub fn trailing_newline(self) -> Self {
+        let is_text = self
+            .meta
+            .get(..5)
+            .is_some_and(|mime| mime.eq_ignore_ascii_case("text/"));
+        if !is_text {
+            return self;
+        }
+        self.map_buffered_text(crate::gemtext::trailing_newline)
+    }
+
+    /// Parse the MIME type of a success (__2X__) response
+    ///
+    /// Other responses don't have a MIME type in their meta, so `None` is
+    /// returned for them.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::success("text/gemini; lang=en", "");
+    /// let mime = response.mime().unwrap();
+    /// assert_eq!(mime.essence(), "text/gemini");
+    /// assert_eq!(mime.param("lang"), Some("en"));
+    /// ```
+    pub fn mime(&self) -> Option<ParsedMime> {
+        if (20..30).contains(&self.code) {
+            Some(ParsedMime::parse(&self.meta))
+        } else {
+            None
+        }
+    }
+
+    /// Replace the meta with a serialized MIME type
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// let mut response = Response::success("text/gemini", "");
+    /// let mime = response.mime().unwrap().with_param("lang", "fr");
+    /// response.set_mime(mime);
+    /// assert_eq!(response.meta, "text/gemini; lang=fr");
+    /// ```
+    pub fn set_mime(&mut self, mime: ParsedMime) {
+        self.meta = mime.to_string();
+    }
+
+    /// Check whether the meta declares a UTF-8 body
+    ///
+    /// Gemini defaults to UTF-8 when no charset is given.
+    fn is_utf8(&self) -> bool {
+        ParsedMime::parse(&self.meta)
+            .param("charset")
+            .is_none_or(|value| {
+                value.eq_ignore_ascii_case("utf-8") || value.eq_ignore_ascii_case("utf8")
+            })
+    }
+
+    /// Replace a buffered UTF-8 body of a success response using `f`
+    fn map_buffered_text<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&str) -> String,
+    {
+        if !(20..30).contains(&self.code) || !self.is_utf8() {
+            return self;
+        }
+        if let Some(ResponseBody::Bytes(body)) = &self.body {
+            if let Ok(text) = std::str::from_utf8(body) {
+                let body = Bytes::from(f(text));
+                self.content_length = Some(body.len() as u64);
+                self.body = Some(ResponseBody::Bytes(body));
+            }
+        }
+        self
+    }
+
+    /// Create a response from an HTTP status code
+    ///
+    /// This is intended for gateways that bridge HTTP backends to gemini. The
+    /// status is mapped to the closest gemini status:
+    ///
+    /// | HTTP                  | Gemini                            |
+    /// |-----------------------|-----------------------------------|
+    /// | 2XX                   | __20__ Success with `mime`/`body` |
+    /// | 301, 308              | __31__ Permanent redirect         |
+    /// | other 3XX             | __30__ Redirect                   |
+    /// | 400                   | __59__ Bad request                |
+    /// | 401                   | __60__ Certificate required       |
+    /// | 403                   | __61__ Certificate not authorised |
+    /// | 404                   | __51__ Not found                  |
+    /// | 410                   | __52__ Gone                       |
+    /// | 429                   | __44__ Slow down                  |
+    /// | other 4XX             | __50__ Permanent error            |
+    /// | 500                   | __42__ CGI error                  |
+    /// | 502, 504              | __43__ Proxy error                |
+    /// | 503                   | __41__ Unavailable                |
+    /// | other 5XX             | __40__ Temporary error            |
+    /// | anything else         | __42__ CGI error                  |
+    ///
+    /// For redirects, `body` is the location to redirect to. A redirect without
+    /// a location is a broken backend, so it becomes a __50__ Permanent error.
+    /// For __44__, `body` is the number of seconds to wait (like a
+    /// `Retry-After` header), defaulting to 10 seconds if it can't be parsed.
+    /// For every other non-success status, the body is discarded and a generic
+    /// message is used as the meta.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::from_http_status(404, "<h1>Not Found</h1>", "text/html");
+    /// assert_eq!(response.code, 51);
+    /// ```
+    pub fn from_http_status(status: u16, body: impl Into<Bytes>, mime: impl Into<String>) -> Self {
+        let body: Bytes = body.into();
+        let body_str = || String::from_utf8_lossy(&body).trim().to_owned();
+        match status {
+            200..=299 => Self::success(mime, body),
+            300..=399 => match (status, body_str()) {
+                (_, location) if location.is_empty() => {
+                    Self::error_perm("Redirect without a location")
+                }
+                (301 | 308, location) => Self::redirect_perm(location),
+                (_, location) => Self::redirect(location),
+            },
+            400..=499 => match status {
+                400 => Self::bad_request("Bad Request"),
+                401 => Self::cert_required("Certificate needed"),
+                403 => Self::cert_not_authorised("Forbidden"),
+                404 => Self::not_found("File not found"),
+                410 => Self::gone("File no longer exists"),
+                429 => Self::slow_down(body_str().parse().unwrap_or(10)),
+                _ => Self::error_perm("Permanent Error"),
+            },
+            500..=599 => match status {
+                500 => Self::error_cgi("Internal Server Error"),
+                502 | 504 => Self::error_proxy("Proxy Error"),
+                503 => Self::unavailable("Server Unavailable"),
+                _ => Self::error_temp("Temporary Error"),
+            },
+            _ => Self::error_cgi("Internal Server Error"),
+        }
+    }
+
+    /// Soft-wrap the body of a `text/gemini` response at `width` columns
+    ///
+    /// This only applies to success responses whose body was set with a
+    /// buffer (e.g. [success](Response::success)). Streamed bodies and bodies
+    /// that are not valid UTF-8 are left untouched. See
+    /// [gemtext::wrap](crate::gemtext::wrap) for the wrapping rules.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::success("text/gemini", "A very long line of text").wrap(80);
+    /// ```
+    pub fn wrap(self, width: usize) -> Self {
+        let mime = self.meta.split(';').next().unwrap_or("").trim();
+        if !mime.eq_ignore_ascii_case("text/gemini") {
+            return self;
+        }
+        self.map_buffered_text(|text| crate::gemtext::wrap(text, width))
+    }
+
     /// Get the full header for this response
     pub fn header(&self) -> String {
-        let meta = self.meta.lines().next().unwrap();
+        let meta = self.meta.lines().next().unwrap_or("");
         format!("{} {}\r\n", self.code, meta)
     }
 
+    /// Transform the body with `filter` as it is sent
+    ///
+    /// The filter is called with each chunk of the body and returns the chunk
+    /// to send in its place. Once the body has ended, the filter is called one
+    /// last time with an empty chunk, so that any data it has held back can be
+    /// sent.
+    ///
+    /// Chunks are split wherever the underlying reader happens to split them,
+    /// so they don't line up with lines or even UTF-8 characters. Filters that
+    /// work on lines must buffer the incomplete end of each chunk until the
+    /// rest arrives.
+    ///
+    /// The length of a streamed body is no longer known after it is mapped.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use bytes::Bytes;
+    /// use gemfra::response::Response;
+    ///
+    /// let response = Response::text("hello").map_body(|chunk: Bytes| {
+    ///     Bytes::from(chunk.to_ascii_uppercase())
+    /// });
+    /// ```
+    pub fn map_body<F>(mut self, mut filter: F) -> Self
+    where
+        F: FnMut(Bytes) -> Bytes + Send + Sync + 'static,
+    {
+        self.body = match self.body.take() {
+            Some(ResponseBody::Bytes(body)) => {
+                let mut mapped = filter(body).to_vec();
+                mapped.extend_from_slice(&filter(Bytes::new()));
+                self.content_length = Some(mapped.len() as u64);
+                Some(ResponseBody::Bytes(mapped.into()))
+            }
+            Some(ResponseBody::Async(reader)) => {
+                self.content_length = None;
+                Some(ResponseBody::Async(Box::pin(MapBody::new(reader, filter))))
+            }
+            Some(ResponseBody::Sync(reader)) => {
+                self.content_length = None;
+                Some(ResponseBody::Sync(Box::new(MapBody::new(reader, filter))))
+            }
+            None => None,
+        };
+        self
+    }
+
+    /// Read and discard the body of the response
+    ///
+    /// The number of body bytes that were read is returned. This can be used
+    /// when only the header of a response is needed, or when measuring how
+    /// long it takes to generate a body.
+    pub async fn drain(mut self) -> Result<u64, io::Error> {
+        match self.body.take() {
+            Some(ResponseBody::Bytes(body)) => Ok(body.len() as u64),
+            Some(ResponseBody::Async(mut reader)) => {
+                tokio::io::copy(&mut reader, &mut tokio::io::sink()).await
+            }
+            Some(ResponseBody::Sync(mut reader)) => io::copy(&mut reader, &mut io::sink()),
+            None => Ok(0),
+        }
+    }
+
     /// Send the response to an async stream
-    pub async fn send_async<W>(self, writer: &mut W) -> Result<(), io::Error>
+    ///
+    /// Returns the number of bytes that were written, including the header.
+    pub async fn send_async<W>(self, writer: &mut W) -> Result<u64, io::Error>
     where
         W: AsyncWrite + Unpin + ?Sized,
     {
         let header = self.header();
+        self.send_async_with_header(&header, writer).await
+    }
+
+    /// Send the response to an async stream with a custom header
+    ///
+    /// This is used by protocols whose header differs from gemini.
+    pub(crate) async fn send_async_with_header<W>(
+        self,
+        header: &str,
+        writer: &mut W,
+    ) -> Result<u64, io::Error>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
         writer.write_all(header.as_bytes()).await?;
+        if self.flush_header {
+            writer.flush().await?;
+        }
+        let mut written = header.len() as u64;
 
         match self.body {
+            Some(ResponseBody::Bytes(body)) => {
+                writer.write_all(&body).await?;
+                written += body.len() as u64;
+            }
             Some(ResponseBody::Async(mut reader)) => {
-                tokio::io::copy(&mut reader, writer).await?;
+                written += tokio::io::copy(&mut reader, writer).await?;
             }
             Some(ResponseBody::Sync(mut reader)) => {
                 let mut buf = [0; 1024];
@@ -367,25 +1389,58 @@ impl Response {
                         break;
                     }
                     writer.write_all(&buf[..read]).await?;
+                    written += read as u64;
                 }
             }
             None => {}
         }
 
-        Ok(())
+        if let Some(callback) = self.on_sent {
+            callback(written);
+        }
+
+        Ok(written)
     }
 
     /// Send the response to a sync stream
-    pub async fn send_sync<W>(self, writer: &mut W) -> Result<(), io::Error>
+    ///
+    /// Async bodies are copied with a buffer of
+    /// [DEFAULT_BUFFER_SIZE](Response::DEFAULT_BUFFER_SIZE) bytes. Returns the
+    /// number of bytes that were written, including the header.
+    pub async fn send_sync<W>(self, writer: &mut W) -> Result<u64, io::Error>
+    where
+        W: Write + ?Sized,
+    {
+        self.send_sync_buffered(writer, Self::DEFAULT_BUFFER_SIZE)
+            .await
+    }
+
+    /// Send the response to a sync stream, copying async bodies with a
+    /// buffer of `buffer_size` bytes
+    ///
+    /// Larger buffers mean fewer writes for large bodies.
+    pub async fn send_sync_buffered<W>(
+        self,
+        writer: &mut W,
+        buffer_size: usize,
+    ) -> Result<u64, io::Error>
     where
         W: Write + ?Sized,
     {
         let header = self.header();
         writer.write_all(header.as_bytes())?;
+        if self.flush_header {
+            writer.flush()?;
+        }
+        let mut written = header.len() as u64;
 
         match self.body {
+            Some(ResponseBody::Bytes(body)) => {
+                writer.write_all(&body)?;
+                written += body.len() as u64;
+            }
             Some(ResponseBody::Async(mut reader)) => {
-                let mut buf = [0; 1024];
+                let mut buf = vec![0; buffer_size.max(1)];
 
                 loop {
                     let read = reader.read(&mut buf).await?;
@@ -393,14 +1448,1443 @@ impl Response {
                         break;
                     }
                     writer.write_all(&buf[..read])?;
+                    written += read as u64;
                 }
             }
             Some(ResponseBody::Sync(mut reader)) => {
-                io::copy(&mut reader, writer)?;
+                written += io::copy(&mut reader, writer)?;
             }
             None => {}
         };
 
+        if let Some(callback) = self.on_sent {
+            callback(written);
+        }
+
+        Ok(written)
+    }
+}
+
+/// Format a number of seconds as a short retry hint, such as `30s` or `5m`
+fn retry_hint(seconds: u32) -> String {
+    match seconds {
+        0..=59 => format!("{seconds}s"),
+        60..=3599 => format!("{}m", seconds.div_ceil(60)),
+        _ => format!("{}h", seconds.div_ceil(3600)),
+    }
+}
+
+/// A predicate that selects a variant
+type VariantPredicate<'a> = Box<dyn Fn(&Request) -> bool + 'a>;
+/// A builder for the response of a variant
+type VariantBuilder<'a> = Box<dyn FnOnce() -> Response + 'a>;
+
+/// Select between alternate responses based on the request
+///
+/// Each variant has a predicate over the [Request], such as the client
+/// certificate or the remote address. The first variant whose predicate matches
+/// is built. If none match, the default is built instead. Only the selected
+/// response is ever built.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::response::{Response, Variants};
+/// # fn example(request: &gemfra::request::Request) -> Response {
+///
+/// Variants::new(|| Response::gemtext("# Full page"))
+///     .variant(
+///         |request| request.remote_addr.starts_with("10."),
+///         || Response::gemtext("# Internal page"),
+///     )
+///     .select(request)
+/// # }
+/// ```
+pub struct Variants<'a> {
+    variants: Vec<(VariantPredicate<'a>, VariantBuilder<'a>)>,
+    default: VariantBuilder<'a>,
+}
+
+impl<'a> Variants<'a> {
+    /// Create a set of variants with the default response
+    pub fn new<F>(default: F) -> Self
+    where
+        F: FnOnce() -> Response + 'a,
+    {
+        Self {
+            variants: Vec::new(),
+            default: Box::new(default),
+        }
+    }
+
+    /// Add a variant that is used when `predicate` matches
+    ///
+    /// Variants are checked in the order they are added.
+    pub fn variant<P, F>(mut self, predicate: P, build: F) -> Self
+    where
+        P: Fn(&Request) -> bool + 'a,
+        F: FnOnce() -> Response + 'a,
+    {
+        self.variants.push((Box::new(predicate), Box::new(build)));
+        self
+    }
+
+    /// Build the response of the first matching variant
+    pub fn select(self, request: &Request) -> Response {
+        for (predicate, build) in self.variants {
+            if predicate(request) {
+                return build();
+            }
+        }
+        (self.default)()
+    }
+}
+
+/// The result of a command that must finish successfully
+#[cfg(feature = "process")]
+type CommandStatus = Pin<Box<dyn std::future::Future<Output = io::Result<()>> + Send + Sync>>;
+
+/// Wait for a command to finish, failing if it was unsuccessful
+#[cfg(feature = "process")]
+async fn command_status(
+    status: tokio::task::JoinHandle<io::Result<std::process::ExitStatus>>,
+) -> io::Result<()> {
+    match status.await {
+        Ok(Ok(status)) if status.success() => Ok(()),
+        Ok(Ok(status)) => Err(io::Error::other(format!(
+            "the command exited with {status}"
+        ))),
+        Ok(Err(err)) => Err(err),
+        Err(err) => Err(io::Error::other(err)),
+    }
+}
+
+/// The next message of a broadcast, along with the receiver to wait for the
+/// message after it
+#[cfg(feature = "broadcast")]
+type NextMessage<T> = Pin<
+    Box<
+        dyn std::future::Future<Output = Option<(Bytes, tokio::sync::broadcast::Receiver<T>)>>
+            + Send
+            + Sync,
+    >,
+>;
+
+/// Wait for the next message of a broadcast, giving up at `deadline`
+#[cfg(feature = "broadcast")]
+async fn receive_until<T>(
+    mut receiver: tokio::sync::broadcast::Receiver<T>,
+    deadline: tokio::time::Instant,
+) -> Option<(Bytes, tokio::sync::broadcast::Receiver<T>)>
+where
+    T: Into<Bytes> + Clone,
+{
+    use tokio::sync::broadcast::error::RecvError;
+
+    loop {
+        match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            Ok(Ok(message)) => return Some((message.into(), receiver)),
+            Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) | Err(_) => return None,
+        }
+    }
+}
+
+/// A body that sends the messages of a broadcast as they arrive
+///
+/// The deadline is set when the body is first read, which is when the
+/// response starts sending.
+#[cfg(feature = "broadcast")]
+struct BroadcastBody<T> {
+    chunk: Bytes,
+    receiver: Option<tokio::sync::broadcast::Receiver<T>>,
+    max_wait: std::time::Duration,
+    deadline: Option<tokio::time::Instant>,
+    next: Option<NextMessage<T>>,
+}
+
+#[cfg(feature = "broadcast")]
+impl<T> AsyncRead for BroadcastBody<T>
+where
+    T: Into<Bytes> + Clone + Send + Sync + 'static,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        use std::task::Poll;
+
+        let this = &mut *self;
+        loop {
+            if !this.chunk.is_empty() {
+                let len = buf.remaining().min(this.chunk.len());
+                let chunk = this.chunk.split_to(len);
+                buf.put_slice(&chunk);
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.next.is_none() {
+                let receiver = match this.receiver.take() {
+                    Some(receiver) => receiver,
+                    None => return Poll::Ready(Ok(())),
+                };
+                let deadline = *this
+                    .deadline
+                    .get_or_insert_with(|| tokio::time::Instant::now() + this.max_wait);
+                this.next = Some(Box::pin(receive_until(receiver, deadline)));
+            }
+            let next = this.next.as_mut().expect("the next message is set");
+            let message = std::task::ready!(next.as_mut().poll(cx));
+            this.next = None;
+            if let Some((chunk, receiver)) = message {
+                this.chunk = chunk;
+                this.receiver = Some(receiver);
+            }
+        }
+    }
+}
+
+/// The stdout of a command, which fails at the end if the command failed
+#[cfg(feature = "process")]
+struct CommandOutput {
+    first: Bytes,
+    stdout: tokio::process::ChildStdout,
+    status: Option<CommandStatus>,
+}
+
+#[cfg(feature = "process")]
+impl AsyncRead for CommandOutput {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        use std::task::Poll;
+
+        if !self.first.is_empty() {
+            let len = buf.remaining().min(self.first.len());
+            let chunk = self.first.split_to(len);
+            buf.put_slice(&chunk);
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.stdout).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == filled && buf.remaining() > 0 => {}
+            poll => return poll,
+        }
+
+        // The output has ended, so make sure the command succeeded
+        match &mut self.status {
+            Some(status) => {
+                let result = std::task::ready!(status.as_mut().poll(cx));
+                self.status = None;
+                Poll::Ready(result)
+            }
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// A reader that transforms each chunk of another reader
+struct MapBody<R, F> {
+    reader: R,
+    filter: F,
+    buf: Vec<u8>,
+    out: Bytes,
+    done: bool,
+}
+
+impl<R, F> MapBody<R, F>
+where
+    F: FnMut(Bytes) -> Bytes,
+{
+    fn new(reader: R, filter: F) -> Self {
+        Self {
+            reader,
+            filter,
+            buf: vec![0; 8 * 1024],
+            out: Bytes::new(),
+            done: false,
+        }
+    }
+
+    /// Copy as much of the filtered output as possible into `out`
+    fn take_output(&mut self, out: &mut [u8]) -> usize {
+        let len = out.len().min(self.out.len());
+        out[..len].copy_from_slice(&self.out.split_to(len));
+        len
+    }
+
+    /// Filter the next chunk that was read, where an empty chunk is the end
+    fn filter(&mut self, read: usize) {
+        if read == 0 {
+            self.done = true;
+        }
+        self.out = (self.filter)(Bytes::copy_from_slice(&self.buf[..read]));
+    }
+}
+
+impl<R, F> Read for MapBody<R, F>
+where
+    R: Read,
+    F: FnMut(Bytes) -> Bytes,
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.out.is_empty() || out.is_empty() {
+                return Ok(self.take_output(out));
+            }
+            if self.done {
+                return Ok(0);
+            }
+            let read = self.reader.read(&mut self.buf)?;
+            self.filter(read);
+        }
+    }
+}
+
+// The filter is never pinned, so it doesn't need to be Unpin
+impl<R: Unpin, F> Unpin for MapBody<R, F> {}
+
+impl<R, F> AsyncRead for MapBody<R, F>
+where
+    R: AsyncRead + Unpin,
+    F: FnMut(Bytes) -> Bytes,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if !this.out.is_empty() || buf.remaining() == 0 {
+                let len = buf.remaining().min(this.out.len());
+                buf.put_slice(&this.out.split_to(len));
+                return std::task::Poll::Ready(Ok(()));
+            }
+            if this.done {
+                return std::task::Poll::Ready(Ok(()));
+            }
+            let mut read_buf = tokio::io::ReadBuf::new(&mut this.buf);
+            std::task::ready!(Pin::new(&mut this.reader).poll_read(cx, &mut read_buf))?;
+            let read = read_buf.filled().len();
+            this.filter(read);
+        }
+    }
+}
+
+/// A lazy reader that serializes each item of an iterator as a JSON line
+#[cfg(feature = "json")]
+struct JsonLines<I> {
+    items: I,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+#[cfg(feature = "json")]
+impl<I> Read for JsonLines<I>
+where
+    I: Iterator,
+    I::Item: serde::Serialize,
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.buf.len() {
+                let len = out.len().min(self.buf.len() - self.pos);
+                out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+                self.pos += len;
+                return Ok(len);
+            }
+            if self.done {
+                return Ok(0);
+            }
+
+            self.buf.clear();
+            self.pos = 0;
+            match self.items.next() {
+                Some(item) => match serde_json::to_writer(&mut self.buf, &item) {
+                    Ok(()) => self.buf.push(b'\n'),
+                    Err(err) => {
+                        eprintln!("Could not serialize item: {err}");
+                        self.buf.clear();
+                        self.done = true;
+                    }
+                },
+                None => self.done = true,
+            }
+        }
+    }
+}
+
+/// A lazy reader that sends each item of an iterator as a gemtext line
+struct GemtextLines<I> {
+    lines: I,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<I, L, E> Read for GemtextLines<I>
+where
+    I: Iterator<Item = Result<L, E>>,
+    L: Into<String>,
+    E: Display,
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.buf.len() {
+                let len = out.len().min(self.buf.len() - self.pos);
+                out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+                self.pos += len;
+                return Ok(len);
+            }
+            if self.done {
+                return Ok(0);
+            }
+
+            self.pos = 0;
+            match self.lines.next() {
+                Some(Ok(line)) => {
+                    let mut line: String = line.into();
+                    let len = line.trim_end_matches(['\r', '\n']).len();
+                    line.truncate(len);
+                    line.push('\n');
+                    self.buf = line.into_bytes();
+                }
+                Some(Err(err)) => {
+                    eprintln!("Could not generate line: {err}");
+                    self.buf.clear();
+                    self.done = true;
+                }
+                None => {
+                    self.buf.clear();
+                    self.done = true;
+                }
+            }
+        }
+    }
+}
+
+/// A gemini status code
+///
+/// Use [code](Status::code) to get the numeric code, or
+/// [from_code](Status::from_code) to go the other way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Status {
+    /// __10__ Input
+    Input,
+    /// __11__ Sensitive Input
+    SensitiveInput,
+    /// __20__ Success
+    Success,
+    /// __30__ Temporary Redirect
+    Redirect,
+    /// __31__ Permanent Redirect
+    RedirectPermanent,
+    /// __40__ Temporary Failure
+    TempError,
+    /// __41__ Server Unavailable
+    Unavailable,
+    /// __42__ CGI Error
+    CgiError,
+    /// __43__ Proxy Error
+    ProxyError,
+    /// __44__ Slow Down
+    SlowDown,
+    /// __50__ Permanent Failure
+    PermError,
+    /// __51__ Not Found
+    NotFound,
+    /// __52__ Gone
+    Gone,
+    /// __53__ Proxy Request Refused
+    ProxyRefused,
+    /// __59__ Bad Request
+    BadRequest,
+    /// __60__ Client Certificate Required
+    CertRequired,
+    /// __61__ Certificate Not Authorised
+    CertNotAuthorised,
+    /// __62__ Certificate Not Valid
+    CertNotValid,
+}
+
+impl Status {
+    /// All of the status codes
+    const ALL: [Status; 18] = [
+        Status::Input,
+        Status::SensitiveInput,
+        Status::Success,
+        Status::Redirect,
+        Status::RedirectPermanent,
+        Status::TempError,
+        Status::Unavailable,
+        Status::CgiError,
+        Status::ProxyError,
+        Status::SlowDown,
+        Status::PermError,
+        Status::NotFound,
+        Status::Gone,
+        Status::ProxyRefused,
+        Status::BadRequest,
+        Status::CertRequired,
+        Status::CertNotAuthorised,
+        Status::CertNotValid,
+    ];
+
+    /// Get the numeric response code
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::response::Status;
+    ///
+    /// assert_eq!(Status::NotFound.code(), 51);
+    /// ```
+    pub fn code(&self) -> u32 {
+        match self {
+            Status::Input => 10,
+            Status::SensitiveInput => 11,
+            Status::Success => 20,
+            Status::Redirect => 30,
+            Status::RedirectPermanent => 31,
+            Status::TempError => 40,
+            Status::Unavailable => 41,
+            Status::CgiError => 42,
+            Status::ProxyError => 43,
+            Status::SlowDown => 44,
+            Status::PermError => 50,
+            Status::NotFound => 51,
+            Status::Gone => 52,
+            Status::ProxyRefused => 53,
+            Status::BadRequest => 59,
+            Status::CertRequired => 60,
+            Status::CertNotAuthorised => 61,
+            Status::CertNotValid => 62,
+        }
+    }
+
+    /// Get the status of a numeric response code
+    ///
+    /// Returns `None` for codes that gemini does not define.
+    pub fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|status| status.code() == code)
+    }
+
+    /// Check whether the status is a 2X success, which is the only status
+    /// that may have a body
+    pub fn is_success(&self) -> bool {
+        self.code() / 10 == 2
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+/// A redirect to another URL
+///
+/// The URL is sent to the client verbatim, as the spec requires. It may be
+/// absolute, such as `gemini://example.com/page`, or relative to the URL of
+/// the request, such as `../page`.
+///
+/// A Redirect converts into a [Response], so routes using the
+/// [route](macro@crate::routed::route) macro can return it directly.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::response::{Redirect, Response};
+///
+/// let response = Response::from(Redirect::permanent("/new"));
+/// assert_eq!((response.code, response.meta.as_str()), (31, "/new"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    url: String,
+    permanent: bool,
+}
+
+impl Redirect {
+    /// A temporary redirect (__30__) to `url`
+    pub fn to(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            permanent: false,
+        }
+    }
+
+    /// A permanent redirect (__31__) to `url`
+    pub fn permanent(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            permanent: true,
+        }
+    }
+
+    /// Get the URL that is redirected to
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Check whether the redirect is permanent
+    pub fn is_permanent(&self) -> bool {
+        self.permanent
+    }
+}
+
+impl From<Redirect> for Response {
+    fn from(redirect: Redirect) -> Self {
+        if redirect.permanent {
+            Response::redirect_perm(redirect.url)
+        } else {
+            Response::redirect(redirect.url)
+        }
+    }
+}
+
+/// A fluent builder for a [Response]
+///
+/// This is created with [Response::build]. The response is checked when it is
+/// [finished](ResponseBuilder::finish) rather than as each part is set, so
+/// the parts may be given in any order.
+#[derive(Debug, Default)]
+pub struct ResponseBuilder {
+    status: Option<Status>,
+    meta: String,
+    body: Option<Bytes>,
+}
+
+impl ResponseBuilder {
+    /// The maximum length of the meta in bytes
+    pub const MAX_META_LEN: usize = 1024;
+
+    /// Set the status of the response
+    pub fn status(mut self, status: Status) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Set the meta of the response
+    pub fn meta(mut self, meta: impl Into<String>) -> Self {
+        self.meta = meta.into();
+        self
+    }
+
+    /// Set the MIME type of a success response
+    ///
+    /// This is the same as [meta](ResponseBuilder::meta), but reads better
+    /// for success responses.
+    pub fn mime(self, mime: impl Into<String>) -> Self {
+        self.meta(mime)
+    }
+
+    /// Set the body of the response
+    ///
+    /// Only success responses may have a body.
+    pub fn body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Check and create the response
+    ///
+    /// This fails with a __42__ CGI error if:
+    ///
+    /// * No status was set
+    /// * The meta is empty
+    /// * The meta is longer than [MAX_META_LEN](ResponseBuilder::MAX_META_LEN)
+    ///   bytes or contains a line break
+    /// * A body was set for a status other than success
+    pub fn finish(self) -> Result<Response, GemError> {
+        let status = self
+            .status
+            .ok_or_else(|| GemError::runtime_error("The response has no status"))?;
+        if self.meta.is_empty() {
+            return Err(GemError::runtime_error("The response has no meta"));
+        }
+        if self.meta.len() > Self::MAX_META_LEN {
+            return Err(GemError::runtime_error(format!(
+                "The response meta is longer than {} bytes",
+                Self::MAX_META_LEN
+            )));
+        }
+        if self.meta.contains(['\r', '\n']) {
+            return Err(GemError::runtime_error(
+                "The response meta contains a line break",
+            ));
+        }
+        let response = Response::new(status.code(), self.meta);
+        match self.body {
+            Some(_) if !status.is_success() => Err(GemError::runtime_error(format!(
+                "A {status} response can not have a body"
+            ))),
+            Some(body) => Ok(response.body(body)),
+            None => Ok(response),
+        }
+    }
+}
+
+/// A builder for a gemtext body
+///
+/// Each method adds one kind of line, so the body can be written without
+/// worrying about how gemtext is formatted. The built body is always valid
+/// gemtext, see [GemDoc] for how each line is guarded:
+///
+/// * Headings are clamped to levels 1 to 3.
+/// * Line breaks in links, headings, and list items are replaced with
+///   spaces, and whitespace in link URLs is percent-encoded, so a link is
+///   always a single line.
+/// * Preformatted blocks get their own fence lines, and their content can't
+///   close the block early.
+/// * Text that would be read as another line type is escaped.
+///
+/// The builder can be given straight to [Response::gemtext].
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::response::{GemtextBuilder, Response};
+///
+/// let mut page = GemtextBuilder::new();
+/// page.heading(1, "My Capsule")
+///     .text("Welcome!")
+///     .link("/about", Some("About me"))
+///     .preformatted("rust", "fn main() {}");
+/// let response = Response::gemtext(page);
+/// assert_eq!(response.meta, "text/gemini");
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct GemtextBuilder {
+    doc: GemDoc,
+}
+
+impl GemtextBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, block: Block) -> &mut Self {
+        self.doc.blocks.push(block);
+        self
+    }
+
+    /// Add a heading, where `level` is clamped to 1 to 3
+    pub fn heading(&mut self, level: u8, text: impl Into<String>) -> &mut Self {
+        self.push(Block::Heading {
+            level: level.clamp(1, 3),
+            text: text.into(),
+        })
+    }
+
+    /// Add a line of text
+    ///
+    /// Each line of `line` is added as its own text line. An empty line adds
+    /// a blank line.
+    pub fn text(&mut self, line: impl Into<String>) -> &mut Self {
+        self.push(Block::Text(line.into()))
+    }
+
+    /// Add a link with an optional label
+    pub fn link(&mut self, url: impl Into<String>, label: Option<&str>) -> &mut Self {
+        self.push(Block::Link {
+            url: url.into(),
+            label: label.map(str::to_owned),
+        })
+    }
+
+    /// Add a list item
+    ///
+    /// Consecutive items are part of the same list.
+    pub fn list_item(&mut self, text: impl Into<String>) -> &mut Self {
+        let text = text.into();
+        match self.doc.blocks.last_mut() {
+            Some(Block::List(items)) => {
+                items.push(text);
+                self
+            }
+            _ => self.push(Block::List(vec![text])),
+        }
+    }
+
+    /// Add a quote, where each line of `text` is a quote line
+    pub fn quote(&mut self, text: impl Into<String>) -> &mut Self {
+        self.push(Block::Quote(text.into()))
+    }
+
+    /// Add a preformatted block with alt text
+    pub fn preformatted(&mut self, alt: impl Into<String>, block: impl Into<String>) -> &mut Self {
+        self.push(Block::Preformatted {
+            alt: alt.into(),
+            content: block.into(),
+        })
+    }
+
+    /// Build the gemtext body
+    pub fn build(self) -> String {
+        self.doc.render()
+    }
+}
+
+impl From<GemtextBuilder> for Bytes {
+    fn from(builder: GemtextBuilder) -> Self {
+        builder.build().into()
+    }
+}
+
+/// Guess the MIME type of a file from its extension
+///
+/// The extension is matched case-insensitively and may start with a `.`.
+/// Unknown extensions are `application/octet-stream`.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::response::mime_for_extension;
+///
+/// assert_eq!(mime_for_extension("gmi"), "text/gemini");
+/// assert_eq!(mime_for_extension(".PNG"), "image/png");
+/// assert_eq!(mime_for_extension("unknown"), "application/octet-stream");
+/// ```
+pub fn mime_for_extension(extension: &str) -> &'static str {
+    let extension = extension.strip_prefix('.').unwrap_or(extension);
+    match extension.to_ascii_lowercase().as_str() {
+        "gmi" | "gemini" => "text/gemini",
+        "txt" => "text/plain",
+        "md" => "text/markdown",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "atom" => "application/atom+xml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// A MIME type with its parameters
+///
+/// This is the structured form of the meta of a success response, such as
+/// `text/gemini; charset=utf-8; lang=en`. The type and parameter names are
+/// compared case-insensitively and parameters keep their original order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedMime {
+    essence: String,
+    params: Vec<(String, String)>,
+}
+
+impl ParsedMime {
+    /// Create a MIME type without any parameters
+    pub fn new(essence: impl Into<String>) -> Self {
+        Self {
+            essence: essence.into(),
+            params: Vec::new(),
+        }
+    }
+
+    /// Parse a MIME type from a meta string
+    ///
+    /// Parameters that don't have a value are ignored, and quotes around
+    /// values are removed.
+    pub fn parse(meta: &str) -> Self {
+        let mut parts = meta.split(';');
+        let essence = parts.next().unwrap_or("").trim().to_owned();
+        let params = parts
+            .filter_map(|param| param.split_once('='))
+            .map(|(key, value)| {
+                (
+                    key.trim().to_owned(),
+                    value.trim().trim_matches('"').to_owned(),
+                )
+            })
+            .collect();
+        Self { essence, params }
+    }
+
+    /// The type and subtype, such as `text/gemini`
+    pub fn essence(&self) -> &str {
+        &self.essence
+    }
+
+    /// Check whether the type and subtype match `essence`
+    pub fn is(&self, essence: &str) -> bool {
+        self.essence.eq_ignore_ascii_case(essence)
+    }
+
+    /// Get the value of a parameter
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Get all of the parameters in order
+    pub fn params(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Set a parameter, replacing any existing value
+    pub fn set_param(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        let value = value.into();
+        match self
+            .params
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case(&name))
+        {
+            Some(param) => param.1 = value,
+            None => self.params.push((name, value)),
+        }
+    }
+
+    /// Set a parameter, replacing any existing value
+    pub fn with_param(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.set_param(name, value);
+        self
+    }
+
+    /// Remove a parameter, returning its value
+    pub fn remove_param(&mut self, name: &str) -> Option<String> {
+        let index = self
+            .params
+            .iter()
+            .position(|(key, _)| key.eq_ignore_ascii_case(name))?;
+        Some(self.params.remove(index).1)
+    }
+}
+
+impl Display for ParsedMime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.essence)?;
+        for (key, value) in &self.params {
+            write!(f, "; {key}={value}")?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+impl Response {
+    /// Read the full body of the response as a string
+    pub(crate) async fn read_body(self) -> String {
+        let header_len = self.header().len();
+        let mut buf = Vec::new();
+        self.send_sync(&mut buf).await.unwrap();
+        String::from_utf8(buf.split_off(header_len)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redirect() {
+        for url in ["gemini://example.com/a?b", "../a b", "//other/x", "?query"] {
+            let response = Response::from(Redirect::to(url));
+            assert_eq!((response.code, response.meta.as_str()), (30, url));
+            let response = Response::from(Redirect::permanent(url));
+            assert_eq!((response.code, response.meta.as_str()), (31, url));
+        }
+        assert!(Redirect::permanent("/").is_permanent());
+        assert_eq!(Redirect::to("/x").url(), "/x");
+    }
+
+    #[test]
+    fn test_download() {
+        let download = |path| Response::download(path, "my report.pdf", "application/pdf", "");
+        assert_eq!(download("/files/42").meta, "./42/my%20report.pdf");
+        assert_eq!(download("/files/").meta, "./my%20report.pdf");
+        assert_eq!(download("/a b").meta, "./a%20b/my%20report.pdf");
+        assert_eq!(download("/files/42/my report.pdf").code, 20);
+        assert_eq!(download("/100%41").meta, "./100%2541/my%20report.pdf");
+
+        let response = Response::download("/files/a%41.pdf", "a%41.pdf", "application/pdf", "");
+        assert_eq!(response.code, 20);
+    }
+
+    #[test]
+    fn test_status_matches_error_types() {
+        use crate::error::GemErrorType;
+
+        // Every error status has an error type with the same code, and the
+        // other way around
+        for code in 0..100 {
+            let error_type = GemErrorType::from_code(code).filter(|t| t.code() == code);
+            let status = Status::from_code(code).filter(|s| s.code() >= 40);
+            assert_eq!(
+                error_type.map(|t| t.code()),
+                status.map(|s| s.code()),
+                "{code}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        for code in [10, 20, 31, 44, 59, 62] {
+            assert_eq!(Status::from_code(code).unwrap().code(), code);
+        }
+        assert_eq!(Status::from_code(21), None);
+
+        let response = Response::build()
+            .status(Status::Success)
+            .mime("text/plain")
+            .body("Hello")
+            .finish()
+            .unwrap();
+        assert_eq!((response.code, response.meta.as_str()), (20, "text/plain"));
+        assert_eq!(response.content_length, Some(5));
+
+        let response = Response::build()
+            .meta("Missing")
+            .status(Status::NotFound)
+            .finish()
+            .unwrap();
+        assert_eq!(response.header(), "51 Missing\r\n");
+
+        for builder in [
+            Response::build().meta("No status"),
+            Response::build().status(Status::NotFound),
+            Response::build()
+                .status(Status::Input)
+                .meta("a".repeat(1025)),
+            Response::build()
+                .status(Status::Redirect)
+                .meta("/a\r\n20 x"),
+            Response::build().status(Status::NotFound).body("Missing"),
+        ] {
+            assert!(builder.finish().is_err());
+        }
+
+        // Responses built by hand can still have an empty meta
+        assert_eq!(Response::new(51, "").header(), "51 \r\n");
+    }
+
+    #[tokio::test]
+    async fn test_from_http_status() {
+        let response = Response::from_http_status(200, "# Hello", "text/gemini");
+        assert_eq!(response.code, 20);
+        assert_eq!(response.meta, "text/gemini");
+        assert_eq!(response.len(), Some(7));
+        assert_eq!(response.read_body().await, "# Hello");
+
+        let response = Response::from_http_status(301, "gemini://example.com/\n", "");
+        assert_eq!(response.code, 31);
+        assert_eq!(response.meta, "gemini://example.com/");
+
+        let response = Response::from_http_status(429, "30", "text/plain");
+        assert_eq!(response.code, 44);
+        assert_eq!(response.meta, "30");
+
+        let codes = [
+            (302, "/new", 30),
+            (302, "", 50),
+            (308, " \n", 50),
+            (404, "", 51),
+            (410, "", 52),
+            (418, "", 50),
+            (429, "", 44),
+            (500, "", 42),
+            (503, "", 41),
+            (599, "", 40),
+            (102, "", 42),
+        ];
+        for (http, body, gemini) in codes {
+            let response = Response::from_http_status(http, body, "");
+            assert_eq!(response.code, gemini);
+            let header = response.header();
+            assert!(header.len() > format!("{gemini} \r\n").len(), "{header:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_from_async_file() {
+        let file = tokio::fs::File::open("Cargo.toml").await.unwrap();
+        let length = file.metadata().await.unwrap().len();
+        let response = Response::from_async_file("text/plain", file).await;
+        assert_eq!(response.code, 20);
+        assert_eq!(response.len(), Some(length));
+    }
+
+    #[tokio::test]
+    async fn test_trailing_newline() {
+        for body in ["Hello", "Hello\n", "Hello\n\n\n"] {
+            let response = Response::gemtext(body).trailing_newline();
+            assert_eq!(response.len(), Some(6));
+            assert_eq!(response.read_body().await, "Hello\n");
+        }
+
+        let response = Response::success("image/png", "Hello").trailing_newline();
+        assert_eq!(response.read_body().await, "Hello");
+    }
+
+    #[tokio::test]
+    async fn test_success_with_charset() {
+        let response = Response::success_with_charset("text/plain", "iso-8859-1", &b"Hello"[..])
+            .trailing_newline();
+        assert_eq!(response.meta, "text/plain; charset=iso-8859-1");
+        assert_eq!(response.len(), Some(5));
+
+        let response =
+            Response::success_with_charset("text/plain", "UTF-8", "Hello").trailing_newline();
+        assert_eq!(response.len(), Some(6));
+    }
+
+    #[test]
+    fn test_parsed_mime() {
+        let mut mime = ParsedMime::parse("Text/Plain; Charset=\"iso-8859-1\" ; lang=en");
+        assert!(mime.is("text/plain"));
+        assert_eq!(mime.param("charset"), Some("iso-8859-1"));
+
+        mime.set_param("charset", "utf-8");
+        assert_eq!(mime.remove_param("lang"), Some("en".to_owned()));
+        assert_eq!(mime.to_string(), "Text/Plain; Charset=utf-8");
+
+        assert!(Response::not_found("text/plain").mime().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_success_blocking() {
+        let response = Response::success_blocking("text/plain", || Ok::<_, String>("done")).await;
+        assert_eq!(response.read_body().await, "done");
+
+        let response = Response::success_blocking("text/plain", || Err::<&str, _>("failed")).await;
+        assert_eq!(response.code, 42);
+    }
+
+    #[cfg(feature = "process")]
+    #[tokio::test]
+    async fn test_from_command() {
+        use std::time::Duration;
+        use tokio::process::Command;
+
+        let timeout = Duration::from_secs(5);
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo hello; echo oops >&2"]);
+        let response = Response::from_command(command, "text/plain", timeout).await;
+        assert_eq!(response.read_body().await, "hello\n");
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "exit 3"]);
+        let response = Response::from_command(command, "text/plain", timeout).await;
+        assert_eq!(response.code, 42);
+
+        let command = Command::new("gemfra-missing-command");
+        let response = Response::from_command(command, "text/plain", timeout).await;
+        assert_eq!(response.code, 42);
+
+        let mut command = Command::new("sleep");
+        command.arg("5");
+        let response =
+            Response::from_command(command, "text/plain", Duration::from_millis(50)).await;
+        assert_eq!(response.code, 42);
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo partial; exit 1"]);
+        let response = Response::from_command(command, "text/plain", timeout).await;
+        assert_eq!(response.code, 20);
+        assert!(response.drain().await.is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_jsonl_stream() {
+        let response = Response::jsonl_stream(vec![("a", 1), ("b", 2)]);
+        assert_eq!(response.read_body().await, "[\"a\",1]\n[\"b\",2]\n");
+
+        // Maps with non-string keys can't be serialized
+        let items = vec![std::collections::HashMap::from([((1, 2), 3)])];
+        let response = Response::jsonl_stream(items);
+        assert_eq!(response.read_body().await, "");
+    }
+
+    #[test]
+    fn test_variants() {
+        let variants = || {
+            Variants::new(|| Response::gemtext("default"))
+                .variant(|request| request.path == "/a", || Response::text("a"))
+                .variant(
+                    |request| request.path.starts_with("/a"),
+                    || Response::text("b"),
+                )
+        };
+        assert_eq!(variants().select(&Request::mock("/a")).len(), Some(1));
+        assert_eq!(variants().select(&Request::mock("/ab")).meta, "text/plain");
+        assert_eq!(variants().select(&Request::mock("/")).meta, "text/gemini");
+    }
+
+    /// A reader that only implements [AsyncRead]
+    struct Hello(bool);
+
+    impl AsyncRead for Hello {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            if !self.0 {
+                buf.put_slice(b"Hello");
+                self.0 = true;
+            }
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_success_async() {
+        let response = Response::success_async("text/plain", Hello(false));
+        assert_eq!(response.read_body().await, "Hello");
+    }
+
+    #[tokio::test]
+    async fn test_send_sync_buffered() {
+        let body = "a".repeat(100);
+        let response = Response::success_async("text/plain", io::Cursor::new(body.clone()));
+        let mut output = Vec::new();
+        response.send_sync_buffered(&mut output, 7).await.unwrap();
+        assert_eq!(output, format!("20 text/plain\r\n{body}").into_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_map_body() {
+        // Number each line, holding back incomplete lines until the next chunk
+        fn number_lines() -> impl FnMut(Bytes) -> Bytes + Send + Sync + 'static {
+            let mut pending = Vec::new();
+            let mut line = 0;
+            move |chunk: Bytes| {
+                let end = chunk.is_empty();
+                pending.extend_from_slice(&chunk);
+                let mut out = Vec::new();
+                while let Some(i) = pending.iter().position(|&b| b == b'\n') {
+                    let rest = pending.split_off(i + 1);
+                    line += 1;
+                    out.extend_from_slice(format!("{line}: ").as_bytes());
+                    out.append(&mut pending);
+                    pending = rest;
+                }
+                if end && !pending.is_empty() {
+                    line += 1;
+                    out.extend_from_slice(format!("{line}: ").as_bytes());
+                    out.append(&mut pending);
+                }
+                Bytes::from(out)
+            }
+        }
+
+        let response = Response::text("a\nb").map_body(number_lines());
+        assert_eq!(response.len(), Some(9));
+        assert_eq!(response.read_body().await, "1: a\n2: b");
+
+        let body = "x\n".repeat(5000);
+        let expected: String = (1..=5000).map(|i| format!("{i}: x\n")).collect();
+        let response = Response::success_async("text/plain", io::Cursor::new(body.clone()))
+            .map_body(number_lines());
+        assert_eq!(response.len(), None);
+        assert_eq!(response.read_body().await, expected);
+        let response =
+            Response::success_sync("text/plain", io::Cursor::new(body)).map_body(number_lines());
+        assert_eq!(response.read_body().await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_flush_header() {
+        #[derive(Default)]
+        struct Flushes {
+            data: Vec<u8>,
+            flushed: Vec<usize>,
+        }
+        impl Write for Flushes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Write::write(&mut self.data, buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.flushed.push(self.data.len());
+                Ok(())
+            }
+        }
+
+        let mut output = Flushes::default();
+        let response = Response::success_async("text/plain", Hello(false)).flush_header(true);
+        response.send_sync(&mut output).await.unwrap();
+        assert_eq!(output.flushed, ["20 text/plain\r\n".len()]);
+
+        let mut output = Flushes::default();
+        let response = Response::success_async("text/plain", Hello(false));
+        response.send_sync(&mut output).await.unwrap();
+        assert!(output.flushed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_negotiate() {
+        let doc = GemDoc::parse("# Hi\n=> /a A");
+        let response = Response::negotiate(&doc, "/dir.md/page.TXT");
+        assert_eq!(response.meta, "text/plain");
+        assert_eq!(response.read_body().await, "Hi\nA: /a\n");
+        for path in ["/page", "/page.gmi", "/dir.txt/page", "/page.png"] {
+            assert_eq!(
+                Response::negotiate(&doc, path).meta,
+                "text/gemini",
+                "{path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gemtext_lines() {
+        let response = Response::gemtext_lines(["# Title", "", "=> /a A\r\n", "text\n"]);
+        assert_eq!(response.meta, "text/gemini");
+        assert_eq!(response.read_body().await, "# Title\n\n=> /a A\ntext\n");
+
+        let lines = [Ok("a"), Err("broken"), Ok("b")];
+        let response = Response::try_gemtext_lines(lines);
+        assert_eq!(response.read_body().await, "a\n");
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[tokio::test]
+    async fn test_broadcast() {
+        use std::time::Duration;
+        use tokio::sync::broadcast;
+
+        let (sender, receiver) = broadcast::channel::<&'static str>(4);
+        let response = Response::broadcast("text/gemini", receiver, Duration::from_secs(10));
+        sender.send("a\n").unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            sender.send("b\n").unwrap();
+        });
+        // The body ends once the sender is dropped
+        assert_eq!(response.read_body().await, "a\nb\n");
+
+        let (sender, receiver) = broadcast::channel::<String>(4);
+        let response = Response::broadcast("text/plain", receiver, Duration::from_millis(20));
+        sender.send("only".to_owned()).unwrap();
+        assert_eq!(response.read_body().await, "only");
+        drop(sender);
+    }
+
+    #[test]
+    fn test_retry_hint() {
+        let response = Response::error_temp_retry("Database is busy", 30);
+        assert_eq!(response.header(), "40 Database is busy, retry in 30s\r\n");
+        let response = Response::unavailable_retry("Down for maintenance", 600);
+        assert_eq!(
+            response.header(),
+            "41 Down for maintenance, retry in 10m\r\n"
+        );
+        assert_eq!(retry_hint(61), "2m");
+        assert_eq!(retry_hint(3600), "1h");
+        assert_eq!(retry_hint(5400), "2h");
+    }
+
+    #[tokio::test]
+    async fn test_gemtext_builder() {
+        let mut page = GemtextBuilder::new();
+        page.heading(5, "Title")
+            .text("=> not a link")
+            .link("/a b", Some("A\nlink"))
+            .list_item("one")
+            .list_item("two")
+            .quote("quoted")
+            .preformatted("alt", "```\ncode")
+            .link("/b", None);
+        let expected = "### Title\n => not a link\n=> /a%20b A link\n* one\n* two\n> quoted\n```alt\n ```\ncode\n```\n=> /b\n";
+        assert_eq!(page.clone().build(), expected);
+        assert_eq!(Response::gemtext(page).read_body().await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_file() {
+        let dir = std::env::temp_dir().join(format!("gemfra-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.gmi"), "# Hello").unwrap();
+
+        let response = Response::file(dir.join("index.gmi")).await.ok().unwrap();
+        assert_eq!((response.code, response.meta.as_str()), (20, "text/gemini"));
+        assert_eq!(response.len(), Some(7));
+        assert_eq!(response.read_body().await, "# Hello");
+
+        for path in [dir.join("missing.txt"), dir.clone()] {
+            let err = Response::file(&path).await.err().unwrap();
+            assert_eq!(err.error_type, crate::error::GemErrorType::NotFound);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_body_sized() {
+        let response = Response::new(20, "text/plain").body_sized(&b"Hello"[..], 5);
+        assert_eq!(response.len(), Some(5));
+        assert_eq!(response.read_body().await, "Hello");
+
+        let response = Response::new(20, "text/plain").body_async(&b"Hello"[..]);
+        assert_eq!(response.len(), None);
+    }
+
+    #[tokio::test]
+    async fn test_send_length() {
+        let mut output = Vec::new();
+        let sent = Response::gemtext("Hello")
+            .send_async(&mut output)
+            .await
+            .unwrap();
+        assert_eq!(sent, output.len() as u64);
+
+        let mut output = Vec::new();
+        let body = io::Cursor::new(vec![b'a'; 3000]);
+        let sent = Response::success_sync("text/plain", body)
+            .send_sync(&mut output)
+            .await
+            .unwrap();
+        assert_eq!((sent, output.len()), (3000 + 15, 3015));
+    }
+
+    #[tokio::test]
+    async fn test_on_sent() {
+        let sent = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+
+        let counter = sent.clone();
+        let response = Response::gemtext("Hello").on_sent(move |bytes| {
+            counter.store(bytes, std::sync::atomic::Ordering::SeqCst);
+        });
+        let mut output = Vec::new();
+        response.send_async(&mut output).await.unwrap();
+        assert_eq!(
+            sent.load(std::sync::atomic::Ordering::SeqCst),
+            output.len() as u64
+        );
+
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let response = Response::gemtext("Hello").on_sent(|_| panic!("the response failed"));
+        assert!(response.send_sync(&mut Broken).await.is_err());
+    }
+}
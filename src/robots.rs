@@ -0,0 +1,151 @@
+//! Robots
+//!
+//! Gemini crawlers follow a variation of the web's `robots.txt` convention,
+//! described in the [robots.txt companion specification](gemini://geminiprotocol.net/docs/companion/robots.gmi).
+//! [Robots] builds a `/robots.txt` file from a set of disallow rules for each
+//! user agent.
+//!
+//! Rather than naming specific crawlers, Gemini uses virtual user agents that
+//! describe what a crawler does. The recognized agents are:
+//!
+//! * `*`: Every crawler
+//! * `archiver`: Crawlers that archive content for posterity
+//! * `indexer`: Crawlers that build search engines
+//! * `researcher`: Crawlers that study the structure of Geminispace
+//! * `webproxy`: Proxies that serve Gemini content over the web
+//!
+//! ### Example
+//!
+//! ```
+//! use gemfra::robots::Robots;
+//!
+//! let robots = Robots::new()
+//!     .disallow("*", "/private/")
+//!     .disallow("archiver", "/drafts/");
+//! assert_eq!(
+//!     robots.to_string(),
+//!     "User-agent: *\nDisallow: /private/\n\nUser-agent: archiver\nDisallow: /drafts/\n",
+//! );
+//! ```
+
+use std::fmt::Display;
+
+#[cfg(feature = "routed")]
+use async_trait::async_trait;
+
+#[cfg(feature = "routed")]
+use crate::{
+    error::AnyError,
+    request::Request,
+    response::Response,
+    routed::{Params, Route},
+};
+
+/// A robots.txt file
+///
+/// Rules are grouped by user agent in the order the agents were first added.
+/// The file is generated with [Display].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Robots {
+    groups: Vec<(String, Vec<String>)>,
+}
+
+impl Robots {
+    /// Create an empty robots.txt, which allows every crawler everywhere
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Disallow `agent` from crawling any path that starts with `path`
+    ///
+    /// See the [module](self) for the recognized agents.
+    pub fn disallow(mut self, agent: impl Into<String>, path: impl Into<String>) -> Self {
+        let agent = agent.into();
+        let path = path.into();
+        match self.groups.iter_mut().find(|(name, _)| *name == agent) {
+            Some((_, paths)) => paths.push(path),
+            None => self.groups.push((agent, vec![path])),
+        }
+        self
+    }
+
+    /// Disallow `agent` from crawling the whole capsule
+    pub fn disallow_all(self, agent: impl Into<String>) -> Self {
+        self.disallow(agent, "/")
+    }
+
+    /// Create a route that serves the file at `/robots.txt`
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::{robots::Robots, routed::RoutedApp};
+    ///
+    /// let mut app = RoutedApp::new();
+    /// app.register_owned(Robots::new().disallow_all("webproxy").route());
+    /// ```
+    #[cfg(feature = "routed")]
+    pub fn route(&self) -> RobotsRoute {
+        RobotsRoute {
+            body: self.to_string(),
+        }
+    }
+}
+
+impl Display for Robots {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (agent, paths)) in self.groups.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            writeln!(f, "User-agent: {agent}")?;
+            for path in paths {
+                writeln!(f, "Disallow: {path}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A route that serves a [Robots] file at `/robots.txt`
+///
+/// This is created with [route](Robots::route).
+#[cfg(feature = "routed")]
+pub struct RobotsRoute {
+    body: String,
+}
+
+#[cfg(feature = "routed")]
+#[async_trait]
+impl<S> Route<S> for RobotsRoute {
+    fn endpoint(&self) -> &str {
+        "/robots.txt"
+    }
+
+    fn content_hint(&self) -> Option<&str> {
+        Some("text/plain")
+    }
+
+    async fn handle(&self, _params: &Params, _request: Request) -> Result<Response, AnyError> {
+        Ok(Response::text(self.body.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_robots() {
+        assert_eq!(Robots::new().to_string(), "");
+
+        let robots = Robots::new()
+            .disallow("indexer", "/a")
+            .disallow_all("webproxy")
+            .disallow("indexer", "/b");
+        assert_eq!(
+            robots.to_string(),
+            "User-agent: indexer\nDisallow: /a\nDisallow: /b\n\nUser-agent: webproxy\nDisallow: /\n"
+        );
+    }
+}
@@ -28,13 +28,27 @@
 //! > include gemfra-codegen in your Cargo.toml file
 //!
 
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    ops::Deref,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
 use async_trait::async_trait;
+use bytes::Bytes;
 
-use crate::request::Request;
+use crate::request::{CertAllowlist, Request, Upload};
 use crate::response::Response;
-use crate::{application::Application, error::AnyError};
+use crate::session::SessionBackend;
+use crate::{
+    application::Application,
+    error::{AnyError, GemError},
+};
 
-pub use gemfra_codegen::route;
+pub use gemfra_codegen::{route, static_site};
 pub use route_recognizer::{Params, Router};
 
 /// A handler to an endpoint
@@ -81,8 +95,17 @@ pub use route_recognizer::{Params, Router};
 ///     Ok(Response::success("text/gemini", format!("You've found {var}")))
 /// }
 /// ```
+///
+/// ## State
+///
+/// `S` is the [State] of the [RoutedApp] that the route can be registered to.
+/// A route that doesn't use the state should implement `Route<S>` for every
+/// `S`, so that it can be registered to any app. The route macro does this
+/// for routes without a `State` parameter, and only implements the route for
+/// the state that is requested otherwise, so registering a route to an app
+/// with a different state fails to compile.
 #[async_trait]
-pub trait Route {
+pub trait Route<S = ()> {
     /// The endpoint that this route handles
     ///
     /// The endpoint can have four kinds of route segments:
@@ -95,6 +118,23 @@ pub trait Route {
     /// variable of [handle](Route::handle).
     fn endpoint(&self) -> &str;
 
+    /// Additional endpoints that this route handles
+    ///
+    /// Each alias is registered alongside the [endpoint](Route::endpoint) and
+    /// should have the same params. By default, a route has no aliases.
+    fn aliases(&self) -> &[&str] {
+        &[]
+    }
+
+    /// The MIME type that this route is expected to respond with
+    ///
+    /// This is metadata only and is not enforced. It is used by introspection
+    /// tools such as the [routes listing](Introspection) to provide richer
+    /// information. By default, no hint is provided.
+    fn content_hint(&self) -> Option<&str> {
+        None
+    }
+
     /// Handle a request for the route
     ///
     /// Take a gemini request and return a gemini response. It is possible to
@@ -103,48 +143,2055 @@ pub trait Route {
     ///
     /// params are the path parameters that were requested when registering the route
     async fn handle(&self, params: &Params, request: Request) -> Result<Response, AnyError>;
+
+    /// Handle an upload for the route
+    ///
+    /// Uploads are sent with the [Titan](crate::protocol::Titan) protocol, see
+    /// [handle_upload](Application::handle_upload). By default, every upload
+    /// is rejected with a __59__ Bad Request.
+    async fn handle_upload(
+        &self,
+        _params: &Params,
+        _request: Request,
+        _upload: Upload<'_>,
+    ) -> Result<Response, AnyError> {
+        Ok(Response::bad_request("Uploads are not supported"))
+    }
 }
 
-/// An application that can have multiple endpoints
+/// Convert the result of a route function into the result of [Route::handle]
 ///
-/// Endpoints are registered using [register](RoutedApp::register) where each
-/// endpoint refers to a different [Route].
+/// This lets functions using the [route](macro@route) macro return anything
+/// that converts into a [Response], such as a
+/// [Redirect](crate::response::Redirect), along with any error that converts
+/// into an [AnyError].
 ///
-/// Once the app is setup, you can start it with a protocol command, see
-/// [protocol](crate::protocol).
-pub struct RoutedApp {
-    router: Router<&'static (dyn Route + Send + Sync)>,
+/// ### Example
+///
+/// ```
+/// use gemfra::{
+///     routed::route,
+///     request::Request,
+///     response::Redirect,
+///     error::GemError,
+/// };
+///
+/// #[route("/old/*path")]
+/// async fn old(request: Request, path: &str) -> Result<Redirect, GemError> {
+///     Ok(Redirect::permanent(format!("/new/{path}")))
+/// }
+/// ```
+pub trait IntoRouteResult {
+    /// Convert into the result of a route
+    fn into_route_result(self) -> Result<Response, AnyError>;
 }
 
-impl RoutedApp {
-    /// Create a new routed capsule
-    #[inline]
-    pub fn new() -> Self {
+impl<T, E> IntoRouteResult for Result<T, E>
+where
+    T: Into<Response>,
+    E: Into<AnyError>,
+{
+    fn into_route_result(self) -> Result<Response, AnyError> {
+        self.map(Into::into).map_err(Into::into)
+    }
+}
+
+/// A route that serves a static file
+///
+/// This is usually created by the [static_site](macro@static_site) macro,
+/// which embeds the content into the binary. The endpoint should not contain
+/// any params.
+pub struct StaticRoute {
+    endpoint: &'static str,
+    aliases: &'static [&'static str],
+    mime: &'static str,
+    body: &'static [u8],
+}
+
+impl StaticRoute {
+    /// Create a route that responds to `endpoint` with `body`
+    pub const fn new(endpoint: &'static str, mime: &'static str, body: &'static [u8]) -> Self {
         Self {
-            router: Router::new(),
+            endpoint,
+            aliases: &[],
+            mime,
+            body,
         }
     }
 
-    /// Register a route to the app.
-    #[inline]
-    pub fn register(&mut self, route: &'static (dyn Route + Send + Sync)) {
-        self.router.add(route.endpoint(), route)
+    /// Also serve the file from each of the `aliases`
+    pub const fn with_aliases(mut self, aliases: &'static [&'static str]) -> Self {
+        self.aliases = aliases;
+        self
     }
 }
 
 #[async_trait]
-impl Application for RoutedApp {
-    async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
-        let route = match self.router.recognize(&request.path) {
-            Ok(val) => val,
-            Err(_) => {
-                return Ok(Response::not_found("Path not found"));
+impl<S> Route<S> for StaticRoute {
+    fn endpoint(&self) -> &str {
+        self.endpoint
+    }
+
+    fn aliases(&self) -> &[&str] {
+        self.aliases
+    }
+
+    fn content_hint(&self) -> Option<&str> {
+        Some(self.mime)
+    }
+
+    async fn handle(&self, _params: &Params, _request: Request) -> Result<Response, AnyError> {
+        Ok(Response::success(self.mime, Bytes::from_static(self.body)))
+    }
+}
+
+/// A route that is handled by an async function or closure
+///
+/// This is usually created with the [route_fn](crate::route_fn) macro and
+/// registered with [register_owned](RoutedApp::register_owned).
+///
+/// Unlike the [route](macro@route) macro, the handler only receives the
+/// request, so path parameters are not available. Use the route macro for
+/// routes that need parameters.
+pub struct FnRoute<F> {
+    endpoint: String,
+    handler: F,
+}
+
+impl<F, Fut> FnRoute<F>
+where
+    F: Fn(Request) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Response, AnyError>> + Send,
+{
+    /// Create a route for `endpoint` that calls `handler`
+    pub fn new(endpoint: impl Into<String>, handler: F) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            handler,
+        }
+    }
+}
+
+#[async_trait]
+impl<S, F, Fut> Route<S> for FnRoute<F>
+where
+    F: Fn(Request) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Response, AnyError>> + Send,
+{
+    fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    async fn handle(&self, _params: &Params, request: Request) -> Result<Response, AnyError> {
+        (self.handler)(request).await
+    }
+}
+
+/// Create a route from an endpoint and an async closure
+///
+/// This is a shorthand for very small routes that would be too verbose with
+/// the [route](macro@crate::routed::route) macro. The closure takes the
+/// [Request] and returns a future of `Result<Response, AnyError>`. The route
+/// can be registered with [register_owned](crate::routed::RoutedApp::register_owned).
+///
+/// Compared to the route macro, the closure can't receive path parameters and
+/// the route has no name that can be referenced elsewhere.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::{response::Response, route_fn, routed::RoutedApp};
+///
+/// let mut app = RoutedApp::new();
+/// app.register_owned(route_fn!("/hello", |_request| async {
+///     Ok(Response::gemtext("# Hello World!"))
+/// }));
+/// ```
+#[macro_export]
+macro_rules! route_fn {
+    ($endpoint:expr, $handler:expr $(,)?) => {
+        $crate::routed::FnRoute::new($endpoint, $handler)
+    };
+}
+
+/// A route wrapper that caches generated responses
+///
+/// Successful (__2X__) responses with a buffered body are kept for the time to
+/// live. Requests for the same path and query are served from the cache until
+/// the response expires, after which the route is run again. Responses with a
+/// streamed body and all other status codes are never cached.
+///
+/// The cache key is the request path and query. Anything else that the route
+/// uses to generate its response, such as the client certificate, is not part
+/// of the key, so routes that show per-user content should not be cached. There
+/// is no manual invalidation; changes will be visible once the cached response
+/// expires.
+///
+/// Since every query is cached separately, the cache holds at most
+/// [max_entries](Cached::max_entries) responses. Once it is full, the response
+/// that expires first is evicted to make room.
+///
+/// ### Example
+///
+/// ```
+/// use std::time::Duration;
+/// use gemfra::{
+///     routed::{route, Cached, RoutedApp},
+///     request::Request,
+///     response::Response,
+///     error::AnyError,
+/// };
+///
+/// #[route("/feed")]
+/// async fn feed(request: Request) -> Result<Response, AnyError> {
+///     Ok(Response::gemtext("# An expensive feed"))
+/// }
+///
+/// let mut app = RoutedApp::new();
+/// app.register_owned(Cached::new(feed, Duration::from_secs(60)));
+/// ```
+pub struct Cached<R> {
+    route: R,
+    ttl: Duration,
+    max_entries: usize,
+    cache: Mutex<HashMap<String, CachedResponse>>,
+}
+
+struct CachedResponse {
+    code: u32,
+    meta: String,
+    body: Bytes,
+    expires: Instant,
+}
+
+impl<R> Cached<R> {
+    /// Cache the responses of `route` for `ttl`
+    ///
+    /// Up to 256 responses are cached by default.
+    pub fn new(route: R, ttl: Duration) -> Self {
+        Self {
+            route,
+            ttl,
+            max_entries: 256,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set the maximum number of responses that are cached at once
+    pub fn max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max.max(1);
+        self
+    }
+
+    fn key(request: &Request) -> String {
+        match &request.query {
+            Some(query) => format!("{}?{query}", request.path),
+            None => request.path.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S, R> Route<S> for Cached<R>
+where
+    R: Route<S> + Send + Sync,
+{
+    fn endpoint(&self) -> &str {
+        self.route.endpoint()
+    }
+
+    fn aliases(&self) -> &[&str] {
+        self.route.aliases()
+    }
+
+    fn content_hint(&self) -> Option<&str> {
+        self.route.content_hint()
+    }
+
+    async fn handle(&self, params: &Params, request: Request) -> Result<Response, AnyError> {
+        let key = Self::key(&request);
+        let now = Instant::now();
+        if let Some(cached) = self.cache.lock().unwrap().get(&key) {
+            if cached.expires > now {
+                return Ok(
+                    Response::new(cached.code, cached.meta.clone()).body(cached.body.clone())
+                );
+            }
+        }
+
+        let response = self.route.handle(params, request).await?;
+        if (20..30).contains(&response.code) {
+            if let Some(body) = response.buffered_body() {
+                let mut cache = self.cache.lock().unwrap();
+                cache.retain(|_, cached| cached.expires > now);
+                if cache.len() >= self.max_entries && !cache.contains_key(&key) {
+                    let oldest = cache
+                        .iter()
+                        .min_by_key(|(_, cached)| cached.expires)
+                        .map(|(key, _)| key.clone());
+                    if let Some(oldest) = oldest {
+                        cache.remove(&oldest);
+                    }
+                }
+                cache.insert(
+                    key,
+                    CachedResponse {
+                        code: response.code,
+                        meta: response.meta.clone(),
+                        body: body.clone(),
+                        expires: now + self.ttl,
+                    },
+                );
             }
+        }
+        Ok(response)
+    }
+
+    /// Uploads are never cached
+    async fn handle_upload(
+        &self,
+        params: &Params,
+        request: Request,
+        upload: Upload<'_>,
+    ) -> Result<Response, AnyError> {
+        self.route.handle_upload(params, request, upload).await
+    }
+}
+
+/// A route wrapper that limits how often each client can request the route
+///
+/// Each client may make `max_requests` requests to the route within every
+/// window of `per`. Once a client reaches the limit, it is sent a __44__ Slow
+/// Down with the number of seconds until the window ends. Other routes are not
+/// affected, so expensive routes such as a search can be limited while cheap
+/// ones are left alone.
+///
+/// The limit is tracked by a key that is made of the route's
+/// [endpoint](Route::endpoint) and the client's identity, as
+/// `{endpoint} {identity}`. The identity is the
+/// [fingerprint](crate::request::Certificate::fingerprint) of the client
+/// certificate if one was given, otherwise the client's
+/// [address](Request::remote_addr). Requests to an alias of the route count
+/// towards the same limit.
+///
+/// The windows are kept in memory by default, so each process has its own
+/// limits. A shared [backend](RateLimited::backend) can be used to apply the
+/// limits across every process.
+///
+/// ### Example
+///
+/// ```
+/// use std::time::Duration;
+/// use gemfra::{
+///     routed::{route, RateLimited, RoutedApp},
+///     request::Request,
+///     response::Response,
+///     error::AnyError,
+/// };
+///
+/// #[route("/search")]
+/// async fn search(request: Request) -> Result<Response, AnyError> {
+///     Ok(Response::gemtext("# An expensive search"))
+/// }
+///
+/// let mut app = RoutedApp::new();
+/// // Allow 10 searches per minute from each client
+/// app.register_owned(RateLimited::new(search, 10, Duration::from_secs(60)));
+/// ```
+pub struct RateLimited<R> {
+    route: R,
+    max_requests: u32,
+    per: Duration,
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+    backend: Option<Arc<dyn SessionBackend<RateWindow>>>,
+}
+
+/// The requests that a client has made in the current window of a
+/// [RateLimited] route
+///
+/// This is what is kept in a [backend](RateLimited::backend).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateWindow {
+    /// When the window started
+    pub start: SystemTime,
+    /// The number of requests that were made in the window
+    pub count: u32,
+}
+
+impl<R> RateLimited<R> {
+    /// Allow each client `max_requests` requests to `route` every `per`
+    pub fn new(route: R, max_requests: u32, per: Duration) -> Self {
+        Self {
+            route,
+            max_requests,
+            per,
+            windows: Mutex::new(HashMap::new()),
+            backend: None,
+        }
+    }
+
+    /// Keep the windows in `backend` instead of in memory
+    ///
+    /// Windows are stored by the key described [above](RateLimited), and a
+    /// window is no longer needed once `per` has passed since it started, so
+    /// the backend may expire it then. Since the backend isn't atomic, clients
+    /// might get a few more requests than the limit under contention. If the
+    /// backend fails, the request fails with a __42__ CGI Error.
+    ///
+    /// The backend is shared, so several routes can use the same backend.
+    pub fn backend<B>(mut self, backend: Arc<B>) -> Self
+    where
+        B: SessionBackend<RateWindow> + 'static,
+    {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Get the number of seconds to wait for a window that started `elapsed`
+    /// ago to end
+    fn retry_after(&self, elapsed: Duration) -> u32 {
+        let remaining = self.per.saturating_sub(elapsed);
+        let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+        seconds.max(1) as u32
+    }
+
+    /// Count a request in memory, returning the seconds to wait if the client
+    /// is over the limit
+    fn check_memory(&self, key: String) -> Option<u32> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        windows.retain(|_, (start, _)| now.duration_since(*start) < self.per);
+        let (start, count) = windows.entry(key).or_insert((now, 0));
+        if *count >= self.max_requests {
+            return Some(self.retry_after(now.duration_since(*start)));
+        }
+        *count += 1;
+        None
+    }
+
+    /// Count a request in a backend, returning the seconds to wait if the
+    /// client is over the limit
+    async fn check_backend(
+        &self,
+        backend: &dyn SessionBackend<RateWindow>,
+        key: String,
+    ) -> Result<Option<u32>, AnyError> {
+        let now = SystemTime::now();
+        let elapsed = |window: &RateWindow| now.duration_since(window.start).unwrap_or_default();
+        let window = match backend.get(&key).await? {
+            Some(window) if elapsed(&window) < self.per => window,
+            _ => RateWindow {
+                start: now,
+                count: 0,
+            },
+        };
+        if window.count >= self.max_requests {
+            return Ok(Some(self.retry_after(elapsed(&window))));
+        }
+        let window = RateWindow {
+            count: window.count + 1,
+            ..window
         };
+        backend.set(&key, window).await?;
+        Ok(None)
+    }
 
-        let params = route.params();
-        let handler = **route.handler();
+    /// Record a request to `endpoint`, returning the seconds to wait if the
+    /// client is limited
+    async fn limited(&self, endpoint: &str, request: &Request) -> Result<Option<u32>, AnyError> {
+        let key = Self::key(endpoint, request);
+        match &self.backend {
+            Some(backend) => self.check_backend(backend.as_ref(), key).await,
+            None => Ok(self.check_memory(key)),
+        }
+    }
+
+    fn key(endpoint: &str, request: &Request) -> String {
+        let identity = match &request.client_cert {
+            Some(cert) => cert.fingerprint(),
+            None => request.remote_addr.clone(),
+        };
+        format!("{endpoint} {identity}")
+    }
+}
+
+#[async_trait]
+impl<S, R> Route<S> for RateLimited<R>
+where
+    R: Route<S> + Send + Sync,
+{
+    fn endpoint(&self) -> &str {
+        self.route.endpoint()
+    }
+
+    fn aliases(&self) -> &[&str] {
+        self.route.aliases()
+    }
+
+    fn content_hint(&self) -> Option<&str> {
+        self.route.content_hint()
+    }
+
+    async fn handle(&self, params: &Params, request: Request) -> Result<Response, AnyError> {
+        if let Some(seconds) = self.limited(self.route.endpoint(), &request).await? {
+            return Ok(Response::slow_down(seconds));
+        }
+        self.route.handle(params, request).await
+    }
+
+    /// Uploads count towards the same limit as requests
+    async fn handle_upload(
+        &self,
+        params: &Params,
+        request: Request,
+        upload: Upload<'_>,
+    ) -> Result<Response, AnyError> {
+        if let Some(seconds) = self.limited(self.route.endpoint(), &request).await? {
+            return Ok(Response::slow_down(seconds));
+        }
+        self.route.handle_upload(params, request, upload).await
+    }
+}
+
+/// A route that lists the routes of an app as a gemtext page
+///
+/// Every static endpoint is linked relative to the
+/// [script](Request::script) of the request, in the order that the endpoints
+/// were given. Endpoints with params or wildcards have no single URL, so they
+/// are listed separately by their pattern (such as `/posts/:id`) without a
+/// link.
+///
+/// The sitemap only knows about the endpoints it was created with, so it
+/// should be created once every other route has been registered.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::{
+///     routed::{route, RoutedApp, SiteMap},
+///     request::Request,
+///     response::Response,
+///     error::AnyError,
+/// };
+///
+/// #[route("/about")]
+/// async fn about(request: Request) -> Result<Response, AnyError> {
+///     Ok(Response::gemtext("# About"))
+/// }
+///
+/// let mut app = RoutedApp::new();
+/// app.register(&about);
+/// let sitemap = SiteMap::new("/sitemap", app.routes());
+/// app.register_owned(sitemap);
+/// ```
+pub struct SiteMap {
+    endpoint: String,
+    title: String,
+    endpoints: Vec<String>,
+}
+
+impl SiteMap {
+    /// Create a sitemap at `endpoint` that lists `endpoints`
+    pub fn new<I, S>(endpoint: impl Into<String>, endpoints: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            endpoint: endpoint.into(),
+            title: "Sitemap".to_owned(),
+            endpoints: endpoints.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Set the heading of the page, which is `Sitemap` by default
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    /// Render the sitemap with links relative to `script`
+    fn render(&self, script: &str) -> String {
+        let is_dynamic = |endpoint: &str| {
+            endpoint
+                .split('/')
+                .any(|segment| segment.starts_with(':') || segment.starts_with('*'))
+        };
+        let script = script.trim_end_matches('/');
+
+        let mut body = format!("# {}\n\n", self.title);
+        for endpoint in self.endpoints.iter().filter(|e| !is_dynamic(e)) {
+            body.push_str(&format!("=> {script}{endpoint} {endpoint}\n"));
+        }
+        let dynamic: Vec<_> = self.endpoints.iter().filter(|e| is_dynamic(e)).collect();
+        if !dynamic.is_empty() {
+            body.push_str("\n## Dynamic pages\n\n");
+            for endpoint in dynamic {
+                body.push_str(&format!("* {endpoint}\n"));
+            }
+        }
+        body
+    }
+}
+
+#[async_trait]
+impl<S> Route<S> for SiteMap {
+    fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    fn content_hint(&self) -> Option<&str> {
+        Some("text/gemini")
+    }
+
+    async fn handle(&self, _params: &Params, request: Request) -> Result<Response, AnyError> {
+        Ok(Response::gemtext(self.render(&request.script)))
+    }
+}
+
+/// State that is shared by every route of a [RoutedApp]
+///
+/// The state is given to the app with [with_state](RoutedApp::with_state) and
+/// is cloned into the [extensions](Request::extensions) of every request, so
+/// it should be cheap to clone, such as an [Arc](std::sync::Arc).
+///
+/// The [route](macro@route) macro will pass the state to any parameter with the
+/// type `State<S>`. Such a route implements [Route] only for the state `S`, so
+/// it can only be registered to a `RoutedApp<S>`.
+///
+/// ### Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use gemfra::{
+///     routed::{route, RoutedApp, State},
+///     request::Request,
+///     response::Response,
+///     error::AnyError,
+/// };
+///
+/// struct Config {
+///     title: String,
+/// }
+///
+/// #[route("/")]
+/// async fn index(request: Request, config: State<Arc<Config>>) -> Result<Response, AnyError> {
+///     Ok(Response::gemtext(format!("# {}", config.title)))
+/// }
+///
+/// let config = Arc::new(Config { title: "My Capsule".to_owned() });
+/// let mut app = RoutedApp::with_state(config);
+/// app.register(&index);
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct State<S>(pub S);
+
+impl<S> Deref for State<S> {
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Behavior that runs around every routed request
+///
+/// Middleware is added to a [RoutedApp] with
+/// [with_middleware](RoutedApp::with_middleware). It is given the request and
+/// the [Next] step of the chain. Calling [run](Next::run) passes the request
+/// on to the next middleware, or to the matched route once every middleware
+/// has run. Middleware can short-circuit the request by returning a response
+/// without calling `next`, and it can inspect or replace the response that
+/// `next` returns.
+///
+/// Middleware runs after the path has been
+/// [normalized](RoutedApp::normalize_paths) and
+/// [rewritten](RoutedApp::rewrite), and also runs for paths that don't match
+/// any route, in which case `next` returns a __51__ Not Found.
+///
+/// [Uploads](Application::handle_upload) run through the same chain. Their
+/// [protocol](Request::protocol) is `TITAN`, and once the chain ends, the
+/// upload is given to the route's [handle_upload](Route::handle_upload).
+///
+/// ### Example
+///
+/// ```
+/// use async_trait::async_trait;
+/// use gemfra::{
+///     error::AnyError,
+///     request::Request,
+///     response::Response,
+///     routed::{Middleware, Next, RoutedApp},
+/// };
+///
+/// struct RequireCert;
+///
+/// #[async_trait]
+/// impl Middleware for RequireCert {
+///     async fn handle(&self, request: Request, next: Next<'_>) -> Result<Response, AnyError> {
+///         if request.client_cert.is_none() {
+///             return Ok(Response::cert_required("Certificate needed"));
+///         }
+///         next.run(request).await
+///     }
+/// }
+///
+/// let mut app = RoutedApp::new();
+/// app.with_middleware(RequireCert);
+/// ```
+#[async_trait]
+pub trait Middleware {
+    /// Handle a request, calling `next` to continue the chain
+    async fn handle(&self, request: Request, next: Next<'_>) -> Result<Response, AnyError>;
+}
+
+/// The future returned by the end of a middleware chain
+type Endpoint<'a> = dyn Fn(Request) -> Pin<Box<dyn Future<Output = Result<Response, AnyError>> + Send + 'a>>
+    + Send
+    + Sync
+    + 'a;
+
+/// The rest of a middleware chain, see [Middleware]
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware + Send + Sync>],
+    endpoint: &'a Endpoint<'a>,
+}
+
+impl Next<'_> {
+    /// Pass the request to the rest of the chain
+    pub async fn run(self, request: Request) -> Result<Response, AnyError> {
+        match self.middleware.split_first() {
+            Some((middleware, rest)) => {
+                let next = Next {
+                    middleware: rest,
+                    endpoint: self.endpoint,
+                };
+                middleware.handle(request, next).await
+            }
+            None => (self.endpoint)(request).await,
+        }
+    }
+}
+
+/// Configuration for the reserved introspection paths of a [RoutedApp]
+///
+/// Introspection is disabled by default. Once enabled with
+/// [introspection](RoutedApp::introspection), the following paths are
+/// reserved under the configured prefix:
+///
+/// * `{prefix}/routes`: A gemtext listing of the registered routes and the
+///   version of gemfra that is running.
+/// * `{prefix}/timings`: A gemtext listing of how long each route takes to
+///   handle a request. This requires the `timing` feature, see
+///   [timings](RoutedApp::timings).
+///
+/// If any certificates are allowed with [allow_cert](Introspection::allow_cert),
+/// only clients presenting one of those certificates can access the reserved
+/// paths. Otherwise the paths are available to anyone.
+///
+/// ### Example
+///
+/// ```
+/// use gemfra::routed::{Introspection, RoutedApp};
+///
+/// let mut app = RoutedApp::new();
+/// app.introspection(Introspection::new("/_gemfra").allow_cert("my-cert-hash"));
+/// ```
+pub struct Introspection {
+    prefix: String,
+    allowed_certs: CertAllowlist,
+}
+
+impl Introspection {
+    /// Create a new introspection config that serves from `prefix`
+    pub fn new(prefix: impl Into<String>) -> Self {
+        let prefix: String = prefix.into();
+        Self {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            allowed_certs: CertAllowlist::default(),
+        }
+    }
+
+    /// Allow a client certificate (by its [hash](crate::request::Certificate::hash))
+    /// to access the introspection paths
+    ///
+    /// The hash is compared by [fingerprint](crate::request::Certificate::fingerprint).
+    pub fn allow_cert(mut self, hash: impl AsRef<str>) -> Self {
+        self.allowed_certs.allow(hash.as_ref());
+        self
+    }
+
+    /// Get the sub-path of an introspection request, if the path is reserved
+    fn reserved<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(self.prefix.as_str())
+            .filter(|rest| rest.starts_with('/'))
+    }
+
+    /// Check that the client is allowed to access the introspection paths
+    fn authorise(&self, request: &Request) -> Result<(), Response> {
+        if self.allowed_certs.is_empty() {
+            return Ok(());
+        }
+        match &request.client_cert {
+            Some(cert) if self.allowed_certs.allows(cert) => Ok(()),
+            Some(_) => Err(Response::cert_not_authorised("Certificate not Authorised")),
+            None => Err(Response::cert_required("Certificate needed")),
+        }
+    }
+}
+
+/// How long a route has taken to handle its requests
+///
+/// The time is measured from when the route is called until it returns a
+/// response, so streamed bodies are not included.
+#[cfg(feature = "timing")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteTiming {
+    /// The number of requests that were handled
+    pub count: u64,
+    /// The fastest request
+    pub min: Duration,
+    /// The slowest request
+    pub max: Duration,
+    /// The total time of every request
+    pub total: Duration,
+}
+
+#[cfg(feature = "timing")]
+impl RouteTiming {
+    fn new(elapsed: Duration) -> Self {
+        Self {
+            count: 1,
+            min: elapsed,
+            max: elapsed,
+            total: elapsed,
+        }
+    }
+
+    fn record(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.min = self.min.min(elapsed);
+        self.max = self.max.max(elapsed);
+        self.total = self.total.saturating_add(elapsed);
+    }
+
+    /// Get the average time of a request
+    pub fn average(&self) -> Duration {
+        let nanos = self.total.as_nanos() / u128::from(self.count.max(1));
+        Duration::new(
+            (nanos / 1_000_000_000) as u64,
+            (nanos % 1_000_000_000) as u32,
+        )
+    }
+}
+
+/// An application that can have multiple endpoints
+///
+/// Endpoints are registered using [register](RoutedApp::register) where each
+/// endpoint refers to a different [Route].
+///
+/// When a path matches multiple endpoints, the route with the highest priority
+/// wins, see [register_with_priority](RoutedApp::register_with_priority).
+///
+/// State that is shared by every route can be given with
+/// [with_state](RoutedApp::with_state), see [State].
+///
+/// Once the app is setup, you can start it with a protocol command, see
+/// [protocol](crate::protocol).
+pub struct RoutedApp<S: 'static = ()> {
+    routers: BTreeMap<i32, Router<&'static (dyn Route<S> + Send + Sync)>>,
+    routes: Vec<&'static (dyn Route<S> + Send + Sync)>,
+    introspection: Option<Introspection>,
+    normalize_paths: bool,
+    #[cfg(feature = "nfc")]
+    nfc_paths: bool,
+    rewrites: Vec<Rewrite>,
+    validation_prefix: Option<String>,
+    status_pages: HashMap<u32, StatusPage>,
+    middleware: Vec<Box<dyn Middleware + Send + Sync>>,
+    #[cfg(feature = "timing")]
+    timings: Mutex<HashMap<String, RouteTiming>>,
+    state: S,
+}
+
+/// A handler that renders the page for a status, see [on_status](RoutedApp::on_status)
+type StatusPage = Box<dyn Fn(&str, &Response) -> Response + Send + Sync>;
+
+/// A path prefix rewrite rule, see [rewrite](RoutedApp::rewrite)
+struct Rewrite {
+    from: String,
+    to: String,
+    redirect: bool,
+}
+
+impl Rewrite {
+    /// Rewrite `path` if it starts with the prefix
+    ///
+    /// The prefix only matches whole segments, so `/old` matches `/old` and
+    /// `/old/page`, but not `/older`.
+    fn apply(&self, path: &str) -> Option<String> {
+        let rest = path.strip_prefix(self.from.as_str())?;
+        if rest.is_empty() || rest.starts_with('/') || self.from.ends_with('/') {
+            Some(format!("{}{rest}", self.to))
+        } else {
+            None
+        }
+    }
+}
+
+impl RoutedApp {
+    /// Create a new routed capsule
+    #[inline]
+    pub fn new() -> Self {
+        Self::with_state(())
+    }
+}
+
+impl<S: 'static> RoutedApp<S> {
+    /// Create a new routed capsule with state that is shared by every route
+    ///
+    /// See [State] for how routes can access the state.
+    pub fn with_state(state: S) -> Self {
+        Self {
+            routers: BTreeMap::new(),
+            routes: Vec::new(),
+            introspection: None,
+            normalize_paths: false,
+            #[cfg(feature = "nfc")]
+            nfc_paths: false,
+            rewrites: Vec::new(),
+            validation_prefix: None,
+            status_pages: HashMap::new(),
+            middleware: Vec::new(),
+            #[cfg(feature = "timing")]
+            timings: Mutex::new(HashMap::new()),
+            state,
+        }
+    }
+
+    /// Register a route to the app.
+    ///
+    /// The route is registered with a priority of `0`.
+    #[inline]
+    pub fn register(&mut self, route: &'static (dyn Route<S> + Send + Sync)) {
+        self.register_with_priority(route, 0);
+    }
+
+    /// Register a route to the app with an explicit priority.
+    ///
+    /// When a path matches the endpoints of multiple routes, the route with the
+    /// highest priority is used regardless of registration order. Routes with
+    /// the same priority are resolved by the recognizer, which prefers static
+    /// segments over params, and params over wildcards.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::{
+    ///     routed::{route, RoutedApp},
+    ///     request::Request,
+    ///     response::Response,
+    ///     error::AnyError,
+    /// };
+    ///
+    /// #[route("/*path")]
+    /// async fn fallback(request: Request, path: &str) -> Result<Response, AnyError> {
+    ///     Ok(Response::gemtext(format!("fallback for {path}")))
+    /// }
+    ///
+    /// let mut app = RoutedApp::new();
+    /// // The fallback will handle every path, even if more specific routes exist
+    /// app.register_with_priority(&fallback, 10);
+    /// ```
+    pub fn register_with_priority(
+        &mut self,
+        route: &'static (dyn Route<S> + Send + Sync),
+        priority: i32,
+    ) {
+        let router = self.routers.entry(priority).or_default();
+        router.add(route.endpoint(), route);
+        for alias in route.aliases() {
+            router.add(alias, route);
+        }
+        self.routes.push(route);
+    }
+
+    /// Register an owned route to the app.
+    ///
+    /// Routes live for the rest of the program, so the route is leaked to give
+    /// it a static lifetime. This should only be used while setting up the app.
+    pub fn register_owned<R>(&mut self, route: R)
+    where
+        R: Route<S> + Send + Sync + 'static,
+    {
+        self.register(Box::leak(Box::new(route)));
+    }
+
+    /// Get the endpoints of all registered routes in registration order
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.routes.iter().map(|route| route.endpoint())
+    }
+
+    /// Normalize request paths before they are routed
+    ///
+    /// This is disabled by default, so routes see the path exactly as it was
+    /// requested. When enabled, the following rules are applied to the path:
+    ///
+    /// * Repeated slashes are collapsed, so `/a//b` becomes `/a/b`.
+    /// * `.` segments are removed, so `/a/./b` becomes `/a/b`.
+    /// * `..` segments remove the previous segment, so `/a/../b` becomes `/b`.
+    /// * The path always starts with a `/`, and a trailing `/` is kept.
+    ///
+    /// Paths that try to go above the root, such as `/../a`, are rejected with
+    /// a __59__ Bad Request. The normalized path replaces [path](Request::path)
+    /// before it is given to the route.
+    pub fn normalize_paths(&mut self, enabled: bool) {
+        self.normalize_paths = enabled;
+    }
+
+    /// Normalize request paths to Unicode NFC before they are routed
+    ///
+    /// The same text can be encoded in different ways, so `/café` might be
+    /// requested with a precomposed `é` (NFC) or with an `e` followed by a
+    /// combining accent (NFD). With this enabled, both forms match the same
+    /// route. Endpoints should be written in NFC, which is what most editors
+    /// produce.
+    ///
+    /// This is disabled by default, so routes see the path exactly as it was
+    /// requested. When enabled, the normalized path replaces
+    /// [path](Request::path) before it is given to the route. The path is
+    /// normalized as it is given by the server, which is already decoded for
+    /// CGI and SCGI.
+    #[cfg(feature = "nfc")]
+    pub fn nfc_paths(&mut self, enabled: bool) {
+        self.nfc_paths = enabled;
+    }
+
+    /// Silently rewrite paths that start with `from` to start with `to`
+    ///
+    /// Rewrites are applied to the [path](Request::path) before it is routed,
+    /// after [normalization](RoutedApp::normalize_paths). Prefixes only match
+    /// whole segments, so `/old` matches `/old` and `/old/page`, but not
+    /// `/older`. Rules are checked in the order they are added and only the
+    /// first matching rule is applied.
+    ///
+    /// This keeps old links working after the structure of a capsule changes.
+    /// To tell clients about the new location, use
+    /// [rewrite_redirect](RoutedApp::rewrite_redirect) instead.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::routed::RoutedApp;
+    ///
+    /// let mut app = RoutedApp::new();
+    /// // `/posts/hello` will be handled by the route for `/blog/hello`
+    /// app.rewrite("/posts", "/blog");
+    /// ```
+    pub fn rewrite(&mut self, from: impl Into<String>, to: impl Into<String>) {
+        self.rewrites.push(Rewrite {
+            from: from.into(),
+            to: to.into(),
+            redirect: false,
+        });
+    }
+
+    /// Redirect paths that start with `from` to start with `to`
+    ///
+    /// This is the same as [rewrite](RoutedApp::rewrite), but a __31__
+    /// permanent redirect is sent to the rewritten path (including the script
+    /// and query) instead of handling it directly.
+    pub fn rewrite_redirect(&mut self, from: impl Into<String>, to: impl Into<String>) {
+        self.rewrites.push(Rewrite {
+            from: from.into(),
+            to: to.into(),
+            redirect: true,
+        });
+    }
+
+    /// Serve status-only responses for every path under `prefix`
+    ///
+    /// This is disabled by default. Once enabled, a request for
+    /// `{prefix}/some/page` is handled as a request for `/some/page` with
+    /// [handle_request_meta](Application::handle_request_meta), so the client
+    /// only receives the status and meta of the page. Normal requests are not
+    /// affected.
+    ///
+    /// This lets link-validation crawlers check a whole capsule cheaply. Rather
+    /// than downloading every page, a crawler requests each link with the
+    /// prefix added and only looks at the status:
+    ///
+    /// * __2X__: The link is valid.
+    /// * __3X__: The link moved. The redirect is to the real path, so the
+    ///   crawler should add the prefix again before following it.
+    /// * __4X__/__5X__/__6X__: The link is broken, or needs a certificate.
+    ///
+    /// Since the crawler still needs the links of each page, it can fetch the
+    /// pages it crawls normally and validate the links to other capsules or
+    /// files with the prefix.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::routed::RoutedApp;
+    ///
+    /// let mut app = RoutedApp::new();
+    /// // `/_validate/blog` responds with the status of `/blog`
+    /// app.validation_prefix("/_validate");
+    /// ```
+    pub fn validation_prefix(&mut self, prefix: impl Into<String>) {
+        let prefix: String = prefix.into();
+        self.validation_prefix = Some(prefix.trim_end_matches('/').to_owned());
+    }
+
+    /// Get the path of a validation request, if the path is under the
+    /// [validation prefix](RoutedApp::validation_prefix)
+    fn validation_path(&self, path: &str) -> Option<String> {
+        let rest = path.strip_prefix(self.validation_prefix.as_deref()?)?;
+        if rest.is_empty() {
+            Some("/".to_owned())
+        } else if rest.starts_with('/') {
+            Some(rest.to_owned())
+        } else {
+            None
+        }
+    }
+
+    /// Render a custom page whenever a response has the status `code`
+    ///
+    /// The handler is given the path of the request and the response that
+    /// was produced. It can return a __20__ gemtext page to present the error
+    /// to the user, or a response with the same status and a friendlier meta.
+    ///
+    /// The handler is used for responses without a body, including the
+    /// responses for [GemError]s returned by routes and the __51__ for paths
+    /// that don't match any route. Responses that already have a body are
+    /// sent as they are, so a route that renders its own page is never
+    /// replaced. Requests under the
+    /// [validation prefix](RoutedApp::validation_prefix) are not affected.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use gemfra::{response::Response, routed::RoutedApp};
+    ///
+    /// let mut app = RoutedApp::new();
+    /// app.on_status(51, |path, _response| {
+    ///     Response::gemtext(format!("# Not Found\n\n{path} does not exist.\n\n=> / Home"))
+    /// });
+    /// app.on_status(52, |_path, response| Response::gone(format!("{} :(", response.meta)));
+    /// ```
+    pub fn on_status<F>(&mut self, code: u32, handler: F)
+    where
+        F: Fn(&str, &Response) -> Response + Send + Sync + 'static,
+    {
+        self.status_pages.insert(code, Box::new(handler));
+    }
+
+    /// Add a [Middleware] that runs around every request
+    ///
+    /// Middleware runs in the order that it was added, so the first
+    /// middleware sees the request first and the response last.
+    pub fn with_middleware<M>(&mut self, middleware: M)
+    where
+        M: Middleware + Send + Sync + 'static,
+    {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Enable the reserved introspection paths
+    ///
+    /// See [Introspection] for the available paths.
+    pub fn introspection(&mut self, config: Introspection) {
+        self.introspection = Some(config);
+    }
+
+    /// Get how long each route has taken to handle its requests
+    ///
+    /// The timings are keyed by the [endpoint](Route::endpoint) of the route,
+    /// so requests to an alias are counted with the route. Only routes that
+    /// have handled a request are included. The timings are also available
+    /// from the `timings` [introspection](Introspection) path.
+    #[cfg(feature = "timing")]
+    pub fn timings(&self) -> Vec<(String, RouteTiming)> {
+        let timings = self.timings.lock().unwrap_or_else(|err| err.into_inner());
+        let mut timings: Vec<_> = timings
+            .iter()
+            .map(|(endpoint, timing)| (endpoint.clone(), *timing))
+            .collect();
+        timings.sort_by(|a, b| a.0.cmp(&b.0));
+        timings
+    }
+
+    /// Handle a request for a reserved introspection path
+    fn handle_introspection(
+        &self,
+        config: &Introspection,
+        path: &str,
+        request: &Request,
+    ) -> Response {
+        if let Err(response) = config.authorise(request) {
+            return response;
+        }
+
+        match path {
+            "/routes" => {
+                let mut body = format!("# Routes\n\ngemfra {}\n\n", env!("CARGO_PKG_VERSION"));
+                for route in &self.routes {
+                    body.push_str("* ");
+                    body.push_str(route.endpoint());
+                    if let Some(hint) = route.content_hint() {
+                        body.push_str(" (");
+                        body.push_str(hint);
+                        body.push(')');
+                    }
+                    body.push('\n');
+                }
+                Response::success("text/gemini", body)
+            }
+            #[cfg(feature = "timing")]
+            "/timings" => {
+                let mut body = "# Timings\n\n".to_owned();
+                for (endpoint, timing) in self.timings() {
+                    body.push_str(&format!(
+                        "* {endpoint}: {} requests, min {:.2?}, avg {:.2?}, max {:.2?}\n",
+                        timing.count,
+                        timing.min,
+                        timing.average(),
+                        timing.max
+                    ));
+                }
+                Response::success("text/gemini", body)
+            }
+            _ => Response::not_found("Path not found"),
+        }
+    }
+}
+
+/// Normalize a path as described in [normalize_paths](RoutedApp::normalize_paths)
+///
+/// Returns `None` if the path goes above the root.
+fn normalize_path(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    let mut trailing = false;
+    for segment in path.split('/') {
+        trailing = true;
+        match segment {
+            "" | "." => continue,
+            ".." => {
+                segments.pop()?;
+                continue;
+            }
+            segment => segments.push(segment),
+        }
+        trailing = false;
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+impl Default for RoutedApp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl<S> Application for RoutedApp<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+        self.respond(request, None).await
+    }
+
+    async fn handle_upload(
+        &self,
+        request: Request,
+        upload: Upload<'_>,
+    ) -> Result<Response, AnyError> {
+        self.respond(request, Some(upload)).await
+    }
+}
+
+impl<S> RoutedApp<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    /// Route a request, then apply the status pages
+    async fn respond(
+        &self,
+        mut request: Request,
+        upload: Option<Upload<'_>>,
+    ) -> Result<Response, AnyError> {
+        if let Some(path) = self.validation_path(&request.path) {
+            request.path = path;
+            // Status pages are skipped so that the real status is reported
+            let response = self.route_request(request, upload).await?;
+            return Ok(Response::new(response.code, response.meta));
+        }
+        if self.status_pages.is_empty() {
+            return self.route_request(request, upload).await;
+        }
+
+        let path = request.path.clone();
+        let response = match self.route_request(request, upload).await {
+            Ok(response) => response,
+            Err(err) => match err.downcast_ref::<GemError>() {
+                Some(gem_err) if self.status_pages.contains_key(&gem_err.error_type.code()) => {
+                    Response::from(*err.downcast::<GemError>().unwrap())
+                }
+                _ => return Err(err),
+            },
+        };
+        Ok(match self.status_pages.get(&response.code) {
+            Some(page) if !response.has_body() => page(&path, &response),
+            _ => response,
+        })
+    }
+
+    /// Route a request, or an upload, to its handler
+    async fn route_request(
+        &self,
+        mut request: Request,
+        upload: Option<Upload<'_>>,
+    ) -> Result<Response, AnyError> {
+        request.extensions.insert(State(self.state.clone()));
+
+        #[cfg(feature = "nfc")]
+        if self.nfc_paths {
+            use unicode_normalization::UnicodeNormalization;
+            request.path = request.path.nfc().collect();
+        }
+
+        if self.normalize_paths {
+            match normalize_path(&request.path) {
+                Some(path) => request.path = path,
+                None => return Ok(Response::bad_request("Invalid path")),
+            }
+        }
+
+        let rewrite = self
+            .rewrites
+            .iter()
+            .find_map(|rule| Some((rule, rule.apply(&request.path)?)));
+        if let Some((rule, path)) = rewrite {
+            if rule.redirect {
+                let mut url = format!("{}{path}", request.script.trim_end_matches('/'));
+                if let Some(query) = &request.query {
+                    url.push('?');
+                    url.push_str(query);
+                }
+                return Ok(Response::redirect_perm(url));
+            }
+            request.path = path;
+        }
+
+        if let Some(config) = &self.introspection {
+            if let Some(path) = config.reserved(&request.path) {
+                return Ok(self.handle_introspection(config, path, &request));
+            }
+        }
+
+        if self.middleware.is_empty() {
+            return self.dispatch(request, upload).await;
+        }
+        // The upload is given to the route the first time the chain ends
+        let upload = Mutex::new(upload);
+        let endpoint = |request| {
+            let upload = upload.lock().unwrap_or_else(|err| err.into_inner()).take();
+            Box::pin(self.dispatch(request, upload)) as Pin<Box<_>>
+        };
+        let next = Next {
+            middleware: &self.middleware,
+            endpoint: &endpoint,
+        };
+        next.run(request).await
+    }
+
+    /// Give a request, or an upload, to the route that matches its path
+    async fn dispatch(
+        &self,
+        request: Request,
+        upload: Option<Upload<'_>>,
+    ) -> Result<Response, AnyError> {
+        let route = match self
+            .routers
+            .values()
+            .rev()
+            .find_map(|router| router.recognize(&request.path).ok())
+        {
+            Some(val) => val,
+            None => {
+                return Ok(Response::not_found("Path not found"));
+            }
+        };
+
+        let params = route.params();
+        let handler = **route.handler();
+        let result = async {
+            match upload {
+                Some(upload) => handler.handle_upload(params, request, upload).await,
+                None => handler.handle(params, request).await,
+            }
+        };
+
+        #[cfg(feature = "timing")]
+        {
+            let start = Instant::now();
+            let result = result.await;
+            let elapsed = start.elapsed();
+            self.timings
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .entry(handler.endpoint().to_owned())
+                .and_modify(|timing| timing.record(elapsed))
+                .or_insert_with(|| RouteTiming::new(elapsed));
+            result
+        }
+        #[cfg(not(feature = "timing"))]
+        result.await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[route("/foo/:bar", mime = "text/plain")]
+    async fn foo_route(_request: Request, bar: &str) -> Result<Response, AnyError> {
+        Ok(Response::success("text/plain", bar.to_owned()))
+    }
+
+    #[tokio::test]
+    async fn test_validation_prefix() {
+        let mut app = RoutedApp::new();
+        app.register(&foo_route);
+
+        let response = app
+            .handle_request(Request::mock("/_validate/foo/bar"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 51);
+
+        app.validation_prefix("/_validate/");
+        let response = app
+            .handle_request(Request::mock("/_validate/foo/bar"))
+            .await
+            .unwrap();
+        assert_eq!((response.code, response.meta.as_str()), (20, "text/plain"));
+        assert_eq!(response.len(), None);
+        for path in ["/_validate", "/_validate/foo", "/_validatefoo/bar"] {
+            let response = app.handle_request(Request::mock(path)).await.unwrap();
+            assert_eq!(response.code, 51, "{path}");
+        }
+
+        let response = app.handle_request(Request::mock("/foo/bar")).await.unwrap();
+        assert_eq!(response.read_body().await, "bar");
+    }
+
+    #[route("/gone")]
+    async fn gone_route(_request: Request) -> Result<Response, AnyError> {
+        Err(GemError::gone("Moved away").into())
+    }
+
+    #[tokio::test]
+    async fn test_on_status() {
+        let mut app = RoutedApp::new();
+        app.register(&foo_route);
+        app.register(&gone_route);
+        app.on_status(51, |path, _| {
+            Response::gemtext(format!("# {path} is missing"))
+        });
+        app.on_status(52, |_, response| {
+            Response::gone(format!("{}!", response.meta))
+        });
+        app.on_status(20, |_, _| Response::gemtext("replaced"));
+
+        let response = app.handle_request(Request::mock("/missing")).await.unwrap();
+        assert_eq!(response.code, 20);
+        assert_eq!(response.read_body().await, "# /missing is missing");
+
+        let response = app.handle_request(Request::mock("/gone")).await.unwrap();
+        assert_eq!((response.code, response.meta.as_str()), (52, "Moved away!"));
+
+        // Responses with a body are not replaced
+        let response = app.handle_request(Request::mock("/foo/bar")).await.unwrap();
+        assert_eq!(response.read_body().await, "bar");
+
+        // Neither are validation requests
+        app.validation_prefix("/_validate");
+        let response = app
+            .handle_request(Request::mock("/_validate/missing"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 51);
+    }
+
+    /// Adds its name to the meta of the response, or blocks a path
+    struct Tag(&'static str, &'static str);
+
+    #[async_trait]
+    impl Middleware for Tag {
+        async fn handle(&self, request: Request, next: Next<'_>) -> Result<Response, AnyError> {
+            if request.path == self.1 {
+                return Ok(Response::error_perm(self.0));
+            }
+            let response = next.run(request).await?;
+            Ok(Response::new(
+                response.code,
+                format!("{} {}", response.meta, self.0),
+            ))
+        }
+    }
+
+    struct Notes;
+
+    #[async_trait]
+    impl Route for Notes {
+        fn endpoint(&self) -> &str {
+            "/notes/:name"
+        }
+
+        async fn handle(&self, _params: &Params, _request: Request) -> Result<Response, AnyError> {
+            Ok(Response::text("notes"))
+        }
+
+        async fn handle_upload(
+            &self,
+            params: &Params,
+            _request: Request,
+            upload: Upload<'_>,
+        ) -> Result<Response, AnyError> {
+            let name = params.find("name").unwrap().to_owned();
+            let body = upload.bytes().await?;
+            Ok(Response::new(20, format!("{name} {body:?}")))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        use crate::request::TitanParams;
+
+        let mut app = RoutedApp::new();
+        app.register_owned(Notes);
+        app.register(&foo_route);
+        app.with_middleware(Tag("tag", "/notes/blocked"));
+
+        let upload = |body: &'static [u8]| {
+            let params = TitanParams {
+                size: body.len() as u64,
+                mime: "text/plain".to_owned(),
+                token: None,
+            };
+            Upload::new(params, body)
+        };
+
+        let response = app
+            .handle_upload(Request::mock("/notes/a"), upload(b"hi"))
+            .await
+            .unwrap();
+        assert_eq!(response.meta, "a b\"hi\" tag");
+
+        let response = app
+            .handle_upload(Request::mock("/notes/blocked"), upload(b"hi"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 50);
+
+        // Routes that don't accept uploads reject them
+        let response = app
+            .handle_upload(Request::mock("/foo/a"), upload(b"hi"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 59);
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        let mut app = RoutedApp::new();
+        app.register(&foo_route);
+        app.with_middleware(Tag("outer", "/foo/a"));
+        app.with_middleware(Tag("inner", "/foo/b"));
+
+        let response = app.handle_request(Request::mock("/foo/c")).await.unwrap();
+        assert_eq!(response.meta, "text/plain inner outer");
+
+        let response = app.handle_request(Request::mock("/missing")).await.unwrap();
+        assert_eq!(response.meta, "Path not found inner outer");
+
+        let response = app.handle_request(Request::mock("/foo/a")).await.unwrap();
+        assert_eq!((response.code, response.meta.as_str()), (50, "outer"));
+
+        let response = app.handle_request(Request::mock("/foo/b")).await.unwrap();
+        assert_eq!(response.meta, "inner outer");
+    }
+
+    #[cfg(feature = "nfc")]
+    #[route("/caf\u{e9}")]
+    async fn cafe_route(_request: Request) -> Result<Response, AnyError> {
+        Ok(Response::gemtext("Coffee"))
+    }
+
+    #[cfg(feature = "nfc")]
+    #[tokio::test]
+    async fn test_nfc_paths() {
+        let nfc = "/caf\u{e9}";
+        let nfd = "/cafe\u{301}";
+
+        let mut app = RoutedApp::new();
+        app.register(&foo_route);
+        app.register(&cafe_route);
+        let response = app.handle_request(Request::mock(nfd)).await.unwrap();
+        assert_eq!(response.code, 51);
+        let response = app
+            .handle_request(Request::mock("/foo/cafe\u{301}"))
+            .await
+            .unwrap();
+        assert_eq!(response.read_body().await, "cafe\u{301}");
+
+        app.nfc_paths(true);
+        for path in [nfc, nfd] {
+            let response = app.handle_request(Request::mock(path)).await.unwrap();
+            assert_eq!(response.code, 20, "{path}");
+        }
+        let response = app
+            .handle_request(Request::mock("/foo/cafe\u{301}"))
+            .await
+            .unwrap();
+        assert_eq!(response.read_body().await, "caf\u{e9}");
+    }
+
+    #[tokio::test]
+    async fn test_introspection() {
+        let mut app = RoutedApp::new();
+        app.register(&foo_route);
+
+        let response = app
+            .handle_request(Request::mock("/_gemfra/routes"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 51);
+
+        app.introspection(Introspection::new("/_gemfra/"));
+        let response = app
+            .handle_request(Request::mock("/_gemfra/routes"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 20);
+        let body = response.read_body().await;
+        assert!(body.contains("* /foo/:bar (text/plain)\n"), "{body}");
+
+        app.introspection(Introspection::new("/_gemfra").allow_cert("SHA256:AB:CD"));
+        let response = app
+            .handle_request(Request::mock("/_gemfra/routes"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 60);
+
+        // Hashes are compared by fingerprint, like the stats route
+        let now = chrono::Utc::now().fixed_offset();
+        for (hash, code) in [("ef", 61), ("abcd", 20), ("AB:CD", 20)] {
+            let mut request = Request::mock("/_gemfra/routes");
+            request.client_cert = Some(crate::request::Certificate {
+                hash: hash.to_owned(),
+                issuer: Default::default(),
+                subject: Default::default(),
+                not_after: now,
+                not_before: now,
+            });
+            let response = app.handle_request(request).await.unwrap();
+            assert_eq!(response.code, code);
+        }
+    }
+
+    #[cfg(feature = "timing")]
+    #[tokio::test]
+    async fn test_timings() {
+        let mut app = RoutedApp::new();
+        app.register(&foo_route);
+        app.introspection(Introspection::new("/_gemfra"));
+        for path in ["/foo/a", "/foo/b", "/missing"] {
+            app.handle_request(Request::mock(path)).await.unwrap();
+        }
+
+        let timings = app.timings();
+        assert_eq!(timings.len(), 1);
+        assert_eq!(timings[0].0, "/foo/:bar");
+        assert_eq!(timings[0].1.count, 2);
+        assert!(timings[0].1.min <= timings[0].1.average());
+
+        let response = app
+            .handle_request(Request::mock("/_gemfra/timings"))
+            .await
+            .unwrap();
+        let body = response.read_body().await;
+        assert!(body.contains("* /foo/:bar: 2 requests"), "{body}");
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn test_timing_average() {
+        let mut timing = RouteTiming::new(Duration::from_secs(3));
+        timing.record(Duration::from_millis(1500));
+        assert_eq!(timing.average(), Duration::from_millis(2250));
+
+        // More requests than fit in a u32 don't panic
+        timing.count = u64::from(u32::MAX) + 1;
+        timing.total = Duration::from_secs(1 << 32);
+        assert_eq!(timing.average(), Duration::from_secs(1));
+
+        timing.total = Duration::MAX;
+        timing.record(Duration::from_secs(1));
+        assert_eq!(timing.total, Duration::MAX);
+    }
+
+    #[tokio::test]
+    async fn test_route_fn() {
+        let mut app = RoutedApp::new();
+        app.register_owned(route_fn!("/hello", |request: Request| async move {
+            Ok(Response::text(request.path))
+        }));
+
+        let response = app.handle_request(Request::mock("/hello")).await.unwrap();
+        assert_eq!(response.code, 20);
+        assert_eq!(response.read_body().await, "/hello");
+    }
+
+    #[tokio::test]
+    async fn test_static_site() {
+        let app = static_site!("tests/site");
+        assert_eq!(
+            app.routes().collect::<Vec<_>>(),
+            [
+                "/blog/hello.txt",
+                "/blog/index.gmi",
+                "/data.zip",
+                "/index.gmi"
+            ]
+        );
+
+        let response = app.handle_request(Request::mock("/blog/")).await.unwrap();
+        assert_eq!(response.meta, "text/gemini");
+        assert_eq!(response.read_body().await, "# Blog\n");
+
+        let response = app.handle_request(Request::mock("/")).await.unwrap();
+        assert_eq!(response.read_body().await, "# Home\n");
+
+        let response = app
+            .handle_request(Request::mock("/blog/hello.txt"))
+            .await
+            .unwrap();
+        assert_eq!(response.meta, "text/plain");
+
+        // The MIME types come from mime_for_extension
+        let response = app
+            .handle_request(Request::mock("/data.zip"))
+            .await
+            .unwrap();
+        assert_eq!(response.meta, "application/zip");
+    }
+
+    #[route("/number/:value", on_error = bad_request)]
+    async fn number_route(_request: Request, value: &str) -> Result<Response, AnyError> {
+        if value == "gone" {
+            return Err(crate::error::GemError::gone("Gone").into());
+        }
+        let value: u32 = value.parse()?;
+        Ok(Response::text(value.to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_on_error() {
+        let mut app = RoutedApp::new();
+        app.register(&number_route);
+
+        let response = app
+            .handle_request(Request::mock("/number/5"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 20);
+        let response = app
+            .handle_request(Request::mock("/number/x"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 59);
+        let response = app
+            .handle_request(Request::mock("/number/gone"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 52);
+    }
+
+    #[route("/search")]
+    async fn search_route(
+        _request: Request,
+        #[query] tag: Vec<String>,
+        #[query] year: Vec<i32>,
+        #[query] page: Option<u32>,
+    ) -> Result<Response, AnyError> {
+        Ok(Response::text(format!("{tag:?} {year:?} {page:?}")))
+    }
+
+    #[tokio::test]
+    async fn test_query_params() {
+        let mut app = RoutedApp::new();
+        app.register(&search_route);
+
+        let search = |query: &str| {
+            let mut request = Request::mock("/search");
+            request.query = Some(query.to_owned());
+            app.handle_request(request)
+        };
+
+        let response = search("tag=a&year=2020&tag=b%20c&year=2021").await.unwrap();
+        assert_eq!(
+            response.read_body().await,
+            r#"["a", "b c"] [2020, 2021] None"#
+        );
+        let response = search("page=2").await.unwrap();
+        assert_eq!(response.read_body().await, "[] [] Some(2)");
+
+        for query in ["year=2020&year=x", "page=x"] {
+            let err = search(query).await.err().unwrap();
+            let err = err.downcast::<crate::error::GemError>().unwrap();
+            assert_eq!(err.error_type, crate::error::GemErrorType::BadRequest);
+        }
+    }
+
+    #[route("/foo/*rest")]
+    async fn wildcard_route(_request: Request, rest: &str) -> Result<Response, AnyError> {
+        Ok(Response::text(format!("wildcard {rest}")))
+    }
+
+    #[tokio::test]
+    async fn test_priority() {
+        let mut app = RoutedApp::new();
+        app.register(&wildcard_route);
+        app.register(&foo_route);
+
+        let response = app.handle_request(Request::mock("/foo/a")).await.unwrap();
+        assert_eq!(response.read_body().await, "a");
+
+        let mut app = RoutedApp::new();
+        app.register(&foo_route);
+        app.register_with_priority(&wildcard_route, 1);
+
+        let response = app.handle_request(Request::mock("/foo/a")).await.unwrap();
+        assert_eq!(response.read_body().await, "wildcard a");
+
+        // Both routes match, and the less specific one has the higher priority
+        let mut app = RoutedApp::new();
+        app.register_with_priority(&foo_route, -1);
+        app.register(&wildcard_route);
+
+        let response = app.handle_request(Request::mock("/foo/a")).await.unwrap();
+        assert_eq!(response.read_body().await, "wildcard a");
+    }
+
+    #[route("/a/:bar", "/b/:bar")]
+    async fn alias_route(_request: Request, bar: &str) -> Result<Response, AnyError> {
+        Ok(Response::text(bar.to_owned()))
+    }
+
+    #[tokio::test]
+    async fn test_aliases() {
+        let mut app = RoutedApp::new();
+        app.register(&alias_route);
+
+        for path in ["/a/x", "/b/x"] {
+            let response = app.handle_request(Request::mock(path)).await.unwrap();
+            assert_eq!(response.read_body().await, "x");
+        }
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/a//b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize_path("//a/b/").as_deref(), Some("/a/b/"));
+        assert_eq!(normalize_path("/a/./b/.").as_deref(), Some("/a/b/"));
+        assert_eq!(normalize_path("/a/../b").as_deref(), Some("/b"));
+        assert_eq!(normalize_path("/a/b/..").as_deref(), Some("/a/"));
+        assert_eq!(normalize_path("").as_deref(), Some("/"));
+        assert_eq!(normalize_path("/../a"), None);
+        assert_eq!(normalize_path("/a/../../b"), None);
+    }
+
+    #[tokio::test]
+    async fn test_normalize_paths() {
+        let mut app = RoutedApp::new();
+        app.register(&foo_route);
+
+        let response = app.handle_request(Request::mock("/foo//a")).await.unwrap();
+        assert_eq!(response.code, 51);
+
+        app.normalize_paths(true);
+        let response = app.handle_request(Request::mock("/foo//a")).await.unwrap();
+        assert_eq!(response.read_body().await, "a");
+
+        let response = app
+            .handle_request(Request::mock("/foo/../../a"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 59);
+    }
+
+    #[route("/state")]
+    async fn state_route(
+        _request: Request,
+        state: State<&'static str>,
+    ) -> Result<Response, AnyError> {
+        Ok(Response::text(*state))
+    }
+
+    #[tokio::test]
+    async fn test_state() {
+        let mut app = RoutedApp::with_state("shared");
+        app.register(&state_route);
+
+        let response = app.handle_request(Request::mock("/state")).await.unwrap();
+        assert_eq!(response.read_body().await, "shared");
+    }
+
+    #[tokio::test]
+    async fn test_cached() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        static CALLS: AtomicU32 = AtomicU32::new(0);
+        let route = FnRoute::new("/count", |_request| async {
+            Ok(Response::text(
+                CALLS.fetch_add(1, Ordering::SeqCst).to_string(),
+            ))
+        });
+        let mut app = RoutedApp::new();
+        app.register_owned(Cached::new(route, Duration::from_secs(60)));
+
+        for _ in 0..2 {
+            let response = app.handle_request(Request::mock("/count")).await.unwrap();
+            assert_eq!(response.read_body().await, "0");
+        }
+
+        let mut request = Request::mock("/count");
+        request.query = Some("a".to_owned());
+        let response = app.handle_request(request).await.unwrap();
+        assert_eq!(response.read_body().await, "1");
+    }
+
+    #[tokio::test]
+    async fn test_cached_max_entries() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        static CALLS: AtomicU32 = AtomicU32::new(0);
+        let route = FnRoute::new("/count", |_request| async {
+            Ok(Response::text(
+                CALLS.fetch_add(1, Ordering::SeqCst).to_string(),
+            ))
+        });
+        let cached = Cached::new(route, Duration::from_secs(60)).max_entries(2);
+        let mut app = RoutedApp::new();
+        app.register_owned(cached);
+
+        let request = |query: &str| Request::builder("/count").query(query).build();
+        for (query, body) in [("a", "0"), ("b", "1"), ("a", "0"), ("c", "2")] {
+            let response = app.handle_request(request(query)).await.unwrap();
+            assert_eq!(response.read_body().await, body);
+        }
+        // The oldest response was evicted to make room for `c`
+        let response = app.handle_request(request("a")).await.unwrap();
+        assert_eq!(response.read_body().await, "3");
+    }
+
+    #[tokio::test]
+    async fn test_sitemap() {
+        let mut app = RoutedApp::new();
+        app.register(&foo_route);
+        app.register_owned(FnRoute::new("/about", |_request| async {
+            Ok(Response::text(""))
+        }));
+        app.register_owned(SiteMap::new("/sitemap", app.routes()).title("Index"));
+
+        let mut request = Request::mock("/sitemap");
+        request.script = "/cgi/".to_owned();
+        let response = app.handle_request(request).await.unwrap();
+        assert_eq!(
+            response.read_body().await,
+            "# Index\n\n=> /cgi/about /about\n\n## Dynamic pages\n\n* /foo/:bar\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let route = FnRoute::new("/search", |_request| async { Ok(Response::text("ok")) });
+        let mut app = RoutedApp::new();
+        app.register_owned(RateLimited::new(route, 2, Duration::from_secs(60)));
+        app.register(&foo_route);
+
+        let request = |path: &str, addr: &str| {
+            let mut request = Request::mock(path);
+            request.remote_addr = addr.to_owned();
+            request
+        };
+        for _ in 0..2 {
+            let response = app.handle_request(request("/search", "1.1.1.1")).await;
+            assert_eq!(response.unwrap().code, 20);
+        }
+        let response = app
+            .handle_request(request("/search", "1.1.1.1"))
+            .await
+            .unwrap();
+        assert_eq!((response.code, response.meta.as_str()), (44, "60"));
+
+        // Other clients and routes are not limited
+        let response = app.handle_request(request("/search", "2.2.2.2")).await;
+        assert_eq!(response.unwrap().code, 20);
+        let response = app.handle_request(request("/foo/a", "1.1.1.1")).await;
+        assert_eq!(response.unwrap().code, 20);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_backend() {
+        use crate::session::SessionStore;
+
+        // Two workers that share a backend share their limits
+        let store = Arc::new(SessionStore::new(Duration::from_secs(60)));
+        let route = || FnRoute::new("/search", |_request| async { Ok(Response::text("ok")) });
+        let first = RateLimited::new(route(), 2, Duration::from_secs(60)).backend(store.clone());
+        let second = RateLimited::new(route(), 2, Duration::from_secs(60)).backend(store.clone());
+        let params = Params::new();
+
+        let (first, second): (&dyn Route, &dyn Route) = (&first, &second);
+        for route in [first, second] {
+            let response = route.handle(&params, Request::mock("/search")).await;
+            assert_eq!(response.unwrap().code, 20);
+        }
+        let response = first.handle(&params, Request::mock("/search")).await;
+        assert_eq!(response.unwrap().code, 44);
+
+        let window = store.get("/search 127.0.0.1").unwrap();
+        assert_eq!(window.count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_rewrite() {
+        let mut app = RoutedApp::new();
+        app.register(&foo_route);
+        app.rewrite("/bar", "/foo");
+        app.rewrite_redirect("/old/", "/foo/");
+
+        let response = app.handle_request(Request::mock("/bar/a")).await.unwrap();
+        assert_eq!(response.read_body().await, "a");
+
+        let response = app.handle_request(Request::mock("/barn/a")).await.unwrap();
+        assert_eq!(response.code, 51);
 
-        handler.handle(params, request).await
+        let mut request = Request::mock("/old/a");
+        request.query = Some("q".to_owned());
+        let response = app.handle_request(request).await.unwrap();
+        assert_eq!(response.code, 31);
+        assert_eq!(response.meta, "/foo/a?q");
     }
 }
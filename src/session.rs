@@ -0,0 +1,235 @@
+//! Client sessions
+//!
+//! Gemini clients present their certificate with every request, but there is
+//! no built-in way to keep state between requests. A [SessionStore] associates
+//! a value with a client certificate's
+//! [fingerprint](crate::request::Certificate::fingerprint) for a limited time.
+//!
+//! ### Example
+//!
+//! ```
+//! use std::time::Duration;
+//! use gemfra::{
+//!     error::{GemError, GemErrorType, ToGemError},
+//!     request::Request,
+//!     session::SessionStore,
+//! };
+//!
+//! fn visits(sessions: &SessionStore<u32>, request: &Request) -> Result<u32, GemError> {
+//!     let cert = request
+//!         .client_cert
+//!         .as_ref()
+//!         .replace_gem(GemErrorType::CertNeeded, "A certificate is needed")?;
+//!     let fingerprint = cert.fingerprint();
+//!     let visits = sessions.get(&fingerprint).unwrap_or(0) + 1;
+//!     sessions.set(fingerprint, visits);
+//!     Ok(visits)
+//! }
+//!
+//! let sessions = SessionStore::<u32>::new(Duration::from_secs(60 * 60));
+//! ```
+//!
+//! ### Shared sessions
+//!
+//! A [SessionStore] only lives in the memory of one process, so SCGI
+//! deployments with several workers each see their own sessions. Code that
+//! accepts any [SessionBackend] can instead be given a store that is shared
+//! between processes, such as one backed by Redis in a separate crate.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use crate::error::AnyError;
+
+/// The number of independently locked shards in a [SessionStore]
+const SHARDS: usize = 16;
+
+struct Entry<V> {
+    value: V,
+    expires: Instant,
+}
+
+/// An in-memory store of sessions keyed by certificate fingerprint
+///
+/// Every session expires after it has not been accessed for the store's
+/// time to live. Expired sessions are removed when they are next accessed, or
+/// by calling [evict_expired](SessionStore::evict_expired).
+///
+/// The store is safe to share between requests (e.g. in an
+/// [Arc](std::sync::Arc)). Sessions are spread across several locks so that
+/// concurrent requests rarely wait on each other.
+pub struct SessionStore<V> {
+    shards: Vec<Mutex<HashMap<String, Entry<V>>>>,
+    ttl: Duration,
+}
+
+impl<V> SessionStore<V> {
+    /// Create a new store where sessions live for `ttl` after their last use
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            ttl,
+        }
+    }
+
+    /// Lock the shard that holds `fingerprint`
+    fn shard(&self, fingerprint: &str) -> MutexGuard<'_, HashMap<String, Entry<V>>> {
+        let mut hasher = DefaultHasher::new();
+        fingerprint.hash(&mut hasher);
+        let shard = &self.shards[hasher.finish() as usize % self.shards.len()];
+        shard.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Store a value for the session, returning the previous value
+    pub fn set(&self, fingerprint: impl Into<String>, value: V) -> Option<V> {
+        let fingerprint = fingerprint.into();
+        let entry = Entry {
+            value,
+            expires: Instant::now() + self.ttl,
+        };
+        self.shard(&fingerprint)
+            .insert(fingerprint, entry)
+            .filter(|prev| prev.expires > Instant::now())
+            .map(|prev| prev.value)
+    }
+
+    /// Remove the session, returning its value
+    pub fn remove(&self, fingerprint: &str) -> Option<V> {
+        self.shard(fingerprint)
+            .remove(fingerprint)
+            .filter(|prev| prev.expires > Instant::now())
+            .map(|prev| prev.value)
+    }
+
+    /// Remove all expired sessions
+    pub fn evict_expired(&self) {
+        let now = Instant::now();
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap_or_else(|err| err.into_inner());
+            shard.retain(|_, entry| entry.expires > now);
+        }
+    }
+}
+
+impl<V> SessionStore<V>
+where
+    V: Clone,
+{
+    /// Get the value of the session
+    ///
+    /// Accessing a session extends its life by the store's time to live.
+    pub fn get(&self, fingerprint: &str) -> Option<V> {
+        let now = Instant::now();
+        let mut shard = self.shard(fingerprint);
+        match shard.get_mut(fingerprint) {
+            Some(entry) if entry.expires > now => {
+                entry.expires = now + self.ttl;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                shard.remove(fingerprint);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// A store of sessions keyed by string
+///
+/// This is the interface for session stores that live outside of the
+/// process, so that several processes can share their sessions. The
+/// in-memory [SessionStore] implements it for single process use.
+///
+/// ### Contract
+///
+/// * [get](SessionBackend::get) returns the value that was last
+///   [set](SessionBackend::set) for the key, or `None` if there is no value or
+///   it was [removed](SessionBackend::remove).
+/// * Values may expire. A backend should drop values that have not been
+///   accessed for a while, and an expired value behaves as if it was removed.
+/// * An error means that the store could not be reached. It should not be
+///   used to signal a missing value.
+///
+/// ### Consistency
+///
+/// Each operation is independent, so a `get` followed by a `set` is not
+/// atomic. When two requests update the same key at once, the last `set`
+/// wins. Code built on a backend, such as a rate limiter, should tolerate
+/// being slightly inaccurate under contention. Backends are not expected to
+/// provide stronger guarantees, although they may.
+#[async_trait]
+pub trait SessionBackend<V>: Send + Sync {
+    /// Get the value of the session
+    async fn get(&self, key: &str) -> Result<Option<V>, AnyError>;
+
+    /// Store a value for the session
+    async fn set(&self, key: &str, value: V) -> Result<(), AnyError>;
+
+    /// Remove the session
+    async fn remove(&self, key: &str) -> Result<(), AnyError>;
+}
+
+#[async_trait]
+impl<V> SessionBackend<V> for SessionStore<V>
+where
+    V: Clone + Send + Sync + 'static,
+{
+    async fn get(&self, key: &str) -> Result<Option<V>, AnyError> {
+        Ok(SessionStore::get(self, key))
+    }
+
+    async fn set(&self, key: &str, value: V) -> Result<(), AnyError> {
+        SessionStore::set(self, key, value);
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), AnyError> {
+        SessionStore::remove(self, key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_session_store() {
+        let store = SessionStore::new(Duration::from_secs(60));
+        assert_eq!(store.get("abc"), None);
+        assert_eq!(store.set("abc", 1), None);
+        assert_eq!(store.set("abc", 2), Some(1));
+        assert_eq!(store.get("abc"), Some(2));
+        assert_eq!(store.remove("abc"), Some(2));
+        assert_eq!(store.get("abc"), None);
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let store = SessionStore::new(Duration::ZERO);
+        store.set("abc", 1);
+        assert_eq!(store.get("abc"), None);
+
+        store.set("abc", 1);
+        store.evict_expired();
+        assert!(store.shard("abc").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_session_backend() {
+        let backend: Box<dyn SessionBackend<u32>> =
+            Box::new(SessionStore::new(Duration::from_secs(60)));
+        assert_eq!(backend.get("abc").await.unwrap(), None);
+        backend.set("abc", 1).await.unwrap();
+        assert_eq!(backend.get("abc").await.unwrap(), Some(1));
+        backend.remove("abc").await.unwrap();
+        assert_eq!(backend.get("abc").await.unwrap(), None);
+    }
+}
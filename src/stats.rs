@@ -0,0 +1,349 @@
+//! Request statistics
+//!
+//! [Stats] collects a few simple statistics about the requests that an
+//! application handles: how many requests there were, how many responses were
+//! sent with each status code, and how large the bodies of success responses
+//! were. The statistics are kept in memory and can be served as a gemtext page
+//! with [route](Stats::route), so no external monitoring is needed.
+//!
+//! ### Example
+//!
+//! ```no_run
+//! use gemfra::{protocol::Cgi, routed::RoutedApp, stats::Stats};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let stats = Stats::new();
+//!
+//!     let mut app = RoutedApp::new();
+//!     app.register_owned(stats.route().allow_cert("my-cert-hash"));
+//!
+//!     stats.wrap(app).run_cgi().await;
+//! }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    application::Application,
+    error::AnyError,
+    error::{CloseConnection, GemError, GemErrorType},
+    request::{Request, Upload},
+    response::Response,
+};
+
+#[cfg(feature = "routed")]
+use crate::{
+    request::CertAllowlist,
+    routed::{Params, Route},
+};
+
+/// The upper bounds of the response body size buckets, in bytes
+const BUCKETS: [(u64, &str); 5] = [
+    (1, "Empty"),
+    (1024, "Under 1 KiB"),
+    (16 * 1024, "Under 16 KiB"),
+    (256 * 1024, "Under 256 KiB"),
+    (1024 * 1024, "Under 1 MiB"),
+];
+
+/// A snapshot of the collected statistics
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// The number of requests that were handled
+    pub requests: u64,
+    /// The number of responses for each status code
+    pub codes: BTreeMap<u32, u64>,
+    /// The number of success response bodies that were sent
+    pub response_bodies: u64,
+    /// The total size of every success response body that was sent
+    pub response_bytes: u64,
+    /// The number of response bodies in each size bucket
+    ///
+    /// The buckets are: empty, under 1 KiB, under 16 KiB, under 256 KiB,
+    /// under 1 MiB, and 1 MiB or more.
+    pub response_sizes: [u64; 6],
+}
+
+impl StatsSnapshot {
+    /// Get the average size of a success response body
+    pub fn average_response_size(&self) -> u64 {
+        self.response_bytes
+            .checked_div(self.response_bodies)
+            .unwrap_or(0)
+    }
+
+    /// Render the statistics as gemtext
+    pub fn to_gemtext(&self) -> String {
+        let mut body = format!(
+            "# Stats\n\n* Requests: {}\n* Response bodies sent: {}\n* Average response body size: {} bytes\n\n## Responses\n\n",
+            self.requests,
+            self.response_bodies,
+            self.average_response_size()
+        );
+        for (code, count) in &self.codes {
+            body.push_str(&format!("* {code}: {count}\n"));
+        }
+        body.push_str("\n## Response body sizes\n\n");
+        let labels = BUCKETS
+            .iter()
+            .map(|(_, label)| *label)
+            .chain(std::iter::once("1 MiB or more"));
+        for (label, count) in labels.zip(self.response_sizes) {
+            body.push_str(&format!("* {label}: {count}\n"));
+        }
+        body
+    }
+
+    fn record_response_body(&mut self, size: u64) {
+        self.response_bodies += 1;
+        self.response_bytes += size;
+        let bucket = BUCKETS
+            .iter()
+            .position(|(limit, _)| size < *limit)
+            .unwrap_or(BUCKETS.len());
+        self.response_sizes[bucket] += 1;
+    }
+}
+
+/// An in-process statistics collector
+///
+/// Stats can be cloned cheaply, and every clone records into the same
+/// statistics. Requests are only recorded by applications that were
+/// [wrapped](Stats::wrap).
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    data: Arc<Mutex<StatsSnapshot>>,
+}
+
+impl Stats {
+    /// Create a new collector without any statistics
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the requests handled by `app`
+    ///
+    /// The status code of every response is recorded, including the responses
+    /// for errors. Errors that aren't a [GemError] are recorded as a __42__
+    /// CGI Error, even if the app sends a custom
+    /// [internal error response](Application::internal_error_response).
+    ///
+    /// The body size of a success response is recorded once it has been sent,
+    /// so bodies that fail to send are not counted. Request bodies, such as
+    /// uploads, are not recorded.
+    pub fn wrap<A>(&self, app: A) -> StatsApp<A> {
+        StatsApp {
+            app,
+            stats: self.clone(),
+        }
+    }
+
+    /// Get the statistics that have been collected so far
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.data.lock().unwrap().clone()
+    }
+
+    /// Create a route that serves the statistics at `/_gemfra/stats`
+    ///
+    /// The route is only available to the certificates allowed with
+    /// [allow_cert](StatsRoute::allow_cert). If no certificates are allowed,
+    /// every request is rejected.
+    #[cfg(feature = "routed")]
+    pub fn route(&self) -> StatsRoute {
+        StatsRoute {
+            stats: self.clone(),
+            allowed_certs: CertAllowlist::default(),
+        }
+    }
+
+    fn record_code(&self, code: u32) {
+        let mut data = self.data.lock().unwrap();
+        data.requests += 1;
+        *data.codes.entry(code).or_default() += 1;
+    }
+}
+
+/// An application that records its requests into [Stats]
+///
+/// This is created with [wrap](Stats::wrap).
+pub struct StatsApp<A> {
+    app: A,
+    stats: Stats,
+}
+
+#[async_trait]
+impl<A> Application for StatsApp<A>
+where
+    A: Application + Send + Sync,
+{
+    async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+        let result = self.app.handle_request(request).await;
+        self.record(result)
+    }
+
+    async fn handle_upload(
+        &self,
+        request: Request,
+        upload: Upload<'_>,
+    ) -> Result<Response, AnyError> {
+        let result = self.app.handle_upload(request, upload).await;
+        self.record(result)
+    }
+
+    fn inner(&self) -> Option<&(dyn Application + Send + Sync)> {
+        Some(&self.app)
+    }
+}
+
+impl<A> StatsApp<A>
+where
+    A: Application,
+{
+    /// Record the status of a response, and its body size once it is sent
+    fn record(&self, result: Result<Response, AnyError>) -> Result<Response, AnyError> {
+        let response = match result {
+            Ok(response) => response,
+            // No response is sent for a closed connection
+            Err(err) if err.is::<CloseConnection>() => return Err(err),
+            Err(err) => {
+                // The response for other errors isn't built here, since that
+                // is up to the protocol and may have side effects
+                let code = match err.downcast_ref::<GemError>() {
+                    Some(err) => err.error_type.code(),
+                    None => GemErrorType::RuntimeError.code(),
+                };
+                self.stats.record_code(code);
+                return Err(err);
+            }
+        };
+        self.stats.record_code(response.code);
+        if response.code / 10 != 2 {
+            return Ok(response);
+        }
+
+        let header = response.header().len() as u64;
+        let stats = self.stats.clone();
+        Ok(response.on_sent(move |bytes| {
+            let mut data = stats.data.lock().unwrap();
+            data.record_response_body(bytes.saturating_sub(header));
+        }))
+    }
+}
+
+/// A route that serves [Stats] as gemtext at `/_gemfra/stats`
+///
+/// This is created with [route](Stats::route).
+#[cfg(feature = "routed")]
+pub struct StatsRoute {
+    stats: Stats,
+    allowed_certs: CertAllowlist,
+}
+
+#[cfg(feature = "routed")]
+impl StatsRoute {
+    /// Allow a client certificate (by its [hash](crate::request::Certificate::hash))
+    /// to view the statistics
+    ///
+    /// The hash is compared by [fingerprint](crate::request::Certificate::fingerprint).
+    pub fn allow_cert(mut self, hash: impl AsRef<str>) -> Self {
+        self.allowed_certs.allow(hash.as_ref());
+        self
+    }
+}
+
+#[cfg(feature = "routed")]
+#[async_trait]
+impl<S> Route<S> for StatsRoute {
+    fn endpoint(&self) -> &str {
+        "/_gemfra/stats"
+    }
+
+    fn content_hint(&self) -> Option<&str> {
+        Some("text/gemini")
+    }
+
+    async fn handle(&self, _params: &Params, request: Request) -> Result<Response, AnyError> {
+        Ok(match &request.client_cert {
+            Some(cert) if self.allowed_certs.allows(cert) => {
+                Response::gemtext(self.stats.snapshot().to_gemtext())
+            }
+            Some(_) => Response::cert_not_authorised("Certificate not Authorised"),
+            None => Response::cert_required("Certificate needed"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestApp;
+
+    #[async_trait]
+    impl Application for TestApp {
+        async fn handle_request(&self, request: Request) -> Result<Response, AnyError> {
+            match request.path.as_str() {
+                "/" => Ok(Response::gemtext("Hello")),
+                "/big" => Ok(Response::text("a".repeat(2000))),
+                "/missing" => Err(GemError::not_found("Missing").into()),
+                "/drop" => Err(CloseConnection::new("dropped").into()),
+                _ => Err("oops".into()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let stats = Stats::new();
+        let app = stats.wrap(TestApp);
+        for path in ["/", "/big", "/missing", "/error", "/drop", "/"] {
+            if let Ok(response) = app.handle_request(Request::mock(path)).await {
+                response.send_async(&mut Vec::new()).await.unwrap();
+            }
+        }
+        // A body that isn't sent isn't counted, and neither is a closed connection
+        app.handle_request(Request::mock("/")).await.ok().unwrap();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests, 6);
+        assert_eq!(snapshot.codes, BTreeMap::from([(20, 4), (42, 1), (51, 1)]));
+        assert_eq!(snapshot.response_bodies, 3);
+        assert_eq!(snapshot.response_bytes, 2010);
+        assert_eq!(snapshot.average_response_size(), 670);
+        assert_eq!(snapshot.response_sizes, [0, 2, 1, 0, 0, 0]);
+        assert!(snapshot.to_gemtext().contains("* 51: 1\n"));
+    }
+
+    #[cfg(feature = "routed")]
+    #[tokio::test]
+    async fn test_stats_route() {
+        let route: &dyn Route = &Stats::new().route().allow_cert("SHA256:AB:CD");
+        let params = Params::new();
+
+        let response = route
+            .handle(&params, Request::mock("/_gemfra/stats"))
+            .await
+            .unwrap();
+        assert_eq!(response.code, 60);
+
+        let now = chrono::Utc::now().fixed_offset();
+        for (hash, code) in [("ef", 61), ("abcd", 20)] {
+            let mut request = Request::mock("/_gemfra/stats");
+            request.client_cert = Some(crate::request::Certificate {
+                hash: hash.to_owned(),
+                issuer: Default::default(),
+                subject: Default::default(),
+                not_after: now,
+                not_before: now,
+            });
+            let response = route.handle(&params, request).await.unwrap();
+            assert_eq!(response.code, code);
+        }
+    }
+}